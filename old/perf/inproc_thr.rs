@@ -29,219 +29,95 @@
     along with this program.  If not, see <http://www.gnu.org/licenses/>.
 */
 
-// #include "../include/zmq.h"
-
-// #include <stdio.h>
-// #include <stdlib.h>
-// #include <string.h>
-
-// #include "platform.hpp"
-
-// #if defined ZMQ_HAVE_WINDOWS
-// #include <windows.h>
-// #include <process.h>
-// #else
-// #include <pthread.h>
-// #endif
-
-static int message_count;
-static size_t message_size;
-
-// #if defined ZMQ_HAVE_WINDOWS
-static unsigned int __stdcall worker (ctx: *mut c_void)
-// #else
-static void *worker (ctx: *mut c_void)
-// #endif
-{
-    s: *mut c_void;
-    rc: i32;
-    i: i32;
-let mut msg = ZmqMessage::default();
-
-    s = zmq_socket (ctx, ZMQ_PUSH);
-    if (!s) {
-        printf ("error in zmq_socket: %s\n", zmq_strerror (errno));
-        exit (1);
-    }
-
-    rc = zmq_connect (s, "inproc://thr_test");
-    if (rc != 0) {
-        printf ("error in zmq_connect: %s\n", zmq_strerror (errno));
-        exit (1);
-    }
-
-    for (i = 0; i != message_count; i+= 1) {
-        rc = zmq_msg_init_size (&msg, message_size);
-        if (rc != 0) {
-            printf ("error in zmq_msg_init_size: %s\n", zmq_strerror (errno));
-            exit (1);
+//  Throughput of the lock-free pipe underneath inproc:// pipes. The worker
+//  thread plays the PUSH socket: it writes message_count messages of
+//  message_size bytes, flushing each one as a send does, and wakes the
+//  reading thread whenever the flush finds it asleep. The main thread plays
+//  the PULL socket.
+
+use std::env;
+use std::process::exit;
+use std::thread;
+use std::time::Instant;
+
+use zeromq::ypipe::{ypipe, ZmqYPipeReadEnd, ZmqYPipeWriteEnd};
+use zeromq::ypipe_base::{ZmqYPipeReader, ZmqYPipeWriter};
+
+//  MESSAGE_PIPE_GRANULARITY, as used by inproc:// pipes.
+const GRANULARITY: usize = 256;
+
+fn worker(
+    mut pipe: ZmqYPipeWriteEnd<Vec<u8>, GRANULARITY>,
+    reader: thread::Thread,
+    message_size: usize,
+    message_count: usize,
+) {
+    for _ in 0..message_count {
+        pipe.write(vec![0u8; message_size], false);
+        if !pipe.flush() {
+            reader.unpark();
         }
-// #if defined ZMQ_MAKE_VALGRIND_HAPPY
-        memset (zmq_msg_data (&msg), 0, message_size);
-// #endif
-
-        rc = zmq_sendmsg (s, &msg, 0);
-        if (rc < 0) {
-            printf ("error in zmq_sendmsg: %s\n", zmq_strerror (errno));
-            exit (1);
-        }
-        rc = zmq_msg_close (&msg);
-        if (rc != 0) {
-            printf ("error in zmq_msg_close: %s\n", zmq_strerror (errno));
-            exit (1);
-        }
-    }
-
-    rc = zmq_close (s);
-    if (rc != 0) {
-        printf ("error in zmq_close: %s\n", zmq_strerror (errno));
-        exit (1);
     }
-
-// #if defined ZMQ_HAVE_WINDOWS
-    return 0;
-// #else
-    return null_mut();
-// #endif
 }
 
-int main (argc: i32, char *argv[])
-{
-// #if defined ZMQ_HAVE_WINDOWS
-    HANDLE local_thread;
-// #else
-    pZmqThread local_thread;
-// #endif
-    ctx: *mut c_void;
-    s: *mut c_void;
-    rc: i32;
-    i: i32;
-let mut msg = ZmqMessage::default();
-    watch: *mut c_void;
-    unsigned long elapsed;
-    unsigned long throughput;
-    double megabits;
-
-    if (argc != 3) {
-        printf ("usage: inproc_thr <message-size> <message-count>\n");
-        return 1;
-    }
-
-    message_size = atoi (argv[1]);
-    message_count = atoi (argv[2]);
-
-    ctx = zmq_init (1);
-    if (!ctx) {
-        printf ("error in zmq_init: %s\n", zmq_strerror (errno));
-        return -1;
-    }
-
-    s = zmq_socket (ctx, ZMQ_PULL);
-    if (!s) {
-        printf ("error in zmq_socket: %s\n", zmq_strerror (errno));
-        return -1;
+//  Receives the next message, sleeping until the worker wakes us while the
+//  pipe is empty.
+fn recv(pipe: &mut ZmqYPipeReadEnd<Vec<u8>, GRANULARITY>) -> Vec<u8> {
+    loop {
+        match pipe.read() {
+            Some(msg) => return msg,
+            None => thread::park(),
+        }
     }
+}
 
-    rc = zmq_bind (s, "inproc://thr_test");
-    if (rc != 0) {
-        printf ("error in zmq_bind: %s\n", zmq_strerror (errno));
-        return -1;
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    if args.len() != 3 {
+        println!("usage: inproc_thr <message-size> <message-count>");
+        exit(1);
     }
 
-// #if defined ZMQ_HAVE_WINDOWS
-    local_thread = (HANDLE) _beginthreadex (null_mut(), 0, worker, ctx, 0, null_mut());
-    if (local_thread == 0) {
-        printf ("error in _beginthreadex\n");
-        return -1;
-    }
-// #else
-    rc = pthread_create (&local_thread, null_mut(), worker, ctx);
-    if (rc != 0) {
-        printf ("error in pthread_create: %s\n", zmq_strerror (rc));
-        return -1;
+    let message_size: usize = args[1].parse().unwrap_or(0);
+    let message_count: usize = args[2].parse().unwrap_or(0);
+    if message_count == 0 {
+        println!("message count must be positive");
+        exit(1);
     }
-// #endif
 
-    rc = zmq_msg_init (&msg);
-    if (rc != 0) {
-        printf ("error in zmq_msg_init: %s\n", zmq_strerror (errno));
-        return -1;
-    }
+    let (writer, mut pipe) = ypipe();
+    let local_thread = {
+        let reader = thread::current();
+        thread::spawn(move || worker(writer, reader, message_size, message_count))
+    };
 
-    printf ("message size: %d [B]\n",  message_size);
-    printf ("message count: %d\n",  message_count);
+    println!("message size: {} [B]", message_size);
+    println!("message count: {}", message_count);
 
-    rc = zmq_recvmsg (s, &msg, 0);
-    if (rc < 0) {
-        printf ("error in zmq_recvmsg: %s\n", zmq_strerror (errno));
-        return -1;
-    }
-    if (zmq_msg_size (&msg) != message_size) {
-        printf ("message of incorrect size received\n");
-        return -1;
+    if recv(&mut pipe).len() != message_size {
+        println!("message of incorrect size received");
+        exit(1);
     }
 
-    watch = zmq_stopwatch_start ();
+    let watch = Instant::now();
 
-    for (i = 0; i != message_count - 1; i+= 1) {
-        rc = zmq_recvmsg (s, &msg, 0);
-        if (rc < 0) {
-            printf ("error in zmq_recvmsg: %s\n", zmq_strerror (errno));
-            return -1;
+    for _ in 0..message_count - 1 {
+        if recv(&mut pipe).len() != message_size {
+            println!("message of incorrect size received");
+            exit(1);
         }
-        if (zmq_msg_size (&msg) != message_size) {
-            printf ("message of incorrect size received\n");
-            return -1;
-        }
-    }
-
-    elapsed = zmq_stopwatch_stop (watch);
-    if (elapsed == 0)
-        elapsed = 1;
-
-    rc = zmq_msg_close (&msg);
-    if (rc != 0) {
-        printf ("error in zmq_msg_close: %s\n", zmq_strerror (errno));
-        return -1;
     }
 
-// #if defined ZMQ_HAVE_WINDOWS
-    DWORD rc2 = WaitForSingleObject (local_thread, INFINITE);
-    if (rc2 == WAIT_FAILED) {
-        printf ("error in WaitForSingleObject\n");
-        return -1;
-    }
-    BOOL rc3 = CloseHandle (local_thread);
-    if (rc3 == 0) {
-        printf ("error in CloseHandle\n");
-        return -1;
-    }
-// #else
-    rc = pthread_join (local_thread, null_mut());
-    if (rc != 0) {
-        printf ("error in pthread_join: %s\n", zmq_strerror (rc));
-        return -1;
-    }
-// #endif
+    let elapsed = (watch.elapsed().as_micros() as u64).max(1);
 
-    rc = zmq_close (s);
-    if (rc != 0) {
-        printf ("error in zmq_close: %s\n", zmq_strerror (errno));
-        return -1;
+    if local_thread.join().is_err() {
+        println!("error in worker thread");
+        exit(1);
     }
 
-    rc = zmq_ctx_term (ctx);
-    if (rc != 0) {
-        printf ("error in zmq_ctx_term: %s\n", zmq_strerror (errno));
-        return -1;
-    }
-
-    throughput =
-      (unsigned long) ((double) message_count / (double) elapsed * 1000000);
-    megabits = (double) (throughput * message_size * 8) / 1000000;
-
-    printf ("mean throughput: %d [msg/s]\n",  throughput);
-    printf ("mean throughput: %.3f [Mb/s]\n", (double) megabits);
+    let throughput = (message_count as f64 / elapsed as f64 * 1000000.0) as u64;
+    let megabits = (throughput * message_size as u64 * 8) as f64 / 1000000.0;
 
-    return 0;
+    println!("mean throughput: {} [msg/s]", throughput);
+    println!("mean throughput: {:.3} [Mb/s]", megabits);
 }
@@ -0,0 +1,175 @@
+//  Async front-end over ZmqSocket for the tokio runtime.
+//
+//  The socket is driven in non-blocking mode (ZMQ_DONTWAIT) and parked on
+//  its ZMQ_FD, the read end of the socket's mailbox signaler, which is
+//  registered with the tokio reactor. ZMQ_FD is edge-triggered and only
+//  tells that the socket state *may* have changed: the actual readiness is
+//  reported by ZMQ_EVENTS, and a send/recv may consume the signal without
+//  draining the socket. Hence readiness is always taken from ZMQ_EVENTS,
+//  and the fd readiness is cleared (and ZMQ_EVENTS re-checked) before the
+//  task goes back to sleep, so that no edge is lost.
+
+use std::future::poll_fn;
+use std::os::fd::{AsRawFd, RawFd};
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+
+use futures_core::Stream;
+use futures_sink::Sink;
+use tokio::io::unix::AsyncFd;
+use tokio::io::Interest;
+
+use crate::defines::{ZmqFileDesc, ZMQ_DONTWAIT, ZMQ_POLLIN, ZMQ_POLLOUT};
+use crate::message::ZmqMessage;
+use crate::socket::{get_sock_opt_zmq_events, get_sock_opt_zmq_fd, ZmqSocket};
+
+//  The socket owns the descriptor, the reactor only borrows it.
+struct ZmqNotifyFd(ZmqFileDesc);
+
+impl AsRawFd for ZmqNotifyFd {
+    fn as_raw_fd(&self) -> RawFd {
+        self.0
+    }
+}
+
+fn is_eagain(err: &anyhow::Error) -> bool {
+    err.to_string().contains("EAGAIN")
+}
+
+pub struct ZmqAsyncSocket<'a> {
+    //  Declared before the socket so that it is deregistered from the
+    //  reactor before the socket closes the descriptor.
+    notify_fd: AsyncFd<ZmqNotifyFd>,
+    socket: ZmqSocket<'a>,
+    //  Message accepted by Sink::start_send but not yet handed to the socket.
+    pending: Option<ZmqMessage>,
+}
+
+impl<'a> ZmqAsyncSocket<'a> {
+    //  Must be called from within a tokio runtime.
+    pub fn new(mut socket: ZmqSocket<'a>) -> anyhow::Result<Self> {
+        let fd = get_sock_opt_zmq_fd(&mut socket)?;
+        //  SAFETY: the descriptor belongs to the socket's mailbox, which lives
+        //  as long as the socket, and the socket outlives the registration.
+        let notify_fd =
+            unsafe { AsyncFd::register_with_interest(ZmqNotifyFd(fd), Interest::READABLE)? };
+        Ok(Self {
+            notify_fd,
+            socket,
+            pending: None,
+        })
+    }
+
+    pub fn get_ref(&self) -> &ZmqSocket<'a> {
+        &self.socket
+    }
+
+    pub fn get_mut(&mut self) -> &mut ZmqSocket<'a> {
+        &mut self.socket
+    }
+
+    pub fn into_inner(self) -> ZmqSocket<'a> {
+        self.socket
+    }
+
+    //  Resolves once ZMQ_EVENTS reports the requested event (ZMQ_POLLIN or
+    //  ZMQ_POLLOUT).
+    fn poll_event(&mut self, cx: &mut Context<'_>, event: i32) -> Poll<anyhow::Result<()>> {
+        loop {
+            let events = get_sock_opt_zmq_events(&mut self.socket)?;
+            if events & event as u32 != 0 {
+                return Poll::Ready(Ok(()));
+            }
+
+            let mut guard = ready!(self.notify_fd.poll_read_ready(cx))?;
+            //  Reading ZMQ_EVENTS above processed the pending commands, so
+            //  the edge has been consumed; re-arm and look again.
+            guard.clear_ready();
+        }
+    }
+
+    pub fn poll_recv(&mut self, cx: &mut Context<'_>) -> Poll<anyhow::Result<ZmqMessage>> {
+        loop {
+            ready!(self.poll_event(cx, ZMQ_POLLIN))?;
+            match self.socket.recv(ZMQ_DONTWAIT as i32) {
+                Ok(msg) => return Poll::Ready(Ok(msg)),
+                Err(err) if is_eagain(&err) => continue,
+                Err(err) => return Poll::Ready(Err(err)),
+            }
+        }
+    }
+
+    pub fn poll_send(
+        &mut self,
+        cx: &mut Context<'_>,
+        msg: &mut ZmqMessage,
+    ) -> Poll<anyhow::Result<()>> {
+        loop {
+            ready!(self.poll_event(cx, ZMQ_POLLOUT))?;
+            match self.socket.send(msg, ZMQ_DONTWAIT as i32) {
+                Ok(()) => return Poll::Ready(Ok(())),
+                Err(err) if is_eagain(&err) => continue,
+                Err(err) => return Poll::Ready(Err(err)),
+            }
+        }
+    }
+
+    pub async fn send(&mut self, msg: ZmqMessage) -> anyhow::Result<()> {
+        let mut msg = msg;
+        poll_fn(|cx| self.poll_send(cx, &mut msg)).await
+    }
+
+    pub async fn recv(&mut self) -> anyhow::Result<ZmqMessage> {
+        poll_fn(|cx| self.poll_recv(cx)).await
+    }
+
+    fn poll_flush_pending(&mut self, cx: &mut Context<'_>) -> Poll<anyhow::Result<()>> {
+        if let Some(mut msg) = self.pending.take() {
+            match self.poll_send(cx, &mut msg) {
+                Poll::Ready(result) => return Poll::Ready(result),
+                Poll::Pending => {
+                    self.pending = Some(msg);
+                    return Poll::Pending;
+                }
+            }
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+//  Yields incoming messages; the stream never ends on its own, errors
+//  are passed through as items.
+impl<'a> Stream for ZmqAsyncSocket<'a> {
+    type Item = anyhow::Result<ZmqMessage>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.get_mut().poll_recv(cx).map(Some)
+    }
+}
+
+impl<'a> Sink<ZmqMessage> for ZmqAsyncSocket<'a> {
+    type Error = anyhow::Error;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<anyhow::Result<()>> {
+        self.get_mut().poll_flush_pending(cx)
+    }
+
+    fn start_send(self: Pin<&mut Self>, item: ZmqMessage) -> anyhow::Result<()> {
+        let this = self.get_mut();
+        //  poll_ready has not flushed the previous message yet; refuse the
+        //  new one rather than overwrite it.
+        if this.pending.is_some() {
+            anyhow::bail!("EAGAIN: start_send called before poll_ready");
+        }
+        this.pending = Some(item);
+        Ok(())
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<anyhow::Result<()>> {
+        self.get_mut().poll_flush_pending(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<anyhow::Result<()>> {
+        self.get_mut().poll_flush_pending(cx)
+    }
+}
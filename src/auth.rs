@@ -0,0 +1,556 @@
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::net::IpAddr;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread::{self, JoinHandle};
+
+use anyhow::{anyhow, bail};
+use ipnet::IpNet;
+
+use crate::config::CRYPTO_BOX_PUBLICKEYBYTES;
+use crate::context::{ZmqContext, ZmqSharedContext};
+use crate::defines::{ZmqFileDesc, ZMQ_DONTWAIT, ZMQ_LINGER, ZMQ_POLLIN, ZMQ_REP};
+use crate::message::{ZmqMessage, ZMQ_MSG_MORE};
+use crate::socket::{get_sock_opt_zmq_events, get_sock_opt_zmq_fd, ZmqSocket};
+use crate::utils::{zmq_z85_decode, zmq_z85_encode};
+
+//  Well-known endpoint of the ZAP handler (RFC 27/ZAP).
+pub const ZAP_ENDPOINT: &str = "inproc://zeromq.zap.01";
+pub const ZAP_VERSION: &str = "1.0";
+
+//  How often the handler thread checks whether it has been stopped.
+const ZAP_POLL_INTERVAL_MS: i32 = 100;
+
+//  Which CURVE clients a domain accepts.
+#[derive(Default, Debug, Clone, PartialEq)]
+pub enum ZmqCurveAuth {
+    //  CURVE is not configured; every CURVE client is refused.
+    #[default]
+    Deny,
+    //  Any client with a valid CURVE handshake, i.e. encryption only.
+    AllowAny,
+    //  Only clients whose long-term public key is listed.
+    Keys(HashSet<[u8; CRYPTO_BOX_PUBLICKEYBYTES]>),
+}
+
+//  Access rules for one ZAP domain.
+#[derive(Default, Debug, Clone)]
+pub struct ZmqAuthDomainPolicy {
+    //  If not empty, only these addresses get in and `deny` is ignored.
+    pub allow: Vec<IpNet>,
+    pub deny: Vec<IpNet>,
+    //  PLAIN credentials; PLAIN is refused while this is empty.
+    pub passwords: HashMap<String, String>,
+    pub curve: ZmqCurveAuth,
+}
+
+impl ZmqAuthDomainPolicy {
+    //  Adds an address ("10.0.0.1") or network ("10.0.0.0/8") to the
+    //  allow list.
+    pub fn allow(&mut self, address: &str) -> anyhow::Result<()> {
+        self.allow.push(parse_net(address)?);
+        Ok(())
+    }
+
+    pub fn deny(&mut self, address: &str) -> anyhow::Result<()> {
+        self.deny.push(parse_net(address)?);
+        Ok(())
+    }
+
+    pub fn set_password(&mut self, username: &str, password: &str) {
+        self.passwords.insert(username.to_string(), password.to_string());
+    }
+
+    //  Replaces the PLAIN credentials with the contents of a password file:
+    //  one "username=password" per line, '#' starts a comment.
+    pub fn load_passwords(&mut self, path: &Path) -> anyhow::Result<()> {
+        let text = fs::read_to_string(path)?;
+        let mut passwords = HashMap::new();
+        for line in text.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            match line.split_once('=') {
+                Some((username, password)) => {
+                    passwords.insert(username.trim().to_string(), password.trim().to_string());
+                }
+                None => bail!("EINVAL: malformed line in {}: {}", path.display(), line),
+            }
+        }
+        self.passwords = passwords;
+        Ok(())
+    }
+
+    pub fn curve_allow_any(&mut self) {
+        self.curve = ZmqCurveAuth::AllowAny;
+    }
+
+    pub fn add_curve_key(&mut self, public_key: &[u8]) -> anyhow::Result<()> {
+        let key: [u8; CRYPTO_BOX_PUBLICKEYBYTES] = public_key
+            .try_into()
+            .map_err(|_| anyhow!("EINVAL: CURVE public key must be {} bytes", CRYPTO_BOX_PUBLICKEYBYTES))?;
+        match &mut self.curve {
+            ZmqCurveAuth::Keys(keys) => {
+                keys.insert(key);
+            }
+            curve => *curve = ZmqCurveAuth::Keys(HashSet::from([key])),
+        }
+        Ok(())
+    }
+
+    //  Replaces the CURVE key list with the public keys of the certificates
+    //  found in a directory. Certificates use the zcert text format, of
+    //  which only the `public-key = "<z85>"` line is read; files without
+    //  one are skipped.
+    pub fn load_curve_keys(&mut self, dir: &Path) -> anyhow::Result<()> {
+        let mut keys = HashSet::new();
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            if !path.is_file() {
+                continue;
+            }
+            let text = match fs::read_to_string(&path) {
+                Ok(text) => text,
+                Err(_) => continue,
+            };
+            if let Some(key) = parse_cert_public_key(&text) {
+                keys.insert(key);
+            }
+        }
+        self.curve = ZmqCurveAuth::Keys(keys);
+        Ok(())
+    }
+
+    fn is_allowed(&self, address: Option<IpAddr>) -> Result<(), &'static str> {
+        if !self.allow.is_empty() {
+            return match address {
+                Some(ip) if self.allow.iter().any(|net| net.contains(&ip)) => Ok(()),
+                _ => Err("Address not in allow list"),
+            };
+        }
+        match address {
+            Some(ip) if self.deny.iter().any(|net| net.contains(&ip)) => Err("Address is denied"),
+            _ => Ok(()),
+        }
+    }
+}
+
+pub fn parse_net(address: &str) -> anyhow::Result<IpNet> {
+    if let Ok(net) = address.parse::<IpNet>() {
+        return Ok(net);
+    }
+    address
+        .parse::<IpAddr>()
+        .map(IpNet::from)
+        .map_err(|_| anyhow!("EINVAL: invalid address {}", address))
+}
+
+fn parse_cert_public_key(text: &str) -> Option<[u8; CRYPTO_BOX_PUBLICKEYBYTES]> {
+    for line in text.lines() {
+        let line = line.trim();
+        let value = match line.strip_prefix("public-key") {
+            Some(rest) => rest.trim_start(),
+            None => continue,
+        };
+        let value = value.strip_prefix('=')?.trim().trim_matches('"');
+        let mut key = [0u8; CRYPTO_BOX_PUBLICKEYBYTES];
+        if value.len() != 40 || zmq_z85_decode(&mut key, value.as_bytes()).is_err() {
+            return None;
+        }
+        return Some(key);
+    }
+    None
+}
+
+//  A parsed ZAP request, see RFC 27.
+#[derive(Default, Debug, Clone)]
+pub struct ZmqZapRequest {
+    pub version: String,
+    pub request_id: Vec<u8>,
+    pub domain: String,
+    pub address: String,
+    pub routing_id: Vec<u8>,
+    pub mechanism: String,
+    pub credentials: Vec<Vec<u8>>,
+}
+
+impl ZmqZapRequest {
+    pub fn parse(mut frames: Vec<Vec<u8>>) -> anyhow::Result<Self> {
+        if frames.len() < 6 {
+            bail!("EPROTO: ZAP request has {} frames", frames.len());
+        }
+        let credentials = frames.split_off(6);
+        let mut frames = frames.into_iter();
+        let mut frame = || frames.next().unwrap();
+        let text = |frame: Vec<u8>| String::from_utf8_lossy(&frame).into_owned();
+        //  The ids are opaque and go back to the client as they came.
+        let version = text(frame());
+        let request_id = frame();
+        let domain = text(frame());
+        let address = text(frame());
+        let routing_id = frame();
+        let mechanism = text(frame());
+        Ok(Self {
+            version,
+            request_id,
+            domain,
+            address,
+            routing_id,
+            mechanism,
+            credentials,
+        })
+    }
+}
+
+#[derive(Default, Debug, Clone)]
+pub struct ZmqZapReply {
+    pub request_id: Vec<u8>,
+    pub status_code: String,
+    pub status_text: String,
+    //  Becomes the User-Id property of messages from this peer.
+    pub user_id: String,
+    //  ZMTP-encoded metadata, added to the peer's message properties.
+    pub metadata: Vec<u8>,
+}
+
+impl ZmqZapReply {
+    fn new(request_id: &[u8], status_code: &str, status_text: &str) -> Self {
+        Self {
+            request_id: request_id.to_vec(),
+            status_code: status_code.to_string(),
+            status_text: status_text.to_string(),
+            ..Default::default()
+        }
+    }
+
+    fn accept(request_id: &[u8], user_id: String) -> Self {
+        Self {
+            user_id,
+            ..Self::new(request_id, "200", "OK")
+        }
+    }
+
+    pub fn frames(&self) -> Vec<Vec<u8>> {
+        vec![
+            ZAP_VERSION.as_bytes().to_vec(),
+            self.request_id.clone(),
+            self.status_code.as_bytes().to_vec(),
+            self.status_text.as_bytes().to_vec(),
+            self.user_id.as_bytes().to_vec(),
+            self.metadata.clone(),
+        ]
+    }
+}
+
+//  Per-domain access rules. Requests for a domain without its own entry
+//  fall back to `default`, which is also used for the empty domain.
+#[derive(Default, Debug, Clone)]
+pub struct ZmqAuthPolicy {
+    pub default: ZmqAuthDomainPolicy,
+    pub domains: HashMap<String, ZmqAuthDomainPolicy>,
+}
+
+impl ZmqAuthPolicy {
+    //  The rules for a ZMQ_ZAP_DOMAIN, created empty on first use.
+    pub fn domain(&mut self, domain: &str) -> &mut ZmqAuthDomainPolicy {
+        if domain.is_empty() {
+            return &mut self.default;
+        }
+        self.domains.entry(domain.to_string()).or_default()
+    }
+
+    pub fn authenticate(&self, request: &ZmqZapRequest) -> ZmqZapReply {
+        let id = &request.request_id;
+        if request.version != ZAP_VERSION {
+            return ZmqZapReply::new(id, "400", "Unsupported ZAP version");
+        }
+
+        let policy = self.domains.get(&request.domain).unwrap_or(&self.default);
+        if let Err(reason) = policy.is_allowed(request.address.parse::<IpAddr>().ok()) {
+            return ZmqZapReply::new(id, "400", reason);
+        }
+
+        match request.mechanism.as_str() {
+            //  Nothing to check beyond the address.
+            "NULL" => ZmqZapReply::accept(id, String::new()),
+            "PLAIN" => {
+                if request.credentials.len() != 2 {
+                    return ZmqZapReply::new(id, "400", "Malformed PLAIN credentials");
+                }
+                let username = String::from_utf8_lossy(&request.credentials[0]);
+                let password = &request.credentials[1];
+                match policy.passwords.get(username.as_ref()) {
+                    Some(expected) if constant_time_eq(expected.as_bytes(), password) => {
+                        ZmqZapReply::accept(id, username.into_owned())
+                    }
+                    _ => ZmqZapReply::new(id, "400", "Invalid username or password"),
+                }
+            }
+            "CURVE" => {
+                let key = match request.credentials.first() {
+                    Some(key) if key.len() == CRYPTO_BOX_PUBLICKEYBYTES => key,
+                    _ => return ZmqZapReply::new(id, "400", "Malformed CURVE credentials"),
+                };
+                let known = match &policy.curve {
+                    ZmqCurveAuth::Deny => false,
+                    ZmqCurveAuth::AllowAny => true,
+                    ZmqCurveAuth::Keys(keys) => keys.contains(key.as_slice()),
+                };
+                if !known {
+                    return ZmqZapReply::new(id, "400", "Unknown public key");
+                }
+                //  The z85 form of the client key, as zauth does.
+                let mut z85 = [0u8; CRYPTO_BOX_PUBLICKEYBYTES * 5 / 4 + 1];
+                let user_id = zmq_z85_encode(&mut z85, key, key.len())
+                    .map(|s| s.trim_end_matches('\0').to_string())
+                    .unwrap_or_default();
+                ZmqZapReply::accept(id, user_id)
+            }
+            //  The GSSAPI mechanism has already authenticated the principal.
+            "GSSAPI" => {
+                let principal = request
+                    .credentials
+                    .first()
+                    .map(|p| String::from_utf8_lossy(p).into_owned())
+                    .unwrap_or_default();
+                ZmqZapReply::accept(id, principal)
+            }
+            _ => ZmqZapReply::new(id, "400", "Unsupported mechanism"),
+        }
+    }
+}
+
+//  Compares without returning early, so the time taken does not tell how
+//  much of a guessed password was right.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+//  Built-in ZAP handler. It binds a REP socket to ZAP_ENDPOINT and answers
+//  requests on its own thread according to a ZmqAuthPolicy, which may be
+//  changed while the handler runs. Stopped on drop.
+//
+//  The handler only locks the context while it works its socket, so the
+//  application keeps creating and using sockets on it meanwhile.
+pub struct ZmqAuth {
+    policy: Arc<Mutex<ZmqAuthPolicy>>,
+    terminated: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl ZmqAuth {
+    pub fn start(ctx: &ZmqSharedContext, policy: ZmqAuthPolicy) -> anyhow::Result<Self> {
+        let mut guard = ctx.lock().unwrap();
+        //  SAFETY: the handler thread holds a clone of `ctx`, which keeps the
+        //  context alive for as long as the socket, and only uses the socket
+        //  with the context locked.
+        let context = unsafe { &mut *(&mut *guard as *mut ZmqContext<'static>) };
+        let mut handler = context.create_socket(ZMQ_REP as i32)?;
+        let linger = 0i32.to_le_bytes();
+        handler.setsockopt(ZMQ_LINGER, &linger, linger.len())?;
+        //  Bind before returning, so that no connection made afterwards
+        //  misses the handler.
+        handler.bind(ZAP_ENDPOINT)?;
+        let notify_fd = get_sock_opt_zmq_fd(&mut handler)?;
+        drop(guard);
+
+        let policy = Arc::new(Mutex::new(policy));
+        let terminated = Arc::new(AtomicBool::new(false));
+        let thread = {
+            let ctx = ctx.clone();
+            let policy = policy.clone();
+            let terminated = terminated.clone();
+            thread::Builder::new()
+                .name("ZAP".to_string())
+                .spawn(move || zap_handler_loop(ctx, handler, notify_fd, policy, terminated))?
+        };
+
+        Ok(Self {
+            policy,
+            terminated,
+            thread: Some(thread),
+        })
+    }
+
+    //  Locks the live policy; changes apply to the next request.
+    pub fn policy(&self) -> MutexGuard<'_, ZmqAuthPolicy> {
+        self.policy.lock().unwrap()
+    }
+
+    pub fn stop(&mut self) {
+        self.terminated.store(true, Ordering::Release);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+impl Drop for ZmqAuth {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+fn zap_handler_loop(
+    ctx: ZmqSharedContext,
+    mut handler: ZmqSocket<'static>,
+    notify_fd: ZmqFileDesc,
+    policy: Arc<Mutex<ZmqAuthPolicy>>,
+    terminated: Arc<AtomicBool>,
+) {
+    while !terminated.load(Ordering::Acquire) {
+        {
+            let _ctx = ctx.lock().unwrap();
+            if serve_requests(&mut handler, &policy).is_err() {
+                //  ETERM or worse; the handler is done.
+                break;
+            }
+        }
+        //  ZMQ_FD is edge-triggered; the socket was drained above, so the
+        //  next request will signal it. Wake up every ZAP_POLL_INTERVAL_MS
+        //  to check for termination.
+        let mut pollfd = libc::pollfd {
+            fd: notify_fd,
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        unsafe { libc::poll(&mut pollfd, 1, ZAP_POLL_INTERVAL_MS) };
+    }
+    let _ctx = ctx.lock().unwrap();
+    let _ = handler.close();
+}
+
+//  Answers the requests queued on the handler socket.
+fn serve_requests(handler: &mut ZmqSocket, policy: &Mutex<ZmqAuthPolicy>) -> anyhow::Result<()> {
+    while get_sock_opt_zmq_events(handler)? & ZMQ_POLLIN as u32 != 0 {
+        let frames = match recv_frames(handler) {
+            Ok(frames) => frames,
+            Err(e) if e.to_string().starts_with("EAGAIN") => return Ok(()),
+            Err(e) => return Err(e),
+        };
+
+        let reply = match ZmqZapRequest::parse(frames) {
+            Ok(request) => policy.lock().unwrap().authenticate(&request),
+            //  REP still owes an answer; there is no request id to echo.
+            Err(_) => ZmqZapReply::new(&[], "500", "Malformed ZAP request"),
+        };
+        send_frames(handler, reply.frames())?;
+    }
+    Ok(())
+}
+
+fn recv_frames(socket: &mut ZmqSocket) -> anyhow::Result<Vec<Vec<u8>>> {
+    let mut frames = vec![];
+    loop {
+        let mut msg = socket.recv(ZMQ_DONTWAIT as i32)?;
+        frames.push(msg.data().to_vec());
+        if !msg.flag_set(ZMQ_MSG_MORE) {
+            return Ok(frames);
+        }
+    }
+}
+
+fn send_frames(socket: &mut ZmqSocket, frames: Vec<Vec<u8>>) -> anyhow::Result<()> {
+    let count = frames.len();
+    for (i, frame) in frames.into_iter().enumerate() {
+        let mut msg = ZmqMessage::default();
+        msg.init_size(frame.len())?;
+        msg.data_mut().copy_from_slice(&frame);
+        if i + 1 < count {
+            msg.set_flags(ZMQ_MSG_MORE);
+        }
+        socket.send(&mut msg, ZMQ_DONTWAIT as i32)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(
+        domain: &str,
+        address: &str,
+        mechanism: &str,
+        credentials: &[&[u8]],
+    ) -> ZmqZapRequest {
+        ZmqZapRequest {
+            version: ZAP_VERSION.to_string(),
+            request_id: b"1".to_vec(),
+            domain: domain.to_string(),
+            address: address.to_string(),
+            routing_id: vec![],
+            mechanism: mechanism.to_string(),
+            credentials: credentials.iter().map(|c| c.to_vec()).collect(),
+        }
+    }
+
+    #[test]
+    fn constant_time_eq_matches_eq() {
+        assert!(constant_time_eq(b"secret", b"secret"));
+        assert!(!constant_time_eq(b"secret", b"secreT"));
+        assert!(!constant_time_eq(b"secret", b"secret!"));
+        assert!(constant_time_eq(b"", b""));
+    }
+
+    #[test]
+    fn plain_passwords() {
+        let mut policy = ZmqAuthPolicy::default();
+        policy.default.set_password("admin", "secret");
+
+        let reply = policy.authenticate(&request("", "127.0.0.1", "PLAIN", &[b"admin", b"secret"]));
+        assert_eq!(
+            (reply.status_code.as_str(), reply.user_id.as_str()),
+            ("200", "admin")
+        );
+        let reply = policy.authenticate(&request("", "127.0.0.1", "PLAIN", &[b"admin", b"wrong"]));
+        assert_eq!(reply.status_code, "400");
+        let reply =
+            policy.authenticate(&request("", "127.0.0.1", "PLAIN", &[b"nobody", b"secret"]));
+        assert_eq!(reply.status_code, "400");
+    }
+
+    #[test]
+    fn binary_ids_are_kept() {
+        let frames = vec![
+            ZAP_VERSION.as_bytes().to_vec(),
+            vec![0xff, 0x00, 0xfe],
+            b"global".to_vec(),
+            b"127.0.0.1".to_vec(),
+            vec![0x00, 0x80, 0xc0],
+            b"NULL".to_vec(),
+        ];
+        let request = ZmqZapRequest::parse(frames).unwrap();
+        assert_eq!(request.request_id, [0xff, 0x00, 0xfe]);
+        assert_eq!(request.routing_id, [0x00, 0x80, 0xc0]);
+
+        let reply = ZmqAuthPolicy::default().authenticate(&request);
+        assert_eq!(reply.status_code, "200");
+        assert_eq!(reply.frames()[1], [0xff, 0x00, 0xfe]);
+    }
+
+    #[test]
+    fn domains_and_addresses() {
+        let mut policy = ZmqAuthPolicy::default();
+        policy.default.deny("10.0.0.0/8").unwrap();
+        policy.domain("global").allow("192.168.1.1").unwrap();
+
+        let status = |domain, address| {
+            policy
+                .authenticate(&request(domain, address, "NULL", &[]))
+                .status_code
+        };
+        assert_eq!(status("", "10.1.2.3"), "400");
+        assert_eq!(status("", "127.0.0.1"), "200");
+        assert_eq!(status("global", "192.168.1.1"), "200");
+        assert_eq!(status("global", "192.168.1.2"), "400");
+        //  Unknown domains get the default rules.
+        assert_eq!(status("other", "10.1.2.3"), "400");
+    }
+}
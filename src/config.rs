@@ -22,6 +22,11 @@ pub const MAX_WM_DELTA: i32 = 1024;
 //  Maximum number of events the I/O thread can process in one go.
 pub const MAX_IO_EVENTS: i32 = 256;
 
+//  Number and size of the receive buffers an io_uring I/O thread registers
+//  with the kernel. Reads beyond the number of buffers fall back to recv(2).
+pub const URING_REGISTERED_BUFFERS: usize = 64;
+pub const URING_REGISTERED_BUFFER_SIZE: usize = 8192;
+
 //  Maximal batch size of packets forwarded by a ZMQ proxy.
 //  Increasing this value improves throughput at the expense of
 //  latency and fairness.
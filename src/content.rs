@@ -1,10 +1,174 @@
+use std::ffi::c_void;
+use std::mem;
+
+use bytes::{Bytes, BytesMut};
 use serde::{Deserialize, Serialize};
 
 #[derive(Default, Debug, Clone, Deserialize, Serialize)]
 pub struct ZmqContent {
     pub data: Vec<u8>,
     pub size: usize,
-    // msg_free_fn: *ffn;
+    // msg_free_fn: *ffn; see ZmqExternalData.
     pub hint: Vec<u8>,
     // pub refcnt: AtomicU64,
 }
+
+//  Payload storage for messages too large to be kept inline (VSM). A
+//  buffer starts out Unique while it is being filled and is frozen into a
+//  reference-counted Shared buffer once it is copied, so copies of a
+//  message, e.g. one per subscriber pipe, all point to the same bytes.
+#[derive(Debug, Deserialize, Serialize)]
+pub enum ZmqMsgBuffer {
+    Unique(BytesMut),
+    Shared(Bytes),
+}
+
+impl Default for ZmqMsgBuffer {
+    fn default() -> Self {
+        ZmqMsgBuffer::Shared(Bytes::new())
+    }
+}
+
+impl Clone for ZmqMsgBuffer {
+    //  O(1) for frozen buffers. A Unique buffer cannot be frozen through a
+    //  shared reference, so it is copied; ZmqMessage::copy freezes first.
+    fn clone(&self) -> Self {
+        match self {
+            ZmqMsgBuffer::Unique(buf) => ZmqMsgBuffer::Shared(Bytes::copy_from_slice(buf)),
+            ZmqMsgBuffer::Shared(buf) => ZmqMsgBuffer::Shared(buf.clone()),
+        }
+    }
+}
+
+impl ZmqMsgBuffer {
+    pub fn zeroed(size: usize) -> Self {
+        ZmqMsgBuffer::Unique(BytesMut::zeroed(size))
+    }
+
+    pub fn len(&self) -> usize {
+        self.as_slice().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn as_slice(&self) -> &[u8] {
+        match self {
+            ZmqMsgBuffer::Unique(buf) => buf,
+            ZmqMsgBuffer::Shared(buf) => buf,
+        }
+    }
+
+    //  Copy-on-write: a shared buffer is reclaimed if this is the last
+    //  reference to it and copied otherwise, so that other copies of the
+    //  message never see the change.
+    pub fn as_mut_slice(&mut self) -> &mut [u8] {
+        if let ZmqMsgBuffer::Shared(buf) = self {
+            let buf = mem::take(buf);
+            *self = ZmqMsgBuffer::Unique(match buf.try_into_mut() {
+                Ok(buf) => buf,
+                Err(buf) => BytesMut::from(&buf[..]),
+            });
+        }
+        match self {
+            ZmqMsgBuffer::Unique(buf) => buf,
+            ZmqMsgBuffer::Shared(_) => unreachable!(),
+        }
+    }
+
+    //  Turns the buffer into a shared one and returns a new reference to it.
+    pub fn freeze(&mut self) -> Bytes {
+        if let ZmqMsgBuffer::Unique(buf) = self {
+            *self = ZmqMsgBuffer::Shared(mem::take(buf).freeze());
+        }
+        match self {
+            ZmqMsgBuffer::Shared(buf) => buf.clone(),
+            ZmqMsgBuffer::Unique(_) => unreachable!(),
+        }
+    }
+
+    //  True if other messages hold references to the same bytes.
+    pub fn is_shared(&self) -> bool {
+        match self {
+            ZmqMsgBuffer::Unique(_) => false,
+            ZmqMsgBuffer::Shared(buf) => !buf.is_unique(),
+        }
+    }
+
+    pub fn truncate(&mut self, size: usize) {
+        match self {
+            ZmqMsgBuffer::Unique(buf) => buf.truncate(size),
+            ZmqMsgBuffer::Shared(buf) => buf.truncate(size),
+        }
+    }
+}
+
+//  msg_free_fn: given the data and the hint a message was initialised with,
+//  releases the data once the message and all its copies are closed.
+pub type ZmqFreeFn = fn(data: *mut c_void, hint: *mut c_void);
+
+//  Memory handed to a message by zmq_msg_init_data, used in place. As the
+//  owner of a Bytes it is dropped with the last copy of the message, on
+//  whichever thread that happens, and then calls the free function.
+pub struct ZmqExternalData {
+    pub data: *mut u8,
+    pub size: usize,
+    pub ffn: Option<ZmqFreeFn>,
+    pub hint: *mut c_void,
+}
+
+//  Whoever initialises a message with the data vouches for it, and for the
+//  free function, being usable from any thread; as with libzmq, where the
+//  last copy may well be closed by an I/O thread.
+unsafe impl Send for ZmqExternalData {}
+
+impl AsRef<[u8]> for ZmqExternalData {
+    fn as_ref(&self) -> &[u8] {
+        if self.size == 0 {
+            return &[];
+        }
+        unsafe { std::slice::from_raw_parts(self.data, self.size) }
+    }
+}
+
+impl Drop for ZmqExternalData {
+    fn drop(&mut self) {
+        if let Some(ffn) = self.ffn {
+            ffn(self.data as *mut c_void, self.hint);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    fn free_counted(data: *mut c_void, hint: *mut c_void) {
+        let freed = unsafe { &*(hint as *const AtomicUsize) };
+        freed.fetch_add(1, Ordering::SeqCst);
+        drop(unsafe { Box::from_raw(data as *mut [u8; 100]) });
+    }
+
+    #[test]
+    fn external_data_is_shared_and_freed_once() {
+        let freed = AtomicUsize::new(0);
+        let data = Box::into_raw(Box::new([7u8; 100])) as *mut u8;
+        let bytes = Bytes::from_owner(ZmqExternalData {
+            data,
+            size: 100,
+            ffn: Some(free_counted),
+            hint: &freed as *const AtomicUsize as *mut c_void,
+        });
+        let buffer = ZmqMsgBuffer::Shared(bytes);
+        let copy = buffer.clone();
+        assert_eq!(copy.as_slice().as_ptr(), data as *const u8);
+        assert!(buffer.is_shared());
+
+        drop(buffer);
+        assert_eq!(freed.load(Ordering::SeqCst), 0);
+        drop(copy);
+        assert_eq!(freed.load(Ordering::SeqCst), 1);
+    }
+}
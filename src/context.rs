@@ -2,39 +2,47 @@ use std::collections::{HashMap, HashSet};
 use std::mem::size_of;
 use std::ptr::null_mut;
 use std::sync::atomic::Ordering;
-use std::sync::Mutex;
+use std::sync::{Arc, Mutex};
 use std::{mem, process};
 
 use anyhow::{anyhow, bail};
 
+use ipnet::IpNet;
 #[cfg(not(windows))]
 use libc::{gid_t, pid_t, uid_t};
 
 use crate::address::ZmqAddress;
+use crate::auth::parse_net;
 use serde::{Deserialize, Serialize};
 
 use crate::defines::{
     ZMQ_BLOCKY, ZMQ_CURVE, ZMQ_DEALER, ZMQ_GSSAPI, ZMQ_GSSAPI_NT_HOSTBASED,
     ZMQ_GSSAPI_NT_KRB5_PRINCIPAL, ZMQ_GSSAPI_NT_USER_NAME, ZMQ_IO_THREADS, ZMQ_IPV6, ZMQ_MAX_MSGSZ,
-    ZMQ_MAX_SOCKETS, ZMQ_MAX_SOCKETS_DFLT, ZMQ_MESSAGE_SIZE, ZMQ_NULL, ZMQ_PAIR, ZMQ_PLAIN,
-    ZMQ_PUB, ZMQ_PULL, ZMQ_PUSH, ZMQ_SOCKET_LIMIT, ZMQ_SUB, ZMQ_ZERO_COPY_RECV,
+    ZMQ_MAX_SOCKETS, ZMQ_MAX_SOCKETS_DFLT, ZMQ_MECHANISM_CUSTOM, ZMQ_MESSAGE_SIZE, ZMQ_NULL, ZMQ_PAIR, ZMQ_PLAIN,
+    ZMQ_PUB, ZMQ_PULL, ZMQ_PUSH, ZMQ_SHM_RING_SIZE, ZMQ_SHM_RING_SIZE_DFLT, ZMQ_SHM_RING_SIZE_MIN,
+    ZMQ_SOCKET_LIMIT, ZMQ_SUB, ZMQ_TCP_MAX_CONNECTIONS, ZMQ_TCP_MAX_CONNECTIONS_PER_IP,
+    ZMQ_TCP_PROXY_PROTOCOL, ZMQ_WS_ALLOWED_ORIGIN, ZMQ_WS_DEFLATE, ZMQ_WS_RESPONSE_HEADER,
+    ZMQ_ZERO_COPY_RECV, ZMQ_ZMTP_COMPRESSION, ZMQ_ZMTP_COMPRESSION_THRESHOLD,
+    ZMQ_ZMTP_COMPRESSION_THRESHOLD_DFLT,
 };
 use crate::endpoint::ZmqEndpoint;
 use crate::endpoint_uri::EndpointUriPair;
 use crate::engine::ZmqEngine;
 use crate::mailbox::ZmqMailbox;
 use crate::mailbox_interface::ZmqMailboxInterface;
+use crate::mechanism_registry::{ZmqMechanismFactory, ZmqMechanismRegistry};
 use crate::message::ZmqMessage;
 use crate::thread_command::{ThreadCommandType, ZmqThreadCommand};
 // use crate::object::ZmqObject;
 use crate::own::ZmqOwn;
 use crate::pending_connection::PendingConnection;
-use crate::pipe::{send_hello_msg, ZmqPipe};
+use crate::pipe::{send_hello_msg, ZmqPipe, ZmqUpipeReader, ZmqUpipeWriter};
 use crate::reaper::ZmqReaper;
 use crate::session_base::ZmqSessionBase;
 use crate::socket::ZmqSocket;
 use crate::thread_context::ZmqThreadContext;
 use crate::utils::zmq_z85_decode;
+use crate::ws_router::ZmqWsPort;
 
 pub const CONNECT_SIDE: i32 = 0;
 pub const BIND_SIDE: i32 = 1;
@@ -178,7 +186,8 @@ pub struct ZmqContext<'a> {
     // if true, router socket accepts non-zmq tcp connections
     pub raw_socket: bool,
     pub raw_notify: bool, //  Provide connect notifications
-    //  Address of SOCKS proxy
+    //  Address of SOCKS proxy, or "http://host:port" for an HTTP
+    //  CONNECT proxy (see http_connecter.rs).
     pub socks_proxy_address: String,
     // Credentials for SOCKS proxy.
     // Connection method will be basic auth if username
@@ -192,7 +201,15 @@ pub struct ZmqContext<'a> {
     tcp_keepalive_idle: i32,
     tcp_keepalive_intvl: i32,
     // TCP accept() filters
-    pub tcp_accept_filters: Vec<ZmqAddress>,
+    pub tcp_accept_filters: Vec<IpNet>,
+    //  Caps on the connections a TCP listener keeps open, in total and
+    //  per peer address; 0 means no cap.
+    pub tcp_max_connections: i32,
+    pub tcp_max_connections_per_ip: i32,
+    //  Accepted TCP connections start with a PROXY protocol header naming
+    //  the real client; see proxy_protocol.rs. Accept filters and caps
+    //  still see the address of the balancer.
+    pub tcp_proxy_protocol: bool,
     // IPC accept() filters
     #[cfg(target_os = "linux")]
     pub ipc_uid_accept_filters: HashSet<uid_t>,
@@ -221,6 +238,11 @@ pub struct ZmqContext<'a> {
     pub gss_service_principal_nt: i32,
     //  If true, gss encryption will be disabled
     pub gss_plaintext: bool,
+    //  Custom security mechanisms, see mechanism_registry.rs
+    #[serde(skip)]
+    pub mechanism_registry: ZmqMechanismRegistry,
+    //  Registered mechanism used when mechanism is ZMQ_MECHANISM_CUSTOM
+    pub custom_mechanism: String,
     //  ID of the socket.
     pub socket_id: i32,
     //  If true, socket conflates outgoing/incoming messages.
@@ -241,13 +263,11 @@ pub struct ZmqContext<'a> {
     //  Time in milliseconds to wait for a PING response before disconnecting
     pub heartbeat_timeout: i32,
     //  If true, the socket will send all outstanding messages to a peer
-    #[cfg(target_feature = "vmci")]
+    //  Also applied to vsock:// sockets, which need no VMCI SDK.
     pub vmci_buffer_size: u64,
-    #[cfg(target_feature = "vmci")]
     pub vmci_buffer_min_size: u64,
-    #[cfg(target_feature = "vmci")]
     pub vmci_buffer_max_size: u64,
-    #[cfg(target_feature = "vmci")]
+    //  In milliseconds.
     pub vmci_connect_timeout: i32,
     //  When creating a new ZMQ socket, if this option is set the value
     //  will be used as the File Descriptor instead of allocating a new
@@ -285,6 +305,18 @@ pub struct ZmqContext<'a> {
     pub wss_trust_pem: String,
     pub wss_hostname: String,
     pub wss_trust_system: bool,
+    //  Offer/accept permessage-deflate on ws:// and wss://; see
+    //  ws_deflate.rs.
+    pub ws_deflate: bool,
+    //  Extra "Name: value" headers for the upgrade response, and the
+    //  Origins browsers may connect from (any if empty), for ws:// and
+    //  wss:// binds.
+    pub ws_response_headers: Vec<String>,
+    pub ws_allowed_origins: Vec<String>,
+    //  Offer per-frame compression in the handshake metadata, and the
+    //  smallest frame worth compressing; see zmtp_compression.rs.
+    pub zmtp_compression: bool,
+    pub zmtp_compression_threshold: i32,
     //  Hello msg
     pub hello_msg: Vec<u8>,
     pub can_send_hello_msg: bool,
@@ -296,8 +328,22 @@ pub struct ZmqContext<'a> {
     pub can_recv_hiccup_msg: bool,
     //  This option removes several delays caused by scheduling, interrupts and context switching.
     pub busy_poll: i32,
+    //  Size of each direction's ring for shm:// connections.
+    pub shm_ring_size: u64,
+    //  quic:// client configuration, built on first connect and kept for
+    //  the TLS session tickets it collects (0-RTT reconnects). Changing the
+    //  wss trust options drops it.
+    #[serde(skip)]
+    pub quic_client_config: Option<quinn_proto::ClientConfig>,
+    //  ws:// listening addresses shared by path, see ws_router.rs.
+    #[serde(skip)]
+    pub ws_ports: Arc<Mutex<HashMap<String, Arc<Mutex<ZmqWsPort>>>>>,
 }
 
+//  A context used from several threads, e.g. by the ZAP handler (auth.rs)
+//  next to the application's own sockets.
+pub type ZmqSharedContext = Arc<Mutex<ZmqContext<'static>>>;
+
 impl<'a> ZmqContext<'a> {
     //  Create the context object.
     // ctx_t ();
@@ -364,6 +410,11 @@ impl<'a> ZmqContext<'a> {
             wss_trust_pem: "".to_string(),
             wss_hostname: "".to_string(),
             wss_trust_system: false,
+            ws_deflate: false,
+            ws_response_headers: vec![],
+            ws_allowed_origins: vec![],
+            zmtp_compression: false,
+            zmtp_compression_threshold: ZMQ_ZMTP_COMPRESSION_THRESHOLD_DFLT,
             hello_msg: vec![],
             can_send_hello_msg: false,
             disconnect_msg: vec![],
@@ -407,6 +458,9 @@ impl<'a> ZmqContext<'a> {
             tcp_keepalive_idle: 0,
             tcp_keepalive_intvl: 0,
             tcp_accept_filters: vec![],
+            tcp_max_connections: 0,
+            tcp_max_connections_per_ip: 0,
+            tcp_proxy_protocol: false,
             #[cfg(target_os = "linux")]
             ipc_uid_accept_filters: Default::default(),
             #[cfg(target_os = "linux")]
@@ -426,6 +480,8 @@ impl<'a> ZmqContext<'a> {
             gss_principal_nt: 0,
             gss_service_principal_nt: 0,
             gss_plaintext: false,
+            mechanism_registry: ZmqMechanismRegistry::default(),
+            custom_mechanism: "".to_string(),
             socket_id: 0,
             conflate: false,
             handshake_ivl: 0,
@@ -447,6 +503,9 @@ impl<'a> ZmqContext<'a> {
             vmci_sync: Mutex::new(0),
             out_batch_size: 0,
             busy_poll: 0,
+            shm_ring_size: ZMQ_SHM_RING_SIZE_DFLT,
+            quic_client_config: None,
+            ws_ports: Default::default(),
             pid: 0,
             ..Default::default()
         }
@@ -1301,6 +1360,26 @@ impl<'a> ZmqContext<'a> {
     //                             const PendingConnection &pending_connection_,
     //                             side side_);
 
+    //  Makes a custom mechanism available to sockets of this context.
+    pub fn register_mechanism(
+        &mut self,
+        factory: Arc<dyn ZmqMechanismFactory>,
+    ) -> anyhow::Result<()> {
+        self.mechanism_registry.register(factory)
+    }
+
+    //  Selects a registered mechanism for subsequent connections, the
+    //  counterpart of setting ZMQ_PLAIN_SERVER or ZMQ_CURVE_SERVER.
+    pub fn set_custom_mechanism(&mut self, name: &str, as_server: bool) -> anyhow::Result<()> {
+        if !self.mechanism_registry.contains(name) {
+            bail!("EINVAL: mechanism {} is not registered", name);
+        }
+        self.custom_mechanism = name.to_string();
+        self.as_server = as_server as i32;
+        self.mechanism = ZMQ_MECHANISM_CUSTOM as i32;
+        Ok(())
+    }
+
     pub fn set_curve_key(
         &mut self,
         destination: &mut [u8],
@@ -1579,21 +1658,42 @@ impl<'a> ZmqContext<'a> {
                 let mut filter_str = String::new();
                 // let mut rc = do_setsockopt_string_allow_empty_strict(
                 //     opt_val, opt_val_len, &mut filter_str, UCHAR_MAX);
-                if set_opt_string(opt_val, &mut filter_str).is_ok() {
-                    if filter_str.empty() {
-                        self.tcp_accept_filters.clear();
-                    } else {
-                        // TODO substitute with ZmqAddress in filter capacity
-                        // let mut mask = TcpAddressMask::default();
-                        // let mut rc = mask.resolve(&filter_str, ipv6);
-                        // if rc == 0 {
-                        //     self.tcp_accept_filters.push_back(mask);
-                        // }
-                    }
+                set_opt_string(opt_val, &mut filter_str)?;
+                if filter_str.is_empty() {
+                    self.tcp_accept_filters.clear();
+                } else {
+                    //  An address ("10.0.0.1") or a CIDR mask ("10.0.0.0/8",
+                    //  "fd00::/8").
+                    self.tcp_accept_filters.push(parse_net(&filter_str)?);
                 }
                 return Ok(());
             }
 
+            ZMQ_TCP_MAX_CONNECTIONS => {
+                if is_int && value >= 0 {
+                    self.tcp_max_connections = value;
+                    return Ok(());
+                }
+            }
+
+            ZMQ_ZMTP_COMPRESSION_THRESHOLD => {
+                if is_int && value >= 0 {
+                    self.zmtp_compression_threshold = value;
+                    return Ok(());
+                }
+            }
+
+            ZMQ_TCP_MAX_CONNECTIONS_PER_IP => {
+                if is_int && value >= 0 {
+                    self.tcp_max_connections_per_ip = value;
+                    return Ok(());
+                }
+            }
+
+            ZMQ_TCP_PROXY_PROTOCOL => {
+                return set_opt_bool(opt_val, &mut self.tcp_proxy_protocol);
+            }
+
             // #if defined ZMQ_HAVE_SO_PEERCRED || defined ZMQ_HAVE_LOCAL_PEERCRED
             #[cfg(target_os = "linux")]
             ZMQ_IPC_FILTER_UID => {
@@ -1821,7 +1921,7 @@ impl<'a> ZmqContext<'a> {
                 }
             }
 
-            // #ifdef ZMQ_HAVE_VMCI
+            //  Used by vsock:// too, so available without ZMQ_HAVE_VMCI.
             ZMQ_VMCI_BUFFER_SIZE => {
                 return set_opt_u64(opt_val, &mut self.vmci_buffer_size);
             }
@@ -1835,9 +1935,11 @@ impl<'a> ZmqContext<'a> {
             }
 
             ZMQ_VMCI_CONNECT_TIMEOUT => {
-                return set_opt_u64(opt_val, &mut self.vmci_connect_timeout);
+                if is_int && value >= 0 {
+                    self.vmci_connect_timeout = value;
+                    return Ok(());
+                }
             }
-            // #endif
             ZMQ_USE_FD => {
                 if (is_int && value >= -1) {
                     self.use_fd = value;
@@ -1939,6 +2041,8 @@ impl<'a> ZmqContext<'a> {
                     self.wss_trust_pem =
                         String::from_raw_parts(opt_val as *mut u8, opt_val_len, opt_val_len + 1);
                 };
+                //  Later quic:// connects verify against the new anchors.
+                self.quic_client_config = None;
                 return Ok(());
             }
             ZMQ_WSS_HOSTNAME => {
@@ -1951,8 +2055,41 @@ impl<'a> ZmqContext<'a> {
             ZMQ_WSS_TRUST_SYSTEM => {
                 // return do_setsockopt_int_as_bool_strict(opt_val, opt_val_len,
                 //                                         &mut self.wss_trust_system);
+                self.quic_client_config = None;
                 return set_opt_bool(opt_val, &mut self.wss_trust_system);
             }
+            ZMQ_WS_DEFLATE => {
+                return set_opt_bool(opt_val, &mut self.ws_deflate);
+            }
+            ZMQ_ZMTP_COMPRESSION => {
+                return set_opt_bool(opt_val, &mut self.zmtp_compression);
+            }
+            ZMQ_WS_RESPONSE_HEADER => {
+                let mut header = String::new();
+                set_opt_string(opt_val, &mut header)?;
+                if header.is_empty() {
+                    self.ws_response_headers.clear();
+                    return Ok(());
+                }
+                //  "Name: value", on a line of its own.
+                let valid_name = header.split_once(':').is_some_and(|(name, _)| {
+                    !name.is_empty() && name.bytes().all(|c| c.is_ascii_graphic())
+                });
+                if valid_name && !header.contains(['\r', '\n']) {
+                    self.ws_response_headers.push(header);
+                    return Ok(());
+                }
+            }
+            ZMQ_WS_ALLOWED_ORIGIN => {
+                let mut origin = String::new();
+                set_opt_string(opt_val, &mut origin)?;
+                if origin.is_empty() {
+                    self.ws_allowed_origins.clear();
+                } else {
+                    self.ws_allowed_origins.push(origin);
+                }
+                return Ok(());
+            }
             // #endif
             ZMQ_HELLO_MSG => {
                 unsafe {
@@ -2018,6 +2155,15 @@ impl<'a> ZmqContext<'a> {
                 return Ok(());
             }
 
+            ZMQ_SHM_RING_SIZE => {
+                let mut size = 0u64;
+                set_opt_u64(opt_val, &mut size)?;
+                if size >= ZMQ_SHM_RING_SIZE_MIN {
+                    self.shm_ring_size = size;
+                    return Ok(());
+                }
+            }
+
             // #endif
 
             // _ =>
@@ -2115,6 +2261,30 @@ impl<'a> ZmqContext<'a> {
                 return Ok(self.tcp_maxrt.to_le_bytes().to_vec());
             }
 
+            ZMQ_TCP_MAX_CONNECTIONS => {
+                return Ok(self.tcp_max_connections.to_le_bytes().to_vec());
+            }
+
+            ZMQ_TCP_MAX_CONNECTIONS_PER_IP => {
+                return Ok(self.tcp_max_connections_per_ip.to_le_bytes().to_vec());
+            }
+
+            ZMQ_TCP_PROXY_PROTOCOL => {
+                return bool_to_vec(self.tcp_proxy_protocol);
+            }
+
+            ZMQ_WS_DEFLATE => {
+                return bool_to_vec(self.ws_deflate);
+            }
+
+            ZMQ_ZMTP_COMPRESSION => {
+                return bool_to_vec(self.zmtp_compression);
+            }
+
+            ZMQ_ZMTP_COMPRESSION_THRESHOLD => {
+                return Ok(self.zmtp_compression_threshold.to_le_bytes().to_vec());
+            }
+
             ZMQ_RECONNECT_STOP => {
                 return Ok(self.reconnect_stop.to_le_bytes().to_vec());
             }
@@ -2528,6 +2698,10 @@ impl<'a> ZmqContext<'a> {
                 return i32_to_vec(self.priority);
             }
 
+            ZMQ_SHM_RING_SIZE => {
+                return Ok(self.shm_ring_size.to_le_bytes().to_vec());
+            }
+
             // #endif
             _ => {
                 // #if defined(ZMQ_ACT_MILITANT)
@@ -2634,11 +2808,18 @@ impl<'a> ZmqContext<'a> {
         self.send_command(tid, &mut cmd);
     }
 
-    pub fn send_hiccup(&mut self, tid: u32, destination: ZmqAddress, pipe: &mut [u8]) {
+    pub fn send_hiccup(
+        &mut self,
+        tid: u32,
+        destination: ZmqAddress,
+        pipe: ZmqUpipeWriter,
+        stale: Option<ZmqUpipeReader>,
+    ) {
         let mut cmd = ZmqThreadCommand::default();
         cmd.destination = destination;
         cmd.cmd_type = ThreadCommandType::Hiccup;
         cmd.args.hiccup.pipe = pipe;
+        cmd.args.hiccup.stale = stale;
         self.send_command(tid, &mut cmd);
     }
 
@@ -2760,25 +2941,42 @@ impl<'a> ZmqContext<'a> {
     }
 }
 
+//  An empty value clears the set, anything else adds one id to it.
+fn set_opt_id_hash_set<T: std::hash::Hash + Eq>(
+    opt_val: &[u8],
+    set: &mut HashSet<T>,
+    from_le_bytes: fn([u8; 4]) -> T,
+) -> Result<(), anyhow::Error> {
+    if opt_val.is_empty() {
+        set.clear();
+        return Ok(());
+    }
+    let Ok(bytes) = <[u8; 4]>::try_from(opt_val) else {
+        bail!("EINVAL: invalid id filter length {}", opt_val.len());
+    };
+    set.insert(from_le_bytes(bytes));
+    Ok(())
+}
+
 fn set_opt_gid_hash_set(
     opt_val: &[u8],
     ipc_gid_accept_filters: &mut HashSet<u32>,
 ) -> Result<(), anyhow::Error> {
-    todo!()
+    set_opt_id_hash_set(opt_val, ipc_gid_accept_filters, u32::from_le_bytes)
 }
 
 fn set_opt_pid_hash_set(
     opt_val: &[u8],
     ipc_pid_accept_filters: &mut HashSet<i32>,
 ) -> Result<(), anyhow::Error> {
-    todo!()
+    set_opt_id_hash_set(opt_val, ipc_pid_accept_filters, i32::from_le_bytes)
 }
 
 fn set_opt_uid_hash_set(
     opt_val: &[u8],
     ipc_uid_accept_filters: &mut HashSet<u32>,
 ) -> Result<(), anyhow::Error> {
-    todo!()
+    set_opt_id_hash_set(opt_val, ipc_uid_accept_filters, u32::from_le_bytes)
 }
 
 pub fn clipped_maxsocket(mut max_requested: i32) -> i32 {
@@ -1,374 +1,366 @@
-// /*
-//     Copyright (c) 2007-2016 Contributors as noted in the AUTHORS file
-
-//     This file is part of libzmq, the ZeroMQ core engine in C+= 1.
-
-//     libzmq is free software; you can redistribute it and/or modify it under
-//     the terms of the GNU Lesser General Public License (LGPL) as published
-//     by the Free Software Foundation; either version 3 of the License, or
-//     (at your option) any later version.
-
-//     As a special exception, the Contributors give you permission to link
-//     this library with independent modules to produce an executable,
-//     regardless of the license terms of these independent modules, and to
-//     copy and distribute the resulting executable under terms of your choice,
-//     provided that you also meet, for each linked independent module, the
-//     terms and conditions of the license of that module. An independent
-//     module is a module which is not derived from or based on this library.
-//     If you modify this library, you must extend this exception to your
-//     version of the library.
-
-//     libzmq is distributed in the hope that it will be useful, but WITHOUT
-//     ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
-//     FITNESS FOR A PARTICULAR PURPOSE. See the GNU Lesser General Public
-//     License for more details.
-
-//     You should have received a copy of the GNU Lesser General Public License
-//     along with this program.  If not, see <http://www.gnu.org/licenses/>.
-// */
-
-// use crate::config::{CRYPTO_BOX_BOXZEROBYTES, CRYPTO_BOX_NONCEBYTES, CRYPTO_BOX_ZEROBYTES};
-// use crate::utils::copy_bytes;
-// use anyhow::anyhow;
-
-// pub fn process_welcome(
-//     msg_data_: &[u8],
-//     msg_size_: usize,
-//     server_key_: &[u8],
-//     cn_secret_: &[u8],
-//     cn_server_: &mut [u8],
-//     cn_cookie_: &mut [u8],
-//     cn_precom_: &mut [u8],
-// ) -> anyhow::Result<()> {
-//     if (msg_size_ != 168) {
-//         // errno = EPROTO;
-//         // return -1;
-//         return Err(anyhow!("EPROTO"));
-//     }
-
-//     let mut welcome_nonce: [u8; CRYPTO_BOX_NONCEBYTES as usize] = [0; CRYPTO_BOX_NONCEBYTES];
-//     // std::vector<uint8_t, secure_allocator_t<uint8_t> > welcome_plaintext (
-//     //   CRYPTO_BOX_ZEROBYTES + 128);
-//     let mut welcome_plaintext: Vec<u8> = Vec::with_capacity(CRYPTO_BOX_ZEROBYTES as usize);
-//     welcome_plaintext.fill(0);
-//     let mut welcome_box: [u8; (CRYPTO_BOX_BOXZEROBYTES + 144) as usize] =
-//         [0; CRYPTO_BOX_BOXZEROBYTES + 144];
-
-//     //  Open Box [S' + cookie](C'->S)
-//     // memset (welcome_box, 0, CRYPTO_BOX_BOXZEROBYTES);
-//     // memcpy (welcome_box + CRYPTO_BOX_BOXZEROBYTES, msg_data_ + 24, 144);
-//     copy_bytes(
-//         &mut welcome_box,
-//         CRYPTO_BOX_BOXZEROBYTES as usize,
-//         msg_data_,
-//         24,
-//         144,
-//     );
-
-//     // memcpy (welcome_nonce, "WELCOME-", 8);
-//     copy_bytes(&mut welcome_nonce, 0, b"WELCOME-", 0, 8);
-//     // memcpy (welcome_nonce + 8, msg_data_ + 8, 16);
-//     copy_bytes(&mut welcome_nonce, 8, msg_data_, 8, 16);
-
-//     // TODO
-//     // crypto_box_open(
-//     //     &welcome_plaintext[0],
-//     //     welcome_box,
-//     //     welcome_box.len(),
-//     //     welcome_nonce,
-//     //     server_key_,
-//     //     cn_secret_,
-//     // )?;
-//     // if (rc != 0) {
-//     //   // errno = EPROTO;
-//     //     return -1;
-//     // }
-
-//     // memcpy (cn_server_, &welcome_plaintext[CRYPTO_BOX_ZEROBYTES], 32);
-//     copy_bytes(
-//         cn_server_,
-//         0,
-//         &welcome_plaintext,
-//         CRYPTO_BOX_ZEROBYTES as usize,
-//         32,
-//     );
-
-//     // memcpy (cn_cookie_, &welcome_plaintext[CRYPTO_BOX_ZEROBYTES + 32],
-//     //         16 + 80);
-//     copy_bytes(
-//         cn_cookie_,
-//         0,
-//         &welcome_plaintext,
-//         (CRYPTO_BOX_ZEROBYTES + 32) as usize,
-//         16 + 80,
-//     );
-
-//     //  Message independent precomputation
-//     crypto_box_beforenm(cn_precom_, cn_server_, cn_secret_)?;
-//     // zmq_assert (rc == 0);
-
-//     // return 0;
-//     Ok(())
-// }
-
-// pub fn produce_initiate(
-//     data: &mut [u8],
-//     size: usize,
-//     cn_nonce_: u64,
-//     server_key_: &[u8],
-//     public_key_: &[u8],
-//     secret_key_: &[u8],
-//     cn_public_: &[u8],
-//     cn_secret_: &[u8],
-//     cn_server_: &[u8],
-//     cn_cookie_: &[u8],
-//     metadata_plaintext_: &[u8],
-//     metadata_length_: usize,
-// ) -> anyhow::Result<()> {
-//     let mut vouch_nonce: [u8; CRYPTO_BOX_NONCEBYTES as usize] = [0; CRYPTO_BOX_NONCEBYTES];
-//     // std::vector<uint8_t, secure_allocator_t<uint8_t> > vouch_plaintext (
-//     //   CRYPTO_BOX_ZEROBYTES + 64);
-//     let mut vouch_plaintext: Vec<u8> = Vec::with_capacity((CRYPTO_BOX_ZEROBYTES + 64) as usize);
-//     let mut vouch_box: [u8; (CRYPTO_BOX_BOXZEROBYTES + 80) as usize] =
-//         [0; CRYPTO_BOX_BOXZEROBYTES + 80];
-
-//     //  Create vouch = Box [C',S](C->S')
-//     // std::fill (vouch_plaintext.begin (),
-//     //            vouch_plaintext.begin () + CRYPTO_BOX_ZEROBYTES, 0);
-//     vouch_plaintext.fill(0);
-
-//     // memcpy (&vouch_plaintext[CRYPTO_BOX_ZEROBYTES], cn_public_, 32);
-//     copy_bytes(
-//         &mut vouch_plaintext,
-//         CRYPTO_BOX_ZEROBYTES as usize,
-//         cn_public_,
-//         0,
-//         32,
-//     );
-
-//     // memcpy (&vouch_plaintext[CRYPTO_BOX_ZEROBYTES + 32], server_key_, 32);
-//     copy_bytes(
-//         &mut vouch_plaintext,
-//         (CRYPTO_BOX_ZEROBYTES + 32) as usize,
-//         server_key_,
-//         0,
-//         32,
-//     );
-
-//     // memset (vouch_nonce, 0, CRYPTO_BOX_NONCEBYTES);
-
-//     // memcpy (vouch_nonce, "VOUCH---", 8);
-//     copy_bytes(&mut vouch_nonce, 0, b"VOUCH---", 0, 8);
-//     randombytes(vouch_nonce + 8, 16);
-
-//     crypto_box(
-//         vouch_box,
-//         &vouch_plaintext[0],
-//         vouch_plaintext.size(),
-//         vouch_nonce,
-//         cn_server_,
-//         secret_key_,
-//     )?;
-//     // if (rc == -1)
-//     //     return -1;
-
-//     let mut initiate_nonce: [u8; CRYPTO_BOX_NONCEBYTES as usize] = [0; CRYPTO_BOX_NONCEBYTES];
-
-//     // std::vector<uint8_t> initiate_box (CRYPTO_BOX_BOXZEROBYTES + 144
-//     //                                    + metadata_length_);
-//     let mut initiate_box: Vec<u8> =
-//         Vec::with_capacity((CRYPTO_BOX_BOXZEROBYTES + 144 + metadata_length_) as usize);
-//     initiate_box.fill(0);
-
-//     // std::vector<uint8_t, secure_allocator_t<uint8_t> > initiate_plaintext (
-//     //   CRYPTO_BOX_ZEROBYTES + 128 + metadata_length_);
-//     let mut initiate_plaintext: Vec<u8> =
-//         Vec::with_capacity((CRYPTO_BOX_ZEROBYTES + 128 + metadata_length_) as usize);
-//     initiate_plaintext.fill(0);
-
-//     //  Create Box [C + vouch + metadata](C'->S')
-//     // std::fill (initiate_plaintext.begin (),
-//     //            initiate_plaintext.begin () + CRYPTO_BOX_ZEROBYTES, 0);
-
-//     //  False positives due to https://gcc.gnu.org/bugzilla/show_bug.cgi?id=99578
-//     // #if __GNUC__ >= 11
-//     // #pragma GCC diagnostic ignored "-Warray-bounds"
-//     // #pragma GCC diagnostic ignored "-Wstringop-overflow="
-//     // #endif
-//     //     memcpy (&initiate_plaintext[CRYPTO_BOX_ZEROBYTES], public_key_, 32);
-//     copy_bytes(
-//         &mut initiate_plaintext,
-//         CRYPTO_BOX_ZEROBYTES as usize,
-//         public_key_,
-//         0,
-//         32,
-//     );
-
-//     // memcpy (&initiate_plaintext[CRYPTO_BOX_ZEROBYTES + 32], vouch_nonce + 8,
-//     //         16);
-//     copy_bytes(
-//         &mut initiate_plaintext,
-//         (CRYPTO_BOX_ZEROBYTES + 32) as usize,
-//         &vouch_nonce,
-//         8,
-//         16,
-//     );
-
-//     // memcpy (&initiate_plaintext[CRYPTO_BOX_ZEROBYTES + 48],
-//     //         vouch_box + CRYPTO_BOX_BOXZEROBYTES, 80);
-//     copy_bytes(
-//         &mut initiate_plaintext,
-//         (CRYPTO_BOX_ZEROBYTES + 48) as usize,
-//         &vouch_box,
-//         CRYPTO_BOX_ZEROBYTES as usize,
-//         80,
-//     );
-
-//     if (metadata_length_) {
-//         // memcpy (&initiate_plaintext[CRYPTO_BOX_ZEROBYTES + 48 + 80],
-//         //         metadata_plaintext_, metadata_length_);
-//         copy_bytes(
-//             &mut initiate_plaintext,
-//             (CRYPTO_BOX_ZEROBYTES + 48 + 80) as usize,
-//             metadata_plaintext_,
-//             0,
-//             metadata_length_,
-//         );
-//     }
-//     // #if __GNUC__ >= 11
-//     // #pragma GCC diagnostic pop
-//     // #pragma GCC diagnostic pop
-//     // #endif
-
-//     // memcpy (initiate_nonce, "CurveZMQINITIATE", 16);
-//     copy_bytes(&mut initiate_nonce, 0, b"CurveZMQINITIATE", 0, 16);
-//     put_uint64(initiate_nonce + 16, cn_nonce_);
-
-//     crypto_box(
-//         &initiate_box[0],
-//         &initiate_plaintext[0],
-//         CRYPTO_BOX_ZEROBYTES + 128 + metadata_length_,
-//         initiate_nonce,
-//         cn_server_,
-//         cn_secret_,
-//     )?;
-
-//     // if (rc == -1)
-//     //     return -1;
-
-//     // uint8_t *initiate = static_cast<uint8_t *> (data);
-//     let initiate: &mut [u8] = data;
-
-//     // zmq_assert (size
-//     //             == 113 + 128 + CRYPTO_BOX_BOXZEROBYTES + metadata_length_);
-
-//     // memcpy (initiate, "\x08INITIATE", 9);
-//     copy_bytes(initiate, 0, b"\x08INITIATE", 0, 9);
-//     //  Cookie provided by the server in the WELCOME command
-//     // memcpy (initiate + 9, cn_cookie_, 96);
-//     copy_bytes(initiate, 9, cn_cookie_, 0, 96);
-//     //  Short nonce, prefixed by "CurveZMQINITIATE"
-//     // memcpy (initiate + 105, initiate_nonce + 16, 8);
-//     copy_bytes(initiate, 105, &initiate_nonce, 16, 8);
-//     //  Box [C + vouch + metadata](C'->S')
-//     // memcpy (initiate + 113, &initiate_box[CRYPTO_BOX_BOXZEROBYTES],
-//     //         128 + metadata_length_ + CRYPTO_BOX_BOXZEROBYTES);
-//     copy_bytes(
-//         initiate,
-//         113,
-//         &initiate_box,
-//         CRYPTO_BOX_BOXZEROBYTES as usize,
-//         128 + metadata_length_ + CRYPTO_BOX_BOXZEROBYTES,
-//     );
-
-//     // return 0;
-//     Ok(())
-// }
-
-// pub fn is_handshake_command_welcome(msg_data_: &[u8], msg_size_: usize) -> bool {
-//     return is_handshake_command(msg_data_, msg_size_, b"\x07WELCOME");
-// }
-
-// pub fn is_handshake_command_ready(msg_data_: &[u8], msg_size_: usize) -> bool {
-//     return is_handshake_command(msg_data_, msg_size_, b"\x05READY");
-// }
-
-// pub fn is_handshake_command_error(msg_data_: &[u8], msg_size_: usize) -> bool {
-//     return is_handshake_command(msg_data_, msg_size_, b"\x05ERROR");
-// }
-
-// //  non-static functions
-// // curve_client_tools_t (
-// //   const uint8_t (&curve_public_key_)[CRYPTO_BOX_PUBLICKEYBYTES],
-// //   const uint8_t (&curve_secret_key_)[CRYPTO_BOX_SECRETKEYBYTES],
-// //   const uint8_t (&curve_server_key_)[CRYPTO_BOX_PUBLICKEYBYTES])
-// // {
-// //     rc: i32;
-// //     memcpy (public_key, curve_public_key_, CRYPTO_BOX_PUBLICKEYBYTES);
-// //     memcpy (secret_key, curve_secret_key_, CRYPTO_BOX_SECRETKEYBYTES);
-// //     memcpy (server_key, curve_server_key_, CRYPTO_BOX_PUBLICKEYBYTES);
-// //
-// //     //  Generate short-Term key pair
-// //     memset (cn_secret, 0, CRYPTO_BOX_SECRETKEYBYTES);
-// //     memset (cn_public, 0, CRYPTO_BOX_PUBLICKEYBYTES);
-// //     rc = crypto_box_keypair (cn_public, cn_secret);
-// //     zmq_assert (rc == 0);
-// // }
-
-// // int produce_hello (data: *mut c_void, const u64 cn_nonce_) const
-// // {
-// //     return produce_hello (data, server_key, cn_nonce_, cn_public,
-// //                           cn_secret);
-// // }
-
-// // int process_welcome (msg_data_: &[u8],
-// //                      msg_size_: usize,
-// //                      cn_precom_: &mut [u8])
-// // {
-// //     return process_welcome (msg_data_, msg_size_, server_key, cn_secret,
-// //                             cn_server, cn_cookie, cn_precom_);
-// // }
-
-// // int produce_initiate (data: *mut c_void,
-// //                       size: usize,
-// //                       const cn_nonce_: u64,
-// //                       metadata_plaintext_: &[u8],
-// //                       const metadata_length_: usize) const
-// // {
-// //     return produce_initiate (data, size, cn_nonce_, server_key,
-// //                              public_key, secret_key, cn_public, cn_secret,
-// //                              cn_server, cn_cookie, metadata_plaintext_,
-// //                              metadata_length_);
-// // }
-
-// //  Our public key (C)
-// // uint8_t public_key[CRYPTO_BOX_PUBLICKEYBYTES];
-
-// //  Our secret key (c)
-// // uint8_t secret_key[CRYPTO_BOX_SECRETKEYBYTES];
-
-// //  Our short-Term public key (C')
-// // uint8_t cn_public[CRYPTO_BOX_PUBLICKEYBYTES];
-
-// //  Our short-Term secret key (c')
-// // uint8_t cn_secret[CRYPTO_BOX_SECRETKEYBYTES];
-
-// //  Server's public key (S)
-// // uint8_t server_key[CRYPTO_BOX_PUBLICKEYBYTES];
-
-// //  Server's short-Term public key (S')
-// // uint8_t cn_server[CRYPTO_BOX_PUBLICKEYBYTES];
-
-// //  Cookie received from server
-// // uint8_t cn_cookie[16 + 80];
-
-// //
-// pub fn is_handshake_command(msg_data_: &[u8], msg_size_: usize, prefix_: &[u8]) -> bool {
-//     let mut N = prefix_.len();
-//     msg_size_ >= (N - 1) && msg_data_[N - 1] == prefix_[N - 1]
-//     // return msg_size_ >= (N - 1) && !memcmp (msg_data_, prefix_, N - 1);
-// }
-
-// // #endif
-
-// // #endif
+/*
+    Copyright (c) 2007-2016 Contributors as noted in the AUTHORS file
+
+    This file is part of libzmq, the ZeroMQ core engine in C+= 1.
+
+    libzmq is free software; you can redistribute it and/or modify it under
+    the terms of the GNU Lesser General Public License (LGPL) as published
+    by the Free Software Foundation; either version 3 of the License, or
+    (at your option) any later version.
+
+    As a special exception, the Contributors give you permission to link
+    this library with independent modules to produce an executable,
+    regardless of the license terms of these independent modules, and to
+    copy and distribute the resulting executable under terms of your choice,
+    provided that you also meet, for each linked independent module, the
+    terms and conditions of the license of that module. An independent
+    module is a module which is not derived from or based on this library.
+    If you modify this library, you must extend this exception to your
+    version of the library.
+
+    libzmq is distributed in the hope that it will be useful, but WITHOUT
+    ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+    FITNESS FOR A PARTICULAR PURPOSE. See the GNU Lesser General Public
+    License for more details.
+
+    You should have received a copy of the GNU Lesser General Public License
+    along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+use anyhow::{anyhow, bail};
+use crypto_box::SalsaBox;
+
+use crate::config::{CRYPTO_BOX_PUBLICKEYBYTES, CRYPTO_BOX_SECRETKEYBYTES};
+use crate::curve_encoding::{
+    curve_box, curve_box_open, curve_keypair, curve_make_nonce, curve_precompute,
+    curve_random_bytes, curve_short_nonce, ZmqCurvePublicKey, ZmqCurveSecretKey, ZmqNonce,
+};
+use crate::utils::copy_bytes;
+
+pub const HELLO_PREFIX: &[u8] = b"\x05HELLO";
+pub const WELCOME_PREFIX: &[u8] = b"\x07WELCOME";
+pub const INITIATE_PREFIX: &[u8] = b"\x08INITIATE";
+pub const READY_PREFIX: &[u8] = b"\x05READY";
+pub const ERROR_PREFIX: &[u8] = b"\x05ERROR";
+
+pub const HELLO_NONCE_PREFIX: &[u8] = b"CurveZMQHELLO---";
+pub const WELCOME_NONCE_PREFIX: &[u8] = b"WELCOME-";
+pub const COOKIE_NONCE_PREFIX: &[u8] = b"COOKIE--";
+pub const VOUCH_NONCE_PREFIX: &[u8] = b"VOUCH---";
+pub const INITIATE_NONCE_PREFIX: &[u8] = b"CurveZMQINITIATE";
+pub const READY_NONCE_PREFIX: &[u8] = b"CurveZMQREADY---";
+
+//  Sizes of the fixed-layout handshake commands (see RFC 26/CurveZMQ).
+pub const HELLO_SIZE: usize = 200;
+pub const WELCOME_SIZE: usize = 168;
+pub const INITIATE_MIN_SIZE: usize = 257;
+pub const COOKIE_SIZE: usize = 16 + 80;
+pub const LONG_NONCE_SIZE: usize = 16;
+
+pub fn is_handshake_command(msg_data: &[u8], msg_size: usize, prefix: &[u8]) -> bool {
+    msg_size >= prefix.len() && msg_data[..prefix.len()] == *prefix
+}
+
+pub fn is_handshake_command_welcome(msg_data: &[u8], msg_size: usize) -> bool {
+    is_handshake_command(msg_data, msg_size, WELCOME_PREFIX)
+}
+
+pub fn is_handshake_command_ready(msg_data: &[u8], msg_size: usize) -> bool {
+    is_handshake_command(msg_data, msg_size, READY_PREFIX)
+}
+
+pub fn is_handshake_command_error(msg_data: &[u8], msg_size: usize) -> bool {
+    is_handshake_command(msg_data, msg_size, ERROR_PREFIX)
+}
+
+//  HELLO: C' + Box [64 * %x0](C'->S)
+pub fn produce_hello(
+    server_key: &[u8],
+    cn_nonce: ZmqNonce,
+    cn_public: &[u8],
+    cn_secret: &[u8],
+) -> anyhow::Result<Vec<u8>> {
+    let hello_nonce = curve_short_nonce(HELLO_NONCE_PREFIX, cn_nonce);
+    let hello_plaintext = [0u8; 64];
+    let hello_box = curve_box(
+        &curve_precompute(server_key, cn_secret)?,
+        &hello_nonce,
+        &hello_plaintext,
+    )?;
+
+    let mut hello: Vec<u8> = Vec::with_capacity(HELLO_SIZE);
+    hello.extend_from_slice(HELLO_PREFIX);
+    //  CurveZMQ major and minor version numbers
+    hello.extend_from_slice(&[1, 0]);
+    //  Anti-amplification padding
+    hello.extend_from_slice(&[0u8; 72]);
+    //  Client public connection key
+    hello.extend_from_slice(&cn_public[..CRYPTO_BOX_PUBLICKEYBYTES]);
+    //  Short nonce, prefixed by "CurveZMQHELLO---"
+    hello.extend_from_slice(&cn_nonce.to_be_bytes());
+    //  Signature, Box [64 * %x0](C'->S)
+    hello.extend_from_slice(&hello_box);
+    debug_assert_eq!(hello.len(), HELLO_SIZE);
+    Ok(hello)
+}
+
+//  WELCOME: long nonce + Box [S' + cookie](S->C'). On success the server's
+//  short-term key and cookie are stored and the session key is returned.
+pub fn process_welcome(
+    msg_data: &[u8],
+    msg_size: usize,
+    server_key: &[u8],
+    cn_secret: &[u8],
+    cn_server: &mut [u8],
+    cn_cookie: &mut [u8],
+) -> anyhow::Result<SalsaBox> {
+    if msg_size != WELCOME_SIZE {
+        bail!("EPROTO");
+    }
+
+    let welcome_nonce = curve_make_nonce(WELCOME_NONCE_PREFIX, &msg_data[8..24]);
+    let welcome_plaintext = curve_box_open(
+        &curve_precompute(server_key, cn_secret)?,
+        &welcome_nonce,
+        &msg_data[24..WELCOME_SIZE],
+    )?;
+
+    copy_bytes(cn_server, 0, &welcome_plaintext, 0, CRYPTO_BOX_PUBLICKEYBYTES);
+    copy_bytes(
+        cn_cookie,
+        0,
+        &welcome_plaintext,
+        CRYPTO_BOX_PUBLICKEYBYTES,
+        COOKIE_SIZE,
+    );
+
+    //  Message independent precomputation
+    curve_precompute(cn_server, cn_secret)
+}
+
+//  INITIATE: cookie + short nonce + Box [C + vouch + metadata](C'->S')
+#[allow(clippy::too_many_arguments)]
+pub fn produce_initiate(
+    cn_nonce: ZmqNonce,
+    server_key: &[u8],
+    public_key: &[u8],
+    secret_key: &[u8],
+    cn_public: &[u8],
+    cn_secret: &[u8],
+    cn_server: &[u8],
+    cn_cookie: &[u8],
+    metadata_plaintext: &[u8],
+) -> anyhow::Result<Vec<u8>> {
+    //  Create vouch = Box [C',S](C->S')
+    let mut vouch_nonce_suffix = [0u8; LONG_NONCE_SIZE];
+    curve_random_bytes(&mut vouch_nonce_suffix);
+    let vouch_nonce = curve_make_nonce(VOUCH_NONCE_PREFIX, &vouch_nonce_suffix);
+
+    let mut vouch_plaintext: Vec<u8> = Vec::with_capacity(64);
+    vouch_plaintext.extend_from_slice(&cn_public[..CRYPTO_BOX_PUBLICKEYBYTES]);
+    vouch_plaintext.extend_from_slice(&server_key[..CRYPTO_BOX_PUBLICKEYBYTES]);
+    let vouch_box = curve_box(
+        &curve_precompute(cn_server, secret_key)?,
+        &vouch_nonce,
+        &vouch_plaintext,
+    )?;
+
+    //  Create Box [C + vouch + metadata](C'->S')
+    let mut initiate_plaintext: Vec<u8> =
+        Vec::with_capacity(128 + metadata_plaintext.len());
+    initiate_plaintext.extend_from_slice(&public_key[..CRYPTO_BOX_PUBLICKEYBYTES]);
+    initiate_plaintext.extend_from_slice(&vouch_nonce_suffix);
+    initiate_plaintext.extend_from_slice(&vouch_box);
+    initiate_plaintext.extend_from_slice(metadata_plaintext);
+
+    let initiate_nonce = curve_short_nonce(INITIATE_NONCE_PREFIX, cn_nonce);
+    let initiate_box = curve_box(
+        &curve_precompute(cn_server, cn_secret)?,
+        &initiate_nonce,
+        &initiate_plaintext,
+    )?;
+
+    let mut initiate: Vec<u8> =
+        Vec::with_capacity(INITIATE_MIN_SIZE + metadata_plaintext.len());
+    initiate.extend_from_slice(INITIATE_PREFIX);
+    //  Cookie provided by the server in the WELCOME command
+    initiate.extend_from_slice(&cn_cookie[..COOKIE_SIZE]);
+    //  Short nonce, prefixed by "CurveZMQINITIATE"
+    initiate.extend_from_slice(&cn_nonce.to_be_bytes());
+    //  Box [C + vouch + metadata](C'->S')
+    initiate.extend_from_slice(&initiate_box);
+    debug_assert_eq!(
+        initiate.len(),
+        INITIATE_MIN_SIZE + metadata_plaintext.len()
+    );
+    Ok(initiate)
+}
+
+//  Long- and short-term keys of a CURVE client.
+#[derive(Default, Debug, Clone)]
+pub struct ZmqCurveClientTools {
+    //  Our public key (C)
+    pub public_key: ZmqCurvePublicKey,
+    //  Our secret key (c)
+    pub secret_key: ZmqCurveSecretKey,
+    //  Our short-term public key (C')
+    pub cn_public: ZmqCurvePublicKey,
+    //  Our short-term secret key (c')
+    pub cn_secret: ZmqCurveSecretKey,
+    //  Server's public key (S)
+    pub server_key: ZmqCurvePublicKey,
+    //  Server's short-term public key (S')
+    pub cn_server: ZmqCurvePublicKey,
+    //  Cookie received from server
+    pub cn_cookie: Vec<u8>,
+}
+
+impl ZmqCurveClientTools {
+    pub fn new(
+        curve_public_key: &[u8],
+        curve_secret_key: &[u8],
+        curve_server_key: &[u8],
+    ) -> anyhow::Result<Self> {
+        if curve_public_key.len() < CRYPTO_BOX_PUBLICKEYBYTES
+            || curve_secret_key.len() < CRYPTO_BOX_SECRETKEYBYTES
+            || curve_server_key.len() < CRYPTO_BOX_PUBLICKEYBYTES
+        {
+            return Err(anyhow!("EINVAL: CURVE client keys are not set"));
+        }
+
+        let mut out = Self {
+            cn_cookie: vec![0; COOKIE_SIZE],
+            ..Default::default()
+        };
+        out.public_key
+            .copy_from_slice(&curve_public_key[..CRYPTO_BOX_PUBLICKEYBYTES]);
+        out.secret_key
+            .copy_from_slice(&curve_secret_key[..CRYPTO_BOX_SECRETKEYBYTES]);
+        out.server_key
+            .copy_from_slice(&curve_server_key[..CRYPTO_BOX_PUBLICKEYBYTES]);
+
+        //  Generate short-term key pair
+        (out.cn_public, out.cn_secret) = curve_keypair();
+        Ok(out)
+    }
+
+    pub fn produce_hello(&self, cn_nonce: ZmqNonce) -> anyhow::Result<Vec<u8>> {
+        produce_hello(&self.server_key, cn_nonce, &self.cn_public, &self.cn_secret)
+    }
+
+    pub fn process_welcome(&mut self, msg_data: &[u8], msg_size: usize) -> anyhow::Result<SalsaBox> {
+        process_welcome(
+            msg_data,
+            msg_size,
+            &self.server_key,
+            &self.cn_secret,
+            &mut self.cn_server,
+            &mut self.cn_cookie,
+        )
+    }
+
+    pub fn produce_initiate(
+        &self,
+        cn_nonce: ZmqNonce,
+        metadata_plaintext: &[u8],
+    ) -> anyhow::Result<Vec<u8>> {
+        produce_initiate(
+            cn_nonce,
+            &self.server_key,
+            &self.public_key,
+            &self.secret_key,
+            &self.cn_public,
+            &self.cn_secret,
+            &self.cn_server,
+            &self.cn_cookie,
+            metadata_plaintext,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::CRYPTO_SECRETBOX_KEYBYTES;
+    use crate::curve_encoding::{curve_secretbox, curve_secretbox_open};
+
+    //  Plays the server side of the handshake the way ZmqCurveServer does.
+    #[test]
+    fn handshake_round_trip() {
+        let (client_public, client_secret) = curve_keypair();
+        let (server_public, server_secret) = curve_keypair();
+        let mut client =
+            ZmqCurveClientTools::new(&client_public, &client_secret, &server_public).unwrap();
+
+        //  HELLO
+        let hello = client.produce_hello(1).unwrap();
+        assert_eq!(hello.len(), HELLO_SIZE);
+        let cn_client = &hello[80..112];
+        let hello_nonce = curve_make_nonce(HELLO_NONCE_PREFIX, &hello[112..120]);
+        let hello_precom = curve_precompute(cn_client, &server_secret).unwrap();
+        let signature = curve_box_open(&hello_precom, &hello_nonce, &hello[120..]).unwrap();
+        assert_eq!(signature, [0u8; 64]);
+
+        //  WELCOME
+        let (cn_public, cn_secret) = curve_keypair();
+        let mut cookie_key = [0u8; CRYPTO_SECRETBOX_KEYBYTES];
+        curve_random_bytes(&mut cookie_key);
+        let cookie_suffix = [7u8; LONG_NONCE_SIZE];
+        let cookie_nonce = curve_make_nonce(COOKIE_NONCE_PREFIX, &cookie_suffix);
+        let cookie_plaintext = [cn_client, &cn_secret].concat();
+        let cookie = curve_secretbox(&cookie_key, &cookie_nonce, &cookie_plaintext).unwrap();
+        let welcome_suffix = [9u8; LONG_NONCE_SIZE];
+        let welcome_nonce = curve_make_nonce(WELCOME_NONCE_PREFIX, &welcome_suffix);
+        let welcome_plaintext = [&cn_public[..], &cookie_suffix, &cookie].concat();
+        let welcome_box = curve_box(&hello_precom, &welcome_nonce, &welcome_plaintext).unwrap();
+        let welcome = [WELCOME_PREFIX, &welcome_suffix, &welcome_box].concat();
+        let client_precom = client.process_welcome(&welcome, welcome.len()).unwrap();
+        assert_eq!(client.cn_server, cn_public);
+
+        //  INITIATE
+        let metadata = b"\x0bSocket-Type\x00\x00\x00\x06DEALER";
+        let initiate = client.produce_initiate(2, metadata).unwrap();
+        assert_eq!(initiate.len(), INITIATE_MIN_SIZE + metadata.len());
+        let cookie_nonce = curve_make_nonce(COOKIE_NONCE_PREFIX, &initiate[9..25]);
+        let cookie_plaintext =
+            curve_secretbox_open(&cookie_key, &cookie_nonce, &initiate[25..105]).unwrap();
+        assert_eq!(cookie_plaintext, [cn_client, &cn_secret].concat());
+
+        let server_precom = curve_precompute(cn_client, &cn_secret).unwrap();
+        let initiate_nonce = curve_make_nonce(INITIATE_NONCE_PREFIX, &initiate[105..113]);
+        let initiate_plaintext =
+            curve_box_open(&server_precom, &initiate_nonce, &initiate[113..]).unwrap();
+        assert_eq!(initiate_plaintext[..32], client_public);
+        assert_eq!(initiate_plaintext[128..], metadata[..]);
+
+        let vouch_precom = curve_precompute(&initiate_plaintext[..32], &cn_secret).unwrap();
+        let vouch_nonce = curve_make_nonce(VOUCH_NONCE_PREFIX, &initiate_plaintext[32..48]);
+        let vouch =
+            curve_box_open(&vouch_precom, &vouch_nonce, &initiate_plaintext[48..128]).unwrap();
+        assert_eq!(vouch, [cn_client, &server_public].concat());
+
+        //  READY, sealed with the server's session key and opened with the client's
+        let ready_nonce = curve_short_nonce(READY_NONCE_PREFIX, 1);
+        let ready_box = curve_box(&server_precom, &ready_nonce, metadata).unwrap();
+        let ready_plaintext = curve_box_open(&client_precom, &ready_nonce, &ready_box).unwrap();
+        assert_eq!(ready_plaintext, metadata);
+    }
+
+    #[test]
+    fn welcome_from_wrong_server_is_rejected() {
+        let (client_public, client_secret) = curve_keypair();
+        let (server_public, _) = curve_keypair();
+        let (_, impostor_secret) = curve_keypair();
+        let mut client =
+            ZmqCurveClientTools::new(&client_public, &client_secret, &server_public).unwrap();
+
+        let hello = client.produce_hello(1).unwrap();
+        let precom = curve_precompute(&hello[80..112], &impostor_secret).unwrap();
+        let welcome_suffix = [9u8; LONG_NONCE_SIZE];
+        let welcome_nonce = curve_make_nonce(WELCOME_NONCE_PREFIX, &welcome_suffix);
+        let welcome_box = curve_box(&precom, &welcome_nonce, &[0u8; 32 + COOKIE_SIZE]).unwrap();
+        let welcome = [WELCOME_PREFIX, &welcome_suffix, &welcome_box].concat();
+        assert!(client.process_welcome(&welcome, welcome.len()).is_err());
+        assert!(client.process_welcome(&welcome[..100], 100).is_err());
+    }
+}
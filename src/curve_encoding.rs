@@ -1,376 +1,321 @@
-// use std::mem;
-
-// use anyhow::anyhow;
-
-// use crate::config::CRYPTO_BOX_NONCEBYTES;
-// use crate::curve_mechanism_base::{
-//     ZmqNonce, CRYPTO_BOX_MACBYTES, FLAGS_LEN, FLAG_MASK, MESSAGE_COMMAND, MESSAGE_COMMAND_LEN,
-//     MESSAGE_HDR_LEN, NONCE_PREFIX_LEN,
-// };
-// use crate::defines::{
-//     ZMQ_PROTOCOL_ERROR_ZMTP_INVALID_SEQUENCE, ZMQ_PROTOCOL_ERROR_ZMTP_MALFORMED_COMMAND_MESSAGE,
-//     ZMQ_PROTOCOL_ERROR_ZMTP_UNEXPECTED_COMMAND,
-// };
-// use crate::message::{
-//     ZmqMessage, CANCEL_CMD_NAME, CANCEL_CMD_NAME_SIZE, SUB_CMD_NAME, SUB_CMD_NAME_SIZE,
-//     ZMQ_MSG_COMMAND, ZMQ_MSG_MORE,
-// };
-// use crate::utils::copy_bytes;
-
-// #[derive(Default, Debug, Clone)]
-// pub struct ZmqCurveEncoding {
-//     // public:
-//     // typedef u64 nonce_t;
-//     //   private:
-//     // const char *_encode_nonce_prefix;
-//     pub encode_nonce_prefix: Vec<u8>,
-//     // const char *_decode_nonce_prefix;
-//     pub decode_nonce_prefix: Vec<u8>,
-//     // nonce_t _cn_nonce;
-//     pub cn_nonce: ZmqNonce,
-//     // nonce_t _cn_peer_nonce;
-//     pub cn_peer_nonce: ZmqNonce,
-//     //  Intermediary buffer used to speed up boxing and unboxing.
-//     // uint8_t _cn_precom[crypto_box_BEFORENMBYTES];
-//     pub cn_precom: Vec<u8>,
-//     // const _downgrade_sub: bool
-//     pub downgrade_sub: bool,
-//     // // ZMQ_NON_COPYABLE_NOR_MOVABLE (curve_encoding_t)
-// }
-
-// impl ZmqCurveEncoding {
-//     // curve_encoding_t (encode_nonce_prefix_: *const c_char,
-//     //     decode_nonce_prefix_: *const c_char,
-//     //     const downgrade_sub_: bool);
-
-//     // int encode (msg: &mut ZmqMessage);
-
-//     // int decode (msg: &mut ZmqMessage error_event_code_: *mut i32);
-
-//     // uint8_t *get_writable_precom_buffer () { return _cn_precom; }
-//     pub fn get_writable_precom_buffer(&mut self) -> &mut [u8] {
-//         &mut self.cn_precom
-//     }
-
-//     // const uint8_t *get_precom_buffer () const { return _cn_precom; }
-//     pub fn get_precom_buffer(&self) -> &[u8] {
-//         &self.cn_precom
-//     }
-
-//     // nonce_t get_and_inc_nonce () { return _cn_nonce+= 1; }
-//     pub fn get_and_inc_nonce(&mut self) -> ZmqNonce {
-//         self.cn_nonce += 1;
-//         self.cn_nonce
-//     }
-
-//     // void set_peer_nonce (nonce_t peer_nonce_) { _cn_peer_nonce = peer_nonce_; }
-//     pub fn set_peer_nonce(&mut self, peer_nonce: ZmqNonce) {
-//         self.cn_peer_nonce = peer_nonce;
-//     }
-
-//     // int check_validity (msg: &mut ZmqMessage error_event_code_: *mut i32);
-
-//     // pub impl curve_encoding_t
-
-//     // curve_encoding_t::curve_encoding_t (encode_nonce_prefix_: * const c_char,
-//     // decode_nonce_prefix_: * const c_char,
-//     // const downgrade_sub_: bool):
-//     // _encode_nonce_prefix (encode_nonce_prefix_),
-//     // _decode_nonce_prefix (decode_nonce_prefix_),
-//     // _cn_nonce (1),
-//     // _cn_peer_nonce (1),
-//     // _downgrade_sub (downgrade_sub_)
-//     // {}
-//     pub fn new(encode_nonce_prefix: &str, decode_nonce_prefix: &str, downgrade_sub: bool) -> Self {
-//         Self {
-//             encode_nonce_prefix: encode_nonce_prefix.as_bytes().into_vec(),
-//             decode_nonce_prefix: decode_nonce_prefix.as_bytes().into_vec(),
-//             cn_peer_nonce: 1,
-//             cn_nonce: 1,
-//             downgrade_sub: downgrade_sub,
-//             cn_precom: vec![],
-//         }
-//     }
-
-//     pub fn check_validity(
-//         &mut self,
-//         msg: &mut ZmqMessage,
-//         error_event_code: &mut u32,
-//     ) -> anyhow::Result<()> {
-//         let size = msg.size();
-//         let message = msg.data().unwrap();
-
-//         // if (size < message_command_len
-//         // || 0 != memcmp (message, message_command, message_command_len))
-
-//         if size < self.message_command_len || msg != self.message_command {
-//             *error_event_code = ZMQ_PROTOCOL_ERROR_ZMTP_UNEXPECTED_COMMAND;
-//             // errno = EPROTO;
-//             // return - 1;
-//             return Err(anyhow!("EPROTO: unexpected command: {}", &message));
-//         }
-
-//         if (size < self.message_header_len + CRYPTO_BOX_MACBYTES + self.flags_len) {
-//             *error_event_code = ZMQ_PROTOCOL_ERROR_ZMTP_MALFORMED_COMMAND_MESSAGE;
-//             // errno = EPROTO;
-//             // return - 1;
-//             return Err(anyhow!("EPROTO: malformed command message"));
-//         }
-
-//         {
-//             let nonce: u64 = get_uint64(message + self.message_command_len);
-//             if (nonce <= _cn_peer_nonce) {
-//                 *error_event_code = ZMQ_PROTOCOL_ERROR_ZMTP_INVALID_SEQUENCE;
-//                 // errno = EPROTO;
-//                 // return - 1;
-//                 return Err(anyhow!("EPROTO: invalid sequence"));
-//             }
-//             set_peer_nonce(nonce);
-//         }
-
-//         // return 0;
-//         Ok(())
-//     }
-
-//     pub fn encode(&mut self, msg: &mut ZmqMessage) -> anyhow::Result<()> {
-//         let mut sub_cancel_len = 0usize;
-//         let mut message_nonce: [u8; CRYPTO_BOX_NONCEBYTES as usize] = [0; CRYPTO_BOX_NONCEBYTES];
-//         // memcpy (message_nonce, _encode_nonce_prefix, nonce_prefix_len);
-//         copy_bytes(
-//             &mut message_nonce,
-//             0,
-//             _encode_nonce_prefix,
-//             0,
-//             self.nonce_prefix_len,
-//         );
-//         put_uint64(message_nonce + self.nonce_prefix_len, get_and_inc_nonce());
-
-//         if (msg.is_subscribe() || msg.is_cancel()) {
-//             if (self.downgrade_sub) {
-//                 sub_cancel_len = 1;
-//             } else {
-//                 sub_cancel_len = if msg.is_cancel() {
-//                     ZmqMessage::CANCEL_CMD_NAME_SIZE
-//                 } else {
-//                     ZmqMessage::SUB_CMD_NAME_SIZE
-//                 };
-//             }
-//         }
-
-//         // #ifdef ZMQ_HAVE_CRYPTO_BOX_EASY_FNS
-//         let mlen = FLAGS_LEN + sub_cancel_len + msg.size();
-//         // std::vector < uint8_t > message_plaintext (mlen);
-//         // #else
-//         let mut message_plaintext: Vec<u8> = Vec::with_capacity(mlen);
-//         //     const size_t mlen =
-//         // CRYPTO_BOX_ZEROBYTES + flags_len + sub_cancel_len + msg.size ();
-//         // std::vector < uint8_t > message_plaintext_with_zerobytes (mlen);
-//         // uint8_t * const message_plaintext =
-//         // & message_plaintext_with_zerobytes[CRYPTO_BOX_ZEROBYTES];
-
-//         // std::fill (message_plaintext_with_zerobytes.begin (),
-//         // message_plaintext_with_zerobytes.begin () + CRYPTO_BOX_ZEROBYTES,
-//         // 0);
-//         message_plaintext.fill(0);
-//         // #endif
-
-//         let flags = msg.flags() & FLAG_MASK;
-//         message_plaintext[0] = flags;
-
-//         // For backward compatibility subscribe/cancel command messages are not stored with
-//         // the message flags, and are encoded in the encoder, so that messages for < 3.0 peers
-//         // can be encoded in the "old" 0/1 way rather than as commands.
-//         if (sub_cancel_len == 1) {
-//             message_plaintext[FLAGS_LEN] = if msg.is_subscribe() { 1 } else { 0 };
-//         } else if (sub_cancel_len == ZmqMessage::SUB_CMD_NAME_SIZE) {
-//             message_plaintext[0] |= ZMQ_MSG_COMMAND;
-//             // memcpy ( &message_plaintext[flags_len], SUB_CMD_NAME,     ZmqMessage::SUB_CMD_NAME_SIZE);
-//             copy_bytes(
-//                 &mut mesage_plaintext,
-//                 FLAGS_LEN,
-//                 SUB_CMD_NAME,
-//                 0,
-//                 SUB_CMD_NAME_SIZE,
-//             );
-//         } else if (sub_cancel_len == ZmqMessage::CANCEL_CMD_NAME_SIZE) {
-//             message_plaintext[0] |= ZMQ_MSG_COMMAND;
-//             // memcpy ( &message_plaintext[flags_len], CANCEL_CMD_NAME,
-//             // ZmqMessage::CANCEL_CMD_NAME_SIZE);
-//             copy_bytes(
-//                 &mut message_plaintext,
-//                 FLAGS_LEN,
-//                 CANCEL_CMD_NAME,
-//                 0,
-//                 CANCEL_CMD_NAME_SIZE,
-//             )
-//         }
-
-//         // this is copying the data from insecure memory, so there is no point in
-//         // using secure_allocator_t for message_plaintext
-//         if (msg.size() > 0) {
-//             // memcpy(&message_plaintext[flags_len + sub_cancel_len], msg.data(),
-//             //        msg.size());
-//             copy_bytes(
-//                 &mut message_plaintext,
-//                 FLAGS_LEN + sub_cancel_len,
-//                 msg.data().unwrap().as_slice(),
-//                 0,
-//                 msg.size(),
-//             )
-//         }
-
-//         // #ifdef ZMQ_HAVE_CRYPTO_BOX_EASY_FNS
-//         let mut msg_box: ZmqMessage = ZmqMessage::default();
-//         msg_box.init_size(MESSAGE_HDR_LEN + mlen + CRYPTO_BOX_MACBYTES)?;
-//         // zmq_assert (rc == 0);
-
-//         crypto_box_easy_afternm(
-//             &mut msg_box.data().unwrap() + MESSAGE_HDR_LEN,
-//             &message_plaintext[0],
-//             mlen,
-//             message_nonce,
-//             _cn_precom,
-//         )?;
-//         // zmq_assert (rc == 0);
-
-//         // msg.move (msg_box);
-//         msg_box = msg.clone();
-
-//         // uint8_t * const message = static_cast <uint8_t * > (msg.data ());
-//         let message = msg.data_mut().unwrap().as_slice();
-//         // #else
-//         // std::vector < uint8_t > message_box (mlen);
-//         //
-//         // int rc =
-//         // crypto_box_afternm ( & message_box[0], & message_plaintext_with_zerobytes[0],
-//         // mlen, message_nonce, _cn_precom);
-//         // zmq_assert (rc == 0);
-//         //
-//         // rc = msg.close ();
-//         // zmq_assert (rc == 0);
-//         //
-//         // rc = msg.init_size (16 + mlen - CRYPTO_BOX_BOXZEROBYTES);
-//         // zmq_assert (rc == 0);
-//         //
-//         // uint8_t *const message = static_cast < uint8_t * > (msg.data ());
-//         //
-//         // memcpy (message + message_header_len, & message_box[CRYPTO_BOX_BOXZEROBYTES],
-//         // mlen - CRYPTO_BOX_BOXZEROBYTES);
-//         // #endif
-
-//         // memcpy (message, message_command, message_command_len);
-//         copy_bytes(message, 0, MESSAGE_COMMAND, 0, MESSAGE_COMMAND_LEN);
-
-//         // memcpy (message + message_command_len, message_nonce + nonce_prefix_len,
-//         // mem::size_of::< ZmqNonce > ());
-
-//         // return 0;
-//         Ok(())
-//     }
-
-//     pub fn decode(
-//         &mut self,
-//         msg: &mut ZmqMessage,
-//         error_event_code_: &mut u32,
-//     ) -> anyhow::Result<()> {
-//         check_validity(msg, error_event_code_)?;
-//         // if (0 != rc) {
-//         // return rc;
-
-//         // uint8_t * const message = static_cast < uint8_t * > (msg.data ());
-//         let message = msg.data().unwrap().as_slice();
-
-//         // uint8_t message_nonce[CRYPTO_BOX_NONCEBYTES];
-//         let mut message_nonce: [u8; CRYPTO_BOX_NONCEBYTES as usize] = [0; CRYPTO_BOX_NONCEBYTES];
-
-//         // memcpy (message_nonce, _decode_nonce_prefix, nonce_prefix_len);
-//         copy_bytes(
-//             &mut message_nonce,
-//             0,
-//             self.decode_nonce_prefix.as_slice(),
-//             0,
-//             NONCE_PREFIX_LEN,
-//         );
-
-//         // memcpy (message_nonce + nonce_prefix_len, message + message_command_len,
-//         // mem::size_of::< ZmqNonce >());
-//         copy_bytes(
-//             &mut message_nonce,
-//             NONCE_PREFIX_LEN as usize,
-//             message,
-//             MESSAGE_COMMAND_LEN as usize,
-//             mem::size_of::<ZmqNonce>(),
-//         );
-
-//         // #ifdef ZMQ_HAVE_CRYPTO_BOX_EASY_FNS
-//         let clen = msg.size() - MESSAGE_HDR_LEN;
-
-//         // uint8_t * const message_plaintext = message + message_header_len;
-//         let message_plaintext = message[MESSAGE_HDR_LEN..];
-
-//         crypto_box_open_easy_afternm(
-//             message_plaintext,
-//             message + MESSAGE_HDR_LEN,
-//             clen,
-//             message_nonce,
-//             _cn_precom,
-//         )?;
-//         // #else
-//         // const size_t clen =
-//         // CRYPTO_BOX_BOXZEROBYTES + msg.size () - message_header_len;
-//         //
-//         // std::vector <uint8_t > message_plaintext_with_zerobytes (clen);
-//         // std::vector< uint8_t > message_box (clen);
-//         //
-//         // std::fill (message_box.begin (),
-//         // message_box.begin () + CRYPTO_BOX_BOXZEROBYTES, 0);
-//         // memcpy ( & message_box[CRYPTO_BOX_BOXZEROBYTES], message + message_header_len,
-//         // msg.size () - message_header_len);
-//         //
-//         // rc = crypto_box_open_afternm ( & message_plaintext_with_zerobytes[0],
-//         // & message_box[0], clen, message_nonce,
-//         // _cn_precom);
-//         //
-//         // const uint8_t * const message_plaintext =
-//         // &message_plaintext_with_zerobytes[CRYPTO_BOX_ZEROBYTES];
-//         // #endif
-
-//         // if (rc == 0) {
-//         let flags = message_plaintext[0];
-
-//         // #ifdef ZMQ_HAVE_CRYPTO_BOX_EASY_FNS
-//         let plaintext_size = clen - FLAGS_LEN - CRYPTO_BOX_MACBYTES;
-
-//         if (plaintext_size > 0) {
-//             // memmove (msg.data (), & message_plaintext[flags_len],
-//             // plaintext_size);
-//         }
-
-//         msg.shrink(plaintext_size);
-//         // // #else
-//         // rc = msg.close ();
-//         // zmq_assert (rc == 0);
-//         //
-//         // rc = msg.init_size (clen - flags_len - CRYPTO_BOX_ZEROBYTES);
-//         // zmq_assert (rc == 0);
-//         //
-//         // // this is copying the data to insecure memory, so there is no point in
-//         // // using secure_allocator_t for message_plaintext
-//         // if (msg.size () > 0) {
-//         // memcpy (msg.data (), & message_plaintext[flags_len],
-//         // msg.size ());
-//         // }
-//         // // #endif
-
-//         msg.set_flags(flags & FLAG_MASK);
-//         // } else {
-//         // // CURVE I : connection key used for MESSAGE is wrong
-//         // * error_event_code_ = ZMQ_PROTOCOL_ERROR_ZMTP_CRYPTOGRAPHIC;
-//         // errno = EPROTO;
-//         // }
-
-//         // return rc;
-//         Ok(())
-//         // }
-//     }
-// }
+use std::mem;
+
+use anyhow::{anyhow, bail};
+use crypto_box::aead::{Aead, OsRng};
+use crypto_box::aead::rand_core::RngCore;
+use crypto_box::{PublicKey, SalsaBox, SecretKey};
+use crypto_secretbox::{KeyInit, XSalsa20Poly1305};
+
+use crate::config::{
+    CRYPTO_BOX_NONCEBYTES, CRYPTO_BOX_PUBLICKEYBYTES, CRYPTO_BOX_SECRETKEYBYTES,
+    CRYPTO_SECRETBOX_KEYBYTES, CRYPTO_SECRETBOX_NONCEBYTES,
+};
+use crate::defines::{
+    ZMQ_PROTOCOL_ERROR_ZMTP_CRYPTOGRAPHIC, ZMQ_PROTOCOL_ERROR_ZMTP_INVALID_SEQUENCE,
+    ZMQ_PROTOCOL_ERROR_ZMTP_MALFORMED_COMMAND_MESSAGE, ZMQ_PROTOCOL_ERROR_ZMTP_UNEXPECTED_COMMAND,
+};
+use crate::message::{
+    ZmqMessage, CANCEL_CMD_NAME, CANCEL_CMD_NAME_SIZE, SUB_CMD_NAME, SUB_CMD_NAME_SIZE,
+    ZMQ_MSG_COMMAND, ZMQ_MSG_MORE,
+};
+use crate::utils::copy_bytes;
+
+pub type ZmqNonce = u64;
+
+//  Right now, we only transport the lower two bit flags of ZmqMessage, so they
+//  are binary identical, and we can just use a bitmask to select them. If we
+//  happened to add more flags, this might change.
+pub const FLAG_MASK: u8 = ZMQ_MSG_MORE | ZMQ_MSG_COMMAND;
+pub const FLAGS_LEN: usize = 1;
+pub const NONCE_PREFIX_LEN: usize = 16;
+pub const MESSAGE_COMMAND: &[u8] = b"\x07MESSAGE";
+pub const MESSAGE_COMMAND_LEN: usize = MESSAGE_COMMAND.len();
+pub const MESSAGE_HDR_LEN: usize = MESSAGE_COMMAND_LEN + mem::size_of::<ZmqNonce>();
+
+//  The RustCrypto boxes emit the NaCl "easy" layout, i.e. the MAC followed by
+//  the ciphertext, without the BOXZEROBYTES padding of crypto_box ().
+pub const CRYPTO_BOX_MACBYTES: usize = 16;
+
+pub type ZmqCurvePublicKey = [u8; CRYPTO_BOX_PUBLICKEYBYTES];
+pub type ZmqCurveSecretKey = [u8; CRYPTO_BOX_SECRETKEYBYTES];
+pub type ZmqCurveBoxNonce = [u8; CRYPTO_BOX_NONCEBYTES];
+
+//  Generates a new long- or short-term key pair, returned as (public, secret).
+pub fn curve_keypair() -> (ZmqCurvePublicKey, ZmqCurveSecretKey) {
+    let secret = SecretKey::generate(&mut OsRng);
+    (secret.public_key().to_bytes(), secret.to_bytes())
+}
+
+//  Derives the public key that belongs to a secret key (crypto_scalarmult_base).
+pub fn curve_public_from_secret(secret_key: &[u8]) -> anyhow::Result<ZmqCurvePublicKey> {
+    let secret = SecretKey::from_slice(secret_key).map_err(|_| anyhow!("EINVAL"))?;
+    Ok(secret.public_key().to_bytes())
+}
+
+pub fn curve_random_bytes(dest: &mut [u8]) {
+    OsRng.fill_bytes(dest);
+}
+
+//  Equivalent of crypto_box_beforenm: precomputes the shared key between
+//  our secret key and the peer's public key.
+pub fn curve_precompute(public_key: &[u8], secret_key: &[u8]) -> anyhow::Result<SalsaBox> {
+    let public = PublicKey::from_slice(public_key).map_err(|_| anyhow!("EINVAL"))?;
+    let secret = SecretKey::from_slice(secret_key).map_err(|_| anyhow!("EINVAL"))?;
+    Ok(SalsaBox::new(&public, &secret))
+}
+
+//  Builds a 24 byte box nonce from a 16 byte prefix and a 64 bit short nonce,
+//  or from an 8 byte prefix and a 16 byte long nonce.
+pub fn curve_make_nonce(prefix: &[u8], suffix: &[u8]) -> ZmqCurveBoxNonce {
+    let mut nonce: ZmqCurveBoxNonce = [0; CRYPTO_BOX_NONCEBYTES];
+    copy_bytes(&mut nonce, 0, prefix, 0, prefix.len());
+    copy_bytes(&mut nonce, prefix.len(), suffix, 0, suffix.len());
+    nonce
+}
+
+pub fn curve_short_nonce(prefix: &[u8], nonce: ZmqNonce) -> ZmqCurveBoxNonce {
+    //  Nonces travel in network byte order.
+    curve_make_nonce(prefix, &nonce.to_be_bytes())
+}
+
+//  crypto_box_easy_afternm: returns MAC + ciphertext.
+pub fn curve_box(
+    precom: &SalsaBox,
+    nonce: &ZmqCurveBoxNonce,
+    plaintext: &[u8],
+) -> anyhow::Result<Vec<u8>> {
+    precom
+        .encrypt(nonce.into(), plaintext)
+        .map_err(|_| anyhow!("crypto_box failed"))
+}
+
+//  crypto_box_open_easy_afternm: verifies the MAC and returns the plaintext.
+pub fn curve_box_open(
+    precom: &SalsaBox,
+    nonce: &ZmqCurveBoxNonce,
+    ciphertext: &[u8],
+) -> anyhow::Result<Vec<u8>> {
+    if ciphertext.len() < CRYPTO_BOX_MACBYTES {
+        bail!("EPROTO");
+    }
+    precom
+        .decrypt(nonce.into(), ciphertext)
+        .map_err(|_| anyhow!("EPROTO: crypto_box_open failed"))
+}
+
+pub fn curve_secretbox(
+    key: &[u8; CRYPTO_SECRETBOX_KEYBYTES],
+    nonce: &[u8; CRYPTO_SECRETBOX_NONCEBYTES],
+    plaintext: &[u8],
+) -> anyhow::Result<Vec<u8>> {
+    XSalsa20Poly1305::new(key.into())
+        .encrypt(nonce.into(), plaintext)
+        .map_err(|_| anyhow!("crypto_secretbox failed"))
+}
+
+pub fn curve_secretbox_open(
+    key: &[u8; CRYPTO_SECRETBOX_KEYBYTES],
+    nonce: &[u8; CRYPTO_SECRETBOX_NONCEBYTES],
+    ciphertext: &[u8],
+) -> anyhow::Result<Vec<u8>> {
+    if ciphertext.len() < CRYPTO_BOX_MACBYTES {
+        bail!("EPROTO");
+    }
+    XSalsa20Poly1305::new(key.into())
+        .decrypt(nonce.into(), ciphertext)
+        .map_err(|_| anyhow!("EPROTO: crypto_secretbox_open failed"))
+}
+
+#[derive(Default, Clone)]
+pub struct ZmqCurveEncoding {
+    pub encode_nonce_prefix: Vec<u8>,
+    pub decode_nonce_prefix: Vec<u8>,
+    pub cn_nonce: ZmqNonce,
+    pub cn_peer_nonce: ZmqNonce,
+    //  Precomputed shared key used to speed up boxing and unboxing.
+    pub cn_precom: Option<SalsaBox>,
+    pub downgrade_sub: bool,
+}
+
+impl ZmqCurveEncoding {
+    pub fn new(encode_nonce_prefix: &str, decode_nonce_prefix: &str, downgrade_sub: bool) -> Self {
+        Self {
+            encode_nonce_prefix: encode_nonce_prefix.as_bytes().to_vec(),
+            decode_nonce_prefix: decode_nonce_prefix.as_bytes().to_vec(),
+            cn_nonce: 1,
+            cn_peer_nonce: 1,
+            cn_precom: None,
+            downgrade_sub,
+        }
+    }
+
+    pub fn set_precom(&mut self, precom: SalsaBox) {
+        self.cn_precom = Some(precom);
+    }
+
+    pub fn get_precom(&self) -> anyhow::Result<&SalsaBox> {
+        self.cn_precom
+            .as_ref()
+            .ok_or_else(|| anyhow!("EFSM: CURVE session key not established"))
+    }
+
+    pub fn get_and_inc_nonce(&mut self) -> ZmqNonce {
+        let nonce = self.cn_nonce;
+        self.cn_nonce += 1;
+        nonce
+    }
+
+    pub fn set_peer_nonce(&mut self, peer_nonce: ZmqNonce) {
+        self.cn_peer_nonce = peer_nonce;
+    }
+
+    pub fn check_validity(
+        &mut self,
+        msg: &mut ZmqMessage,
+        error_event_code: &mut u32,
+    ) -> anyhow::Result<()> {
+        let size = msg.size();
+        let message = msg.data();
+
+        if size < MESSAGE_COMMAND_LEN || message[..MESSAGE_COMMAND_LEN] != *MESSAGE_COMMAND {
+            *error_event_code = ZMQ_PROTOCOL_ERROR_ZMTP_UNEXPECTED_COMMAND;
+            bail!("EPROTO: unexpected command");
+        }
+
+        if size < MESSAGE_HDR_LEN + CRYPTO_BOX_MACBYTES + FLAGS_LEN {
+            *error_event_code = ZMQ_PROTOCOL_ERROR_ZMTP_MALFORMED_COMMAND_MESSAGE;
+            bail!("EPROTO: malformed command message");
+        }
+
+        let mut nonce_bytes = [0u8; mem::size_of::<ZmqNonce>()];
+        copy_bytes(&mut nonce_bytes, 0, message, MESSAGE_COMMAND_LEN, nonce_bytes.len());
+        let nonce = ZmqNonce::from_be_bytes(nonce_bytes);
+        if nonce <= self.cn_peer_nonce {
+            *error_event_code = ZMQ_PROTOCOL_ERROR_ZMTP_INVALID_SEQUENCE;
+            bail!("EPROTO: invalid sequence");
+        }
+        self.set_peer_nonce(nonce);
+
+        Ok(())
+    }
+
+    pub fn encode(&mut self, msg: &mut ZmqMessage) -> anyhow::Result<()> {
+        let nonce = self.get_and_inc_nonce();
+        let message_nonce = curve_short_nonce(&self.encode_nonce_prefix, nonce);
+
+        let mut sub_cancel_len = 0usize;
+        if msg.is_subscribe() || msg.is_cancel() {
+            if self.downgrade_sub {
+                sub_cancel_len = 1;
+            } else {
+                sub_cancel_len = if msg.is_cancel() {
+                    CANCEL_CMD_NAME_SIZE
+                } else {
+                    SUB_CMD_NAME_SIZE
+                };
+            }
+        }
+
+        let mlen = FLAGS_LEN + sub_cancel_len + msg.size();
+        let mut message_plaintext: Vec<u8> = vec![0; mlen];
+        message_plaintext[0] = msg.flags() & FLAG_MASK;
+
+        // For backward compatibility subscribe/cancel command messages are not stored with
+        // the message flags, and are encoded in the encoder, so that messages for < 3.0 peers
+        // can be encoded in the "old" 0/1 way rather than as commands.
+        if sub_cancel_len == 1 {
+            message_plaintext[FLAGS_LEN] = if msg.is_subscribe() { 1 } else { 0 };
+        } else if sub_cancel_len == SUB_CMD_NAME_SIZE {
+            message_plaintext[0] |= ZMQ_MSG_COMMAND;
+            copy_bytes(
+                &mut message_plaintext,
+                FLAGS_LEN,
+                SUB_CMD_NAME,
+                0,
+                SUB_CMD_NAME_SIZE,
+            );
+        } else if sub_cancel_len == CANCEL_CMD_NAME_SIZE {
+            message_plaintext[0] |= ZMQ_MSG_COMMAND;
+            copy_bytes(
+                &mut message_plaintext,
+                FLAGS_LEN,
+                CANCEL_CMD_NAME,
+                0,
+                CANCEL_CMD_NAME_SIZE,
+            );
+        }
+
+        let size = msg.size();
+        if size > 0 {
+            copy_bytes(
+                &mut message_plaintext,
+                (FLAGS_LEN + sub_cancel_len),
+                msg.data(),
+                0,
+                size,
+            );
+        }
+
+        let message_box = curve_box(self.get_precom()?, &message_nonce, &message_plaintext)?;
+
+        msg.close()?;
+        msg.init_size(MESSAGE_HDR_LEN + message_box.len())?;
+        let message = msg.data_mut();
+        copy_bytes(message, 0, MESSAGE_COMMAND, 0, MESSAGE_COMMAND_LEN);
+        copy_bytes(
+            message,
+            MESSAGE_COMMAND_LEN,
+            &nonce.to_be_bytes(),
+            0,
+            mem::size_of::<ZmqNonce>(),
+        );
+        copy_bytes(
+            message,
+            MESSAGE_HDR_LEN,
+            &message_box,
+            0,
+            message_box.len(),
+        );
+
+        Ok(())
+    }
+
+    pub fn decode(&mut self, msg: &mut ZmqMessage, error_event_code: &mut u32) -> anyhow::Result<()> {
+        self.check_validity(msg, error_event_code)?;
+
+        let size = msg.size();
+        let message = msg.data();
+        let message_nonce = curve_make_nonce(
+            &self.decode_nonce_prefix,
+            &message[MESSAGE_COMMAND_LEN..MESSAGE_HDR_LEN],
+        );
+
+        let message_plaintext =
+            match curve_box_open(self.get_precom()?, &message_nonce, &message[MESSAGE_HDR_LEN..size]) {
+                Ok(plaintext) => plaintext,
+                Err(e) => {
+                    // CURVE I : connection key used for MESSAGE is wrong
+                    *error_event_code = ZMQ_PROTOCOL_ERROR_ZMTP_CRYPTOGRAPHIC;
+                    return Err(e);
+                }
+            };
+
+        let flags = message_plaintext[0];
+        let plaintext_size = message_plaintext.len() - FLAGS_LEN;
+
+        msg.close()?;
+        msg.init_size(plaintext_size)?;
+        if plaintext_size > 0 {
+            copy_bytes(
+                msg.data_mut(),
+                0,
+                &message_plaintext,
+                FLAGS_LEN,
+                plaintext_size,
+            );
+        }
+        msg.set_flags(flags & FLAG_MASK);
+
+        Ok(())
+    }
+}
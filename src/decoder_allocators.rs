@@ -1,3 +1,4 @@
+use std::cell::RefCell;
 use std::ffi::c_void;
 use std::rc::Rc;
 use std::sync::atomic::AtomicU64;
@@ -259,3 +260,126 @@ impl shared_message_memory_allocator {
         self.msg_content = None;
     }
 }
+
+//  A fixed set of equally sized receive buffers allocated once per I/O
+//  thread. The buffers never move, so their addresses can be registered with
+//  the kernel up front (the io_uring poller does this, see uring.rs) and
+//  reads can then land in them without the destination pages being pinned
+//  on every call.
+#[derive(Default, Debug)]
+pub struct registered_buffer_pool {
+    pub buf_size: usize,
+    pub bufs: Vec<Box<[u8]>>,
+    //  Indexes of the buffers not handed out.
+    pub free: Vec<u16>,
+}
+
+impl registered_buffer_pool {
+    pub fn new(count: usize, buf_size: usize) -> Self {
+        assert!(count <= u16::MAX as usize);
+        Self {
+            buf_size,
+            bufs: (0..count)
+                .map(|_| vec![0u8; buf_size].into_boxed_slice())
+                .collect(),
+            //  Hand out low indexes first.
+            free: (0..count as u16).rev().collect(),
+        }
+    }
+
+    pub fn acquire(&mut self) -> Option<u16> {
+        self.free.pop()
+    }
+
+    pub fn release(&mut self, index: u16) {
+        // zmq_assert (!free.contains (index));
+        self.free.push(index);
+    }
+
+    pub fn buffer(&mut self, index: u16) -> &mut [u8] {
+        &mut self.bufs[index as usize]
+    }
+
+    //  Descriptions of all buffers, in index order, for registering them.
+    pub fn iovecs(&mut self) -> Vec<libc::iovec> {
+        self.bufs
+            .iter_mut()
+            .map(|buf| libc::iovec {
+                iov_base: buf.as_mut_ptr() as *mut c_void,
+                iov_len: buf.len(),
+            })
+            .collect()
+    }
+}
+
+//  A buffer of a registered_buffer_pool the kernel has read 'len' bytes
+//  into, as handed out by the io_uring poller's take_read. The buffer goes
+//  back to the pool when the lease is dropped.
+#[derive(Debug)]
+pub struct registered_buffer {
+    pub pool: Rc<RefCell<registered_buffer_pool>>,
+    pub index: u16,
+    pub len: usize,
+}
+
+impl registered_buffer {
+    pub fn new(pool: Rc<RefCell<registered_buffer_pool>>, index: u16, len: usize) -> Self {
+        Self { pool, index, len }
+    }
+
+    //  Pool buffers never move, so the pointer stays valid for as long as
+    //  the lease is held.
+    pub fn as_mut_ptr(&self) -> *mut u8 {
+        self.pool.borrow_mut().buffer(self.index).as_mut_ptr()
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
+
+impl Drop for registered_buffer {
+    fn drop(&mut self) {
+        self.pool.borrow_mut().release(self.index);
+    }
+}
+
+//  Same interface as c_single_allocator, but the buffer is one the kernel
+//  has already read into, leased from the I/O thread's registered buffers,
+//  so the decoder works on the very memory the data arrived in.
+#[derive(Default, Debug, Clone)]
+pub struct registered_allocator {
+    pub buf: Option<Rc<registered_buffer>>,
+}
+
+impl registered_allocator {
+    //  Takes over a freshly read buffer, returning the one held before to
+    //  the pool.
+    pub fn assign(&mut self, buf: registered_buffer) -> *mut u8 {
+        self.buf = Some(Rc::new(buf));
+        self.allocate()
+    }
+
+    //  Returns a null pointer when no buffer is held.
+    pub fn allocate(&mut self) -> *mut u8 {
+        match &self.buf {
+            Some(buf) => buf.as_mut_ptr(),
+            None => std::ptr::null_mut(),
+        }
+    }
+
+    pub fn deallocate(&mut self) {
+        self.buf = None;
+    }
+
+    pub fn size(&self) -> usize {
+        self.buf.as_ref().map_or(0, |buf| buf.len())
+    }
+
+    //  This buffer is fixed, size must not be changed
+    pub fn resize(&mut self, _new_size: usize) {}
+}
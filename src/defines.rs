@@ -252,6 +252,8 @@ pub const ZMQ_NULL: u8 = 0;
 pub const ZMQ_PLAIN: u8 = 1;
 pub const ZMQ_CURVE: u8 = 2;
 pub const ZMQ_GSSAPI: u8 = 3;
+//  Not in libzmq: a mechanism from the context's mechanism registry
+pub const ZMQ_MECHANISM_CUSTOM: u8 = 0x7f;
 
 //   RADIO-DISH protocol
 pub const ZMQ_GROUP_MAX_LENGTH: usize = 255;
@@ -402,6 +404,22 @@ pub const ZMQ_BUSY_POLL: u8 = 113;
 pub const ZMQ_HICCUP_MSG: u8 = 114;
 pub const ZMQ_XSUB_VERBOSE_UNSUBSCRIBE: u8 = 115;
 pub const ZMQ_TOPICS_COUNT: u8 = 116;
+pub const ZMQ_SHM_RING_SIZE: u8 = 117;
+pub const ZMQ_TCP_MAX_CONNECTIONS: u8 = 118;
+pub const ZMQ_TCP_MAX_CONNECTIONS_PER_IP: u8 = 119;
+pub const ZMQ_TCP_PROXY_PROTOCOL: u8 = 120;
+pub const ZMQ_WS_DEFLATE: u8 = 121;
+pub const ZMQ_WS_RESPONSE_HEADER: u8 = 122;
+pub const ZMQ_WS_ALLOWED_ORIGIN: u8 = 123;
+pub const ZMQ_ZMTP_COMPRESSION: u8 = 124;
+pub const ZMQ_ZMTP_COMPRESSION_THRESHOLD: u8 = 125;
+
+//  Size of each direction's ring of a shm:// connection
+pub const ZMQ_SHM_RING_SIZE_DFLT: u64 = 16 << 20;
+pub const ZMQ_SHM_RING_SIZE_MIN: u64 = 64 << 10;
+
+//  Smallest data frame compressed once ZMQ_ZMTP_COMPRESSION is negotiated
+pub const ZMQ_ZMTP_COMPRESSION_THRESHOLD_DFLT: i32 = 1024;
 
 //  DRAFT ZMQ_RECONNECT_STOP options
 pub const ZMQ_RECONNECT_STOP_CONN_REFUSED: u8 = 0x1;
@@ -416,6 +434,13 @@ pub const ZMQ_MSG_PROPERTY_ROUTING_ID: &'static str = "Routing-Id";
 pub const ZMQ_MSG_PROPERTY_SOCKET_TYPE: &'static str = "Socket-Type";
 pub const ZMQ_MSG_PROPERTY_USER_ID: &'static str = "User-Id";
 pub const ZMQ_MSG_PROPERTY_PEER_ADDRESS: &'static str = "Peer-Address";
+//  tls:// only, see tls_engine.rs.
+pub const ZMQ_MSG_PROPERTY_TLS_PEER_SUBJECT: &'static str = "TLS-Peer-Subject";
+pub const ZMQ_MSG_PROPERTY_TLS_PEER_FINGERPRINT: &'static str = "TLS-Peer-Fingerprint";
+//  ipc:// only, see ipc.rs.
+pub const ZMQ_MSG_PROPERTY_PEER_UID: &'static str = "Peer-Uid";
+pub const ZMQ_MSG_PROPERTY_PEER_GID: &'static str = "Peer-Gid";
+pub const ZMQ_MSG_PROPERTY_PEER_PID: &'static str = "Peer-Pid";
 
 //  Router notify options
 pub const ZMQ_NOTIFY_CONNECT: i32 = 1;
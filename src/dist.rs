@@ -1,6 +1,7 @@
+use std::collections::HashMap;
+
 use crate::message::{ZMQ_MSG_MORE, ZmqMessage};
-use crate::pipe::PipeState::active;
-use crate::pipe::ZmqPipe;
+use crate::pipe::{ZmqPipe, ZmqPipeId};
 
 //  Class manages a set of outbound pipes. It sends each messages to
 //  each of them.
@@ -11,6 +12,10 @@ pub struct ZmqDist {
     // pipes_t pipes;
     pub pipes: Vec<ZmqPipe>,
 
+    //  Position of each pipe in 'pipes', so that matching a pipe does not
+    //  take a scan of the array. Kept up to date by swap_pipes.
+    pub indexes: HashMap<ZmqPipeId, usize>,
+
     //  Number of all the pipes to send the next message to.
     // pipes_t::size_type matching;
     pub matching: usize,
@@ -48,6 +53,7 @@ impl ZmqDist {
     pub fn new() -> Self {
         ZmqDist {
             pipes: Vec::new(),
+            indexes: HashMap::new(),
             matching: 0,
             active: 0,
             eligible: 0,
@@ -61,37 +67,33 @@ impl ZmqDist {
         //  If we are in the middle of sending a message, we'll add new pipe
         //  into the list of eligible pipes. Otherwise we add it to the list
         //  of active pipes.
+        self.indexes.insert(pipe.id, self.pipes.len());
+        self.pipes.push(pipe.clone());
+        let last = self.pipes.len() - 1;
         if self.more {
-            self.pipes.push_back(pipe);
-            self.pipes.swap(self.eligible, self.pipes.size() - 1);
+            self.swap_pipes(self.eligible, last);
             self.eligible += 1;
         } else {
-            self.pipes.push_back(pipe);
-            self.pipes.swap(self.active, self.pipes.size() - 1);
+            self.swap_pipes(self.active, last);
             self.active += 1;
             self.eligible += 1;
         }
     }
 
+    //  Swaps two entries of 'pipes', keeping 'indexes' in step.
+    fn swap_pipes(&mut self, a: usize, b: usize) {
+        if a == b {
+            return;
+        }
+        self.pipes.swap(a, b);
+        self.indexes.insert(self.pipes[a].id, a);
+        self.indexes.insert(self.pipes[b].id, b);
+    }
+
     //     //  Checks if this pipe is present in the distributor.
     //     bool has_pipe (pipe: &mut ZmqPipe);
     pub fn has_pipe(&mut self, pipe: &mut ZmqPipe) -> bool {
-        // let mut claimed_index = self.pipes.index(pipe);
-
-        let mut claimed_index: isize = -1;
-        for i in 0..self.pipes.len() {
-            if self.pipes[i] == pipe {
-                claimed_index = i as isize;
-                break;
-            }
-        }
-
-        // If pipe claims to be outside the available index space it can't be in the distributor.
-        if claimed_index >= self.pipes.size() {
-            return false;
-        }
-
-        return self.pipes[claimed_index] == pipe;
+        self.indexes.contains_key(&pipe.id)
     }
 
     //     //  Activates pipe that have previously reached high watermark.
@@ -100,19 +102,23 @@ impl ZmqDist {
     //     //  Mark the pipe as matching. Subsequent call to send_to_matching
     //     //  will send message also to this pipe.
     //     void match (pipe: &mut ZmqPipe);
-    pub fn mark_matching(&mut self, pipe: &mut ZmqPipe) {
+    pub fn mark_matching(&mut self, pipe: ZmqPipeId) {
+        let Some(&index) = self.indexes.get(&pipe) else {
+            return;
+        };
+
         //  If pipe is already matching do nothing.
-        // if (self.pipes.index(pipe) < self.matching) {
-        //     return;
-        // }
+        if index < self.matching {
+            return;
+        }
 
         //  If the pipe isn't eligible, ignore it.
-        // if (self.pipes.index(pipe) >= self.eligible) {
-        //     return;
-        // }
+        if index >= self.eligible {
+            return;
+        }
 
         //  Mark the pipe as matching.
-        // self.pipes.swap(self.pipes.index(pipe), self.matching);
+        self.swap_pipes(index, self.matching);
         self.matching += 1;
     }
 
@@ -130,8 +136,8 @@ impl ZmqDist {
         // to the beginning of the queue.
         // for (pipes_t::size_type i = prev_matching; i < eligible; += 1i)
         for i in prev_matching..self.eligible {
+            self.swap_pipes(i, self.matching);
             self.matching += 1;
-            self.pipes.swap(i, self.matching);
         }
     }
 
@@ -146,35 +152,42 @@ impl ZmqDist {
     pub fn pipe_terminated(&mut self, pipe: &mut ZmqPipe) {
         //  Remove the pipe from the list; adjust number of matching, active and/or
         //  eligible pipes accordingly.
-        // if (self.pipes.index(pipe) < self.matching) {
-        //     self.pipes.swap(self.pipes.index(pipe), self.matching - 1);
-        //     self.matching -= 1;
-        // }
-        // if (self.pipes.index(pipe) < active) {
-        //     self.pipes.swap(self.pipes.index(pipe), active - 1);
-        //     active -= 1;
-        // }
-        // if (self.pipes.index(pipe) < self.eligible) {
-        //     self.pipes.swap(self.pipes.index(pipe), self.eligible - 1);
-        //     self.eligible -= 1;
-        // }
-
-        self.pipes.erase(pipe);
+        let id = pipe.id;
+        if !self.indexes.contains_key(&id) {
+            return;
+        }
+        if self.indexes[&id] < self.matching {
+            self.swap_pipes(self.indexes[&id], self.matching - 1);
+            self.matching -= 1;
+        }
+        if self.indexes[&id] < self.active {
+            self.swap_pipes(self.indexes[&id], self.active - 1);
+            self.active -= 1;
+        }
+        if self.indexes[&id] < self.eligible {
+            self.swap_pipes(self.indexes[&id], self.eligible - 1);
+            self.eligible -= 1;
+        }
+
+        let last = self.pipes.len() - 1;
+        self.swap_pipes(self.indexes[&id], last);
+        self.pipes.pop();
+        self.indexes.remove(&id);
     }
 
     //     //  Send the message to the matching outbound pipes.
     //     int send_to_matching (msg: &mut ZmqMessage);
     pub fn activated(&mut self, pipe: &mut ZmqPipe) {
         //  Move the pipe from passive to eligible state.
-        if (self.eligible < self.pipes.size()) {
-            // self.pipes.swap(self.pipes.index(pipe), self.eligible);
+        if self.eligible < self.pipes.len() {
+            self.swap_pipes(self.indexes[&pipe.id], self.eligible);
             self.eligible += 1;
         }
 
         //  If there's no message being sent at the moment, move it to
         //  the active state.
-        if (!self.more && active < self.pipes.size()) {
-            self.pipes.swap(self.eligible - 1, self.active);
+        if !self.more && self.active < self.pipes.len() {
+            self.swap_pipes(self.eligible - 1, self.active);
             self.active += 1;
         }
     }
@@ -224,42 +237,27 @@ impl ZmqDist {
             return;
         }
 
-        if (msg.is_vsm()) {
-            // for (pipes_t::size_type i = 0; i < matching;)
-            for i in 0..self.matching {
-                // if (!self.write(self.pipes[i], msg)) {
-                //     //  Use same index again because entry will have been removed.
-                // } else {
-                //     i += 1;
-                // }
-            }
-            let mut rc = msg.init2();
-            // errno_assert (rc == 0);
-            return;
-        }
-
-        //  Add matching-1 references to the message. We already hold one reference,
-        //  that's why -1.
-        msg.add_refs(self.matching - 1);
+        //  Freeze the payload once; every pipe then gets a handle to the same
+        //  buffer, so fanning out a large message costs one reference count
+        //  bump per pipe instead of a copy. VSMs are simply copied.
+        msg.freeze();
 
         //  Push copy of the message to each matching pipe.
-        let mut failed = 0;
         // for (pipes_t::size_type i = 0; i < matching;)
-        for i in 0..self.matching {
-            // if (!self.write(self.pipes[i], msg)) {
-            //     failed += 1;
-            //     //  Use same index again because entry will have been removed.
-            // } else {
-            //     i += 1;
-            // }
-        }
-        if (failed) {
-            msg.rm_refs(failed);
+        let mut i = 0;
+        while i < self.matching {
+            let mut copy = msg.clone();
+            if !self.write_at(i, &mut copy) {
+                //  Use same index again because entry will have been removed.
+                //  The rejected copy is dropped, releasing its reference.
+            } else {
+                i += 1;
+            }
         }
 
-        //  Detach the original message from the data buffer. Note that we don't
-        //  close the message. That's because we've already used all the references.
-        msg.init2();
+        //  Detach the original message from the data buffer. The pipes hold
+        //  the remaining references.
+        let _ = msg.init2();
         // errno_assert (rc == 0);
     }
 
@@ -268,19 +266,28 @@ impl ZmqDist {
     }
 
     pub fn write(&mut self, pipe: &mut ZmqPipe, msg: &mut ZmqMessage) -> bool {
-        if (!pipe.write(msg)) {
-            // self.pipes.swap(self.pipes.index(pipe), self.matching - 1);
+        match self.indexes.get(&pipe.id) {
+            Some(&index) => self.write_at(index, msg),
+            None => false,
+        }
+    }
+
+    //  Same as write, for the pipe at `index`; keeps the matching, active and
+    //  eligible partitions in order when the pipe is full.
+    pub fn write_at(&mut self, index: usize, msg: &mut ZmqMessage) -> bool {
+        if !self.pipes[index].write(msg) {
+            self.swap_pipes(index, self.matching - 1);
             self.matching -= 1;
-            // self.pipes.swap(self.pipes.index(pipe), self.active - 1);
+            self.swap_pipes(self.matching, self.active - 1);
             self.active -= 1;
-            self.pipes.swap(self.active, self.eligible - 1);
+            self.swap_pipes(self.active, self.eligible - 1);
             self.eligible -= 1;
             return false;
         }
-        if (!(msg.flags() & ZMQ_MSG_MORE)) {
-            pipe.flush();
+        if (msg.flags() & ZMQ_MSG_MORE) == 0 {
+            self.pipes[index].flush();
         }
-        return true;
+        true
     }
 
     pub fn check_hwm(&self) -> bool {
@@ -294,3 +301,73 @@ impl ZmqDist {
         return true;
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pipe(id: ZmqPipeId) -> ZmqPipe {
+        ZmqPipe {
+            id,
+            ..Default::default()
+        }
+    }
+
+    fn ids(pipes: &[ZmqPipe]) -> Vec<ZmqPipeId> {
+        let mut ids: Vec<_> = pipes.iter().map(|pipe| pipe.id).collect();
+        ids.sort();
+        ids
+    }
+
+    fn assert_indexed(dist: &ZmqDist) {
+        assert_eq!(dist.indexes.len(), dist.pipes.len());
+        for (index, pipe) in dist.pipes.iter().enumerate() {
+            assert_eq!(dist.indexes[&pipe.id], index);
+        }
+    }
+
+    #[test]
+    fn matching_follows_the_pipes_around() {
+        let mut dist = ZmqDist::new();
+        for id in 1..=4 {
+            dist.attach(&mut pipe(id));
+        }
+        assert_eq!((dist.active, dist.eligible), (4, 4));
+
+        dist.mark_matching(3);
+        dist.mark_matching(1);
+        dist.mark_matching(3);
+        dist.mark_matching(9);
+        assert_eq!(ids(&dist.pipes[..dist.matching]), [1, 3]);
+        assert_indexed(&dist);
+
+        dist.reverse_match();
+        assert_eq!(ids(&dist.pipes[..dist.matching]), [2, 4]);
+        assert_indexed(&dist);
+
+        dist.pipe_terminated(&mut pipe(2));
+        assert_eq!((dist.matching, dist.active, dist.eligible), (1, 3, 3));
+        assert!(!dist.has_pipe(&mut pipe(2)));
+        assert!(dist.has_pipe(&mut pipe(4)));
+        assert_indexed(&dist);
+    }
+
+    #[test]
+    fn full_pipes_leave_and_rejoin() {
+        let mut dist = ZmqDist::new();
+        for id in 1..=3 {
+            dist.attach(&mut pipe(id));
+        }
+
+        //  Pipes without an outpipe refuse every message.
+        let mut msg = ZmqMessage::default();
+        dist.send_to_all(&mut msg);
+        assert_eq!((dist.matching, dist.active, dist.eligible), (0, 0, 0));
+        assert_indexed(&dist);
+
+        dist.activated(&mut pipe(2));
+        assert_eq!((dist.active, dist.eligible), (1, 1));
+        assert_eq!(dist.pipes[0].id, 2);
+        assert_indexed(&dist);
+    }
+}
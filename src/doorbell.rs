@@ -0,0 +1,52 @@
+//  An eventfd used as a doorbell between two threads or processes: ringing
+//  it makes it readable for the side polling it. Used by the shm:// rings,
+//  the quic:// endpoint driver and the ws:// port router.
+
+use std::mem;
+
+use anyhow::bail;
+
+use crate::defines::ZmqFileDesc;
+
+#[derive(Debug)]
+pub struct ZmqDoorbell {
+    pub fd: ZmqFileDesc,
+}
+
+impl ZmqDoorbell {
+    pub fn new() -> anyhow::Result<Self> {
+        let fd = unsafe { libc::eventfd(0, libc::EFD_NONBLOCK | libc::EFD_CLOEXEC) };
+        if fd < 0 {
+            bail!("eventfd failed: {}", std::io::Error::last_os_error());
+        }
+        Ok(Self { fd: fd as ZmqFileDesc })
+    }
+
+    pub fn ring(&self) {
+        let one: u64 = 1;
+        unsafe {
+            libc::write(
+                self.fd as libc::c_int,
+                &one as *const u64 as *const libc::c_void,
+                mem::size_of::<u64>(),
+            )
+        };
+    }
+
+    pub fn drain(&self) {
+        let mut count: u64 = 0;
+        unsafe {
+            libc::read(
+                self.fd as libc::c_int,
+                &mut count as *mut u64 as *mut libc::c_void,
+                mem::size_of::<u64>(),
+            )
+        };
+    }
+}
+
+impl Drop for ZmqDoorbell {
+    fn drop(&mut self) {
+        unsafe { libc::close(self.fd as libc::c_int) };
+    }
+}
@@ -1,5 +1,9 @@
 use crate::address::ZmqAddress;
 use crate::context::ZmqContext;
+#[cfg(all(feature = "io-uring", target_os = "linux"))]
+use crate::decoder_allocators::registered_allocator;
+#[cfg(all(feature = "io-uring", target_os = "linux"))]
+use crate::err::set_errno;
 use crate::defines::ZmqFileDesc;
 use crate::defines::{
     handshake_timer_id, heartbeat_ivl_timer_id, heartbeat_timeout_timer_id, heartbeat_ttl_timer_id,
@@ -15,17 +19,33 @@ use crate::mechanism::ZmqMechanismStatus::ready;
 use crate::message::{ZmqMessage, ZMQ_MSG_COMMAND, ZMQ_MSG_CREDENTIAL};
 use crate::metadata::ZmqMetadata;
 use crate::norm_stream_state::NormRxStreamState;
+use crate::proxy_protocol::{proxy_in_event, proxy_plug, proxy_timer_event, PROXY_HEADER_TIMER_ID};
+use crate::quic_engine::{
+    quic_get_endpoint, quic_in_event, quic_plug, quic_read, quic_reset_pollout, quic_set_pollout,
+    quic_terminate, quic_timer_event, quic_write, ZmqQuicLink, QUIC_DRIVE_TIMER_ID,
+};
 use crate::session_base::ZmqSessionBase;
+use crate::shm_engine::{
+    shm_get_endpoint, shm_in_event, shm_out_event, shm_plug, shm_restart_input,
+    shm_restart_output, shm_terminate, ZmqShmState,
+};
 use crate::socket::ZmqSocket;
+use crate::tls_engine::{
+    tls_engine_read, tls_engine_write, tls_get_endpoint, tls_in_event, tls_out_event, tls_plug,
+    tls_terminate, ZmqTlsState,
+};
 use anyhow::bail;
 use libc::{c_int, read, write, EAGAIN, EPROTO};
 use std::collections::HashMap;
 use std::net::SocketAddr;
 use std::os::raw::c_void;
 use std::ptr::null_mut;
+use std::sync::{Arc, Mutex};
 use windows::Win32::Networking::WinSock::PF_UNIX;
 
-use crate::tcp::{tcp_read, tcp_write};
+#[cfg(target_os = "linux")]
+use crate::ipc::{ipc_peer_credentials, ipc_peer_properties};
+use crate::tcp::{tcp_read, tcp_write, ZmqTcpConnectionSlot};
 use crate::thread_context::ZmqThreadContext;
 use crate::transport::ZmqTransport;
 use crate::udp::{
@@ -33,6 +53,8 @@ use crate::udp::{
 };
 use crate::utils::copy_bytes;
 use crate::v2_decoder::ZmqV2Decoder;
+use crate::vsock::vsock_get_endpoint;
+use crate::zmtp_compression::{zmtp_compression_negotiate, ZmqZmtpCompressor, ZmqZmtpDecompressor};
 
 #[derive(Default, Debug, Clone)]
 pub struct ZmqEngine<'a> {
@@ -48,6 +70,10 @@ pub struct ZmqEngine<'a> {
     pub send_enabled: bool,
     pub inpos: &'a mut [u8],
     pub insize: usize,
+    //  Registered buffer inpos points into while decoding data the ring
+    //  has read; see ring_read.
+    #[cfg(all(feature = "io-uring", target_os = "linux"))]
+    pub rx_buffer: registered_allocator,
     pub outpos: &'a mut [u8],
     pub outsize: usize,
     pub encoder: EncoderBase, // zmq_encoder -- Option<ZmqV2Encoder> for norm engine
@@ -89,6 +115,32 @@ pub struct ZmqEngine<'a> {
     pub wrapper_thread_id: u32,
     pub raw_address: Option<SocketAddr>,
     pub out_buffer: Vec<u8>,
+    //  shm:// connection state, see shm_engine.rs.
+    pub shm: Option<Arc<Mutex<ZmqShmState>>>,
+    pub shm_bell_handle: ZmqHandle,
+    //  Bytes of tx_msg already written to the ring, if a write is pending.
+    pub shm_tx_offset: Option<usize>,
+    //  Message the session refused, pushed again on restart_input.
+    pub shm_rx_msg: Option<ZmqMessage>,
+    //  quic:// connection, see quic_engine.rs.
+    pub quic: Option<ZmqQuicLink>,
+    //  The UDP socket, registered on the connect side only.
+    pub quic_socket_handle: ZmqHandle,
+    //  Output is waiting for the stream to take more data.
+    pub quic_want_write: bool,
+    pub has_quic_timer: bool,
+    //  tls:// session, see tls_engine.rs.
+    pub tls: Option<Arc<Mutex<ZmqTlsState>>>,
+    //  Properties of the peer's certificate.
+    pub tls_properties: HashMap<String, String>,
+    //  Counts the connection against its listener's caps while the engine
+    //  lives; see tcp.rs.
+    pub tcp_slot: Option<Arc<ZmqTcpConnectionSlot>>,
+    //  A PROXY protocol header has yet to be read off the connection.
+    pub proxy_header_pending: bool,
+    //  What has arrived of it so far, and whether its deadline is set.
+    pub proxy_buffer: Vec<u8>,
+    pub has_proxy_timer: bool,
 }
 
 impl<'a> ZmqEngine<'a> {
@@ -135,7 +187,17 @@ impl<'a> ZmqEngine<'a> {
                 self.decoder.get_buffer(&self.inpos, &bufsize);
 
                 // TODO
-                let rc = read(self.fd as c_int, self.inpos as *mut c_void, bufsize);
+                let rc = if self.address.protocol == ZmqTransport::ZmqQuic {
+                    quic_read(self, &mut self.inpos[..bufsize]) as isize
+                } else if self.address.protocol == ZmqTransport::ZmqTls {
+                    tls_engine_read(self, &mut self.inpos[..bufsize]) as isize
+                } else {
+                    #[cfg(not(all(feature = "io-uring", target_os = "linux")))]
+                    let rc = read(self.fd as c_int, self.inpos as *mut c_void, bufsize);
+                    #[cfg(all(feature = "io-uring", target_os = "linux"))]
+                    let rc = self.ring_read(bufsize);
+                    rc
+                };
                 if rc == -1 {
                     // TODO
                     // if (errno != EAGAIN) {
@@ -169,6 +231,13 @@ impl<'a> ZmqEngine<'a> {
             }
         }
 
+        //  Everything read has been decoded; the buffer goes back to the
+        //  ring for the next read.
+        #[cfg(all(feature = "io-uring", target_os = "linux"))]
+        if self.insize == 0 {
+            self.rx_buffer.deallocate();
+        }
+
         //  Tear down the connection if we have failed to decode input data
         //  or the session has rejected the message.
         if rc == -1 {
@@ -211,6 +280,11 @@ impl<'a> ZmqEngine<'a> {
             self.io_object.cancel_timer(self.heartbeat_ivl_timer_id);
             self.has_heartbeat_timer = false;
         }
+
+        if self.has_proxy_timer {
+            self.io_object.cancel_timer(PROXY_HEADER_TIMER_ID);
+            self.has_proxy_timer = false;
+        }
         //  Cancel all fd subscriptions.
         if (!self.io_error) {
             self.io_object.rm_fd(self.handle);
@@ -293,6 +367,16 @@ impl<'a> ZmqEngine<'a> {
         //     // alloc_assert (_metadata);
         // }
 
+        //  Compress frames from here on if both sides advertised it.
+        let peer_compression = self.mechanism.as_ref().and_then(|m| m.peer_compression());
+        if let Some(algorithm) = zmtp_compression_negotiate(self.context, peer_compression) {
+            self.encoder.set_compressor(ZmqZmtpCompressor::new(
+                algorithm,
+                self.context.zmtp_compression_threshold,
+            ));
+            self.decoder.set_decompressor(ZmqZmtpDecompressor::new(algorithm));
+        }
+
         if (self.has_handshake_timer) {
             self.io_thread.cancel_timer(self.handshake_timer_id);
             self.has_handshake_timer = false;
@@ -324,6 +408,10 @@ impl<'a> ZmqEngine<'a> {
     //  Plug the engine to the session.
     // virtual void Plug (ZmqIoThread *io_thread_, pub struct ZmqSessionBase *session_) = 0;
     pub fn plug(&mut self, io_thread_: &mut ZmqThreadContext, session: &mut ZmqSessionBase) {
+        if self.address.protocol == ZmqTransport::ZmqShm {
+            return shm_plug(self, io_thread_, session);
+        }
+
         // zmq_assert (!_plugged);
         self.plugged = true;
 
@@ -338,6 +426,16 @@ impl<'a> ZmqEngine<'a> {
         self.handle = self.io_thread.add_fd(self.fd);
         self.io_error = false;
 
+        if self.address.protocol == ZmqTransport::ZmqQuic {
+            quic_plug(self);
+        }
+        if self.address.protocol == ZmqTransport::ZmqTls {
+            tls_plug(self);
+        }
+        if self.proxy_header_pending {
+            proxy_plug(self);
+        }
+
         self.plug_internal();
     }
 
@@ -347,6 +445,9 @@ impl<'a> ZmqEngine<'a> {
     pub fn terminate(&mut self) {
         match self.address.protocol {
             ZmqTransport::ZmqUdp => udp_terminate(self),
+            ZmqTransport::ZmqShm => shm_terminate(self),
+            ZmqTransport::ZmqQuic => quic_terminate(self),
+            ZmqTransport::ZmqTls => tls_terminate(self),
             _ => self.unplug(),
         }
     }
@@ -360,6 +461,7 @@ impl<'a> ZmqEngine<'a> {
     pub fn restart_input(&mut self) -> bool {
         match self.address.protocol {
             ZmqTransport::ZmqUdp => udp_restart_input(self),
+            ZmqTransport::ZmqShm => return shm_restart_input(self),
             _ => {}
         }
 
@@ -423,6 +525,7 @@ impl<'a> ZmqEngine<'a> {
     pub fn restart_output(&mut self) {
         match self.address.protocol {
             ZmqTransport::ZmqUdp => udp_restart_output(self),
+            ZmqTransport::ZmqShm => return shm_restart_output(self),
             _ => {}
         }
 
@@ -464,13 +567,26 @@ impl<'a> ZmqEngine<'a> {
     pub fn in_event(&mut self) {
         match self.address.protocol {
             ZmqTransport::ZmqUdp => udp_in_event(self),
+            ZmqTransport::ZmqShm => return shm_in_event(self),
+            ZmqTransport::ZmqQuic => return quic_in_event(self),
+            ZmqTransport::ZmqTls => return tls_in_event(self),
             _ => {}
         }
+        if self.proxy_header_pending && !proxy_in_event(self) {
+            return;
+        }
 
         let res = self.in_event_internal();
     }
 
     pub fn out_event(&mut self) {
+        if self.address.protocol == ZmqTransport::ZmqShm {
+            return shm_out_event(self);
+        }
+        if self.address.protocol == ZmqTransport::ZmqTls && !tls_out_event(self) {
+            return;
+        }
+
         // zmq_assert (!_io_error);
 
         //  If write buffer is empty, try to read new data from the encoder.
@@ -527,7 +643,13 @@ impl<'a> ZmqEngine<'a> {
         //  limited transmission buffer and thus the actual number of bytes
         //  written should be reasonably modest.
         // TODO
-        let nbytes = unsafe { write(self.fd as c_int, self._outpos, self._outsize) };
+        let nbytes = if self.address.protocol == ZmqTransport::ZmqQuic {
+            quic_write(self, &self.outpos[..self.outsize]) as isize
+        } else if self.address.protocol == ZmqTransport::ZmqTls {
+            tls_engine_write(self, &self.outpos[..self.outsize]) as isize
+        } else {
+            unsafe { write(self.fd as c_int, self._outpos, self._outsize) }
+        };
 
         //  IO error has occurred. We Stop waiting for output events.
         //  The engine is not terminated until we detect input error;
@@ -590,10 +712,16 @@ impl<'a> ZmqEngine<'a> {
     }
 
     pub fn reset_pollout(&mut self) {
+        if self.address.protocol == ZmqTransport::ZmqQuic {
+            return quic_reset_pollout(self);
+        }
         self.io_object.reset_pollout(self._handle)
     }
 
     pub fn set_pollout(&mut self) {
+        if self.address.protocol == ZmqTransport::ZmqQuic {
+            return quic_set_pollout(self);
+        }
         self.io_object.set_pollout(self._handle)
     }
 
@@ -613,6 +741,10 @@ impl<'a> ZmqEngine<'a> {
     pub fn get_endpoint(&mut self) -> EndpointUriPair {
         match self.address.protocol {
             ZmqTransport::ZmqUdp => udp_get_endpoint(self),
+            ZmqTransport::ZmqShm => shm_get_endpoint(self),
+            ZmqTransport::ZmqQuic => quic_get_endpoint(self),
+            ZmqTransport::ZmqTls => tls_get_endpoint(self),
+            ZmqTransport::ZmqVsock => vsock_get_endpoint(self),
             _ => self.empty_endpoint.clone(),
         }
     }
@@ -783,6 +915,12 @@ impl<'a> ZmqEngine<'a> {
         // std::string fd_string = stream.str ();
         let fd_string = format!("{}", self._s);
         properties_.ZMQ_MAP_INSERT_OR_EMPLACE("__fd".to_string(), fd_string);
+
+        properties_.extend(self.tls_properties.clone());
+        #[cfg(target_os = "linux")]
+        if self.address.protocol == ZmqTransport::ZmqIpc {
+            properties_.extend(ipc_peer_properties(self.fd));
+        }
         return true;
     }
 
@@ -802,6 +940,10 @@ impl<'a> ZmqEngine<'a> {
         } else if (id_ == heartbeat_timeout_timer_id) {
             self.has_timeout_timer = false;
             // error (timeout_error);
+        } else if id_ == QUIC_DRIVE_TIMER_ID {
+            quic_timer_event(self);
+        } else if id_ == PROXY_HEADER_TIMER_ID {
+            proxy_timer_event(self);
         } else {
             // There are no other valid timer ids!
             // assert(false);
@@ -809,7 +951,13 @@ impl<'a> ZmqEngine<'a> {
     }
 
     pub fn read(&mut self, data: &mut [u8], size: usize) -> anyhow::Result<()> {
-        let rc: i32 = tcp_read(self._s, data, size);
+        let rc: i32 = if self.address.protocol == ZmqTransport::ZmqQuic {
+            quic_read(self, &mut data[..size])
+        } else if self.address.protocol == ZmqTransport::ZmqTls {
+            tls_engine_read(self, &mut data[..size])
+        } else {
+            tcp_read(self._s, data, size)
+        };
 
         if (rc == 0) {
             // connection closed by peer
@@ -820,7 +968,38 @@ impl<'a> ZmqEngine<'a> {
         Ok(())
     }
 
+    //  With the io_uring poller the kernel has already read into one of
+    //  the ring's registered buffers. The engine takes over that buffer and
+    //  decodes straight from it instead of from the decoder's own; the
+    //  epoll fallback leaves the read to us.
+    #[cfg(all(feature = "io-uring", target_os = "linux"))]
+    fn ring_read(&mut self, bufsize: usize) -> isize {
+        self.rx_buffer.deallocate();
+        match self.io_object.take_read(self.handle) {
+            None => unsafe { read(self.fd as c_int, self.inpos as *mut c_void, bufsize) },
+            Some(Ok(buf)) => {
+                let len = buf.len();
+                let data = self.rx_buffer.assign(buf);
+                self.inpos = unsafe { std::slice::from_raw_parts_mut(data, len) };
+                len as isize
+            }
+            Some(Err(errno)) => {
+                set_errno(errno);
+                -1
+            }
+        }
+    }
+
     pub fn write(&mut self, data: &mut [u8], size: usize) -> i32 {
+        if self.address.protocol == ZmqTransport::ZmqQuic {
+            return quic_write(self, &data[..size]);
+        }
+        if self.address.protocol == ZmqTransport::ZmqTls {
+            return tls_engine_write(self, &data[..size]);
+        }
+        #[cfg(all(feature = "io-uring", target_os = "linux"))]
+        return self.io_object.submit_write(self.handle, &data[..size]);
+        #[cfg(not(all(feature = "io-uring", target_os = "linux")))]
         return tcp_write(self._s, data, size);
     }
 
@@ -844,19 +1023,11 @@ pub fn get_peer_address(s_: ZmqFileDesc) -> String {
         peer_address.clear();
     }
     // #if defined ZMQ_HAVE_SO_PEERCRED
-    else if (cfg!(so_peer_cred) && family == PF_UNIX) {
+    else if (cfg!(target_os = "linux") && family == PF_UNIX) {
         // struct ucred cred;
-        #[cfg(feature = "so_peer_cred")]
-        {
-            // let cred: ucred = ucred::new();
-            // socklen_t
-            // size = mem::size_of::<cred>();
-            // if (!getsockopt(s_, SOL_SOCKET, SO_PEERCRED, &cred, &size)) {
-            //     std::ostringstream
-            //     buf;
-            //     buf << ":" << cred.uid << ":" << cred.gid << ":" << cred.pid;
-            //     peer_address += buf.str();
-            // }
+        #[cfg(target_os = "linux")]
+        if let Some(cred) = ipc_peer_credentials(s_) {
+            peer_address += &format!(":{}:{}:{}", cred.uid, cred.gid, cred.pid);
         }
     }
     // #elif defined ZMQ_HAVE_LOCAL_PEERCRED
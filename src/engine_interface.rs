@@ -39,7 +39,7 @@ use crate::endpoint_uri::EndpointUriPair;
 use crate::session_base::ZmqSessionBase;
 use crate::thread_context::ZmqThreadContext;
 
-enum ZmqErrorReason {
+pub enum ZmqErrorReason {
     ProtocolError,
     ConnectionError,
     TimeoutError,
@@ -0,0 +1,258 @@
+/*
+    Copyright (c) 2007-2016 Contributors as noted in the AUTHORS file
+
+    This file is part of libzmq, the ZeroMQ core engine in C+= 1.
+
+    libzmq is free software; you can redistribute it and/or modify it under
+    the terms of the GNU Lesser General Public License (LGPL) as published
+    by the Free Software Foundation; either version 3 of the License, or
+    (at your option) any later version.
+
+    As a special exception, the Contributors give you permission to link
+    this library with independent modules to produce an executable,
+    regardless of the license terms of these independent modules, and to
+    copy and distribute the resulting executable under terms of your choice,
+    provided that you also meet, for each linked independent module, the
+    terms and conditions of the license of that module. An independent
+    module is a module which is not derived from or based on this library.
+    If you modify this library, you must extend this exception to your
+    version of the library.
+
+    libzmq is distributed in the hope that it will be useful, but WITHOUT
+    ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+    FITNESS FOR A PARTICULAR PURPOSE. See the GNU Lesser General Public
+    License for more details.
+
+    You should have received a copy of the GNU Lesser General Public License
+    along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+// #include "precompiled.hpp"
+// #if defined ZMQ_IOTHREAD_POLLER_USE_EPOLL
+// #include "epoll.hpp"
+
+// #include <sys/epoll.h>
+// #include <unistd.h>
+
+use libc::{
+    close, epoll_create1, epoll_ctl, epoll_event, epoll_wait, EINTR, EPOLLERR, EPOLLHUP, EPOLLIN,
+    EPOLLOUT, EPOLL_CLOEXEC, EPOLL_CTL_ADD, EPOLL_CTL_DEL, EPOLL_CTL_MOD,
+};
+
+use crate::config::MAX_IO_EVENTS;
+use crate::context::ZmqContext;
+use crate::defines::{ZmqFileDesc, ZmqHandle, RETIRED_FD};
+use crate::events::ZmqEvents;
+use crate::poller_base::WorkerPollerBase;
+
+// typedef epoll_t Poller;
+pub type ZmqPoller<'a> = ZmqEpoll<'a>;
+
+pub struct PollEntry {
+    // fd_t fd;
+    pub fd: ZmqFileDesc,
+    // epoll_event ev;
+    pub ev_events: u32,
+    // i_poll_events *events;
+    pub reactor: ZmqEvents,
+    //  Bumped every time the slot is (re)used, so that events queued for a
+    //  descriptor that was removed and re-added in the same loop iteration
+    //  are not delivered to the new owner.
+    pub generation: u32,
+}
+
+impl Default for PollEntry {
+    fn default() -> Self {
+        Self {
+            fd: RETIRED_FD,
+            ev_events: 0,
+            reactor: ZmqEvents {},
+            generation: 0,
+        }
+    }
+}
+
+pub struct ZmqEpoll<'a> {
+    //  Main epoll file descriptor
+    pub epoll_fd: ZmqFileDesc,
+    //  Registered descriptors, indexed by the descriptor itself. Unlike
+    //  ZmqPoll there is no pollset to rebuild: the kernel keeps the interest
+    //  list and only hands back the descriptors that are actually ready.
+    pub fd_table: Vec<PollEntry>,
+    pub base: WorkerPollerBase<'a>,
+}
+
+impl<'a> ZmqEpoll<'a> {
+    pub fn new(ctx: &mut ZmqContext) -> Self {
+        let epoll_fd = unsafe { epoll_create1(EPOLL_CLOEXEC) };
+        // errno_assert (epoll_fd != epoll_retired_fd);
+        assert_ne!(epoll_fd, RETIRED_FD, "epoll_create1 failed");
+        Self {
+            epoll_fd,
+            fd_table: vec![],
+            base: WorkerPollerBase::new(&mut ctx.thread_ctx),
+        }
+    }
+
+    fn epoll_ctl(&mut self, op: i32, handle_: &ZmqHandle) {
+        let entry = &self.fd_table[*handle_ as usize];
+        let mut ev = epoll_event {
+            events: entry.ev_events,
+            u64: ((entry.generation as u64) << 32) | (entry.fd as u32 as u64),
+        };
+        let rc = unsafe { epoll_ctl(self.epoll_fd, op, entry.fd, &mut ev) };
+        // errno_assert (rc != -1);
+        assert_ne!(rc, -1, "epoll_ctl failed");
+    }
+
+    pub fn add_fd(&mut self, fd: &ZmqHandle, reactor_: &mut ZmqEvents) -> ZmqHandle {
+        self.base.check_thread();
+        // zmq_assert (fd != retired_fd);
+
+        //  If the file descriptor table is too small expand it.
+        let idx = *fd as usize;
+        if self.fd_table.len() <= idx {
+            self.fd_table.resize_with(idx + 1, PollEntry::default);
+        }
+
+        let entry = &mut self.fd_table[idx];
+        // zmq_assert (entry.fd == retired_fd);
+        entry.fd = *fd;
+        entry.ev_events = 0;
+        entry.reactor = std::mem::replace(reactor_, ZmqEvents {});
+        entry.generation = entry.generation.wrapping_add(1);
+
+        self.epoll_ctl(EPOLL_CTL_ADD, fd);
+
+        //  Increase the load metric of the thread.
+        self.base.base.adjust_load(1);
+
+        return *fd;
+    }
+
+    pub fn rm_fd(&mut self, handle_: &ZmqHandle) {
+        self.base.check_thread();
+        self.epoll_ctl(EPOLL_CTL_DEL, handle_);
+        self.fd_table[*handle_ as usize].fd = RETIRED_FD;
+
+        //  Decrease the load metric of the thread.
+        self.base.base.adjust_load(-1);
+    }
+
+    pub fn set_pollin(&mut self, handle_: &ZmqHandle) {
+        self.base.check_thread();
+        self.fd_table[*handle_ as usize].ev_events |= EPOLLIN as u32;
+        self.epoll_ctl(EPOLL_CTL_MOD, handle_);
+    }
+
+    pub fn reset_pollin(&mut self, handle_: &ZmqHandle) {
+        self.base.check_thread();
+        self.fd_table[*handle_ as usize].ev_events &= !(EPOLLIN as u32);
+        self.epoll_ctl(EPOLL_CTL_MOD, handle_);
+    }
+
+    pub fn set_pollout(&mut self, handle_: &ZmqHandle) {
+        self.base.check_thread();
+        self.fd_table[*handle_ as usize].ev_events |= EPOLLOUT as u32;
+        self.epoll_ctl(EPOLL_CTL_MOD, handle_);
+    }
+
+    pub fn reset_pollout(&mut self, handle_: &ZmqHandle) {
+        self.base.check_thread();
+        self.fd_table[*handle_ as usize].ev_events &= !(EPOLLOUT as u32);
+        self.epoll_ctl(EPOLL_CTL_MOD, handle_);
+    }
+
+    pub fn start(&mut self, name: &str) {
+        self.base.start(name);
+    }
+
+    pub fn stop(&mut self) {
+        self.base.check_thread();
+        //  no-op... thread is stopped when no more fds or timers are registered
+    }
+
+    pub fn max_fds(&mut self) -> i32 {
+        return -1;
+    }
+
+    //  Returns the entry an event was registered for, or None if the
+    //  descriptor has been removed (or removed and re-added) since.
+    fn live_entry(&mut self, data: u64) -> Option<&mut PollEntry> {
+        let fd = data as u32 as ZmqFileDesc;
+        let generation = (data >> 32) as u32;
+        match self.fd_table.get_mut(fd as usize) {
+            Some(entry) if entry.fd != RETIRED_FD && entry.generation == generation => Some(entry),
+            _ => None,
+        }
+    }
+
+    //  Main event loop.
+    pub fn loop_fn(&mut self) {
+        let mut ev_buf: Vec<epoll_event> =
+            vec![epoll_event { events: 0, u64: 0 }; MAX_IO_EVENTS as usize];
+
+        loop {
+            //  Execute any due timers.
+            let timeout = self.base.base.execute_timers() as i32;
+
+            if self.base.base.get_load() == 0 && timeout == 0 {
+                break;
+            }
+
+            //  Wait for events. With no descriptors left the interest set
+            //  is empty, so this just sleeps until the next timer is due.
+            let n = unsafe {
+                epoll_wait(
+                    self.epoll_fd,
+                    ev_buf.as_mut_ptr(),
+                    MAX_IO_EVENTS,
+                    if timeout != 0 { timeout } else { -1 },
+                )
+            };
+            if n == -1 {
+                // errno_assert (errno == EINTR);
+                assert_eq!(
+                    std::io::Error::last_os_error().raw_os_error(),
+                    Some(EINTR),
+                    "epoll_wait failed"
+                );
+                continue;
+            }
+
+            for ev in &ev_buf[..n as usize] {
+                let events = ev.events;
+                let data = ev.u64;
+
+                //  A callback may retire (or replace) the descriptor, so the
+                //  entry is looked up again before every dispatch.
+                if events & (EPOLLERR | EPOLLHUP) as u32 != 0 {
+                    if let Some(entry) = self.live_entry(data) {
+                        entry.reactor.in_event();
+                    }
+                }
+                if events & EPOLLOUT as u32 != 0 {
+                    if let Some(entry) = self.live_entry(data) {
+                        entry.reactor.out_event();
+                    }
+                }
+                if events & EPOLLIN as u32 != 0 {
+                    if let Some(entry) = self.live_entry(data) {
+                        entry.reactor.in_event();
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl<'a> Drop for ZmqEpoll<'a> {
+    fn drop(&mut self) {
+        self.base.stop_worker();
+        unsafe {
+            close(self.epoll_fd);
+        }
+    }
+}
+
+// #endif
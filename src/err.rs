@@ -166,6 +166,12 @@ use libc::{
 use thiserror::Error;
 use windows::Win32::Networking::WinSock::WSA_ERROR;
 
+//  Sets errno for callers that report failures the way the system calls
+//  do, like tcp_read and tcp_write.
+pub fn set_errno(errno: i32) {
+    unsafe { *libc::__errno_location() = errno };
+}
+
 pub fn errno_to_string(errno_: i32) -> &'static str {
     match (errno_) {
         // #if defined ZMQ_HAVE_WINDOWS
@@ -727,6 +733,10 @@ pub enum ZmqError {
     InitializeMessageFailed(String),
     #[error("Failed to close message")]
     CloseMessageFailed(String),
+    #[error("Failed to copy message")]
+    CopyMessageFailed(String),
+    #[error("Failed to move message")]
+    MoveMessageFailed(String),
     #[error("Failed to allocate memory")]
     MallocFailed(String),
     #[error("Failed to get message")]
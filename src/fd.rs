@@ -0,0 +1,223 @@
+//  Listening sockets handed over by another process: systemd socket
+//  activation, or the previous instance during a zero-downtime restart.
+//
+//  "fd://N" adopts descriptor N. "fd://name" adopts the one systemd passed
+//  with FileDescriptorName=name (LISTEN_PID, LISTEN_FDS and LISTEN_FDNAMES,
+//  see sd_listen_fds(3)). The descriptor must be a listening TCP or UNIX
+//  stream socket; its connections are then handled as tcp:// or ipc://
+//  ones, and ZMQ_LAST_ENDPOINT reports the socket's own address in that
+//  form.
+//
+//  fd_export goes the other way, handing a socket's tcp://, ipc:// and
+//  fd:// listening descriptors to a successor, which binds them with
+//  "fd://N". Wildcard ipc:// listeners are left out: their file goes away
+//  when the original socket closes them.
+
+use std::env;
+use std::ffi::CStr;
+use std::mem;
+
+use anyhow::{anyhow, bail};
+use libc::{
+    c_int, c_void, close, dup, fcntl, getpid, getsockname, getsockopt, sockaddr, sockaddr_storage,
+    sockaddr_un, socklen_t, AF_INET, AF_INET6, AF_UNIX, FD_CLOEXEC, F_GETFD, F_GETFL, F_SETFD,
+    F_SETFL, O_NONBLOCK, SOCK_STREAM, SOL_SOCKET, SO_ACCEPTCONN, SO_TYPE,
+};
+
+use crate::defines::{ZmqFileDesc, RETIRED_FD};
+use crate::endpoint::make_unconnected_bind_endpoint_pair;
+use crate::ipc::{ipc_accept, ipc_in_event};
+use crate::listener::ZmqListener;
+use crate::address::from_sockaddr;
+use crate::socket::ZmqSocket;
+use crate::tcp::{tcp_accept, tcp_in_event};
+use crate::transport::ZmqTransport;
+
+//  First descriptor passed by systemd.
+const SD_LISTEN_FDS_START: c_int = 3;
+
+//  The descriptor an "fd://" address stands for.
+pub fn fd_resolve(addr: &str) -> anyhow::Result<ZmqFileDesc> {
+    if let Ok(fd) = addr.parse::<c_int>() {
+        if fd < 0 {
+            bail!("EINVAL: invalid descriptor in fd address {}", addr);
+        }
+        return Ok(fd as ZmqFileDesc);
+    }
+
+    //  The variables are inherited by children; they are only meant for
+    //  the process LISTEN_PID names.
+    let pid = env::var("LISTEN_PID")
+        .ok()
+        .and_then(|pid| pid.parse::<libc::pid_t>().ok());
+    if pid != Some(unsafe { getpid() }) {
+        bail!("EINVAL: no descriptors passed by systemd for {}", addr);
+    }
+    let count = env::var("LISTEN_FDS")
+        .ok()
+        .and_then(|count| count.parse::<usize>().ok())
+        .unwrap_or(0);
+    let names = env::var("LISTEN_FDNAMES").unwrap_or_default();
+    names
+        .split(':')
+        .take(count)
+        .position(|name| name == addr)
+        .map(|index| (SD_LISTEN_FDS_START + index as c_int) as ZmqFileDesc)
+        .ok_or_else(|| anyhow!("EINVAL: no descriptor named {} passed by systemd", addr))
+}
+
+fn fd_int_option(fd: ZmqFileDesc, option: c_int) -> Option<c_int> {
+    let mut value: c_int = 0;
+    let mut len = mem::size_of::<c_int>() as socklen_t;
+    let rc = unsafe {
+        getsockopt(
+            fd as c_int,
+            SOL_SOCKET,
+            option,
+            &mut value as *mut c_int as *mut c_void,
+            &mut len,
+        )
+    };
+    (rc == 0).then_some(value)
+}
+
+//  The transport the socket's connections are handled as, and its address
+//  in that transport's form.
+pub fn fd_socket_name(fd: ZmqFileDesc) -> anyhow::Result<(ZmqTransport, String)> {
+    let mut storage: sockaddr_storage = unsafe { mem::zeroed() };
+    let mut len = mem::size_of::<sockaddr_storage>() as socklen_t;
+    let rc = unsafe {
+        getsockname(
+            fd as c_int,
+            &mut storage as *mut sockaddr_storage as *mut sockaddr,
+            &mut len,
+        )
+    };
+    if rc != 0 {
+        bail!("ENOTSOCK: descriptor {} is not a socket", fd);
+    }
+
+    match storage.ss_family as c_int {
+        AF_INET | AF_INET6 => {
+            let address = from_sockaddr(&storage)
+                .ok_or_else(|| anyhow!("EINVAL: cannot read the address of descriptor {}", fd))?;
+            Ok((ZmqTransport::ZmqTcp, format!("tcp://{}", address)))
+        }
+        AF_UNIX => {
+            let address = unsafe { &*(&storage as *const sockaddr_storage as *const sockaddr_un) };
+            let path_len = (len as usize).saturating_sub(mem::size_of::<libc::sa_family_t>());
+            let path: Vec<u8> = address.sun_path[..path_len.min(address.sun_path.len())]
+                .iter()
+                .map(|&c| c as u8)
+                .collect();
+            //  Abstract names start with a NUL and run to the end of the
+            //  address.
+            let path = match path.split_first() {
+                Some((0, name)) => format!("@{}", String::from_utf8_lossy(name)),
+                _ => CStr::from_bytes_until_nul(&path)
+                    .map(|path| path.to_string_lossy().into_owned())
+                    .unwrap_or_else(|_| String::from_utf8_lossy(&path).into_owned()),
+            };
+            Ok((ZmqTransport::ZmqIpc, format!("ipc://{}", path)))
+        }
+        family => bail!(
+            "EAFNOSUPPORT: descriptor {} has unsupported address family {}",
+            fd,
+            family
+        ),
+    }
+}
+
+pub fn fd_set_local_address(listener: &mut ZmqListener, addr: &mut str) -> anyhow::Result<()> {
+    let fd = fd_resolve(addr)?;
+    if fd_int_option(fd, SO_TYPE) != Some(SOCK_STREAM) {
+        bail!("ENOTSOCK: descriptor {} is not a stream socket", fd);
+    }
+    if fd_int_option(fd, SO_ACCEPTCONN) != Some(1) {
+        bail!("EINVAL: descriptor {} is not listening", fd);
+    }
+    let (protocol, endpoint) = fd_socket_name(fd)?;
+
+    //  Descriptors from systemd are blocking and inheritable.
+    unsafe {
+        let flags = fcntl(fd as c_int, F_GETFL);
+        if flags < 0 || fcntl(fd as c_int, F_SETFL, flags | O_NONBLOCK) < 0 {
+            bail!(
+                "failed to make descriptor {} non-blocking: {}",
+                fd,
+                std::io::Error::last_os_error()
+            );
+        }
+        let flags = fcntl(fd as c_int, F_GETFD);
+        if flags >= 0 {
+            fcntl(fd as c_int, F_SETFD, flags | FD_CLOEXEC);
+        }
+    }
+
+    listener.fd = fd;
+    listener.address.protocol = protocol;
+    listener.endpoint = endpoint;
+    listener.socket.event_listening(
+        &make_unconnected_bind_endpoint_pair(&listener.endpoint),
+        listener.fd,
+    );
+    Ok(())
+}
+
+pub fn fd_in_event(listener: &mut ZmqListener) -> anyhow::Result<()> {
+    match listener.address.protocol {
+        ZmqTransport::ZmqIpc => ipc_in_event(listener),
+        _ => tcp_in_event(listener),
+    }
+}
+
+pub fn fd_accept(listener: &mut ZmqListener) -> anyhow::Result<ZmqFileDesc> {
+    match listener.address.protocol {
+        ZmqTransport::ZmqIpc => ipc_accept(listener),
+        _ => tcp_accept(listener),
+    }
+}
+
+//  The socket file of an adopted UNIX socket belongs to whoever created
+//  it, and may still be in use by a successor; it is left alone.
+pub fn fd_close(listener: &mut ZmqListener) -> anyhow::Result<()> {
+    let fd_for_event = listener.fd;
+    let rc = unsafe { close(listener.fd as c_int) };
+    listener.fd = RETIRED_FD as ZmqFileDesc;
+
+    if rc != 0 {
+        listener
+            .socket
+            .event_close_failed(&make_unconnected_bind_endpoint_pair(&listener.endpoint), -1);
+        bail!("failed to close socket")
+    }
+
+    listener.socket.event_closed(
+        &make_unconnected_bind_endpoint_pair(&listener.endpoint),
+        fd_for_event,
+    );
+    Ok(())
+}
+
+//  Duplicates the socket's listening descriptors, inheritable, for a
+//  successor process to bind with "fd://N". The socket keeps its own; the
+//  caller owns the copies.
+pub fn fd_export(socket: &ZmqSocket) -> anyhow::Result<Vec<(String, ZmqFileDesc)>> {
+    let mut listeners: Vec<(&String, &ZmqFileDesc)> = socket.listener_fds.iter().collect();
+    listeners.sort();
+
+    let mut exported = Vec::with_capacity(listeners.len());
+    for (endpoint, &fd) in listeners {
+        //  dup() leaves FD_CLOEXEC off the copy.
+        let copy = unsafe { dup(fd as c_int) };
+        if copy < 0 {
+            let err = std::io::Error::last_os_error();
+            for (_, fd) in exported {
+                unsafe { close(fd as c_int) };
+            }
+            bail!("failed to duplicate listening socket {}: {}", endpoint, err);
+        }
+        exported.push((endpoint.clone(), copy as ZmqFileDesc));
+    }
+    Ok(exported)
+}
@@ -77,6 +77,28 @@ impl ZmqIoObject {
         self.poller.reset_pollout(handle_);
     }
 
+    //  Completion based I/O, see uring.rs.
+    #[cfg(all(feature = "io-uring", target_os = "linux"))]
+    pub fn take_read(
+        &mut self,
+        handle_: ZmqHandle,
+    ) -> Option<Result<crate::decoder_allocators::registered_buffer, i32>> {
+        self.poller.take_read(&handle_)
+    }
+
+    #[cfg(all(feature = "io-uring", target_os = "linux"))]
+    pub fn submit_write(&mut self, handle_: ZmqHandle, data: &[u8]) -> i32 {
+        self.poller.submit_write(&handle_, data)
+    }
+
+    #[cfg(all(feature = "io-uring", target_os = "linux"))]
+    pub fn take_accept(
+        &mut self,
+        handle_: ZmqHandle,
+    ) -> Option<(ZmqFileDesc, libc::sockaddr_storage, libc::socklen_t)> {
+        self.poller.take_accept(&handle_)
+    }
+
     // void add_timer (timeout: i32, id_: i32);
     pub fn add_timer(&mut self, timeout: i32, id_: i32) {
         self.poller.add_timer(timeout, self, id_);
@@ -1,9 +1,12 @@
+#[cfg(any(target_os = "windows", target_os = "solaris"))]
 use crate::address_family::{AF_INET, AF_INET6, AF_UNSPEC};
 
 #[cfg(target_os = "linux")]
 use libc::{
     getaddrinfo, if_nametoindex, AI_NUMERICHOST, AI_PASSIVE, AI_V4MAPPED,
 };
+#[cfg(not(target_os = "windows"))]
+use libc::{freeifaddrs, getifaddrs, ifaddrs, sockaddr_in, sockaddr_in6, ECONNREFUSED, EOPNOTSUPP};
 use libc::{c_void, c_uint, close, free, malloc, strcmp, EINVAL, ENODEV, ENOMEM, memcpy, c_char, sockaddr};
 use std::ffi::{CStr, CString};
 use std::mem;
@@ -167,8 +170,12 @@ impl IpResolver {
             let mut addresses = self.resolve_nic_name(addr_str)?;
             if addresses.len() > 0 {
                 resolved = true;
+                //  Link-local addresses are only reachable through the
+                //  interface they were found on.
+                let nic_index = do_if_nametoindex(addr_str);
                 for addr in addresses {
-                    out.push(SocketAddr::new(addr, port));
+                    let zone = if is_link_local(&addr) { nic_index } else { zone_id };
+                    out.push(with_zone_id(SocketAddr::new(addr, port), zone));
                 }
             }
         }
@@ -178,7 +185,7 @@ impl IpResolver {
             if addresses.len() > 0 {
                 resolved = true;
                 for addr in addresses {
-                    out.push(SocketAddr::new(addr, port));
+                    out.push(with_zone_id(SocketAddr::new(addr, port), zone_id));
                 }
             }
         }
@@ -506,15 +513,90 @@ impl IpResolver {
         Ok(out)
     }
 
-    // #else
+    //  On these platforms, network interface name can be queried
+    //  using getifaddrs function. With ZMQ_IPV6 set, the interface's IPv6
+    //  addresses come first, then its IPv4 ones (usable through IPv4-mapped
+    //  addresses); without it, only IPv4 ones are returned. Link-local
+    //  addresses come after the others of their family.
+    #[cfg(any(
+        target_os = "linux",
+        target_os = "android",
+        target_os = "freebsd",
+        target_os = "macos",
+        target_os = "openbsd",
+        target_os = "netbsd",
+        target_os = "dragonfly"
+    ))]
+    pub fn resolve_nic_name(&mut self, nic_name: &str) -> anyhow::Result<Vec<IpAddr>> {
+        //  Get the addresses. getifaddrs talks to the kernel over netlink,
+        //  which may refuse while busy.
+        let max_attempts = 10;
+        let backoff_msec = 1;
+        let mut ifa: *mut ifaddrs = null_mut();
+        let mut rc = -1;
+        for i in 0..max_attempts {
+            rc = unsafe { getifaddrs(&mut ifa) };
+            if rc == 0 || std::io::Error::last_os_error().raw_os_error() != Some(ECONNREFUSED) {
+                break;
+            }
+            std::thread::sleep(std::time::Duration::from_millis(backoff_msec << i));
+        }
+        if rc != 0 {
+            let err = std::io::Error::last_os_error();
+            //  Windows Subsystem for Linux compatibility
+            if matches!(err.raw_os_error(), Some(EINVAL) | Some(EOPNOTSUPP)) {
+                bail!("ENODEV: no interface named {}", nic_name);
+            }
+            bail!("getifaddrs failed: {}", err);
+        }
+
+        //  Find the corresponding network interface.
+        let mut out: Vec<IpAddr> = vec![];
+        let mut ifp = ifa;
+        while !ifp.is_null() {
+            let entry = unsafe { &*ifp };
+            ifp = entry.ifa_next;
+            if entry.ifa_addr.is_null()
+                || unsafe { CStr::from_ptr(entry.ifa_name) }.to_bytes() != nic_name.as_bytes()
+            {
+                continue;
+            }
+
+            //  The crate's address family constants carry the Linux values;
+            //  sa_family has to be matched against the platform's own.
+            match i32::from(unsafe { (*entry.ifa_addr).sa_family }) {
+                libc::AF_INET => {
+                    let sin = unsafe { &*(entry.ifa_addr as *const sockaddr_in) };
+                    out.push(IpAddr::V4(Ipv4Addr::from(u32::from_be(sin.sin_addr.s_addr))));
+                }
+                libc::AF_INET6 if self.options.ipv6 => {
+                    let sin6 = unsafe { &*(entry.ifa_addr as *const sockaddr_in6) };
+                    out.push(IpAddr::V6(Ipv6Addr::from(sin6.sin6_addr.s6_addr)));
+                }
+                _ => {}
+            }
+        }
+
+        //  Clean-up;
+        unsafe { freeifaddrs(ifa) };
+
+        out.sort_by_key(|addr| (addr.is_ipv4(), is_link_local(addr)));
+        Ok(out)
+    }
 
     //  On other platforms we assume there are no sane interface names.
-    #[cfg(not(target_os = "windows"))]
-    pub fn resolve_nic_name(ip_addr_: IpAddr, nic_: &str) -> i32 {
-        // LIBZMQ_UNUSED (ip_addr_);
-        // LIBZMQ_UNUSED (nic_);
-        // errno = ENODEV;
-        return -1;
+    #[cfg(not(any(
+        target_os = "windows",
+        target_os = "linux",
+        target_os = "android",
+        target_os = "freebsd",
+        target_os = "macos",
+        target_os = "openbsd",
+        target_os = "netbsd",
+        target_os = "dragonfly"
+    )))]
+    pub fn resolve_nic_name(&mut self, nic_name: &str) -> anyhow::Result<Vec<IpAddr>> {
+        Ok(vec![])
     }
 
     // #endif
@@ -644,6 +726,24 @@ fn resolve_win(addr_: &str) -> Result<Vec<IpAddr>, anyhow::Error> {
 //     return addr;
 // }
 
+//  fe80::/10
+fn is_link_local(addr: &IpAddr) -> bool {
+    match addr {
+        IpAddr::V6(addr) => (addr.segments()[0] & 0xffc0) == 0xfe80,
+        IpAddr::V4(_) => false,
+    }
+}
+
+//  Scopes an IPv6 address to interface 'zone_id'; 0 leaves it unscoped.
+fn with_zone_id(addr: SocketAddr, zone_id: u32) -> SocketAddr {
+    match addr {
+        SocketAddr::V6(addr) if zone_id != 0 => {
+            SocketAddr::V6(SocketAddrV6::new(*addr.ip(), addr.port(), addr.flowinfo(), zone_id))
+        }
+        _ => addr,
+    }
+}
+
 pub fn do_if_nametoindex(ifname_: &str) -> u32 {
     let arg_str = CString::new(ifname_).unwrap();
     let mut out = 0u32;
@@ -653,3 +753,82 @@ pub fn do_if_nametoindex(ifname_: &str) -> u32 {
 
     out
 }
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::*;
+    use crate::defines::ZMQ_PAIR;
+    use crate::message::ZmqMessage;
+    use crate::ops::{
+        zmq_bind, zmq_close, zmq_connect, zmq_ctx_new, zmq_ctx_term, zmq_getsockopt, zmq_socket,
+    };
+    use crate::socket_option::ZmqSocketOption;
+
+    fn resolver(ipv6: bool) -> IpResolver {
+        IpResolver {
+            options: IpResolverOptions {
+                bindable: true,
+                allow_nic_name: true,
+                ipv6,
+                expect_port: true,
+                allow_dns: false,
+                allow_path: false,
+            },
+        }
+    }
+
+    #[test]
+    fn bind_on_loopback_interface() {
+        let addresses = resolver(false).resolve_nic_name("lo").unwrap();
+        assert!(addresses.contains(&IpAddr::V4(Ipv4Addr::LOCALHOST)));
+        assert!(addresses.iter().all(IpAddr::is_ipv4));
+
+        let mut ctx = zmq_ctx_new().unwrap();
+        let mut server = zmq_socket(&mut ctx, ZMQ_PAIR).unwrap();
+        let mut client = zmq_socket(&mut ctx, ZMQ_PAIR).unwrap();
+        zmq_bind(&mut server, "tcp://lo:*").unwrap();
+        let last_endpoint = ZmqSocketOption::ZMQ_LAST_ENDPOINT(String::new());
+        let endpoint = match zmq_getsockopt(&mut server, last_endpoint).unwrap() {
+            ZmqSocketOption::ZMQ_LAST_ENDPOINT(endpoint) => endpoint,
+            other => panic!("unexpected option {:?}", other),
+        };
+        assert!(endpoint.starts_with("tcp://127.0.0.1:"), "{}", endpoint);
+
+        zmq_connect(&mut client, &endpoint).unwrap();
+        let mut msg = ZmqMessage::default();
+        msg.init_buffer(b"lo", 2).unwrap();
+        client.send(&mut msg, 0).unwrap();
+        assert_eq!(server.recv(0).unwrap().data(), b"lo");
+
+        zmq_close(&mut client).unwrap();
+        zmq_close(&mut server).unwrap();
+        zmq_ctx_term(&mut ctx).unwrap();
+    }
+
+    #[test]
+    fn ipv6_addresses_come_first() {
+        let addresses = resolver(true).resolve_nic_name("lo").unwrap();
+        let first_ipv4 = addresses.iter().position(IpAddr::is_ipv4).unwrap();
+        assert!(addresses[first_ipv4..].iter().all(IpAddr::is_ipv4));
+        assert!(addresses[first_ipv4..].contains(&IpAddr::V4(Ipv4Addr::LOCALHOST)));
+    }
+
+    #[test]
+    fn unknown_interface_has_no_addresses() {
+        let addresses = resolver(true).resolve_nic_name("nosuchnic0").unwrap();
+        assert!(addresses.is_empty());
+    }
+
+    #[test]
+    fn link_local_addresses_are_scoped() {
+        let link_local: IpAddr = "fe80::1".parse().unwrap();
+        assert!(is_link_local(&link_local));
+        assert!(!is_link_local(&IpAddr::V6(Ipv6Addr::LOCALHOST)));
+        match with_zone_id(SocketAddr::new(link_local, 5555), 3) {
+            SocketAddr::V6(addr) => assert_eq!(addr.scope_id(), 3),
+            SocketAddr::V4(_) => unreachable!(),
+        }
+        let ipv4: SocketAddr = "127.0.0.1:5555".parse().unwrap();
+        assert_eq!(with_zone_id(ipv4, 3), ipv4);
+    }
+}
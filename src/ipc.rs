@@ -2,7 +2,9 @@ use crate::address::ZmqAddress;
 use crate::address_family::AF_UNIX;
 use crate::defines::RETIRED_FD;
 use crate::defines::ZmqFileDesc;
-use crate::dish_session::DishSessionState::Group;
+use crate::defines::{
+    ZMQ_MSG_PROPERTY_PEER_GID, ZMQ_MSG_PROPERTY_PEER_PID, ZMQ_MSG_PROPERTY_PEER_UID,
+};
 use crate::endpoint::make_unconnected_bind_endpoint_pair;
 use crate::ip::{
     create_ipc_wildcard_address, make_socket_noninheritable, ip_open_socket
@@ -15,7 +17,15 @@ use crate::ip::{
 use crate::listener::ZmqListener;
 use anyhow::bail;
 use libc::{accept, bind, c_int, close, getsockopt, listen, rmdir, sockaddr, unlink};
+#[cfg(target_os = "linux")]
+use libc::{
+    c_char, c_void, getgrgid_r, getpwuid_r, gid_t, group, passwd, socklen_t, ucred, uid_t,
+    SO_PEERCRED,
+};
+use std::collections::HashMap;
+use std::ffi::CStr;
 use std::mem;
+use std::ptr::null_mut;
 use windows::Win32::Networking::WinSock::{
     closesocket, WSAGetLastError, SOCKADDR_STORAGE, SOCK_STREAM, SOL_SOCKET, WSA_ERROR,
 };
@@ -141,75 +151,134 @@ pub fn ipc_in_event(listener: &mut ZmqListener) -> anyhow::Result<()> {
     Ok(())
 }
 
-#[cfg(not(windows))]
-pub fn ipc_filter(listener: &mut ZmqListener, fd: ZmqFileDesc) -> bool {
-    use libc::{ucred, SO_PEERCRED, passwd, getpwuid};
+//  Credentials of the process at the other end of a UNIX socket, as of
+//  connect().
+#[cfg(target_os = "linux")]
+pub fn ipc_peer_credentials(fd: ZmqFileDesc) -> Option<ucred> {
+    let mut cred = ucred {
+        pid: 0,
+        uid: 0,
+        gid: 0,
+    };
+    let mut size = mem::size_of::<ucred>() as socklen_t;
+    let rc = unsafe {
+        getsockopt(
+            fd as c_int,
+            libc::SOL_SOCKET,
+            SO_PEERCRED,
+            &mut cred as *mut ucred as *mut c_void,
+            &mut size,
+        )
+    };
+    (rc == 0).then_some(cred)
+}
 
-    if (listener.socket.context.ipc_uid_accept_filters.is_empty()
-        && listener.socket.context.ipc_pid_accept_filters.is_empty()
-        && listener.socket.context.ipc_gid_accept_filters.empty())
-    {
-        return true;
+//  Message properties describing the peer process.
+#[cfg(target_os = "linux")]
+pub fn ipc_peer_properties(fd: ZmqFileDesc) -> HashMap<String, String> {
+    let mut properties = HashMap::new();
+    if let Some(cred) = ipc_peer_credentials(fd) {
+        properties.insert(ZMQ_MSG_PROPERTY_PEER_UID.to_string(), cred.uid.to_string());
+        properties.insert(ZMQ_MSG_PROPERTY_PEER_GID.to_string(), cred.gid.to_string());
+        properties.insert(ZMQ_MSG_PROPERTY_PEER_PID.to_string(), cred.pid.to_string());
     }
+    properties
+}
 
-    // struct ucred cred;
-    let mut cred: ucred = ucred::new();
-    let mut size = mem::size_of::<ucred>() as c_int;
-
-    unsafe {
-        if (getsockopt(fd, SOL_SOCKET, SO_PEERCRED, &mut cred, &mut size)) {
-            return false;
+//  Runs one of the reentrant passwd/group lookups, growing the buffer
+//  until the entry fits. 'size_hint' is the sysconf name for the initial
+//  buffer size. Returns false if there is no such entry.
+#[cfg(target_os = "linux")]
+fn lookup_entry<T>(
+    size_hint: c_int,
+    buf: &mut Vec<c_char>,
+    mut lookup: impl FnMut(&mut [c_char], &mut *mut T) -> c_int,
+) -> bool {
+    let size = unsafe { libc::sysconf(size_hint) };
+    buf.resize(if size > 0 { size as usize } else { 1024 }, 0);
+    loop {
+        let mut result: *mut T = null_mut();
+        match lookup(buf, &mut result) {
+            0 => return !result.is_null(),
+            libc::ERANGE if buf.len() < 1 << 20 => {
+                let len = buf.len() * 2;
+                buf.resize(len, 0);
+            }
+            _ => return false,
         }
     }
-    if (listener
-        .socket
-        .context
-        .ipc_uid_accept_filters
-        .find(cred.uid)
-        != listener.socket.context.ipc_uid_accept_filters.end()
-        || listener
-            .socket
-            .context
-            .ipc_gid_accept_filters
-            .find(cred.gid)
-            != listener.socket.context.ipc_gid_accept_filters.end()
-        || listener
-            .socket
-            .context
-            .ipc_pid_accept_filters
-            .find(cred.pid)
-            != listener.socket.context.ipc_pid_accept_filters.end())
-    {
+}
+
+//  Whether the user is a member of the group, either as their primary
+//  group or as a supplementary one.
+#[cfg(target_os = "linux")]
+fn ipc_user_in_group(uid: uid_t, gid: gid_t) -> bool {
+    let mut pw: passwd = unsafe { mem::zeroed() };
+    let mut pw_buf = Vec::new();
+    let found = lookup_entry(
+        libc::_SC_GETPW_R_SIZE_MAX,
+        &mut pw_buf,
+        |buf, result| unsafe { getpwuid_r(uid, &mut pw, buf.as_mut_ptr(), buf.len(), result) },
+    );
+    if !found {
+        return false;
+    }
+    if pw.pw_gid == gid {
         return true;
     }
 
-    // const struct passwd *pw;
-    let pw = passwd { pw_name: todo!(), pw_passwd: todo!(), pw_uid: todo!(), pw_gid: todo!(), pw_gecos: todo!(), pw_dir: todo!(), pw_shell: todo!() };
-    // const struct Group *gr;
-    let gr = Group {};
-
-    if !(pw = unsafe { getpwuid(cred.uid) }) {
+    let mut gr: group = unsafe { mem::zeroed() };
+    let mut gr_buf = Vec::new();
+    let found = lookup_entry(
+        libc::_SC_GETGR_R_SIZE_MAX,
+        &mut gr_buf,
+        |buf, result| unsafe { getgrgid_r(gid, &mut gr, buf.as_mut_ptr(), buf.len(), result) },
+    );
+    if !found || gr.gr_mem.is_null() {
         return false;
     }
 
-    // for (ZmqOptions::ipc_gid_accept_filters_t::const_iterator
-    //     it = options.ipc_gid_accept_filters.begin (),
-    //     end = options.ipc_gid_accept_filters.end ();
-    //     it != end; it+= 1)
-    for opt in listener.socket.context.ipc_gid_accept_filters.iter() {
-        // todo: getgrgid
-        // if (!(gr = getgrgid(opt))) {
-        //     continue;
-        // }
-        // for (char **mem = gr.gr_mem; *mem; mem+= 1)
-        for mem in gr.gr_mem.iter() {
-            // if (!strcmp (*mem, pw.pw_name))
-            if mem != pw.pw_name {
+    let name = unsafe { CStr::from_ptr(pw.pw_name) };
+    let mut member = gr.gr_mem;
+    unsafe {
+        while !(*member).is_null() {
+            if CStr::from_ptr(*member) == name {
                 return true;
             }
+            member = member.add(1);
         }
     }
-    return false;
+    false
+}
+
+//  Decides whether to accept a connection based on the credentials of the
+//  peer process. A peer passes if it matches any of the configured uid, gid
+//  or pid filters; with no filters set everybody does.
+#[cfg(target_os = "linux")]
+pub fn ipc_filter(listener: &mut ZmqListener, fd: ZmqFileDesc) -> bool {
+    let ctx = &listener.socket.context;
+    if ctx.ipc_uid_accept_filters.is_empty()
+        && ctx.ipc_pid_accept_filters.is_empty()
+        && ctx.ipc_gid_accept_filters.is_empty()
+    {
+        return true;
+    }
+
+    let Some(cred) = ipc_peer_credentials(fd) else {
+        return false;
+    };
+    if ctx.ipc_uid_accept_filters.contains(&cred.uid)
+        || ctx.ipc_gid_accept_filters.contains(&cred.gid)
+        || ctx.ipc_pid_accept_filters.contains(&cred.pid)
+    {
+        return true;
+    }
+
+    //  The peer may still belong to one of the groups without running
+    //  with it as its effective group.
+    ctx.ipc_gid_accept_filters
+        .iter()
+        .any(|&gid| ipc_user_in_group(cred.uid, gid))
 }
 
 pub fn ipc_close(listener: &mut ZmqListener) -> anyhow::Result<()> {
@@ -278,7 +347,14 @@ pub fn ipc_accept(listener: &mut ZmqListener) -> anyhow::Result<ZmqFileDesc> {
     let mut ss_len = mem::size_of_val(&ss) as c_int;
     // #endif
 
+    #[cfg(not(all(feature = "io-uring", target_os = "linux")))]
     let sock = unsafe { accept(listener.fd, (&mut ss) as *mut sockaddr, &mut ss_len) };
+    //  The accept was handed to the I/O thread's ring; pick up its result.
+    #[cfg(all(feature = "io-uring", target_os = "linux"))]
+    let sock = match listener.io_object.take_accept(listener.handle) {
+        Some((fd, _, _)) => fd,
+        None => RETIRED_FD,
+    };
     // #endif
     unsafe {
         if (sock == RETIRED_FD as usize) {
@@ -299,13 +375,11 @@ pub fn ipc_accept(listener: &mut ZmqListener) -> anyhow::Result<ZmqFileDesc> {
 
     // IPC accept() filters
     // #if defined ZMQ_HAVE_SO_PEERCRED || defined ZMQ_HAVE_LOCAL_PEERCRED
-    #[cfg(not(windows))]
-    unsafe {
-        if !ipc_filter(sock, 0) {
-            let rc = close(sock as c_int);
-            // errno_assert (rc == 0);
-            return Ok(RETIRED_FD);
-        }
+    #[cfg(target_os = "linux")]
+    if !ipc_filter(listener, sock) {
+        let rc = unsafe { close(sock as c_int) };
+        // errno_assert (rc == 0);
+        return Ok(RETIRED_FD as ZmqFileDesc);
     }
     // #endif
 
@@ -325,3 +399,16 @@ pub fn ipc_accept(listener: &mut ZmqListener) -> anyhow::Result<ZmqFileDesc> {
 
     return Ok(sock);
 }
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn user_in_group() {
+        let (uid, gid) = unsafe { (libc::getuid(), libc::getgid()) };
+        assert!(ipc_user_in_group(uid, gid));
+        //  Nobody is in a group that does not exist.
+        assert!(!ipc_user_in_group(uid, gid_t::MAX - 1));
+    }
+}
@@ -1,6 +1,9 @@
 extern crate core;
 
 mod address_family;
+#[cfg(unix)]
+pub mod async_socket;
+pub mod auth;
 mod channel;
 mod client;
 mod thread_command;
@@ -22,9 +25,12 @@ mod devpoll;
 mod dgram;
 mod dish;
 mod dist;
+mod doorbell;
 mod encoder;
 mod encoder_interface;
 mod endpoint;
+#[cfg(all(feature = "epoll", target_os = "linux"))]
+mod epoll;
 mod engine_interface;
 mod err;
 mod fair_queue;
@@ -37,14 +43,21 @@ mod io_object;
 mod ip;
 mod ip_resolver;
 mod ipc_connecter;
+mod shm_connecter;
+mod quic_connecter;
+mod tls_connecter;
+mod vsock_connecter;
+mod http_connecter;
 mod lb;
 mod mailbox;
 mod mailbox_interface;
 mod mailbox_safe;
 mod mechanism;
 mod mechanism_base;
+pub mod mechanism_registry;
 mod message;
 mod metadata;
+mod mtrie;
 mod norm;
 mod null_mechanism;
 mod object;
@@ -69,12 +82,14 @@ mod poller_event;
 mod polling_util;
 mod pollset;
 mod proxy;
+mod proxy_protocol;
 mod zmq_pub;
 mod pull;
 mod push;
 mod radio;
 mod raw_decoder;
 mod raw_encoder;
+mod radix_tree;
 mod raw_engine;
 mod reaper;
 mod rep;
@@ -99,30 +114,51 @@ mod thread_context;
 mod timers;
 mod tipc_connecter;
 mod udp;
+#[cfg(all(feature = "io-uring", target_os = "linux"))]
+mod uring;
 mod utils;
 mod v1_decoder;
 mod v1_encoder;
 mod v2_decoder;
 mod v2_encoder;
+mod v2_protocol;
 mod v3_1_encoder;
 mod vmci;
 mod vmci_connecter;
 mod ws_connecter;
 mod ws_decoder;
+mod ws_deflate;
 mod ws_encoder;
 mod ws_engine;
+mod ws_router;
 mod wss_engine;
 mod xpub;
 mod xsub;
 mod zap_client;
+mod zmtp_compression;
 mod zmtp_engine;
+pub mod ypipe;
+pub mod ypipe_base;
+mod ypipe_conflate;
+mod yqueue;
 mod transport;
+pub mod typed_socket;
 mod address;
 mod socket_option;
 mod engine;
 mod events;
 mod listener;
 mod ipc;
+mod shm;
+mod shm_engine;
+mod shm_ring;
+mod quic;
+mod quic_endpoint;
+mod quic_engine;
+mod tls;
+mod tls_engine;
+mod vsock;
+mod fd;
 mod tipc;
 mod ws;
 mod norm_stream_state;
@@ -5,21 +5,37 @@ use crate::endpoint::make_unconnected_bind_endpoint_pair;
 use crate::endpoint::EndpointType::Bind;
 use crate::endpoint_uri::EndpointUriPair;
 use crate::engine::ZmqEngine;
+use crate::fd::{fd_accept, fd_close, fd_in_event, fd_set_local_address};
 use crate::io_object::ZmqIoObject;
 use crate::ip::create_ipc_wildcard_address;
 use crate::ipc::{
-    ipc_accept, ipc_close, ipc_filter, ipc_in_event, ipc_resolve_address, ipc_set_local_address,
+    ipc_accept, ipc_close, ipc_in_event, ipc_resolve_address, ipc_set_local_address,
 };
+use crate::quic::{quic_close, quic_in_event, quic_plug, quic_set_local_address, quic_timer_event};
+use crate::quic_endpoint::ZmqQuicEndpoint;
 use crate::session_base::ZmqSessionBase;
+use crate::shm::{shm_accept, shm_close, shm_create_engine, shm_in_event, shm_set_local_address};
 use crate::socket::ZmqSocket;
-use crate::tcp::{tcp_accept, tcp_create_socket, tcp_in_event, tcp_set_local_address};
+use crate::tcp::{
+    tcp_accept, tcp_create_engine, tcp_create_socket, tcp_in_event, tcp_set_local_address, ZmqTcpConnectionSlot,
+    ZmqTcpConnections,
+};
 use crate::thread_context::ZmqThreadContext;
 use crate::tipc::{tipc_accept, tipc_in_event, tipc_set_local_address};
+use crate::tls::{tls_close, tls_create_engine, tls_in_event, tls_set_local_address};
+use crate::vsock::{
+    vsock_accept, vsock_close, vsock_create_engine, vsock_in_event, vsock_set_local_address,
+};
 use crate::transport::ZmqTransport;
 use crate::vmci::{vmci_accept, vmci_in_event, vmci_set_local_address};
-use crate::ws::{ws_create_engine, ws_create_socket, ws_set_local_address};
+use crate::ws::{
+    ws_close, ws_create_engine, ws_create_socket, ws_in_event, ws_plug, ws_set_local_address,
+};
+use crate::ws_router::{ZmqWsHandoff, ZmqWsPort};
 use anyhow::bail;
 use libc::{close, unlink};
+use rustls::ServerConfig;
+use std::sync::{Arc, Mutex};
 
 #[derive(Default, Debug, Clone)]
 pub struct ZmqListener<'a> {
@@ -33,7 +49,21 @@ pub struct ZmqListener<'a> {
     pub filename: String,
     pub address: ZmqAddress,
     pub wss: bool,
-    pub tls_cred: Vec<u8>,
+    pub tls_cred: Option<Arc<ServerConfig>>,
+    //  quic:// endpoint and its doorbell, see quic.rs.
+    pub quic: Option<Arc<Mutex<ZmqQuicEndpoint>>>,
+    pub quic_bell_handle: ZmqHandle,
+    pub has_quic_timer: bool,
+    //  Connections accepted over TCP, for the connection caps; the slot of
+    //  the one just accepted is handed on to its engine. See tcp.rs.
+    pub tcp_connections: Arc<Mutex<ZmqTcpConnections>>,
+    pub tcp_slot: Option<Arc<ZmqTcpConnectionSlot>>,
+    //  ws:// port this bind shares, its path on it, and where connections
+    //  for the path accepted by other binds arrive. See ws_router.rs.
+    pub ws_port: Option<Arc<Mutex<ZmqWsPort>>>,
+    pub ws_path: String,
+    pub ws_handoff: Option<Arc<ZmqWsHandoff>>,
+    pub ws_bell_handle: ZmqHandle,
 }
 
 impl<'a> ZmqListener<'a> {
@@ -49,11 +79,35 @@ impl<'a> ZmqListener<'a> {
             filename: "".to_string(),
             address: Default::default(),
             wss: false,
-            tls_cred: vec![],
+            tls_cred: None,
+            quic: None,
+            quic_bell_handle: RETIRED_FD as ZmqHandle,
+            has_quic_timer: false,
+            tcp_connections: Arc::new(Mutex::new(ZmqTcpConnections::default())),
+            tcp_slot: None,
+            ws_port: None,
+            ws_path: String::new(),
+            ws_handoff: None,
+            ws_bell_handle: RETIRED_FD as ZmqHandle,
         }
     }
 
     pub fn get_local_address(&mut self, addr: &mut String) -> anyhow::Result<()> {
+        //  The rendezvous socket's name is not the endpoint the user bound to,
+        //  nor does get_socket_name know about UDP.
+        if matches!(
+            self.socket.destination.protocol,
+            ZmqTransport::ZmqShm
+                | ZmqTransport::ZmqQuic
+                | ZmqTransport::ZmqTls
+                | ZmqTransport::ZmqVsock
+                | ZmqTransport::ZmqFd
+                | ZmqTransport::ZmqWs
+                | ZmqTransport::ZmqWss
+        ) {
+            *addr = self.endpoint.clone();
+            return Ok(());
+        }
         *addr = get_socket_name(self.fd, ZmqSocketEnd::SocketEndLocal)?;
         Ok(())
     }
@@ -64,7 +118,12 @@ impl<'a> ZmqListener<'a> {
             ZmqTransport::ZmqIpc => ipc_set_local_address(self, addr),
             ZmqTransport::ZmqTipc => tipc_set_local_address(self, &mut addr.to_string()),
             ZmqTransport::ZmqVmci => vmci_set_local_address(self, &mut addr.to_string()),
-            ZmqTransport::ZmqWs => ws_set_local_address(self, &mut addr.to_string()),
+            ZmqTransport::ZmqWs | ZmqTransport::ZmqWss => ws_set_local_address(self, addr),
+            ZmqTransport::ZmqShm => shm_set_local_address(self, addr),
+            ZmqTransport::ZmqQuic => quic_set_local_address(self, addr),
+            ZmqTransport::ZmqTls => tls_set_local_address(self, addr),
+            ZmqTransport::ZmqVsock => vsock_set_local_address(self, addr),
+            ZmqTransport::ZmqFd => fd_set_local_address(self, addr),
             _ => bail!("Unsupported protocol"),
         }
     }
@@ -72,6 +131,18 @@ impl<'a> ZmqListener<'a> {
     pub fn process_plug(&mut self) {
         self.handle = self.io_object.add_fd(self.fd);
         self.io_object.set_pollin(self.handle);
+        match self.socket.destination.protocol {
+            ZmqTransport::ZmqQuic => quic_plug(self),
+            ZmqTransport::ZmqWs | ZmqTransport::ZmqWss => ws_plug(self),
+            _ => {}
+        }
+    }
+
+    pub fn timer_event(&mut self, id: i32) -> anyhow::Result<()> {
+        match self.socket.destination.protocol {
+            ZmqTransport::ZmqQuic => quic_timer_event(self, id),
+            _ => Ok(()),
+        }
     }
 
     pub fn process_term(&mut self, linger: i32) {
@@ -91,7 +162,11 @@ impl<'a> ZmqListener<'a> {
 
     pub fn create_engine(&mut self) -> anyhow::Result<()> {
         match self.address.protocol {
-            ZmqTransport::ZmqWs => ws_create_engine(self, self.fd),
+            ZmqTransport::ZmqTcp => tcp_create_engine(self, self.fd),
+            ZmqTransport::ZmqWs => ws_create_engine(self, self.fd, vec![]),
+            ZmqTransport::ZmqShm => shm_create_engine(self, self.fd),
+            ZmqTransport::ZmqTls => tls_create_engine(self, self.fd),
+            ZmqTransport::ZmqVsock => vsock_create_engine(self, self.fd),
             _ => {
                 let endpoint_pair = EndpointUriPair::new(
                     &get_socket_name(self.fd, ZmqSocketEnd::SocketEndLocal).unwrap(),
@@ -100,6 +175,7 @@ impl<'a> ZmqListener<'a> {
                 );
 
                 let mut engine = ZmqEngine::new();
+                engine.tcp_slot = self.tcp_slot.take();
                 let io_thread = self.chosen_io_thread();
                 let mut session = ZmqSessionBase::create(io_thread, false, self.socket, None)?;
 
@@ -117,24 +193,29 @@ impl<'a> ZmqListener<'a> {
     }
 
     pub fn in_event(&mut self) -> anyhow::Result<()> {
+        let rc = self.accept_connection();
+        //  The slot tcp_accept took is the engine's now, unless the
+        //  connection was dropped before an engine was made for it.
+        self.tcp_slot = None;
+        rc
+    }
+
+    fn accept_connection(&mut self) -> anyhow::Result<()> {
         match self.socket.destination.protocol {
             ZmqTransport::ZmqIpc => ipc_in_event(self),
             ZmqTransport::ZmqTcp => tcp_in_event(self),
             ZmqTransport::ZmqTipc => tipc_in_event(self),
             ZmqTransport::ZmqVmci => vmci_in_event(self),
+            ZmqTransport::ZmqShm => shm_in_event(self),
+            ZmqTransport::ZmqQuic => quic_in_event(self),
+            ZmqTransport::ZmqTls => tls_in_event(self),
+            ZmqTransport::ZmqVsock => vsock_in_event(self),
+            ZmqTransport::ZmqFd => fd_in_event(self),
+            ZmqTransport::ZmqWs | ZmqTransport::ZmqWss => ws_in_event(self),
             _ => bail!("unsupported protocol"),
         }
     }
 
-    pub fn filter(&mut self) -> anyhow::Result<()> {
-        match self.socket.destination.protocol {
-            #[cfg(not(windows))]
-            ZmqTransport::ZmqIpc => ipc_filter(self, 0),
-            _ => bail!("unsupported protocol"),
-        }
-        Ok(())
-    }
-
     // pub fn close(&mut self) -> anyhow::Result<()>
     //     {
     //         let rc = unsafe { close(self.fd) };
@@ -147,6 +228,12 @@ impl<'a> ZmqListener<'a> {
     pub fn close(&mut self) -> anyhow::Result<()> {
         match self.socket.destination.protocol {
             ZmqTransport::ZmqIpc => ipc_close(self),
+            ZmqTransport::ZmqShm => shm_close(self),
+            ZmqTransport::ZmqQuic => quic_close(self),
+            ZmqTransport::ZmqTls => tls_close(self),
+            ZmqTransport::ZmqVsock => vsock_close(self),
+            ZmqTransport::ZmqFd => fd_close(self),
+            ZmqTransport::ZmqWs | ZmqTransport::ZmqWss => ws_close(self),
             _ => bail!("unsupported protocol"),
         }
     }
@@ -154,9 +241,15 @@ impl<'a> ZmqListener<'a> {
     pub fn accept(&mut self) -> anyhow::Result<ZmqFileDesc> {
         match self.socket.destination.protocol {
             ZmqTransport::ZmqIpc => ipc_accept(self),
-            ZmqTransport::ZmqTcp => tcp_accept(self),
+            ZmqTransport::ZmqTcp
+            | ZmqTransport::ZmqTls
+            | ZmqTransport::ZmqWs
+            | ZmqTransport::ZmqWss => tcp_accept(self),
             ZmqTransport::ZmqTipc => tipc_accept(self),
             ZmqTransport::ZmqVmci => vmci_accept(self),
+            ZmqTransport::ZmqShm => shm_accept(self),
+            ZmqTransport::ZmqVsock => vsock_accept(self),
+            ZmqTransport::ZmqFd => fd_accept(self),
             _ => bail!("unsupported protocol"),
         }
     }
@@ -51,7 +51,9 @@ use crate::defines::{
 };
 use crate::message::{ZmqMessage, ZMQ_MSG_ROUTING_ID};
 use crate::utils::{copy_bytes, get_u32, put_u32};
+use crate::zmtp_compression::zmtp_compression_offer;
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum ZmqMechanismStatus {
     handshaking,
     ready,
@@ -89,6 +91,8 @@ pub const value_len_size: usize = mem::size_of::<u32>();
 pub const ZMTP_PROPERTY_SOCKET_TYPE: &str = "Socket-Type";
 // #define ZMTP_PROPERTY_IDENTITY "Identity"
 pub const ZMTP_PROPERTY_IDENTITY: &str = "Identity";
+//  Algorithms the sender can decompress, see zmtp_compression.rs.
+pub const ZMTP_PROPERTY_COMPRESSION: &str = "Compression";
 
 pub fn property_len(name_len_: usize, value_len_: usize) -> usize {
     name_len_size + name_len_ + value_len_size + value_len_
@@ -283,6 +287,17 @@ impl ZmqMechanism {
             );
         }
 
+        //  Add compression property
+        if let Some(compression) = zmtp_compression_offer(&self.options) {
+            ptr += self.add_property(
+                ptr,
+                ptr_capacity_ - (ptr - ptr_),
+                ZMTP_PROPERTY_COMPRESSION,
+                compression.as_bytes(),
+                compression.len(),
+            );
+        }
+
         // for (std::map<std::string, std::string>::const_iterator
         //     it = options.app_metadata.begin (),
         //     end = options.app_metadata.end ();
@@ -353,6 +368,10 @@ impl ZmqMechanism {
             meta_len += property_len(first.len(), second.len());
         }
 
+        if let Some(compression) = zmtp_compression_offer(&self.options) {
+            meta_len += property_len(ZMTP_PROPERTY_COMPRESSION.len(), compression.len());
+        }
+
         return property_len(ZMTP_PROPERTY_SOCKET_TYPE.len(), socket_type.len())
             + meta_len
             + if self.options.type_ == ZMQ_REQ
@@ -429,74 +448,85 @@ impl ZmqMechanism {
         Ok(())
     }
 
+    //  The peer's Compression property, if it sent one.
+    pub fn peer_compression(&self) -> Option<&str> {
+        self.zmtp_properties
+            .get(ZMTP_PROPERTY_COMPRESSION)
+            .map(String::as_str)
+    }
+
     pub fn check_socket_type(&self, type_: &str) -> bool {
-        match (self.options.type_) {
-            ZMQ_REQ => type_.eq(socket_type_rep) || type_.eq(socket_type_router),
-            ZMQ_REP => type_.eq(socket_type_req) || type_.eq(socket_type_dealer),
-            ZMQ_DEALER => {
-                type_.eq(socket_type_rep)
-                    || type_.eq(socket_type_dealer)
-                    || type_.eq(socket_type_router)
-            }
-            ZMQ_ROUTER => {
-                type_.eq(socket_type_req)
-                    || type_.eq(socket_type_dealer)
-                    || type_.eq(socket_type_router)
-            }
-            ZMQ_PUSH => type_.eq(socket_type_pull),
-            ZMQ_PULL => type_.eq(socket_type_push),
-            ZMQ_PUB => type_.eq(socket_type_sub) || type_.eq(socket_type_xsub),
-            ZMQ_SUB => type_.eq(socket_type_pub) || type_.eq(socket_type_xpub),
-            ZMQ_XPUB => type_.eq(socket_type_sub) || type_.eq(socket_type_xsub),
-            ZMQ_XSUB => type_.eq(socket_type_pub) || type_.eq(socket_type_xpub),
-            ZMQ_PAIR => type_.eq(socket_type_pair),
-            ZMQ_SERVER => type_.eq(socket_type_client),
-            ZMQ_CLIENT => type_.eq(socket_type_server),
-            ZMQ_RADIO => type_.eq(socket_type_dish),
-            ZMQ_DISH => type_.eq(socket_type_radio),
-            ZMQ_GATHER => type_.eq(socket_type_scatter),
-            ZMQ_SCATTER => type_.eq(socket_type_gather),
-            ZMQ_DGRAM => type_.eq(socket_type_dgram),
-            ZMQ_PEER => type_.eq(socket_type_peer),
-            ZMQ_CHANNEL => type_.eq(socket_type_channel),
-            _ => false,
-        }
+        socket_types_compatible(self.options.type_, type_)
     }
 }
 
-trait ZmqMechanismOps {
-    // virtual ~ZmqMechanism ();
+//  Whether a socket of type `own_type` may talk to a peer announcing
+//  `type_` (the Socket-Type property of the handshake).
+pub fn socket_types_compatible(own_type: i32, type_: &str) -> bool {
+    match own_type {
+        ZMQ_REQ => type_.eq(socket_type_rep) || type_.eq(socket_type_router),
+        ZMQ_REP => type_.eq(socket_type_req) || type_.eq(socket_type_dealer),
+        ZMQ_DEALER => {
+            type_.eq(socket_type_rep)
+                || type_.eq(socket_type_dealer)
+                || type_.eq(socket_type_router)
+        }
+        ZMQ_ROUTER => {
+            type_.eq(socket_type_req)
+                || type_.eq(socket_type_dealer)
+                || type_.eq(socket_type_router)
+        }
+        ZMQ_PUSH => type_.eq(socket_type_pull),
+        ZMQ_PULL => type_.eq(socket_type_push),
+        ZMQ_PUB => type_.eq(socket_type_sub) || type_.eq(socket_type_xsub),
+        ZMQ_SUB => type_.eq(socket_type_pub) || type_.eq(socket_type_xpub),
+        ZMQ_XPUB => type_.eq(socket_type_sub) || type_.eq(socket_type_xsub),
+        ZMQ_XSUB => type_.eq(socket_type_pub) || type_.eq(socket_type_xpub),
+        ZMQ_PAIR => type_.eq(socket_type_pair),
+        ZMQ_SERVER => type_.eq(socket_type_client),
+        ZMQ_CLIENT => type_.eq(socket_type_server),
+        ZMQ_RADIO => type_.eq(socket_type_dish),
+        ZMQ_DISH => type_.eq(socket_type_radio),
+        ZMQ_GATHER => type_.eq(socket_type_scatter),
+        ZMQ_SCATTER => type_.eq(socket_type_gather),
+        ZMQ_DGRAM => type_.eq(socket_type_dgram),
+        ZMQ_PEER => type_.eq(socket_type_peer),
+        ZMQ_CHANNEL => type_.eq(socket_type_channel),
+        _ => false,
+    }
+}
 
+pub trait ZmqMechanismOps {
     //  Prepare next handshake command that is to be sent to the peer.
-    // virtual int next_handshake_command (msg: &mut ZmqMessage) = 0; fn next_handshake_command(&mut self, msg: &mut ZmqMessage) -> anyhow::Result<()>;
+    fn next_handshake_command(
+        &mut self,
+        ctx: &mut ZmqContext,
+        msg: &mut ZmqMessage,
+    ) -> anyhow::Result<()>;
 
     //  Process the handshake command received from the peer.
-    // virtual int process_handshake_command (msg: &mut ZmqMessage) = 0; fn process_handshake_command(&mut self, msg: &mut ZmqMessage) -> anyhow::Result<()>;
+    fn process_handshake_command(
+        &mut self,
+        ctx: &mut ZmqContext,
+        msg: &mut ZmqMessage,
+    ) -> anyhow::Result<()>;
 
-    // virtual int encode (ZmqMessage *) { return 0; } fn encode(&mut self, msg: &mut ZmqMessage) -> anyhow::Result<()>;
+    fn encode(&mut self, _msg: &mut ZmqMessage) -> anyhow::Result<()> {
+        Ok(())
+    }
 
-    // virtual int decode (ZmqMessage *) { return 0; } fn decode(&mut self, msg: &mut ZmqMessage) -> anyhow::Result<()>;
+    fn decode(&mut self, _ctx: &mut ZmqContext, _msg: &mut ZmqMessage) -> anyhow::Result<()> {
+        Ok(())
+    }
 
     //  Notifies mechanism about availability of ZAP message.
-    // virtual int zap_msg_available () { return 0; } fn zap_msg_available(&mut self, msg: &mut ZmqMessage) -> anyhow::Result<()>;
+    fn zap_msg_available(&mut self, _ctx: &mut ZmqContext) -> anyhow::Result<()> {
+        Ok(())
+    }
 
     //  Returns the status of this mechanism.
-    // virtual status_t status () const = 0; fn status(&mut self) -> ZmqMechanismStatus;
-
-    //  This is called by parse_property method whenever it
-    //  parses a new property. The function should return 0
-    //  on success and -1 on error, in which case it should
-    //  set errno. Signaling error prevents parser from
-    //  parsing remaining data.
-    //  Derived classes are supposed to override this
-    //  method to handle custom processing.
-    // virtual int
-    // property (const std::string &name, const value_: *mut c_void, length_: usize);
-    // int ZmqMechanism::property (const std::string & /* name */,
-    // const void * /* value_ */,
-    // size_t /* length_ */) fn property(&mut self, name: &str, value: &[u8], length: usize) -> anyhow::Result<()> {
-    //  Default implementation does not check
-    //  property values and returns 0 to signal success.
-    // Ok(())
-    // }
+    fn status(&self) -> ZmqMechanismStatus;
+
+    //  Access to the shared routing id, user id and metadata state.
+    fn mechanism(&mut self) -> &mut ZmqMechanism;
 }
@@ -0,0 +1,118 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Arc;
+
+use anyhow::bail;
+
+pub use crate::context::ZmqContext;
+pub use crate::mechanism::{ZmqMechanism, ZmqMechanismOps, ZmqMechanismStatus};
+pub use crate::message::ZmqMessage;
+pub use crate::session_base::ZmqSessionBase;
+pub use crate::zap_client::ZmqZapClient;
+
+//  Size of the mechanism field in the ZMTP 3.x greeting.
+pub const ZMTP_MECHANISM_NAME_SIZE: usize = 20;
+
+//  Names owned by the built-in mechanisms; they cannot be registered.
+const BUILTIN_MECHANISMS: [&str; 4] = ["NULL", "PLAIN", "CURVE", "GSSAPI"];
+
+//  Creates per-connection instances of a user-supplied security mechanism.
+//  The engine calls `create` once the peer's greeting has announced the
+//  same mechanism name; the returned object then drives the handshake
+//  (`next_handshake_command` / `process_handshake_command`), ZAP
+//  (`zap_msg_available`, typically through a `ZmqZapClient`) and message
+//  framing (`encode` / `decode`) exactly like NULL, PLAIN or CURVE do.
+pub trait ZmqMechanismFactory: Send + Sync {
+    //  Mechanism name as sent in the greeting: 1 to 20 characters out of
+    //  A-Z, 0-9, '-', '_', '.' and '+' (RFC 23/ZMTP).
+    fn name(&self) -> &str;
+
+    //  Creates the mechanism for a new connection. `as_server` reflects
+    //  the socket's role for this mechanism (ZMQ_MECHANISM_SERVER).
+    fn create(
+        &self,
+        session: &mut ZmqSessionBase,
+        peer_address: &str,
+        ctx: &mut ZmqContext,
+        as_server: bool,
+        downgrade_sub: bool,
+    ) -> anyhow::Result<Box<dyn ZmqMechanismOps>>;
+}
+
+//  Custom mechanisms known to a context, keyed by greeting name.
+#[derive(Default, Clone)]
+pub struct ZmqMechanismRegistry {
+    pub factories: HashMap<String, Arc<dyn ZmqMechanismFactory>>,
+}
+
+impl fmt::Debug for ZmqMechanismRegistry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_set().entries(self.factories.keys()).finish()
+    }
+}
+
+impl ZmqMechanismRegistry {
+    //  Adds a mechanism. Fails with EINVAL if the name is not a valid
+    //  greeting name or belongs to a built-in mechanism, and with EEXIST
+    //  if another factory already uses it.
+    pub fn register(&mut self, factory: Arc<dyn ZmqMechanismFactory>) -> anyhow::Result<()> {
+        let name = factory.name().to_string();
+        check_mechanism_name(&name)?;
+        if self.factories.contains_key(&name) {
+            bail!("EEXIST: mechanism {} is already registered", name);
+        }
+        self.factories.insert(name, factory);
+        Ok(())
+    }
+
+    pub fn unregister(&mut self, name: &str) -> bool {
+        self.factories.remove(name).is_some()
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.factories.contains_key(name)
+    }
+
+    pub fn get(&self, name: &str) -> Option<Arc<dyn ZmqMechanismFactory>> {
+        self.factories.get(name).cloned()
+    }
+
+    pub fn create(
+        &self,
+        name: &str,
+        session: &mut ZmqSessionBase,
+        peer_address: &str,
+        ctx: &mut ZmqContext,
+        as_server: bool,
+        downgrade_sub: bool,
+    ) -> anyhow::Result<Box<dyn ZmqMechanismOps>> {
+        match self.factories.get(name) {
+            Some(factory) => factory.create(session, peer_address, ctx, as_server, downgrade_sub),
+            None => bail!("EINVAL: mechanism {} is not registered", name),
+        }
+    }
+}
+
+pub fn check_mechanism_name(name: &str) -> anyhow::Result<()> {
+    if name.is_empty() || name.len() > ZMTP_MECHANISM_NAME_SIZE {
+        bail!("EINVAL: mechanism name must be 1 to {} characters", ZMTP_MECHANISM_NAME_SIZE);
+    }
+    let valid = name
+        .bytes()
+        .all(|c| c.is_ascii_uppercase() || c.is_ascii_digit() || b"-_.+".contains(&c));
+    if !valid {
+        bail!("EINVAL: invalid mechanism name {}", name);
+    }
+    if BUILTIN_MECHANISMS.contains(&name) {
+        bail!("EINVAL: mechanism name {} is reserved", name);
+    }
+    Ok(())
+}
+
+//  The mechanism name as it appears in the greeting, null padded.
+pub fn mechanism_greeting_name(name: &str) -> [u8; ZMTP_MECHANISM_NAME_SIZE] {
+    let mut out = [0u8; ZMTP_MECHANISM_NAME_SIZE];
+    let len = name.len().min(ZMTP_MECHANISM_NAME_SIZE);
+    out[..len].copy_from_slice(&name.as_bytes()[..len]);
+    out
+}
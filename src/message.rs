@@ -1,22 +1,22 @@
 //  Check whether the sizes of public representation of the message (zmq_ZmqMessage)
 //  and private representation of the message (ZmqMessage) match.
 
-use crate::content::ZmqContent;
+use crate::content::{ZmqContent, ZmqExternalData, ZmqFreeFn, ZmqMsgBuffer};
 use crate::defines::ZMQ_GROUP_MAX_LENGTH;
 use crate::err::ZmqError;
 use crate::metadata::ZmqMetadata;
 use crate::utils::copy_bytes;
 use anyhow::anyhow;
-use libc::{c_long, EINVAL};
+use libc::{c_long, c_void, EINVAL};
 use serde::{Deserialize, Serialize};
 use std::mem;
 use std::mem::size_of;
-use std::sync::atomic::AtomicU64;
+use bytes::Bytes;
 
 pub const ZMQ_MSG_SIZE: usize = 64;
 
 pub const MAX_VSM_SIZE: usize =
-    ZMQ_MSG_SIZE - size_of::<*mut ZmqMetadata> + 3 + 16 + size_of::<u32>();
+    ZMQ_MSG_SIZE - (size_of::<*mut ZmqMetadata>() + 3 + 16 + size_of::<u32>());
 
 pub const PING_CMD_NAME_SIZE: usize = 5; // 4PING
 pub const CANCEL_CMD_NAME_SIZE: usize = 7; // 6CANCEL
@@ -116,10 +116,12 @@ pub struct ZmqMessage {
     // refcnt: AtomicCounter,
     //  Different message types.
     // pub u: MsgUnion,
-    pub raw: Vec<u8>,
     pub top_msg_type: MessageType,
     pub metadata: Option<ZmqMetadata>,
-    pub content: ZmqContent,
+    //  Payload of LMSG, ZCLMSG and CMSG messages. The reference count lives
+    //  in the buffer, so copies share the bytes instead of duplicating them.
+    pub buffer: ZmqMsgBuffer,
+    //  Payload of VSM messages, kept inline.
     pub data: [u8; MAX_VSM_SIZE],
     pub size: usize,
     pub msg_type: u8,
@@ -128,7 +130,6 @@ pub struct ZmqMessage {
     pub group_type: u8,
     pub group: String,
     pub type_: u8,
-    pub refcnt: AtomicU64,
     pub sgroup: GroupSgroup,
     pub lgroup: GroupLgroup,
 }
@@ -215,22 +216,31 @@ impl ZmqMessage {
         return self.msg_type >= TYPE_MIN && self.msg_type <= TYPE_MAX;
     }
 
-    pub fn init(
+    //  Small payloads are copied and 'ffn' is not called for them; the
+    //  caller still owns the data.
+    //
+    //  # Safety
+    //
+    //  As for init_data.
+    pub unsafe fn init(
         &mut self,
-        data: &mut [u8],
+        data: *mut u8,
         size: usize,
-        hint: &mut [u8],
+        ffn: Option<ZmqFreeFn>,
+        hint: *mut c_void,
         content: Option<&mut ZmqContent>,
     ) -> anyhow::Result<()> {
         if size < MAX_VSM_SIZE {
             self.init_size(size)?;
-            copy_bytes(self.data_mut(), 0, data, 0, size);
+            if size > 0 {
+                self.data_mut()[..size].copy_from_slice(std::slice::from_raw_parts(data, size));
+            }
             return Ok(());
         }
-        if content.is_some() {
-            return self.init_external_storage(content.unwrap(), data, size, hint);
+        if let Some(content) = content {
+            return self.init_external_storage(content, data, size, ffn, hint);
         }
-        self.init_data(data, size, Some(hint))
+        self.init_data(data, size, ffn, hint)
     }
 
     pub fn init2(&mut self) -> anyhow::Result<()> {
@@ -270,7 +280,7 @@ impl ZmqMessage {
             //   // errno = ENOMEM;
             //     return -1;
             // }
-            self.content = ZmqContent::default();
+            self.buffer = ZmqMsgBuffer::zeroed(size);
             // self._u.content.data = self._u.content + 1;
             // self._u.content.size = size;
             // self._u.content.ffn = NULL;
@@ -280,7 +290,10 @@ impl ZmqMessage {
         Ok(())
     }
 
-    pub fn init_buffer(&mut self, buf_: &mut [u8], size: usize) -> anyhow::Result<()> {
+    pub fn init_buffer(&mut self, buf_: &[u8], size: usize) -> anyhow::Result<()> {
+        if size > MAX_VSM_SIZE {
+            return self.init_bytes(Bytes::copy_from_slice(&buf_[..size]));
+        }
         self.init_size(size)?;
         if size > 0 {
             copy_bytes(self.data_mut(), 0, buf_, 0, size);
@@ -288,12 +301,51 @@ impl ZmqMessage {
         Ok(())
     }
 
-    pub fn init_external_storage(
+    //  Takes over a reference-counted buffer without copying it. Small
+    //  payloads are still copied into the message itself.
+    pub fn init_bytes(&mut self, bytes: Bytes) -> anyhow::Result<()> {
+        if bytes.len() <= MAX_VSM_SIZE {
+            self.init_size(bytes.len())?;
+            self.data[..bytes.len()].copy_from_slice(&bytes);
+            return Ok(());
+        }
+        self.metadata = None;
+        self.msg_type = TYPE_LMSG;
+        self.flags = 0;
+        self.group[0] = 0;
+        self.group_type = GROUP_TYPE_SHORT;
+        self.routing_id = 0;
+        self.buffer = ZmqMsgBuffer::Shared(bytes);
+        Ok(())
+    }
+
+    //  Constant data, the counterpart of zmq_msg_init_data without a free
+    //  function. Never copied.
+    pub fn init_static(&mut self, data: &'static [u8]) -> anyhow::Result<()> {
+        self.metadata = None;
+        self.msg_type = TYPE_CMSG;
+        self.flags = 0;
+        self.group[0] = 0;
+        self.group_type = GROUP_TYPE_SHORT;
+        self.routing_id = 0;
+        self.buffer = ZmqMsgBuffer::Shared(Bytes::from_static(data));
+        Ok(())
+    }
+
+    //  Like init_data. libzmq takes the content from the caller to save an
+    //  allocation; here the reference count lives in the Bytes, so the
+    //  content only records the size.
+    //
+    //  # Safety
+    //
+    //  As for init_data.
+    pub unsafe fn init_external_storage(
         &mut self,
         content: &mut ZmqContent,
-        data: &mut [u8],
+        data: *mut u8,
         size: usize,
-        hint: &mut [u8],
+        ffn: Option<ZmqFreeFn>,
+        hint: *mut c_void,
     ) -> anyhow::Result<()> {
         // zmq_assert (NULL != data);
         // zmq_assert (NULL != content);
@@ -304,59 +356,49 @@ impl ZmqMessage {
         self.group[0] = 0;
         self.group_type = GROUP_TYPE_SHORT;
         self.routing_id = 0;
-        self.content = content.clone();
-        self.content.data = data.clone();
-        self.content.size = size;
-        // self._u.content->ffn = ffn_;
-        self.content.hint = hint.clone();
-        // new (&_u.content->refcnt) AtomicCounter ();
-        self.content.refcnt = AtomicU64::new(0);
-
+        content.size = size;
+        self.buffer = ZmqMsgBuffer::Shared(Bytes::from_owner(ZmqExternalData {
+            data,
+            size,
+            ffn,
+            hint,
+        }));
         Ok(())
     }
 
-    pub fn init_data(
+    //  Wraps 'data' without copying it. Once the message and all its copies
+    //  are closed, ffn(data, hint) is called; without 'ffn' the data is
+    //  constant and simply left alone.
+    //
+    //  # Safety
+    //
+    //  'data' must point to 'size' readable bytes that stay valid and
+    //  unchanged until 'ffn' is called, or for good if there is none. 'ffn'
+    //  may be called from any thread.
+    pub unsafe fn init_data(
         &mut self,
-        data: &mut [u8],
+        data: *mut u8,
         size: usize,
-        hint: Option<&mut [u8]>,
+        ffn: Option<ZmqFreeFn>,
+        hint: *mut c_void,
     ) -> anyhow::Result<()> {
         //  If data is NULL and size is not 0, a segfault
         //  would occur once the data is accessed
         // zmq_assert (data != NULL || size == 0);
 
-        //  Initialize constant message if there's no need to deallocate
-        // if (ffn_ == NULL)
-        // {
         self.metadata = None;
-        self.msg_type = TYPE_CMSG;
+        //  Initialize constant message if there's no need to deallocate
+        self.msg_type = if ffn.is_none() { TYPE_CMSG } else { TYPE_LMSG };
         self.flags = 0;
-        self.data.clone_from_slice(data);
-        self.size = size;
         self.group[0] = 0;
         self.group_type = GROUP_TYPE_SHORT;
         self.routing_id = 0;
-        // }
-        // else {
-        //     _u.metadata = NULL;
-        //     _u.type = type_lmsg;
-        //     _u.flags = 0;
-        //     _u.Group[0] = 0;
-        //     _u.Group.type = group_type_short;
-        //     _u.routing_id = 0;
-        //     _u.content =
-        //       static_cast<ZmqContent *> (malloc (mem::size_of::<ZmqContent>()));
-        //     if (!_u.content) {
-        //       // errno = ENOMEM;
-        //         return -1;
-        //     }
-        //
-        //     _u.content->data = data;
-        //     _u.content->size = size;
-        //     _u.content->ffn = ffn_;
-        //     _u.content->hint = hint;
-        //     new (&_u.content->refcnt) AtomicCounter ();
-        // }
+        self.buffer = ZmqMsgBuffer::Shared(Bytes::from_owner(ZmqExternalData {
+            data,
+            size,
+            ffn,
+            hint,
+        }));
         Ok(())
     }
 
@@ -390,14 +432,14 @@ impl ZmqMessage {
         return 0;
     }
 
-    pub fn init_subscribe(&mut self, size: usize, topic: &mut [u8]) -> anyhow::Result<()> {
+    pub fn init_subscribe(&mut self, size: usize, topic: &[u8]) -> anyhow::Result<()> {
         self.init_size(size)?;
         self.set_flags(ZMQ_MSG_SUBSCRIBE);
         copy_bytes(self.data_mut(), 0, topic, 0, size);
         return Ok(());
     }
 
-    pub fn init_cancel(&mut self, size: usize, topic: &mut [u8]) -> anyhow::Result<()> {
+    pub fn init_cancel(&mut self, size: usize, topic: &[u8]) -> anyhow::Result<()> {
         self.init_size(size)?;
         self.set_flags(ZMQ_MSG_CANCEL);
         copy_bytes(self.data_mut(), 0, topic, 0, size);
@@ -406,34 +448,10 @@ impl ZmqMessage {
 
     pub fn close(&mut self) -> anyhow::Result<()> {
         self.check()?;
-        if self.msg_type == TYPE_LMSG {
-            //  If the content is not shared, or if it is shared and the reference
-            //  count has dropped to zero, deallocate it.
-            if !(self.flags & ZMQ_MSG_SHARED) != 0 || !self.content.refcnt.sub(1) {
-                //  We used "placement new" operator to initialize the reference
-                //  counter so we call the destructor explicitly now.
-                // self._u.content->refcnt.~AtomicCounter ();
-
-                // if (_u.content->ffn)
-                //     _u.content->ffn (_u.content->data,
-                //                           _u.content->hint);
-                // free (_u.content);
-            }
-        }
-
-        if self.is_zcmsg() {
-            // zmq_assert (_u.content->ffn);
-
-            //  If the content is not shared, or if it is shared and the reference
-            //  count has dropped to zero, deallocate it.
-            if (!(self.flags & ZMQ_MSG_SHARED) != 0 || !self.content.refcnt.sub(1)) {
-                //  We used "placement new" operator to initialize the reference
-                //  counter so we call the destructor explicitly now.
-                // self._u.content.refcnt.~AtomicCounter ();
-
-                // _u.content->ffn (_u.content->data,
-                //                         _u.content->hint);
-            }
+        if self.msg_type == TYPE_LMSG || self.is_zcmsg() || self.is_cmsg() {
+            //  Dropping our handle releases the content once the last copy
+            //  sharing it is closed; Bytes keeps the reference count.
+            self.buffer = ZmqMsgBuffer::default();
         }
 
         if self.metadata.is_some() {
@@ -459,85 +477,87 @@ impl ZmqMessage {
         Ok(())
     }
 
-    // pub fn move_(&mut self, src: &mut ZmqMessage) -> i32
-    // {
-    //     //  Check the validity of the source.
-    //     if (unlikely (!src_.check ())) {
-    //       // errno = EFAULT;
-    //         return -1;
-    //     }
-    //
-    //     int rc = close ();
-    //     if (unlikely (rc < 0))
-    //         return rc;
-    //
-    //     *this = src_;
-    //
-    //     rc = src_.init ();
-    //     if (unlikely (rc < 0))
-    //         return rc;
-    //
-    //     return 0;
-    // }
+    pub fn move_(&mut self, src: &mut ZmqMessage) -> anyhow::Result<()> {
+        //  Check the validity of the source.
+        if !src.check() {
+            return Err(anyhow!("EFAULT: invalid source message"));
+        }
 
-    // int copy (ZmqMessage &src_)
-    // {
-    //     //  Check the validity of the source.
-    //     if (unlikely (!src_.check ())) {
-    //       // errno = EFAULT;
-    //         return -1;
-    //     }
-    //
-    //     let rc: i32 = close ();
-    //     if (unlikely (rc < 0))
-    //         return rc;
-    //
-    //     // The initial reference count, when a non-shared message is initially
-    //     // shared (between the original and the copy we create here).
-    //     const AtomicCounter::integer_t initial_shared_refcnt = 2;
-    //
-    //     if (src_.is_lmsg () || src_.is_zcmsg ()) {
-    //         //  One reference is added to shared messages. Non-shared messages
-    //         //  are turned into shared messages.
-    //         if (src_.flags () & shared)
-    //             src_.refcnt ()->add (1);
-    //         else {
-    //             src_.set_flags (shared);
-    //             src_.refcnt ()->set (initial_shared_refcnt);
-    //         }
-    //     }
-    //
-    //     if (src_._u.metadata != NULL)
-    //         src_._u.metadata->add_ref ();
-    //
-    //     if (src_._u.Group.type == group_type_long)
-    //         src_._u.lgroup.content->refcnt.add (1);
-    //
-    //     *this = src_;
-    //
-    //     return 0;
-    // }
+        self.close()?;
+
+        *self = mem::take(src);
+
+        src.init2()
+    }
+
+    pub fn copy(&mut self, src: &mut ZmqMessage) -> anyhow::Result<()> {
+        //  Check the validity of the source.
+        if !src.check() {
+            return Err(anyhow!("EFAULT: invalid source message"));
+        }
 
-    pub fn data(&mut self) -> &[u8] {
+        self.close()?;
+
+        //  Long messages are frozen so that the original and the copy point
+        //  at the same bytes; cloning then only bumps the reference count.
+        src.freeze();
+
+        *self = src.clone();
+
+        Ok(())
+    }
+
+    //  Turns a uniquely owned long message into a shared, immutable one.
+    //  Copies made afterwards are O(1).
+    pub fn freeze(&mut self) {
+        if self.is_lmsg() || self.is_zcmsg() {
+            self.buffer = ZmqMsgBuffer::Shared(mem::take(&mut self.buffer).freeze());
+            self.flags |= ZMQ_MSG_SHARED;
+        }
+    }
+
+    //  True if the payload is referenced by more than one message.
+    pub fn is_shared(&self) -> bool {
+        (self.is_lmsg() || self.is_zcmsg() || self.is_cmsg()) && self.buffer.is_shared()
+    }
+
+    //  Returns the payload as a Bytes handle, sharing it when possible.
+    pub fn to_bytes(&mut self) -> Bytes {
+        match self.msg_type {
+            TYPE_LMSG | TYPE_ZCLMSG | TYPE_CMSG => {
+                self.freeze();
+                match &self.buffer {
+                    ZmqMsgBuffer::Shared(bytes) => bytes.clone(),
+                    ZmqMsgBuffer::Unique(bytes) => Bytes::copy_from_slice(bytes),
+                }
+            }
+            _ => Bytes::copy_from_slice(self.data()),
+        }
+    }
+
+    pub fn data(&self) -> &[u8] {
         //  Check the validity of the message.
         // zmq_assert (check ());
 
         match self.msg_type {
-            TYPE_VSM => self.data.as_slice(),
-            TYPE_LMSG => self.content.data.as_slice(),
-            TYPE_CMSG => self.content.data.as_slice(),
+            TYPE_VSM => &self.data[..self.size],
+            TYPE_LMSG | TYPE_ZCLMSG | TYPE_CMSG => self.buffer.as_slice(),
             TYPE_DELIMITER => self.unused.as_slice(),
-            _ => self.raw.as_slice(),
+            _ => &[],
         }
     }
 
+    //  Long messages shared with other copies are copied on write, so
+    //  modifying the payload never affects another pipe's message.
     pub fn data_mut(&mut self) -> &mut [u8] {
         match self.msg_type {
-            TYPE_VSM => self.data.as_mut_slice(),
-            TYPE_LMSG => self.content.data.as_mut_slice(),
-            TYPE_CMSG => self.content.data.as_mut_slice(),
+            TYPE_VSM => &mut self.data[..self.size],
+            TYPE_LMSG | TYPE_ZCLMSG | TYPE_CMSG => {
+                self.flags &= !ZMQ_MSG_SHARED;
+                self.buffer.as_mut_slice()
+            }
             TYPE_DELIMITER => self.unused.as_mut_slice(),
-            _ => self.raw.as_mut_slice(),
+            _ => &mut [],
         }
     }
 
@@ -547,9 +567,7 @@ impl ZmqMessage {
 
         match self.msg_type {
             TYPE_VSM => self.size,
-            TYPE_LMSG => self.content.size,
-            TYPE_ZCLMSG => self.content.size,
-            TYPE_CMSG => self.size,
+            TYPE_LMSG | TYPE_ZCLMSG | TYPE_CMSG => self.buffer.len(),
             _ => 0, // zmq_assert (false);
                     // return 0;
         }
@@ -562,9 +580,7 @@ impl ZmqMessage {
 
         match self.msg_type {
             TYPE_VSM => self.size = new_size,
-            TYPE_LMSG => self.content.size = new_size,
-            TYPE_ZCLMSG => self.content.size = new_size,
-            TYPE_CMSG => self.size = new_size,
+            TYPE_LMSG | TYPE_ZCLMSG | TYPE_CMSG => self.buffer.truncate(new_size),
             _ => {} // zmq_assert (false);
         }
     }
@@ -650,6 +666,14 @@ impl ZmqMessage {
         return (self.flags & CMD_TYPE_MASK) == ZMQ_MSG_PONG;
     }
 
+    pub fn is_subscribe(&self) -> bool {
+        return (self.flags & CMD_TYPE_MASK) == ZMQ_MSG_SUBSCRIBE;
+    }
+
+    pub fn is_cancel(&self) -> bool {
+        return (self.flags & CMD_TYPE_MASK) == ZMQ_MSG_CANCEL;
+    }
+
     pub fn is_close_cmd(&self) -> bool {
         return (self.flags & CMD_TYPE_MASK) == ZMQ_MSG_CLOSE_CMD;
     }
@@ -668,90 +692,26 @@ impl ZmqMessage {
         return 0;
     }
 
-    pub fn command_body(&mut self) -> Vec<u8> {
-        // unsigned char *data = NULL;
-        let mut data: Vec<u8> = Vec::new();
+    pub fn command_body(&self) -> &[u8] {
+        let data = self.data();
 
         if self.is_ping() || self.is_pong() {
-            data = (self.data().unwrap()) + PING_CMD_NAME_SIZE;
+            &data[PING_CMD_NAME_SIZE..]
         }
         //  With inproc, command flag is not set for sub/cancel
         else if !(self.flags() & ZMQ_MSG_COMMAND != 0) && (self.is_subscribe() || self.is_cancel()) {
-            data = (self.data().unwrap());
+            data
         } else if self.is_subscribe() {
-            data = (self.data().unwrap()) + SUB_CMD_NAME_SIZE;
+            &data[SUB_CMD_NAME_SIZE..]
         } else if self.is_cancel() {
-            data = (self.data().unwrawp()) + CANCEL_CMD_NAME_SIZE;
+            &data[CANCEL_CMD_NAME_SIZE..]
+        } else {
+            &[]
         }
-
-        return data;
     }
 
-    // pub fn add_refs(&mut self, refs_: i32) {
-    //     // zmq_assert (refs_ >= 0);
-    //
-    //     //  Operation not supported for messages with metadata.
-    //     // zmq_assert (_u.metadata == NULL);
-    //
-    //     //  No copies required.
-    //     if !refs_ {
-    //         return;
-    //     }
-    //
-    //     //  VSMs, CMSGS and delimiters can be copied straight away. The only
-    //     //  message type that needs special care are long messages.
-    //     if self.type_ == TYPE_LMSG || self.is_zcmsg() {
-    //         if self.flags & shared {
-    //             self.refcnt().add(refs_);
-    //         } else {
-    //             self.refcnt().set(refs_ + 1);
-    //             self.flags |= shared;
-    //         }
-    //     }
-    // }
-
-    // pub fn rm_refs(&mut self, refs_: i32) -> bool {
-    //     // zmq_assert (refs_ >= 0);
-    //
-    //     //  Operation not supported for messages with metadata.
-    //     // zmq_assert (_u.metadata == NULL);
-    //
-    //     //  No copies required.
-    //     if (!refs_) {
-    //         return true;
-    //     }
-    //
-    //     //  If there's only one reference close the message.
-    //     if (self.type_ != TYPE_ZCLMSG && self.type_ != TYPE_LMSG) || !(self.flags & shared != 0) {
-    //         self.close();
-    //         return false;
-    //     }
-    //
-    //     //  The only message type that needs special care are long and zcopy messages.
-    //     if self.type_ == TYPE_LMSG && !self.content.refcnt.sub(refs_ as u32) {
-    //         //  We used "placement new" operator to initialize the reference
-    //         //  counter so we call the destructor explicitly now.
-    //         // self._u.content.refcnt.~AtomicCounter ();
-    //
-    //         // if (_u.content->ffn)
-    //         //     _u.content->ffn (_u.content->data, _u.content->hint);
-    //         // free (_u.content);
-    //
-    //         return false;
-    //     }
-    //
-    //     if self.is_zcmsg() && !self.content.refcnt.sub(refs_ as u32) {
-    //         // storage for rfcnt is provided externally
-    //         // if (self._u.content->ffn) {
-    //         //     self._u.content->ffn (_u.content->data,
-    //         //                             _u.content->hint);
-    //         // }
-    //
-    //         return false;
-    //     }
-    //
-    //     return true;
-    // }
+    //  The C++ add_refs/rm_refs pair is not needed: copies share the
+    //  payload through `clone` and release it when dropped or closed.
 
     // pub fn get_routing_id(&self) -> u32 {
     //     return self.routing_id;
@@ -0,0 +1,379 @@
+/*
+    Copyright (c) 2007-2016 Contributors as noted in the AUTHORS file
+
+    This file is part of libzmq, the ZeroMQ core engine in C+= 1.
+
+    libzmq is free software; you can redistribute it and/or modify it under
+    the terms of the GNU Lesser General Public License (LGPL) as published
+    by the Free Software Foundation; either version 3 of the License, or
+    (at your option) any later version.
+
+    As a special exception, the Contributors give you permission to link
+    this library with independent modules to produce an executable,
+    regardless of the license terms of these independent modules, and to
+    copy and distribute the resulting executable under terms of your choice,
+    provided that you also meet, for each linked independent module, the
+    terms and conditions of the license of that module. An independent
+    module is a module which is not derived from or based on this library.
+    If you modify this library, you must extend this exception to your
+    version of the library.
+
+    libzmq is distributed in the hope that it will be useful, but WITHOUT
+    ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+    FITNESS FOR A PARTICULAR PURPOSE. See the GNU Lesser General Public
+    License for more details.
+
+    You should have received a copy of the GNU Lesser General Public License
+    along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+// #include "precompiled.hpp"
+// #include "mtrie.hpp"
+// #include "generic_mtrie_impl.hpp"
+
+use std::mem;
+
+use crate::pipe::ZmqPipeId;
+
+//  Same layout as the radix tree nodes, except that instead of a reference
+//  count every node holds the set of values (pipes) subscribed to the key
+//  that ends at it.
+#[derive(Debug, Clone)]
+pub struct MtrieNode<T> {
+    pub prefix: Vec<u8>,
+    pub pipes: Vec<T>,
+    pub children: Vec<MtrieNode<T>>,
+}
+
+impl<T> Default for MtrieNode<T> {
+    fn default() -> Self {
+        Self {
+            prefix: vec![],
+            pipes: vec![],
+            children: vec![],
+        }
+    }
+}
+
+impl<T> MtrieNode<T> {
+    fn find_child(&self, first_byte: u8) -> Result<usize, usize> {
+        self.children
+            .binary_search_by_key(&first_byte, |child| child.prefix[0])
+    }
+
+    fn split(&mut self, at: usize) {
+        let child = MtrieNode {
+            prefix: self.prefix.split_off(at),
+            pipes: mem::take(&mut self.pipes),
+            children: mem::take(&mut self.children),
+        };
+        self.children.push(child);
+    }
+
+    fn merge_with_child(&mut self) {
+        let child = self.children.pop().unwrap();
+        self.prefix.extend_from_slice(&child.prefix);
+        self.pipes = child.pipes;
+        self.children = child.children;
+    }
+
+    fn is_redundant(&self) -> bool {
+        self.pipes.is_empty() && self.children.is_empty()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ZmqMtrieRmResult {
+    NotFound,
+    LastValueRemoved,
+    ValuesRemain,
+}
+
+//  Multi-trie (prefix tree). Each node in the trie is a set of values.
+#[derive(Debug, Clone)]
+pub struct ZmqGenericMtrie<T> {
+    pub root: MtrieNode<T>,
+    //  Number of prefixes that have at least one value.
+    pub num_prefixes: usize,
+}
+
+//  Pipes are held by id; see ZmqPipe::id.
+// typedef generic_mtrie_t<pipe_t> mtrie_t;
+pub type ZmqMtrie = ZmqGenericMtrie<ZmqPipeId>;
+
+impl<T> Default for ZmqGenericMtrie<T> {
+    fn default() -> Self {
+        Self {
+            root: MtrieNode::default(),
+            num_prefixes: 0,
+        }
+    }
+}
+
+impl<T: PartialEq> ZmqGenericMtrie<T> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn num_prefixes(&self) -> usize {
+        self.num_prefixes
+    }
+
+    //  Add key to the trie. Returns true iff no entry with the same prefix
+    //  and size existed before.
+    pub fn add(&mut self, prefix: &[u8], size: usize, value: T) -> bool {
+        let mut key = &prefix[..size];
+        let mut node = &mut self.root;
+        loop {
+            let matched = node
+                .prefix
+                .iter()
+                .zip(key.iter())
+                .take_while(|(x, y)| x == y)
+                .count();
+            if matched < node.prefix.len() {
+                node.split(matched);
+            }
+            key = &key[matched..];
+
+            if key.is_empty() {
+                break;
+            }
+
+            match node.find_child(key[0]) {
+                Ok(idx) => node = &mut node.children[idx],
+                Err(idx) => {
+                    node.children.insert(
+                        idx,
+                        MtrieNode {
+                            prefix: key.to_vec(),
+                            ..Default::default()
+                        },
+                    );
+                    node = &mut node.children[idx];
+                    break;
+                }
+            }
+        }
+
+        let result = node.pipes.is_empty();
+        if result {
+            self.num_prefixes += 1;
+        }
+        if !node.pipes.contains(&value) {
+            node.pipes.push(value);
+        }
+        result
+    }
+
+    //  Removes a specific entry from the trie.
+    //  Returns the result of the operation.
+    pub fn rm(&mut self, prefix: &[u8], size: usize, value: &T) -> ZmqMtrieRmResult {
+        let mut key = &prefix[..size];
+        let mut path: Vec<usize> = vec![];
+        let mut node = &self.root;
+        while !key.is_empty() {
+            match node.find_child(key[0]) {
+                Ok(idx) if key.starts_with(&node.children[idx].prefix) => {
+                    node = &node.children[idx];
+                    key = &key[node.prefix.len()..];
+                    path.push(idx);
+                }
+                _ => return ZmqMtrieRmResult::NotFound,
+            }
+        }
+        if !node.pipes.contains(value) {
+            return ZmqMtrieRmResult::NotFound;
+        }
+
+        let (last, parent_path) = match path.split_last() {
+            Some((last, parent_path)) => (*last, parent_path),
+            None => {
+                self.root.pipes.retain(|pipe| pipe != value);
+                if !self.root.pipes.is_empty() {
+                    return ZmqMtrieRmResult::ValuesRemain;
+                }
+                self.num_prefixes -= 1;
+                return ZmqMtrieRmResult::LastValueRemoved;
+            }
+        };
+
+        let mut parent = &mut self.root;
+        for idx in parent_path {
+            parent = &mut parent.children[*idx];
+        }
+        let node = &mut parent.children[last];
+        node.pipes.retain(|pipe| pipe != value);
+        if !node.pipes.is_empty() {
+            return ZmqMtrieRmResult::ValuesRemain;
+        }
+        self.num_prefixes -= 1;
+
+        match node.children.len() {
+            0 => {
+                parent.children.remove(last);
+                if !parent_path.is_empty() && parent.pipes.is_empty() && parent.children.len() == 1
+                {
+                    parent.merge_with_child();
+                }
+            }
+            1 => node.merge_with_child(),
+            _ => {}
+        }
+        ZmqMtrieRmResult::LastValueRemoved
+    }
+
+    //  Remove all entries with a specific value from the trie.
+    //  The call_on_uniq flag controls if the callback is invoked
+    //  when there are no entries left on a prefix only (true)
+    //  or on every removal (false).
+    pub fn rm_value<F>(&mut self, value: &T, mut func: F, call_on_uniq: bool)
+    where
+        F: FnMut(&[u8], usize),
+    {
+        //  Depth-first, without recursing: 'path' leads to the node being
+        //  visited, 'next' holds for each level the next child to visit. A
+        //  node is pruned once all of its children have been, so nodes are
+        //  only dropped or merged where a value was actually removed.
+        let mut buffer: Vec<u8> = vec![];
+        let mut path: Vec<usize> = vec![];
+        let mut next: Vec<usize> = vec![0];
+        self.rm_value_at(&path, value, &mut buffer, &mut func, call_on_uniq);
+
+        while let Some(child) = next.last().copied() {
+            let node = Self::node_at(&mut self.root, &path);
+            if child < node.children.len() {
+                *next.last_mut().unwrap() += 1;
+                path.push(child);
+                next.push(0);
+                self.rm_value_at(&path, value, &mut buffer, &mut func, call_on_uniq);
+                continue;
+            }
+
+            next.pop();
+            let Some(idx) = path.pop() else {
+                break;
+            };
+            let parent = Self::node_at(&mut self.root, &path);
+            let node = &mut parent.children[idx];
+            buffer.truncate(buffer.len() - node.prefix.len());
+            if node.is_redundant() {
+                parent.children.remove(idx);
+                *next.last_mut().unwrap() -= 1;
+            } else if node.pipes.is_empty() && node.children.len() == 1 {
+                node.merge_with_child();
+            }
+        }
+    }
+
+    fn node_at<'n>(root: &'n mut MtrieNode<T>, path: &[usize]) -> &'n mut MtrieNode<T> {
+        path.iter().fold(root, |node, &idx| &mut node.children[idx])
+    }
+
+    //  Removes the value from the node at 'path', whose prefix is appended
+    //  to 'buffer'.
+    fn rm_value_at<F>(
+        &mut self,
+        path: &[usize],
+        value: &T,
+        buffer: &mut Vec<u8>,
+        func: &mut F,
+        call_on_uniq: bool,
+    ) where
+        F: FnMut(&[u8], usize),
+    {
+        let node = Self::node_at(&mut self.root, path);
+        buffer.extend_from_slice(&node.prefix);
+
+        let count = node.pipes.len();
+        node.pipes.retain(|pipe| pipe != value);
+        if node.pipes.len() != count {
+            if !call_on_uniq || node.pipes.is_empty() {
+                func(buffer, buffer.len());
+            }
+            if node.pipes.is_empty() {
+                self.num_prefixes -= 1;
+            }
+        }
+    }
+
+    //  Calls a callback function for all matching entries, i.e. any node
+    //  corresponding to data or a prefix of it.
+    pub fn match_<F>(&mut self, data: &[u8], size: usize, mut func: F)
+    where
+        F: FnMut(&mut T),
+    {
+        let mut data = &data[..size];
+        let mut node = &mut self.root;
+        loop {
+            //  Signal the pipes attached to this node.
+            for pipe in node.pipes.iter_mut() {
+                func(pipe);
+            }
+
+            if data.is_empty() {
+                break;
+            }
+            match node.find_child(data[0]) {
+                Ok(idx) if data.starts_with(&node.children[idx].prefix) => {
+                    node = &mut node.children[idx];
+                    data = &data[node.prefix.len()..];
+                }
+                _ => break,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn matches(trie: &mut ZmqMtrie, data: &[u8]) -> Vec<ZmqPipeId> {
+        let mut pipes = vec![];
+        trie.match_(data, data.len(), |pipe| pipes.push(*pipe));
+        pipes.sort();
+        pipes
+    }
+
+    #[test]
+    fn add_rm_match() {
+        let mut trie = ZmqMtrie::new();
+        assert!(trie.add(b"abc", 3, 1));
+        assert!(!trie.add(b"abc", 3, 2));
+        assert!(trie.add(b"ab", 2, 1));
+        assert!(trie.add(b"", 0, 3));
+        assert_eq!(matches(&mut trie, b"abcd"), vec![1, 1, 2, 3]);
+        assert_eq!(matches(&mut trie, b"b"), vec![3]);
+
+        assert_eq!(trie.rm(b"abc", 3, &1), ZmqMtrieRmResult::ValuesRemain);
+        assert_eq!(trie.rm(b"abc", 3, &1), ZmqMtrieRmResult::NotFound);
+        assert_eq!(trie.rm(b"abc", 3, &2), ZmqMtrieRmResult::LastValueRemoved);
+        assert_eq!(trie.num_prefixes(), 2);
+        assert_eq!(matches(&mut trie, b"abcd"), vec![1, 3]);
+    }
+
+    #[test]
+    fn rm_value_prunes() {
+        let mut trie = ZmqMtrie::new();
+        trie.add(b"check", 5, 1);
+        trie.add(b"checkpoint", 10, 1);
+        trie.add(b"checklist", 9, 2);
+        trie.add(b"other", 5, 1);
+
+        let mut removed = vec![];
+        trie.rm_value(&1, |data, size| removed.push(data[..size].to_vec()), true);
+        removed.sort();
+        assert_eq!(
+            removed,
+            vec![b"check".to_vec(), b"checkpoint".to_vec(), b"other".to_vec()]
+        );
+        assert_eq!(trie.num_prefixes(), 1);
+
+        //  Only "checklist" is left, as a single node under the root.
+        assert_eq!(trie.root.children.len(), 1);
+        assert_eq!(trie.root.children[0].prefix, b"checklist");
+        assert!(trie.root.children[0].children.is_empty());
+        assert_eq!(matches(&mut trie, b"checklist"), vec![2]);
+    }
+}
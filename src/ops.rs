@@ -1,3 +1,4 @@
+use crate::content::ZmqFreeFn;
 use crate::context::ZmqContext;
 use crate::defines::ZmqFileDesc;
 use crate::defines::{
@@ -9,6 +10,7 @@ use crate::err::ZmqError;
 use crate::err::ZmqError::{
     AddItemToPollerFailed, AddTimerFailed, BindSocketFailed, CancelTimerFailed, CheckTagFailed,
     CloseMessageFailed, CloseSocketFailed, ConnectPeerSocketFailed, ConnectSocketFailed,
+    CopyMessageFailed, MoveMessageFailed,
     ExecuteTimerFailed, GetMessageFailed, GetPollerSignalerFailed, GetSocketOptionFailed,
     GetSocketPeerStateFailed, GetTimerTimeoutFailed, InitializeMessageFailed, InvalidEvent,
     InvalidFileDescriptor, InvalidMessageProperty, InvalidPollerEventArray,
@@ -18,6 +20,7 @@ use crate::err::ZmqError::{
     SetMessagePropertyFailed, SetTimerIntervalFailed, ShutdownContextFailed,
     TerminateEndpointFailed, UnsupportedSocketType,
 };
+use crate::fd::fd_export;
 use crate::ip::{initialize_network, shutdown_network};
 use crate::message::{ZmqMessage, ZMQ_MSG_MORE, ZMQ_MSG_SHARED};
 
@@ -358,6 +361,20 @@ pub fn zmq_unbind(
     }
 }
 
+//  Inheritable copies of the socket's listening descriptors, by endpoint,
+//  for a successor process to bind with "fd://N".
+pub fn zmq_export_listener_fds(
+    sock: &mut ZmqSocket,
+) -> Result<Vec<(String, ZmqFileDesc)>, ZmqError> {
+    match fd_export(sock) {
+        Ok(fds) => Ok(fds),
+        Err(e) => Err(InvalidFileDescriptor(format!(
+            "failed to export listening sockets: {}",
+            e
+        ))),
+    }
+}
+
 pub fn zmq_disconnect(
     options: &mut ZmqContext,
     s_: &mut ZmqSocket,
@@ -421,13 +438,15 @@ pub fn zmq_send(
 pub fn zmq_send_const(
     options: &mut ZmqContext,
     s_: &mut ZmqSocket,
-    buf: &mut [u8],
+    buf: &'static [u8],
     len_: usize,
     flags: i32,
 ) -> Result<(), ZmqError> {
     // let mut s = as_socket_base(s_)?;
     let mut msg: ZmqMessage = ZmqMessage::default();
-    zmq_msg_init_data(&mut msg, buf as &mut [u8], len_, None)?;
+    if let Err(e) = msg.init_static(&buf[..len_]) {
+        return Err(InitializeMessageFailed(format!("zmq_send_const failed: {}", e)));
+    }
     match s_sendmsg(options, s_, &mut msg, flags) {
         Ok(_) => Ok(()),
         Err(e) => Err(e),
@@ -636,13 +655,17 @@ pub fn zmq_msg_init_buffer(
     }
 }
 
-pub fn zmq_msg_init_data(
+//  # Safety
+//
+//  See ZmqMessage::init_data.
+pub unsafe fn zmq_msg_init_data(
     msg: &mut ZmqMessage,
-    data: &mut [u8],
+    data: *mut u8,
     size: usize,
-    hint: Option<&mut [u8]>,
+    ffn: Option<ZmqFreeFn>,
+    hint: *mut c_void,
 ) -> Result<(), ZmqError> {
-    match msg.init_data(data, size, hint) {
+    match msg.init_data(data, size, ffn, hint) {
         Ok(_) => Ok(()),
         Err(e) => Err(InitializeMessageFailed(format!(
             "zmq_msg_init_data failed: {}",
@@ -676,20 +699,20 @@ pub fn zmq_msg_close(msg: &mut ZmqMessage) -> Result<(), ZmqError> {
     }
 }
 
-// pub fn zmq_msg_move (dest_: *mut ZmqMessage, src_: *mut ZmqMessage)
-// {
-//     todo!()
-//     // TODO: convert raw message to ZmqMessage and move from source to dest
-//     // return (dest_ as *mut ZmqMessage).move(src_ as *mut ZmqMessage);
-// }
+pub fn zmq_msg_move(dest_: &mut ZmqMessage, src_: &mut ZmqMessage) -> Result<(), ZmqError> {
+    match dest_.move_(src_) {
+        Ok(_) => Ok(()),
+        Err(e) => Err(MoveMessageFailed(format!("zmq_msg_move failed: {}", e))),
+    }
+}
 
-// pub fn zmq_msg_copy (dest_: &mut ZmqMessage, src_: &mut ZmqMessage) -> i32
-// {
-//     // return (reinterpret_cast<ZmqMessage *> (dest_))
-//     //   ->copy (*reinterpret_cast<ZmqMessage *> (src_));
-//     dest_ = src_;
-//     return 0;
-// }
+//  Large payloads are shared between the two messages, not duplicated.
+pub fn zmq_msg_copy(dest_: &mut ZmqMessage, src_: &mut ZmqMessage) -> Result<(), ZmqError> {
+    match dest_.copy(src_) {
+        Ok(_) => Ok(()),
+        Err(e) => Err(CopyMessageFailed(format!("zmq_msg_copy failed: {}", e))),
+    }
+}
 
 // pub fn zmq_msg_data (msg: &mut ZmqMessage) -> Vec<u8>
 // {
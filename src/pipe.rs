@@ -47,14 +47,20 @@
 //  If conflate is true, only the most recently arrived message could be
 //  read (older messages are discarded)
 
+use crate::config::MESSAGE_PIPE_GRANULARITY;
 use crate::context::ZmqContext;
 use crate::endpoint_uri::EndpointUriPair;
 use crate::message::{ZmqMessage, ZMQ_MSG_MORE, ZMQ_MSG_ROUTING_ID};
+use crate::ypipe::ypipe;
+use crate::ypipe_base::{ZmqYPipeReader, ZmqYPipeWriter};
+use crate::ypipe_conflate::ypipe_conflate;
 use libc::memcpy;
 use serde::{Deserialize, Serialize};
-use std::collections::VecDeque;
 use std::io::Write;
+use std::mem;
 use std::ptr::null_mut;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
 
 use crate::own::ZmqOwn;
 use crate::pipe::PipeState::{
@@ -69,6 +75,71 @@ use crate::socket::ZmqSocket;
 // const int hwms_[2],
 //               const bool conflate_[2]);
 
+//  Identifies a pipe where libzmq compares pipe pointers, e.g. in the
+//  subscription trie. Copies of a pipe keep the id of the original.
+pub type ZmqPipeId = u64;
+
+static NEXT_PIPE_ID: AtomicU64 = AtomicU64::new(1);
+
+// typedef YpipeBase<ZmqMessage> upipe_t;
+//  The two ends of one direction of a pipepair: the pipe writing to it
+//  owns the writer, the pipe reading from it the reader.
+pub type ZmqUpipeWriter = Box<dyn ZmqYPipeWriter<ZmqMessage>>;
+pub type ZmqUpipeReader = Box<dyn ZmqYPipeReader<ZmqMessage>>;
+
+//  One end of an underlying pipe, shared by a pipe and all of its copies.
+//  The copies live in the same thread as the pipe, so the lock is never
+//  contended; it only makes sure a copy reads from and writes to the very
+//  same end, and sees it replaced on hiccup or dropped on termination.
+#[derive(Debug)]
+pub struct ZmqPipeEnd<T>(Arc<Mutex<Option<T>>>);
+
+impl<T> ZmqPipeEnd<T> {
+    pub fn new(end: T) -> Self {
+        Self(Arc::new(Mutex::new(Some(end))))
+    }
+
+    pub fn lock(&self) -> MutexGuard<'_, Option<T>> {
+        self.0.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    pub fn is_some(&self) -> bool {
+        self.lock().is_some()
+    }
+
+    pub fn take(&self) -> Option<T> {
+        self.lock().take()
+    }
+
+    pub fn replace(&self, end: T) -> Option<T> {
+        self.lock().replace(end)
+    }
+}
+
+impl<T> Default for ZmqPipeEnd<T> {
+    fn default() -> Self {
+        Self(Arc::new(Mutex::new(None)))
+    }
+}
+
+impl<T> Clone for ZmqPipeEnd<T> {
+    fn clone(&self) -> Self {
+        Self(self.0.clone())
+    }
+}
+
+// typedef Ypipe<ZmqMessage, message_pipe_granularity> upipe_normal_t;
+// typedef YpipeConflate<ZmqMessage> upipe_conflate_t;
+pub fn new_upipe(conflate: bool) -> (ZmqUpipeWriter, ZmqUpipeReader) {
+    if conflate {
+        let (writer, reader) = ypipe_conflate();
+        (Box::new(writer), Box::new(reader))
+    } else {
+        let (writer, reader) = ypipe::<_, { MESSAGE_PIPE_GRANULARITY as usize }>();
+        (Box::new(writer), Box::new(reader))
+    }
+}
+
 pub trait i_pipe_events {
     // virtual ~i_pipe_events () ZMQ_DEFAULT; fn read_activated(&mut self, pipe: &mut ZmqPipe);
     fn write_activated(&mut self, pipe: &mut ZmqPipe);
@@ -99,18 +170,21 @@ pub enum PipeState {
 //  Note that pipe can be stored in three different arrays.
 //  The array of inbound pipes (1), the array of outbound pipes (2) and
 //  the generic array of pipes to be deallocated (3).
-#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+#[derive(Default, Debug, Serialize, Deserialize)]
 // pub struct ZmqPipe  : public ZmqObject,
 //                          public array_ZmqItem<1>,
 //                          public array_ZmqItem<2>,
 //                          public array_ZmqItem<3>
 pub struct ZmqPipe {
-    // typedef YpipeBase<ZmqMessage> upipe_t;
+    //  0 for a default constructed pipe.
+    pub id: ZmqPipeId,
     //  Underlying pipes for both directions.
     // upipe_t *_in_pipe;
-    pub in_pipe: VecDeque<ZmqMessage>,
+    #[serde(skip)]
+    pub in_pipe: ZmqPipeEnd<ZmqUpipeReader>,
     // upipe_t *_out_pipe;
-    pub out_pipe: VecDeque<ZmqMessage>,
+    #[serde(skip)]
+    pub out_pipe: ZmqPipeEnd<ZmqUpipeWriter>,
     //  Can the pipe be read from / written to?
     pub in_active: bool,
     pub out_active: bool,
@@ -149,6 +223,41 @@ pub struct ZmqPipe {
     // // ZMQ_NON_COPYABLE_NOR_MOVABLE (ZmqPipe)
 }
 
+//  The pipe arrays hold copies of a pipe. A copy shares the ends of the
+//  underlying pipes with the original, so it reads and writes the same
+//  message streams.
+impl Clone for ZmqPipe {
+    fn clone(&self) -> Self {
+        Self {
+            id: self.id,
+            in_pipe: self.in_pipe.clone(),
+            out_pipe: self.out_pipe.clone(),
+            in_active: self.in_active,
+            out_active: self.out_active,
+            hwm: self.hwm,
+            lwm: self.lwm,
+            in_hwm_boost: self.in_hwm_boost,
+            out_hwm_boost: self.out_hwm_boost,
+            msgs_read: self.msgs_read,
+            msgs_written: self.msgs_written,
+            peers_msgs_read: self.peers_msgs_read,
+            delay: self.delay,
+            router_socket_routing_id: self.router_socket_routing_id.clone(),
+            server_socket_routing_id: self.server_socket_routing_id,
+            conflate: self.conflate,
+            endpoint_pair: self.endpoint_pair.clone(),
+            disconnect_msg: self.disconnect_msg.clone(),
+        }
+    }
+}
+
+//  A pipe and its copies are the same pipe.
+impl PartialEq for ZmqPipe {
+    fn eq(&self, other: &Self) -> bool {
+        self.id == other.id
+    }
+}
+
 impl ZmqPipe {
     //  This allows pipepair to create pipe objects.
     // friend int pipepair (ZmqObject *parents_[2],
@@ -164,34 +273,13 @@ impl ZmqPipe {
         //   Creates two pipe objects. These objects are Connected by two ypipes,
         //   each to pass messages in one direction.
 
-        // typedef Ypipe<ZmqMessage, message_pipe_granularity> upipe_normal_t;
-        // typedef YpipeConflate<ZmqMessage> upipe_conflate_t;
-
-        // ZmqPipe::upipe_t *upipe1;
-        // typedef YpipeBase<ZmqMessage> upipe_t;
-        let mut upipe1: VecDeque<ZmqMessage> = VecDeque::new();
-        // if (conflate_[0]) {
-        //     upipe1 = upipe_conflate_t();
-        // }
-        // else {
-        //     upipe1 = upipe_normal_t();
-        // }
-        // alloc_assert (upipe1);
-
-        // ZmqPipe::upipe_t *upipe2;
-        let mut upipe2: VecDeque<ZmqMessage> = VecDeque::new();
-        // if (conflate_[1]) {
-        //     upipe2 = upipe_conflate_t();
-        // }
-        // else {
-        //     upipe2 = upipe_normal_t();
-        // }
-        // alloc_assert (upipe2);
+        let (upipe1_writer, upipe1_reader) = new_upipe(conflate[0]);
+        let (upipe2_writer, upipe2_reader) = new_upipe(conflate[1]);
 
         pipes[0] = ZmqPipe::new(
             // parents[0],
-            &mut upipe1,
-            &mut upipe2,
+            upipe1_reader,
+            upipe2_writer,
             hwms[1],
             hwms[0],
             conflate[0],
@@ -199,8 +287,8 @@ impl ZmqPipe {
         // alloc_assert (pipes_[0]);
         pipes[1] = ZmqPipe::new(
             // parents[1],
-            &mut upipe2,
-            &mut upipe1,
+            upipe2_reader,
+            upipe1_writer,
             hwms[0],
             hwms[1],
             conflate[1],
@@ -251,23 +339,26 @@ impl ZmqPipe {
             return false;
         }
 
+        let mut guard = self.in_pipe.lock();
+        let Some(in_pipe) = guard.as_mut() else {
+            return false;
+        };
+
         //  Check if there's an item in the pipe.
-        if (!self.in_pipe.check_read()) {
+        if (!in_pipe.check_read()) {
             self.in_active = false;
             return false;
         }
 
         //  If the next item in the pipe is message delimiter,
         //  initiate termination process.
-        // TODO
-        // if (self.in_pipe.probe(is_delimiter)) {
-        //     // ZmqMessage msg;
-        //     let mut msg = ZmqMessage::default();
-        //     let ok = self.in_pipe.read(msg);
-        //     // zmq_assert (ok);
-        //     self.process_delimiter();
-        //     return false;
-        // }
+        if (in_pipe.probe(ZmqMessage::is_delimiter)) {
+            let ok = in_pipe.read().is_some();
+            assert!(ok);
+            drop(guard);
+            self.process_delimiter();
+            return false;
+        }
 
         return true;
     }
@@ -283,9 +374,13 @@ impl ZmqPipe {
         }
 
         loop {
-            if (!self.in_pipe.read(msg)) {
-                self.in_active = false;
-                return false;
+            let next = self.in_pipe.lock().as_mut().and_then(|in_pipe| in_pipe.read());
+            match next {
+                Some(m) => *msg = m,
+                None => {
+                    self.in_active = false;
+                    return false;
+                }
             }
 
             //  If this is a credential, ignore it and receive next message.
@@ -344,7 +439,14 @@ impl ZmqPipe {
 
         let more = (msg.flags() & ZMQ_MSG_MORE) != 0;
         let is_routing_id = msg.is_routing_id();
-        self.out_pipe.write(msg, more);
+        {
+            //  Without an outpipe the message stays with the caller.
+            let mut out_pipe = self.out_pipe.lock();
+            let Some(out_pipe) = out_pipe.as_mut() else {
+                return false;
+            };
+            out_pipe.write(mem::take(msg), more);
+        }
         if (!more && !is_routing_id) {
             self.msgs_written += 1;
         }
@@ -356,10 +458,9 @@ impl ZmqPipe {
     // void rollback () const;
     pub fn rollback(&mut self) -> anyhow::Result<()> {
         //  Remove incomplete message from the outbound pipe.
-        let mut msg = ZmqMessage::default();
-        if (self._out_pipe) {
-            while (self._out_pipe.unwrite(&msg)) {
-                // zmq_assert (msg.flags () & ZMQ_MSG_MORE);
+        if let Some(out_pipe) = self.out_pipe.lock().as_mut() {
+            while let Some(mut msg) = out_pipe.unwrite() {
+                assert!((msg.flags() & ZMQ_MSG_MORE) != 0);
                 msg.close()?;
                 // errno_assert (rc == 0);
             }
@@ -375,7 +476,8 @@ impl ZmqPipe {
             return;
         }
 
-        if (self.out_pipe.len() > 0 && !self.out_pipe.flush()) {
+        let asleep = self.out_pipe.lock().as_mut().is_some_and(|out_pipe| !out_pipe.flush());
+        if (asleep) {
             self.send_activate_read(self._peer);
         }
     }
@@ -389,21 +491,17 @@ impl ZmqPipe {
             return;
         }
 
-        //  We'll drop the pointer to the inpipe. From now on, the peer is
-        //  responsible for deallocating it.
+        //  We'll hand the old inpipe over to the peer. From now on, the peer
+        //  is responsible for deallocating it.
 
         //  Create new inpipe.
-        // self._in_pipe =
-        //     if self._conflate
-        //         ?  ( YpipeConflate<ZmqMessage> ())
-        // :  Ypipe<ZmqMessage, message_pipe_granularity> ();
-        self.in_pipe.clear();
+        let (writer, reader) = new_upipe(self.conflate);
+        let stale = self.in_pipe.replace(reader);
 
-        // alloc_assert (_in_pipe);
         self._in_active = true;
 
         //  Notify the peer about the Hiccup.
-        ctx.send_hiccup(0, self._peer, self._in_pipe);
+        ctx.send_hiccup(0, self._peer, writer, stale);
     }
 
     //  Ensure the pipe won't block on receiving PipeTerm.
@@ -439,7 +537,7 @@ impl ZmqPipe {
         else if (self._state == PipeState::waiting_for_delimiter && !self.delay) {
             //  Drop any unfinished outbound messages.
             self.rollback();
-            self.out_pipe.clear();
+            self.out_pipe.take();
             self.send_pipe_term_ack(self._peer);
             self._state = PipeState::term_ack_sent;
         }
@@ -461,7 +559,7 @@ impl ZmqPipe {
         //  Stop outbound flow of messages.
         self.out_active = false;
 
-        if (self.out_pipe) {
+        if self.out_pipe.is_some() {
             //  Drop any unfinished outbound messages.
             self.rollback();
 
@@ -469,7 +567,9 @@ impl ZmqPipe {
             //  checked; thus the delimiter can be written even when the pipe is full.
             let mut msg = ZmqMessage::default();
             msg.init_delimiter();
-            self.out_pipe.write(&msg, false);
+            if let Some(out_pipe) = self.out_pipe.lock().as_mut() {
+                out_pipe.write(msg, false);
+            }
             self.flush();
         }
     }
@@ -533,11 +633,13 @@ impl ZmqPipe {
 
     // void send_disconnect_msg ();
     pub fn send_disconnect_msg(&mut self) {
-        if (self.disconnect_msg.size() > 0 && self.out_pipe.is_empty() == false) {
+        if self.disconnect_msg.size() > 0 && self.out_pipe.is_some() {
             // Rollback any incomplete message in the pipe, and push the disconnect message.
             self.rollback();
 
-            self.out_pipe.write(&self.disconnect_msg, false);
+            if let Some(out_pipe) = self.out_pipe.lock().as_mut() {
+                out_pipe.write(mem::take(&mut self.disconnect_msg), false);
+            }
             self.flush();
             self.disconnect_msg.init2();
         }
@@ -569,24 +671,23 @@ impl ZmqPipe {
     }
 
     // void process_hiccup (pipe: *mut c_void) ;
-    pub fn process_hiccup(&mut self, pipe: &mut VecDeque<ZmqMessage>) {
-        //  Destroy old outpipe. Note that the read end of the pipe was already
-        //  migrated to this thread.
-        // zmq_assert (_out_pipe);
-        self.out_pipe.flush();
-        let mut msg = ZmqMessage::default();
-        while (self.out_pipe.read(&mut msg)) {
-            if (!(self.msg.flags() & ZMQ_MSG_MORE)) {
+    pub fn process_hiccup(&mut self, pipe: ZmqUpipeWriter, stale: Option<ZmqUpipeReader>) {
+        //  Destroy old outpipe. Note that the read end of the pipe was
+        //  handed over to us along with the new one.
+        let mut out_pipe = self.out_pipe.take().expect("hiccup without an outpipe");
+        out_pipe.flush();
+        drop(out_pipe);
+        let mut stale = stale.expect("hiccup without the old inpipe");
+        while let Some(mut msg) = stale.read() {
+            if ((msg.flags() & ZMQ_MSG_MORE) == 0) {
                 self.msgs_written -= 1;
             }
-            let rc: i32 = msg.clsose();
+            msg.close();
             // errno_assert (rc == 0);
         }
-        // LIBZMQ_DELETE (_out_pipe);
 
         //  Plug in the new outpipe.
-        // zmq_assert (pipe);
-        self.out_pipe = (pipe.clone());
+        self.out_pipe.replace(pipe);
         self.out_active = true;
 
         //  If appropriate, notify the user about the Hiccup.
@@ -610,7 +711,7 @@ impl ZmqPipe {
                 self._state = PipeState::waiting_for_delimiter;
             } else {
                 self._state = PipeState::term_ack_sent;
-                self.out_pipe.clear();
+                self.out_pipe.take();
                 self.send_pipe_term_ack(self._peer);
             }
         }
@@ -618,7 +719,7 @@ impl ZmqPipe {
         //  Term command as well, so we can move straight to term_ack_sent state.
         else if (self._state == PipeState::delimiter_received) {
             self._state = PipeState::term_ack_sent;
-            self.out_pipe.clear();
+            self.out_pipe.take();
             self.send_pipe_term_ack(self._peer);
         }
         //  This is the case where both ends of the pipe are closed in parallel.
@@ -626,7 +727,7 @@ impl ZmqPipe {
         //  Own ack.
         else if (self._state == PipeState::term_req_sent1) {
             self._state = PipeState::term_req_sent2;
-            self.out_pipe.clear();
+            self.out_pipe.take();
             self.send_pipe_term_ack(self._peer);
         }
     }
@@ -642,7 +743,7 @@ impl ZmqPipe {
         //  the peer before deallocating this side of the pipe.
         //  All the other states are invalid.
         if (self._state == PipeState::term_req_sent1) {
-            self.out_pipe.clear();
+            self.out_pipe.take();
             self.send_pipe_term_ack(self._peer);
         } else {
         }
@@ -654,16 +755,13 @@ impl ZmqPipe {
         //  hand because ZmqMessage doesn't have automatic destructor. Then deallocate
         //  the ypipe itself.
 
-        if (!self.conflate) {
-            let mut msg = ZmqMessage::default();
-            while (self.in_pipe.read(&msg)) {
+        if let Some(mut in_pipe) = self.in_pipe.take() {
+            while let Some(mut msg) = in_pipe.read() {
                 msg.close()?;
                 // errno_assert (rc == 0);
             }
         }
 
-        // LIBZMQ_DELETE (_in_pipe);
-
         //  Deallocate the pipe object
         // delete this;
         Ok(())
@@ -683,7 +781,7 @@ impl ZmqPipe {
             self._state = PipeState::delimiter_received;
         } else {
             self.rollback();
-            self.out_pipe.clone();
+            self.out_pipe.take();
             self.send_pipe_term_ack(self._peer);
             self._state = PipeState::term_ack_sent;
         }
@@ -718,15 +816,16 @@ impl ZmqPipe {
     // _conflate (conflate_)
     pub fn new(
         // parent: &mut ZmqObject,
-        inpipe: &mut VecDeque<ZmqMessage>,
-        outpipe: &mut VecDeque<ZmqMessage>,
+        inpipe: ZmqUpipeReader,
+        outpipe: ZmqUpipeWriter,
         inhwm: i32,
         outhwm: i32,
         conflate: bool,
     ) -> Self {
         let mut out = Self {
-            in_pipe: inpipe.clone(),
-            out_pipe: outpipe.clone(),
+            id: NEXT_PIPE_ID.fetch_add(1, Ordering::Relaxed),
+            in_pipe: ZmqPipeEnd::new(inpipe),
+            out_pipe: ZmqPipeEnd::new(outpipe),
             in_active: true,
             out_active: true,
             hwm: outhwm as u32,
@@ -738,7 +837,7 @@ impl ZmqPipe {
             delay: false,
             router_socket_routing_id: vec![],
             server_socket_routing_id: 0,
-            conflate: false,
+            conflate,
             endpoint_pair: Default::default(),
             disconnect_msg: Default::default(),
             ..Default::default()
@@ -815,12 +914,14 @@ impl ZmqPipe {
     }
 
     pub fn send_hiccup_msg(&mut self, hiccup_: &mut Vec<u8>) {
-        if (!hiccup_.is_empty() && self._out_pipe) {
+        if !hiccup_.is_empty() && self.out_pipe.is_some() {
             let mut msg = ZmqMessage::default();
             msg.init_buffer(hiccup_, hiccup_.size())?;
             // errno_assert (rc == 0);
 
-            self._out_pipe.write(msg, false);
+            if let Some(out_pipe) = self.out_pipe.lock().as_mut() {
+                out_pipe.write(msg, false);
+            }
             self.flush();
         }
     }
@@ -0,0 +1,524 @@
+//  HAProxy PROXY protocol, versions 1 and 2, on the accepting side.
+//
+//  With ZMQ_TCP_PROXY_PROTOCOL set, every connection accepted by a TCP
+//  listener has to start with a PROXY header, sent by the load balancer in
+//  front of us. The header is taken off the stream before the ZMTP greeting
+//  and the client address in it replaces the balancer's as the peer
+//  address, which is what ZAP requests and the Peer-Address property carry.
+//  ZMQ_TCP_ACCEPT_FILTER and ZMQ_TCP_MAX_CONNECTIONS_PER_IP are applied to
+//  that address too. Connections without a well-formed header are dropped.
+//
+//  The header is peeked at and only what belongs to it is consumed, so that
+//  nothing of the greeting behind it is read. Pieces of a header that
+//  arrives in parts are kept with the engine until the rest is in. A
+//  connection that does not deliver its header within ZMQ_HANDSHAKE_IVL,
+//  or PROXY_HEADER_TIMEOUT if that is not set, is dropped.
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+use anyhow::bail;
+use libc::{c_int, c_void, recv, EAGAIN, EINTR, ENOTCONN, EWOULDBLOCK, MSG_PEEK};
+
+use crate::defines::ZmqFileDesc;
+use crate::engine::ZmqEngine;
+use crate::engine_interface::ZmqErrorReason;
+use crate::err::set_errno;
+use crate::tcp::{tcp_peer_ip, tcp_readmit};
+
+const PROXY_V1_PREFIX: &[u8] = b"PROXY ";
+//  Longest possible v1 header, CRLF included.
+const PROXY_V1_MAX_LEN: usize = 107;
+const PROXY_V2_SIGNATURE: &[u8] = b"\r\n\r\n\0\r\nQUIT\n";
+//  Signature, version and command, family, length.
+const PROXY_V2_HEADER_LEN: usize = 16;
+//  Time allowed for the header without ZMQ_HANDSHAKE_IVL, in milliseconds.
+const PROXY_HEADER_TIMEOUT: i32 = 30000;
+
+//  Timer id for the header's deadline.
+pub const PROXY_HEADER_TIMER_ID: i32 = 0x51;
+
+//  What a PROXY header says about the connection.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ZmqProxyHeader {
+    //  Relayed connection from 'source' to 'destination'.
+    Proxied {
+        source: SocketAddr,
+        destination: SocketAddr,
+    },
+    //  The balancer's own connection (health checks) or an address family
+    //  we don't know about; the socket's addresses stand.
+    Local,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ZmqProxyParse {
+    //  Need at least this many bytes.
+    Incomplete(usize),
+    //  The header and its length.
+    Complete(ZmqProxyHeader, usize),
+}
+
+//  Parses the PROXY header at the start of 'data'.
+pub fn proxy_parse(data: &[u8]) -> anyhow::Result<ZmqProxyParse> {
+    let prefix = data.len().min(PROXY_V2_SIGNATURE.len());
+    if data[..prefix] == PROXY_V2_SIGNATURE[..prefix] {
+        return proxy_parse_v2(data);
+    }
+    let prefix = data.len().min(PROXY_V1_PREFIX.len());
+    if data[..prefix] == PROXY_V1_PREFIX[..prefix] {
+        return proxy_parse_v1(data);
+    }
+    bail!("EPROTO: missing PROXY header")
+}
+
+fn proxy_parse_v1(data: &[u8]) -> anyhow::Result<ZmqProxyParse> {
+    let window = &data[..data.len().min(PROXY_V1_MAX_LEN)];
+    let Some(end) = window.windows(2).position(|crlf| crlf == b"\r\n") else {
+        if data.len() >= PROXY_V1_MAX_LEN {
+            bail!("EPROTO: PROXY v1 header too long");
+        }
+        return Ok(ZmqProxyParse::Incomplete(data.len() + 1));
+    };
+
+    let Ok(line) = std::str::from_utf8(&data[PROXY_V1_PREFIX.len()..end]) else {
+        bail!("EPROTO: malformed PROXY v1 header");
+    };
+    let fields: Vec<&str> = line.split(' ').collect();
+    let header = match fields[..] {
+        //  Anything may follow UNKNOWN.
+        ["UNKNOWN", ..] => ZmqProxyHeader::Local,
+        [family @ ("TCP4" | "TCP6"), source, destination, source_port, destination_port] => {
+            let v6 = family == "TCP6";
+            ZmqProxyHeader::Proxied {
+                source: proxy_parse_v1_address(source, source_port, v6)?,
+                destination: proxy_parse_v1_address(destination, destination_port, v6)?,
+            }
+        }
+        _ => bail!("EPROTO: malformed PROXY v1 header"),
+    };
+    Ok(ZmqProxyParse::Complete(header, end + 2))
+}
+
+fn proxy_parse_v1_address(address: &str, port: &str, v6: bool) -> anyhow::Result<SocketAddr> {
+    let ip = if v6 {
+        address.parse::<Ipv6Addr>().map(IpAddr::V6)
+    } else {
+        address.parse::<Ipv4Addr>().map(IpAddr::V4)
+    };
+    //  Ports are plain decimal numbers, without leading zeros.
+    let port_ok = !port.is_empty()
+        && port.bytes().all(|c| c.is_ascii_digit())
+        && (port == "0" || !port.starts_with('0'));
+    match (ip, port_ok.then(|| port.parse::<u16>())) {
+        (Ok(ip), Some(Ok(port))) => Ok(SocketAddr::new(ip, port)),
+        _ => bail!("EPROTO: malformed address in PROXY v1 header"),
+    }
+}
+
+fn proxy_parse_v2(data: &[u8]) -> anyhow::Result<ZmqProxyParse> {
+    if data.len() < PROXY_V2_HEADER_LEN {
+        return Ok(ZmqProxyParse::Incomplete(PROXY_V2_HEADER_LEN));
+    }
+    let version_command = data[12];
+    let family = data[13];
+    let len = PROXY_V2_HEADER_LEN + u16::from_be_bytes([data[14], data[15]]) as usize;
+    if version_command >> 4 != 2 {
+        bail!("EPROTO: unsupported PROXY protocol version");
+    }
+    if data.len() < len {
+        return Ok(ZmqProxyParse::Incomplete(len));
+    }
+    let body = &data[PROXY_V2_HEADER_LEN..len];
+
+    let header = match version_command & 0x0f {
+        //  LOCAL: the addresses, if any, are to be ignored.
+        0 => ZmqProxyHeader::Local,
+        //  PROXY; STREAM over INET or INET6. Whatever follows the
+        //  addresses are TLVs we don't use.
+        1 => match family {
+            0x11 if body.len() >= 12 => {
+                let ip = |at: usize| IpAddr::V4(Ipv4Addr::new(body[at], body[at + 1], body[at + 2], body[at + 3]));
+                let port = |at: usize| u16::from_be_bytes([body[at], body[at + 1]]);
+                ZmqProxyHeader::Proxied {
+                    source: SocketAddr::new(ip(0), port(8)),
+                    destination: SocketAddr::new(ip(4), port(10)),
+                }
+            }
+            0x21 if body.len() >= 36 => {
+                let ip = |at: usize| {
+                    let mut octets = [0u8; 16];
+                    octets.copy_from_slice(&body[at..at + 16]);
+                    IpAddr::V6(Ipv6Addr::from(octets))
+                };
+                let port = |at: usize| u16::from_be_bytes([body[at], body[at + 1]]);
+                ZmqProxyHeader::Proxied {
+                    source: SocketAddr::new(ip(0), port(32)),
+                    destination: SocketAddr::new(ip(16), port(34)),
+                }
+            }
+            0x11 | 0x21 => bail!("EPROTO: truncated addresses in PROXY v2 header"),
+            //  UNSPEC, DGRAM or UNIX: keep the socket's addresses.
+            _ => ZmqProxyHeader::Local,
+        },
+        _ => bail!("EPROTO: unsupported PROXY v2 command"),
+    };
+    Ok(ZmqProxyParse::Complete(header, len))
+}
+
+//  Takes the PROXY header off the socket. 'buffer' holds what has been read
+//  of it so far. Returns None if it has not all arrived yet.
+pub fn proxy_read_header(
+    fd: ZmqFileDesc,
+    buffer: &mut Vec<u8>,
+) -> anyhow::Result<Option<ZmqProxyHeader>> {
+    let mut peeked = vec![0u8; PROXY_V1_MAX_LEN];
+    loop {
+        let nbytes = unsafe {
+            recv(
+                fd as c_int,
+                peeked.as_mut_ptr() as *mut c_void,
+                peeked.len(),
+                MSG_PEEK,
+            )
+        };
+        if nbytes == 0 {
+            bail!("ECONNRESET: connection closed before the PROXY header");
+        }
+        if nbytes < 0 {
+            let err = std::io::Error::last_os_error();
+            return match err.raw_os_error() {
+                Some(EAGAIN) | Some(EWOULDBLOCK) | Some(EINTR) => Ok(None),
+                _ => Err(err.into()),
+            };
+        }
+        let nbytes = nbytes as usize;
+        let start = buffer.len();
+        buffer.extend_from_slice(&peeked[..nbytes]);
+
+        match proxy_parse(buffer)? {
+            ZmqProxyParse::Complete(header, len) => {
+                //  Leave the greeting behind the header where it is.
+                proxy_consume(fd, &mut peeked, len - start)?;
+                buffer.clear();
+                return Ok(Some(header));
+            }
+            //  All of it is header, take it off so that poll stops
+            //  reporting it.
+            ZmqProxyParse::Incomplete(_) => {
+                proxy_consume(fd, &mut peeked, nbytes)?;
+                //  A v2 header with more TLVs than fit may have more
+                //  waiting already.
+                if nbytes < peeked.len() {
+                    return Ok(None);
+                }
+            }
+        }
+    }
+}
+
+//  Reads 'len' bytes that have been peeked at already.
+fn proxy_consume(fd: ZmqFileDesc, scratch: &mut [u8], len: usize) -> anyhow::Result<()> {
+    let nbytes = unsafe { recv(fd as c_int, scratch.as_mut_ptr() as *mut c_void, len, 0) };
+    if nbytes != len as isize {
+        bail!("ECONNRESET: failed to consume the PROXY header");
+    }
+    Ok(())
+}
+
+//  Called by plug() on connections that have to start with a header.
+pub fn proxy_plug(engine: &mut ZmqEngine) {
+    let timeout = if engine.context.handshake_ivl > 0 {
+        engine.context.handshake_ivl
+    } else {
+        PROXY_HEADER_TIMEOUT
+    };
+    engine.io_object.add_timer(timeout, PROXY_HEADER_TIMER_ID);
+    engine.has_proxy_timer = true;
+}
+
+//  Runs ahead of in_event while the header is outstanding. Returns true
+//  once the engine can go on with the greeting.
+pub fn proxy_in_event(engine: &mut ZmqEngine) -> bool {
+    match proxy_read_header(engine.fd, &mut engine.proxy_buffer) {
+        Ok(None) => false,
+        Ok(Some(header)) => {
+            engine.proxy_header_pending = false;
+            if engine.has_proxy_timer {
+                engine.io_object.cancel_timer(PROXY_HEADER_TIMER_ID);
+                engine.has_proxy_timer = false;
+            }
+            let ip = match header {
+                ZmqProxyHeader::Proxied { source, .. } => Some(source.ip().to_canonical()),
+                ZmqProxyHeader::Local => tcp_peer_ip(engine.fd),
+            };
+            if let Some(ip) = ip {
+                engine.peer_address = ip.to_string();
+            }
+            //  The accept filters and the per-IP cap apply to the client,
+            //  whose address only now is known.
+            let ctx = &engine.context;
+            let admitted = ip.ok_or(ENOTCONN).and_then(|ip| {
+                tcp_readmit(
+                    &mut engine.tcp_slot,
+                    &ctx.tcp_accept_filters,
+                    ctx.tcp_max_connections,
+                    ctx.tcp_max_connections_per_ip,
+                    ip,
+                )
+            });
+            if let Err(errno) = admitted {
+                set_errno(errno);
+                proxy_error(engine, ZmqErrorReason::ConnectionError);
+                return false;
+            }
+            true
+        }
+        Err(e) => {
+            let reason = if e.to_string().starts_with("EPROTO") {
+                ZmqErrorReason::ProtocolError
+            } else {
+                ZmqErrorReason::ConnectionError
+            };
+            proxy_error(engine, reason);
+            false
+        }
+    }
+}
+
+//  The header's deadline passed.
+pub fn proxy_timer_event(engine: &mut ZmqEngine) {
+    engine.has_proxy_timer = false;
+    proxy_error(engine, ZmqErrorReason::TimeoutError);
+}
+
+fn proxy_error(engine: &mut ZmqEngine, reason: ZmqErrorReason) {
+    engine.unplug();
+    if let Some(session) = engine.session.take() {
+        session.engine_error(false, reason);
+    }
+    engine.terminate();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use libc::{
+        close, fcntl, send, socketpair, AF_UNIX, F_GETFL, F_SETFL, O_NONBLOCK, SOCK_STREAM,
+    };
+
+    fn complete(data: &[u8]) -> (ZmqProxyHeader, usize) {
+        match proxy_parse(data).unwrap() {
+            ZmqProxyParse::Complete(header, len) => (header, len),
+            incomplete => panic!("{:?}", incomplete),
+        }
+    }
+
+    fn proxied(source: &str, destination: &str) -> ZmqProxyHeader {
+        ZmqProxyHeader::Proxied {
+            source: source.parse().unwrap(),
+            destination: destination.parse().unwrap(),
+        }
+    }
+
+    fn v2(command: u8, family: u8, body: &[u8]) -> Vec<u8> {
+        let mut data = PROXY_V2_SIGNATURE.to_vec();
+        data.push(0x20 | command);
+        data.push(family);
+        data.extend_from_slice(&(body.len() as u16).to_be_bytes());
+        data.extend_from_slice(body);
+        data
+    }
+
+    #[test]
+    fn v1_headers() {
+        let data = b"PROXY TCP4 192.0.2.1 198.51.100.2 56324 443\r\nGREETING";
+        assert_eq!(
+            complete(data),
+            (proxied("192.0.2.1:56324", "198.51.100.2:443"), data.len() - 8)
+        );
+        let data = b"PROXY TCP6 2001:db8::1 2001:db8::2 1 65535\r\n";
+        assert_eq!(
+            complete(data),
+            (proxied("[2001:db8::1]:1", "[2001:db8::2]:65535"), data.len())
+        );
+        assert_eq!(complete(b"PROXY UNKNOWN ff::1\r\n").0, ZmqProxyHeader::Local);
+    }
+
+    #[test]
+    fn v1_incomplete_and_malformed() {
+        assert_eq!(proxy_parse(b"PRO").unwrap(), ZmqProxyParse::Incomplete(4));
+        assert_eq!(
+            proxy_parse(b"PROXY TCP4 1.2.3.4").unwrap(),
+            ZmqProxyParse::Incomplete(19)
+        );
+        for bad in [
+            &b"PROXY TCP4 1.2.3.4 5.6.7.8 01 2\r\n"[..],
+            b"PROXY TCP4 1.2.3.4 5.6.7.8 1 65536\r\n",
+            b"PROXY TCP4 ::1 ::2 1 2\r\n",
+            b"PROXY TCP4 1.2.3.4 5.6.7.8 1\r\n",
+            b"PROXY UDP4 1.2.3.4 5.6.7.8 1 2\r\n",
+            b"GET / HTTP/1.1\r\n",
+        ] {
+            assert!(proxy_parse(bad).is_err(), "{:?}", bad);
+        }
+        let long = [&b"PROXY UNKNOWN "[..], &[b'x'; PROXY_V1_MAX_LEN]].concat();
+        assert!(proxy_parse(&long).is_err());
+    }
+
+    #[test]
+    fn v2_headers() {
+        let body = [192, 0, 2, 1, 198, 51, 100, 2, 0xdc, 0x04, 0x01, 0xbb];
+        let data = v2(1, 0x11, &body);
+        assert_eq!(
+            complete(&data),
+            (proxied("192.0.2.1:56324", "198.51.100.2:443"), data.len())
+        );
+
+        let mut body = vec![0u8; 36];
+        body[15] = 1;
+        body[31] = 2;
+        body[33] = 7;
+        body[35] = 9;
+        //  A TLV behind the addresses.
+        body.extend_from_slice(&[0x04, 0x00, 0x01, 0xff]);
+        let data = v2(1, 0x21, &body);
+        assert_eq!(complete(&data), (proxied("[::1]:7", "[::2]:9"), data.len()));
+
+        assert_eq!(complete(&v2(0, 0x11, &[0; 12])).0, ZmqProxyHeader::Local);
+        assert_eq!(complete(&v2(1, 0x31, &[0; 216])).0, ZmqProxyHeader::Local);
+    }
+
+    #[test]
+    fn v2_incomplete_and_malformed() {
+        let data = v2(1, 0x11, &[0; 12]);
+        assert_eq!(proxy_parse(&data[..5]).unwrap(), ZmqProxyParse::Incomplete(16));
+        assert_eq!(proxy_parse(&data[..20]).unwrap(), ZmqProxyParse::Incomplete(28));
+        assert!(proxy_parse(&v2(1, 0x11, &[0; 8])).is_err());
+        assert!(proxy_parse(&v2(2, 0x11, &[0; 12])).is_err());
+        let mut data = v2(1, 0x11, &[0; 12]);
+        data[12] = 0x11;
+        assert!(proxy_parse(&data).is_err());
+    }
+
+    fn pair() -> (ZmqFileDesc, c_int) {
+        let mut fds = [0 as c_int; 2];
+        unsafe {
+            assert_eq!(socketpair(AF_UNIX, SOCK_STREAM, 0, fds.as_mut_ptr()), 0);
+            fcntl(fds[0], F_SETFL, fcntl(fds[0], F_GETFL) | O_NONBLOCK);
+        }
+        (fds[0] as ZmqFileDesc, fds[1])
+    }
+
+    fn send_all(fd: c_int, data: &[u8]) {
+        let nbytes = unsafe { send(fd, data.as_ptr() as *const c_void, data.len(), 0) };
+        assert_eq!(nbytes, data.len() as isize);
+    }
+
+    fn recv_rest(fd: ZmqFileDesc) -> Vec<u8> {
+        let mut buf = vec![0u8; 64];
+        let nbytes = unsafe { recv(fd as c_int, buf.as_mut_ptr() as *mut c_void, buf.len(), 0) };
+        buf.truncate(nbytes.max(0) as usize);
+        buf
+    }
+
+    #[test]
+    fn header_in_parts_leaves_greeting() {
+        let (fd, peer) = pair();
+        let mut buffer = vec![];
+        let header = b"PROXY TCP4 192.0.2.1 198.51.100.2 56324 443\r\n";
+        send_all(peer, &header[..10]);
+        assert_eq!(proxy_read_header(fd, &mut buffer).unwrap(), None);
+        //  What arrived was taken off the socket.
+        assert_eq!(buffer, &header[..10]);
+        assert_eq!(proxy_read_header(fd, &mut buffer).unwrap(), None);
+
+        send_all(peer, &header[10..]);
+        send_all(peer, b"\xff\0\0\0\0\0\0\0\x01\x7f");
+        assert_eq!(
+            proxy_read_header(fd, &mut buffer).unwrap(),
+            Some(proxied("192.0.2.1:56324", "198.51.100.2:443"))
+        );
+        assert!(buffer.is_empty());
+        assert_eq!(recv_rest(fd), b"\xff\0\0\0\0\0\0\0\x01\x7f");
+        unsafe {
+            close(fd as c_int);
+            close(peer);
+        }
+    }
+
+    #[test]
+    fn large_v2_header_and_closed_connection() {
+        let (fd, peer) = pair();
+        let mut buffer = vec![];
+        let mut body = vec![0u8; 12];
+        body.resize(12 + 300, 0xaa);
+        send_all(peer, &v2(0, 0x11, &body));
+        send_all(peer, b"ZMTP");
+        assert_eq!(
+            proxy_read_header(fd, &mut buffer).unwrap(),
+            Some(ZmqProxyHeader::Local)
+        );
+        assert_eq!(recv_rest(fd), b"ZMTP");
+
+        send_all(peer, b"PROXY ");
+        unsafe { close(peer) };
+        assert_eq!(proxy_read_header(fd, &mut buffer).unwrap(), None);
+        assert!(proxy_read_header(fd, &mut buffer).is_err());
+        unsafe { close(fd as c_int) };
+    }
+
+    #[test]
+    fn readmission_goes_by_the_client_address() {
+        use crate::tcp::{tcp_acquire_slot, ZmqTcpConnections};
+        use libc::{EACCES, ECONNREFUSED};
+        use std::sync::{Arc, Mutex};
+
+        let connections = Arc::new(Mutex::new(ZmqTcpConnections::default()));
+        let balancer: IpAddr = "10.0.0.1".parse().unwrap();
+        let client: IpAddr = "192.0.2.7".parse().unwrap();
+        let filters = vec!["192.0.2.0/24".parse().unwrap()];
+        let accept = || {
+            Some(Arc::new(
+                tcp_acquire_slot(&connections, balancer, 0, 0).unwrap(),
+            ))
+        };
+
+        //  The slot moves from the balancer to the client.
+        let mut first = accept();
+        assert_eq!(tcp_readmit(&mut first, &filters, 0, 1, client), Ok(()));
+        {
+            let counts = connections.lock().unwrap();
+            assert_eq!(counts.total, 1);
+            assert_eq!(counts.per_ip.get(&client), Some(&1));
+            assert_eq!(counts.per_ip.get(&balancer), None);
+        }
+
+        //  The per-IP cap counts clients, not the balancer.
+        let mut second = accept();
+        assert_eq!(
+            tcp_readmit(&mut second, &filters, 0, 1, client),
+            Err(ECONNREFUSED)
+        );
+        assert!(second.is_none());
+        let mut third = accept();
+        let other: IpAddr = "192.0.2.8".parse().unwrap();
+        assert_eq!(tcp_readmit(&mut third, &filters, 0, 1, other), Ok(()));
+
+        //  The filters apply to the client.
+        let mut fourth = accept();
+        let outside: IpAddr = "198.51.100.1".parse().unwrap();
+        assert_eq!(
+            tcp_readmit(&mut fourth, &filters, 0, 0, outside),
+            Err(EACCES)
+        );
+        assert_eq!(
+            tcp_readmit(&mut None, &filters, 0, 0, balancer),
+            Err(EACCES)
+        );
+        assert_eq!(connections.lock().unwrap().total, 2);
+
+        drop((first, third));
+        assert_eq!(connections.lock().unwrap().total, 0);
+        assert!(connections.lock().unwrap().per_ip.is_empty());
+    }
+}
@@ -0,0 +1,147 @@
+//  Listening side of the quic:// transport.
+//
+//  The listener owns the UDP socket and runs the endpoint (see
+//  quic_endpoint.rs) for every connection accepted on it; it polls the
+//  socket and the endpoint's doorbell, which engines ring when they have
+//  written something. Each accepted connection gets an engine of its own,
+//  as with TCP.
+
+use std::sync::{Arc, Mutex};
+
+use anyhow::{anyhow, bail};
+
+use crate::defines::{ZmqFileDesc, ZmqHandle, RETIRED_FD};
+use crate::endpoint::make_unconnected_bind_endpoint_pair;
+use crate::endpoint::EndpointType::Bind;
+use crate::endpoint_uri::EndpointUriPair;
+use crate::engine::ZmqEngine;
+use crate::listener::ZmqListener;
+use crate::address::resolve_address;
+use crate::quic_endpoint::{quic_server_config, ZmqQuicEndpoint};
+use crate::quic_engine::{quic_init, quic_schedule, ZmqQuicLink, QUIC_DRIVE_TIMER_ID};
+use crate::session_base::ZmqSessionBase;
+use crate::wss_engine::wss_server_config;
+
+pub fn quic_set_local_address(listener: &mut ZmqListener, addr: &mut str) -> anyhow::Result<()> {
+    let address = resolve_address(addr, listener.socket.context.ipv6)?;
+
+    //  Certificate and key are the WSS ones.
+    let tls = wss_server_config(listener.socket.context)?;
+    let endpoint = ZmqQuicEndpoint::bind(&address, quic_server_config(&tls)?)
+        .map_err(|e| anyhow!("failed to bind socket: {}", e))?;
+
+    listener.fd = endpoint.socket;
+    listener.endpoint = format!("quic://{}", endpoint.local_addr);
+    listener.tls_cred = Some(tls);
+    listener.quic = Some(Arc::new(Mutex::new(endpoint)));
+    listener.socket.event_listening(
+        &make_unconnected_bind_endpoint_pair(&listener.endpoint),
+        listener.fd,
+    );
+    Ok(())
+}
+
+//  Called by process_plug once the socket is registered.
+pub fn quic_plug(listener: &mut ZmqListener) {
+    let Some(endpoint) = listener.quic.clone() else {
+        return;
+    };
+    let bell = endpoint.lock().unwrap().driver_bell.fd;
+    listener.quic_bell_handle = listener.io_object.add_fd(bell);
+    listener.io_object.set_pollin(listener.quic_bell_handle);
+}
+
+//  Runs the endpoint and starts engines for the connections it accepted.
+pub fn quic_in_event(listener: &mut ZmqListener) -> anyhow::Result<()> {
+    let Some(endpoint) = listener.quic.clone() else {
+        bail!("quic listener is closed");
+    };
+    let (timeout, accepted) = {
+        let mut endpoint = endpoint.lock().unwrap();
+        endpoint.driver_bell.drain();
+        let timeout = endpoint.drive();
+        (timeout, std::mem::take(&mut endpoint.accepted))
+    };
+    quic_schedule(
+        &mut listener.io_object,
+        &mut listener.has_quic_timer,
+        timeout,
+    );
+
+    for ch in accepted {
+        let bell = endpoint.lock().unwrap().conns[&ch].bell.clone();
+        quic_create_engine(
+            listener,
+            ZmqQuicLink {
+                endpoint: endpoint.clone(),
+                ch,
+                bell,
+                driver: false,
+            },
+        )?;
+    }
+    Ok(())
+}
+
+pub fn quic_timer_event(listener: &mut ZmqListener, id: i32) -> anyhow::Result<()> {
+    if id != QUIC_DRIVE_TIMER_ID {
+        return Ok(());
+    }
+    listener.has_quic_timer = false;
+    quic_in_event(listener)
+}
+
+pub fn quic_close(listener: &mut ZmqListener) -> anyhow::Result<()> {
+    let fd_for_event = listener.fd;
+    if listener.has_quic_timer {
+        listener.io_object.cancel_timer(QUIC_DRIVE_TIMER_ID);
+        listener.has_quic_timer = false;
+    }
+    if listener.quic_bell_handle != RETIRED_FD as ZmqHandle {
+        listener.io_object.rm_fd(listener.quic_bell_handle);
+        listener.quic_bell_handle = RETIRED_FD as ZmqHandle;
+    }
+    //  Connections accepted here are run by the listener, so they go down
+    //  with it. Their engines still hold on to the endpoint; the socket is
+    //  closed once the last of them is gone.
+    if let Some(endpoint) = listener.quic.take() {
+        endpoint.lock().unwrap().close_all();
+    }
+    listener.fd = RETIRED_FD as ZmqFileDesc;
+
+    listener.socket.event_closed(
+        &make_unconnected_bind_endpoint_pair(&listener.endpoint),
+        fd_for_event,
+    );
+    Ok(())
+}
+
+pub fn quic_create_engine(listener: &mut ZmqListener, link: ZmqQuicLink) -> anyhow::Result<()> {
+    let remote = link
+        .endpoint
+        .lock()
+        .unwrap()
+        .remote_address(link.ch)
+        .map(|remote| format!("quic://{}", remote))
+        .unwrap_or_default();
+    let endpoint_pair = EndpointUriPair::new(&listener.endpoint, &remote, Bind);
+    let fd = link.bell.fd;
+
+    let mut engine = ZmqEngine::default();
+    quic_init(&mut engine, link, &listener.address, endpoint_pair.clone());
+
+    //  Choose I/O thread to run the session in. Given that we are already
+    //  running in an I/O thread, there must be at least one available.
+    let io_thread = listener
+        .choose_io_thread(listener.socket.context.affinity)
+        .unwrap();
+
+    //  Create and launch a session object.
+    let mut session = ZmqSessionBase::create(io_thread, false, listener.socket, None)?;
+    session.inc_seqnum();
+    listener.own.launch_child(session);
+    listener.own.send_attach(&mut session, engine, false);
+
+    listener.socket.event_accepted(&endpoint_pair, fd);
+    Ok(())
+}
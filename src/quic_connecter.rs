@@ -0,0 +1,121 @@
+use std::sync::{Arc, Mutex};
+
+use anyhow::bail;
+use quinn_proto::{ClientConfig, ConnectionHandle};
+
+use crate::address::ZmqAddress;
+use crate::context::ZmqContext;
+use crate::endpoint::EndpointType::Connect;
+use crate::endpoint_uri::EndpointUriPair;
+use crate::engine::ZmqEngine;
+use crate::address::resolve_address;
+use crate::quic_endpoint::{quic_client_config, ZmqQuicEndpoint};
+use crate::quic_engine::{quic_init, ZmqQuicLink};
+use crate::session_base::ZmqSessionBase;
+use crate::stream_connecter_base::StreamConnecterBase;
+use crate::thread_context::ZmqThreadContext;
+use crate::wss_engine::wss_client_config;
+
+//  Connects to a quic:// listener. There is nothing to wait for: the
+//  engine is created right away and the QUIC handshake runs underneath the
+//  ZMTP one, with the greeting going out as 0-RTT data when the TLS session
+//  can be resumed. Connection failures surface as engine errors and are
+//  retried by the session.
+pub struct QuicConnecter<'a> {
+    pub base: StreamConnecterBase<'a>,
+    //  None if the TLS options are unusable.
+    pub config: Option<ClientConfig>,
+    //  Name the server's certificate is checked against.
+    pub server_name: String,
+    pub ipv6: bool,
+}
+
+impl<'a> QuicConnecter<'a> {
+    //  If 'delayed_start' is true connecter first waits for a while,
+    //  then starts connection process.
+    pub fn new(
+        ctx: &mut ZmqContext,
+        io_thread_: &mut ZmqThreadContext,
+        session: &mut ZmqSessionBase,
+        addr: &mut ZmqAddress,
+        delayed_start_: bool,
+    ) -> Self {
+        // zmq_assert (_addr.protocol == ZmqTransport::ZmqQuic);
+
+        //  The client configuration is kept for the lifetime of the socket
+        //  as it carries the session tickets used for 0-RTT.
+        if ctx.quic_client_config.is_none() {
+            ctx.quic_client_config = wss_client_config(ctx)
+                .and_then(|tls| quic_client_config(&tls))
+                .ok();
+        }
+        let server_name = if ctx.wss_hostname.is_empty() {
+            let (host, _) = addr.addr_str.rsplit_once(':').unwrap_or_default();
+            host.trim_start_matches('[')
+                .trim_end_matches(']')
+                .to_string()
+        } else {
+            ctx.wss_hostname.clone()
+        };
+
+        Self {
+            config: ctx.quic_client_config.clone(),
+            server_name,
+            ipv6: ctx.ipv6,
+            base: StreamConnecterBase::new(io_thread_, session, ctx, addr, delayed_start_),
+        }
+    }
+
+    //  Internal function to start the actual connection establishment.
+    pub fn start_connecting(&mut self) -> anyhow::Result<()> {
+        match self.open() {
+            Ok((endpoint, ch)) => {
+                self.create_engine(endpoint, ch);
+                Ok(())
+            }
+            Err(_) => {
+                self.base.add_reconnect_timer();
+                Ok(())
+            }
+        }
+    }
+
+    //  Open the UDP socket and start the QUIC handshake.
+    pub fn open(&mut self) -> anyhow::Result<(ZmqQuicEndpoint, ConnectionHandle)> {
+        let Some(config) = self.config.clone() else {
+            bail!("EINVAL: invalid TLS options for quic");
+        };
+        let remote = resolve_address(&self.base._addr.addr_str, self.ipv6)?;
+        ZmqQuicEndpoint::connect(&remote, config, &self.server_name)
+    }
+
+    fn create_engine(&mut self, endpoint: ZmqQuicEndpoint, ch: ConnectionHandle) {
+        let local = format!("quic://{}", endpoint.local_addr);
+        let remote = format!("quic://{}", self.base._addr.addr_str);
+        let endpoint_pair = EndpointUriPair::new(&local, &remote, Connect);
+        let bell = endpoint.driver_bell.clone();
+        let fd = bell.fd;
+
+        //  Create the engine object for this connection.
+        let mut engine = ZmqEngine::default();
+        quic_init(
+            &mut engine,
+            ZmqQuicLink {
+                endpoint: Arc::new(Mutex::new(endpoint)),
+                ch,
+                bell,
+                driver: true,
+            },
+            self.base._addr,
+            endpoint_pair.clone(),
+        );
+
+        //  Attach the engine to the corresponding session object.
+        self.base.own.send_attach(self.base._session, engine, true);
+
+        //  Shut the connecter down.
+        self.base.own.terminate();
+
+        self.base._socket.event_connected(&endpoint_pair, fd);
+    }
+}
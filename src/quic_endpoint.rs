@@ -0,0 +1,722 @@
+//  QUIC endpoint shared by the quic:// listener, connecter and engines.
+//
+//  quinn-proto does no I/O of its own. A ZmqQuicEndpoint owns the UDP
+//  socket and the quinn-proto state of every connection on it; drive() moves
+//  datagrams between the two and is run by the I/O object that polls the
+//  socket: the listener on the bind side, the engine on the connect side.
+//
+//  Every connection carries exactly one bidirectional stream, opened by the
+//  connecting side, and ZMTP runs over it unchanged. Engines read and write
+//  that stream through read() and write(); they never touch the socket.
+//  Instead they ring the endpoint's driver bell when there is something to
+//  transmit, and their own bell is rung by drive() when the stream becomes
+//  readable or writable, or the connection goes away. On the connect side
+//  both bells are the same eventfd.
+//
+//  The client keeps one quinn-proto ClientConfig per socket (see
+//  quic_client_config), so a reconnect resumes the TLS session and sends its
+//  greeting as 0-RTT data. If the server turns that down the greeting is
+//  sent again once the handshake completes, ahead of anything written
+//  since. If sending fails because the local address went away, the client
+//  moves the connection to a new socket (connection migration); the server
+//  follows automatically.
+
+use std::collections::HashMap;
+use std::io;
+use std::mem;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, bail};
+use bytes::{Bytes, BytesMut};
+use quinn_proto::crypto::rustls::{QuicClientConfig, QuicServerConfig};
+use quinn_proto::{
+    ClientConfig, Connection, ConnectionHandle, DatagramEvent, Dir, Endpoint, EndpointConfig,
+    Event, ReadError, ReadableError, ServerConfig, StreamEvent, StreamId, VarInt, WriteError,
+};
+
+use crate::address::{from_sockaddr, to_sockaddr};
+use crate::defines::ZmqFileDesc;
+use crate::doorbell::ZmqDoorbell;
+
+//  ALPN protocol id carried in the TLS handshake.
+pub const QUIC_ALPN: &[u8] = b"zmtp/3.1";
+
+//  Largest UDP payload we are prepared to receive.
+const QUIC_MAX_DATAGRAM: usize = 65536;
+
+//  Makes a listener's TLS credentials usable for QUIC: TLS 1.3 only, the
+//  ZMTP ALPN id, and 0-RTT data accepted.
+pub fn quic_server_config(tls: &rustls::ServerConfig) -> anyhow::Result<Arc<ServerConfig>> {
+    let mut tls = tls.clone();
+    tls.alpn_protocols = vec![QUIC_ALPN.to_vec()];
+    tls.max_early_data_size = u32::MAX;
+    let crypto = QuicServerConfig::try_from(tls).map_err(|e| anyhow!("EINVAL: {}", e))?;
+    Ok(Arc::new(ServerConfig::with_crypto(Arc::new(crypto))))
+}
+
+//  Client side counterpart of quic_server_config. Keep the result around:
+//  the TLS session tickets needed for 0-RTT live in it.
+pub fn quic_client_config(tls: &rustls::ClientConfig) -> anyhow::Result<ClientConfig> {
+    let mut tls = tls.clone();
+    tls.alpn_protocols = vec![QUIC_ALPN.to_vec()];
+    tls.enable_early_data = true;
+    let crypto = QuicClientConfig::try_from(Arc::new(tls)).map_err(|e| anyhow!("EINVAL: {}", e))?;
+    Ok(ClientConfig::new(Arc::new(crypto)))
+}
+
+//  Opens a non-blocking UDP socket bound to 'addr'.
+pub fn quic_open_socket(addr: &SocketAddr) -> anyhow::Result<(ZmqFileDesc, SocketAddr)> {
+    let family = if addr.is_ipv4() {
+        libc::AF_INET
+    } else {
+        libc::AF_INET6
+    };
+    let fd = unsafe {
+        libc::socket(
+            family,
+            libc::SOCK_DGRAM | libc::SOCK_NONBLOCK | libc::SOCK_CLOEXEC,
+            0,
+        )
+    };
+    if fd < 0 {
+        bail!("failed to open socket: {}", io::Error::last_os_error());
+    }
+
+    let (storage, len) = to_sockaddr(addr);
+    if unsafe { libc::bind(fd, &storage as *const _ as *const libc::sockaddr, len) } != 0 {
+        let err = io::Error::last_os_error();
+        unsafe { libc::close(fd) };
+        bail!("failed to bind socket: {}", err);
+    }
+
+    //  Report the port the kernel picked for a wildcard bind.
+    let mut storage: libc::sockaddr_storage = unsafe { mem::zeroed() };
+    let mut len = mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+    unsafe { libc::getsockname(fd, &mut storage as *mut _ as *mut libc::sockaddr, &mut len) };
+    let local = from_sockaddr(&storage).unwrap_or(*addr);
+    Ok((fd as ZmqFileDesc, local))
+}
+
+//  A connection and the state its engine needs.
+pub struct ZmqQuicConn {
+    pub conn: Connection,
+    //  The ZMTP stream, once opened (connect side) or accepted (bind side).
+    pub stream: Option<StreamId>,
+    //  Rung when the engine has something to do.
+    pub bell: Arc<ZmqDoorbell>,
+    //  Why the connection went away, once it has.
+    pub lost: Option<String>,
+    //  Stream data written during the handshake, kept in case the server
+    //  rejects 0-RTT and it has to be written again. None once the
+    //  handshake completed.
+    pub early_data: Option<Vec<u8>>,
+    //  Rejected early data not yet written again; it goes out ahead of
+    //  anything the engine writes.
+    pub resend: Vec<u8>,
+    //  The engine let go of the connection; forget it once it drained.
+    pub released: bool,
+}
+
+impl ZmqQuicConn {
+    //  Writes as much of 'resend' as the stream takes. A stream that
+    //  fails outright loses the connection.
+    fn flush_resend(&mut self) {
+        let Some(id) = self.stream else {
+            return;
+        };
+        while !self.resend.is_empty() {
+            match self.conn.send_stream(id).write(&self.resend) {
+                Ok(n) => {
+                    self.resend.drain(..n);
+                }
+                Err(WriteError::Blocked) => return,
+                Err(e) => {
+                    self.lost = Some(format!("failed to resend early data: {}", e));
+                    return;
+                }
+            }
+        }
+    }
+}
+
+pub struct ZmqQuicEndpoint {
+    pub socket: ZmqFileDesc,
+    pub local_addr: SocketAddr,
+    pub endpoint: Endpoint,
+    pub conns: HashMap<ConnectionHandle, ZmqQuicConn>,
+    //  Connections accepted by drive() and not yet handed to an engine.
+    pub accepted: Vec<ConnectionHandle>,
+    //  Rung to have the owner of the socket run drive().
+    pub driver_bell: Arc<ZmqDoorbell>,
+    pub is_server: bool,
+    recv_buf: Vec<u8>,
+    send_buf: Vec<u8>,
+}
+
+impl ZmqQuicEndpoint {
+    fn new(addr: &SocketAddr, server_config: Option<Arc<ServerConfig>>) -> anyhow::Result<Self> {
+        let (socket, local_addr) = quic_open_socket(addr)?;
+        let driver_bell = match ZmqDoorbell::new() {
+            Ok(bell) => bell,
+            Err(e) => {
+                unsafe { libc::close(socket) };
+                return Err(e);
+            }
+        };
+        Ok(Self {
+            socket,
+            local_addr,
+            is_server: server_config.is_some(),
+            endpoint: Endpoint::new(
+                Arc::new(EndpointConfig::default()),
+                server_config,
+                true,
+                None,
+            ),
+            conns: HashMap::new(),
+            accepted: vec![],
+            driver_bell: Arc::new(driver_bell),
+            recv_buf: vec![0u8; QUIC_MAX_DATAGRAM],
+            send_buf: Vec::with_capacity(QUIC_MAX_DATAGRAM),
+        })
+    }
+
+    //  Listening endpoint.
+    pub fn bind(addr: &SocketAddr, server_config: Arc<ServerConfig>) -> anyhow::Result<Self> {
+        Self::new(addr, Some(server_config))
+    }
+
+    //  Connecting endpoint with a single connection to 'remote'.
+    pub fn connect(
+        remote: &SocketAddr,
+        config: ClientConfig,
+        server_name: &str,
+    ) -> anyhow::Result<(Self, ConnectionHandle)> {
+        let any: SocketAddr = if remote.is_ipv4() {
+            (Ipv4Addr::UNSPECIFIED, 0).into()
+        } else {
+            (Ipv6Addr::UNSPECIFIED, 0).into()
+        };
+        let mut ep = Self::new(&any, None)?;
+        let (ch, mut conn) = ep
+            .endpoint
+            .connect(Instant::now(), config, *remote, server_name)
+            .map_err(|e| anyhow!("EINVAL: {}", e))?;
+
+        //  With a resumed session the stream can be opened, and the
+        //  greeting sent, right away as 0-RTT data.
+        let stream = conn.streams().open(Dir::Bi);
+        ep.conns.insert(
+            ch,
+            ZmqQuicConn {
+                conn,
+                stream,
+                bell: ep.driver_bell.clone(),
+                lost: None,
+                early_data: Some(vec![]),
+                resend: vec![],
+                released: false,
+            },
+        );
+        Ok((ep, ch))
+    }
+
+    pub fn wake_driver(&self) {
+        self.driver_bell.ring();
+    }
+
+    fn send(&mut self, destination: &SocketAddr, data: &[u8]) -> io::Result<()> {
+        let (storage, len) = to_sockaddr(destination);
+        let rc = unsafe {
+            libc::sendto(
+                self.socket,
+                data.as_ptr() as *const libc::c_void,
+                data.len(),
+                0,
+                &storage as *const _ as *const libc::sockaddr,
+                len,
+            )
+        };
+        if rc < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    //  Moves the connecting side to a fresh socket, e.g. because the local
+    //  address it was using is gone. The owner has to re-register 'socket'
+    //  with its poller afterwards.
+    pub fn migrate(&mut self) -> anyhow::Result<()> {
+        let any: SocketAddr = if self.local_addr.is_ipv4() {
+            (Ipv4Addr::UNSPECIFIED, 0).into()
+        } else {
+            (Ipv6Addr::UNSPECIFIED, 0).into()
+        };
+        let (socket, local_addr) = quic_open_socket(&any)?;
+        unsafe { libc::close(self.socket) };
+        self.socket = socket;
+        self.local_addr = local_addr;
+        for quic_conn in self.conns.values_mut() {
+            quic_conn.conn.local_address_changed();
+        }
+        Ok(())
+    }
+
+    fn receive(&mut self, now: Instant) {
+        loop {
+            let mut storage: libc::sockaddr_storage = unsafe { mem::zeroed() };
+            let mut len = mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+            let rc = unsafe {
+                libc::recvfrom(
+                    self.socket,
+                    self.recv_buf.as_mut_ptr() as *mut libc::c_void,
+                    self.recv_buf.len(),
+                    0,
+                    &mut storage as *mut _ as *mut libc::sockaddr,
+                    &mut len,
+                )
+            };
+            if rc < 0 {
+                //  EAGAIN, or an ICMP error reported by the kernel; QUIC
+                //  recovers from lost datagrams by itself.
+                if io::Error::last_os_error().kind() == io::ErrorKind::WouldBlock {
+                    return;
+                }
+                continue;
+            }
+            let remote = match from_sockaddr(&storage) {
+                Some(remote) => remote,
+                None => continue,
+            };
+
+            let data = BytesMut::from(&self.recv_buf[..rc as usize]);
+            let mut response = Vec::new();
+            match self
+                .endpoint
+                .handle(now, remote, None, None, data, &mut response)
+            {
+                Some(DatagramEvent::ConnectionEvent(ch, event)) => {
+                    if let Some(quic_conn) = self.conns.get_mut(&ch) {
+                        quic_conn.conn.handle_event(event);
+                    }
+                }
+                Some(DatagramEvent::NewConnection(incoming)) => {
+                    if !self.is_server {
+                        self.endpoint.ignore(incoming);
+                        continue;
+                    }
+                    match self.endpoint.accept(incoming, now, &mut response, None) {
+                        Ok((ch, conn)) => {
+                            let bell = match ZmqDoorbell::new() {
+                                Ok(bell) => Arc::new(bell),
+                                //  Out of descriptors; the client will retry.
+                                Err(_) => continue,
+                            };
+                            self.conns.insert(
+                                ch,
+                                ZmqQuicConn {
+                                    conn,
+                                    stream: None,
+                                    bell,
+                                    lost: None,
+                                    early_data: None,
+                                    resend: vec![],
+                                    released: false,
+                                },
+                            );
+                            self.accepted.push(ch);
+                        }
+                        Err(e) => {
+                            if let Some(transmit) = e.response {
+                                let _ =
+                                    self.send(&transmit.destination, &response[..transmit.size]);
+                            }
+                        }
+                    }
+                }
+                Some(DatagramEvent::Response(transmit)) => {
+                    let _ = self.send(&transmit.destination, &response[..transmit.size]);
+                }
+                None => {}
+            }
+        }
+    }
+
+    //  Feeds quinn-proto's events back and forth and lets the engines know
+    //  about the ones they care about.
+    fn process(&mut self, ch: ConnectionHandle, now: Instant) {
+        let is_server = self.is_server;
+        let quic_conn = self.conns.get_mut(&ch).unwrap();
+        if let Some(timeout) = quic_conn.conn.poll_timeout() {
+            if timeout <= now {
+                quic_conn.conn.handle_timeout(now);
+            }
+        }
+
+        while let Some(event) = quic_conn.conn.poll_endpoint_events() {
+            if let Some(event) = self.endpoint.handle_event(ch, event) {
+                quic_conn.conn.handle_event(event);
+            }
+        }
+
+        let mut wake = false;
+        while let Some(event) = quic_conn.conn.poll() {
+            match event {
+                Event::Connected => {
+                    if let Some(early_data) = quic_conn.early_data.take() {
+                        if !quic_conn.conn.accepted_0rtt() {
+                            //  0-RTT was rejected (or not attempted) and the
+                            //  stream with it; start over on a new one.
+                            quic_conn.stream = quic_conn.conn.streams().open(Dir::Bi);
+                            if quic_conn.stream.is_none() {
+                                quic_conn.lost = Some("no stream to resend early data".into());
+                            }
+                            quic_conn.resend = early_data;
+                        }
+                    }
+                    wake = true;
+                }
+                Event::Stream(StreamEvent::Opened { dir: Dir::Bi }) if is_server => {
+                    if quic_conn.stream.is_none() {
+                        quic_conn.stream = quic_conn.conn.streams().accept(Dir::Bi);
+                    }
+                    wake = true;
+                }
+                Event::Stream(_) => wake = true,
+                Event::ConnectionLost { reason } => {
+                    quic_conn.lost = Some(reason.to_string());
+                    wake = true;
+                }
+                _ => {}
+            }
+        }
+        //  Flow control may hold back the resent data; more of it goes out
+        //  whenever the stream has room.
+        if !quic_conn.resend.is_empty() {
+            quic_conn.flush_resend();
+            wake |= quic_conn.lost.is_some();
+        }
+        if wake {
+            quic_conn.bell.ring();
+        }
+    }
+
+    fn transmit(&mut self, ch: ConnectionHandle, now: Instant) {
+        let mut buf = mem::take(&mut self.send_buf);
+        loop {
+            buf.clear();
+            let quic_conn = self.conns.get_mut(&ch).unwrap();
+            let transmit = match quic_conn.conn.poll_transmit(now, 1, &mut buf) {
+                Some(transmit) => transmit,
+                None => break,
+            };
+            match self.send(&transmit.destination, &buf[..transmit.size]) {
+                Ok(()) => {}
+                Err(e)
+                    if !self.is_server
+                        && matches!(
+                            e.raw_os_error(),
+                            Some(libc::EADDRNOTAVAIL)
+                                | Some(libc::ENETUNREACH)
+                                | Some(libc::EHOSTUNREACH)
+                                | Some(libc::EINVAL)
+                        ) =>
+                {
+                    //  Our address is gone; carry on from a new one. The
+                    //  datagram is lost, QUIC's loss recovery resends it.
+                    if self.migrate().is_err() {
+                        break;
+                    }
+                }
+                //  Socket buffer full and such; also left to loss recovery.
+                Err(_) => break,
+            }
+        }
+        self.send_buf = buf;
+    }
+
+    //  Processes everything received on the socket, due timers and whatever
+    //  the engines queued, and sends the resulting datagrams. Returns how
+    //  long the owner may wait before calling it again at the latest.
+    pub fn drive(&mut self) -> Option<Duration> {
+        let now = Instant::now();
+        self.receive(now);
+
+        let handles: Vec<ConnectionHandle> = self.conns.keys().copied().collect();
+        for ch in handles {
+            self.process(ch, now);
+            self.transmit(ch, now);
+            //  Transmitting may have produced more events (e.g. a
+            //  migration's path challenge).
+            self.process(ch, now);
+        }
+
+        self.conns
+            .retain(|_, quic_conn| !(quic_conn.released && quic_conn.conn.is_drained()));
+
+        self.conns
+            .values_mut()
+            .filter_map(|quic_conn| quic_conn.conn.poll_timeout())
+            .min()
+            .map(|deadline| deadline.saturating_duration_since(now))
+    }
+
+    //  Equivalent of recv(2) on the connection's stream.
+    pub fn read(&mut self, ch: ConnectionHandle, data: &mut [u8]) -> io::Result<usize> {
+        let quic_conn = self
+            .conns
+            .get_mut(&ch)
+            .ok_or_else(|| io::Error::from(io::ErrorKind::NotConnected))?;
+        let id = match quic_conn.stream {
+            Some(id) => id,
+            None if quic_conn.lost.is_some() => return Err(io::ErrorKind::ConnectionReset.into()),
+            None => return Err(io::ErrorKind::WouldBlock.into()),
+        };
+
+        let mut recv = quic_conn.conn.recv_stream(id);
+        let mut chunks = match recv.read(true) {
+            Ok(chunks) => chunks,
+            Err(ReadableError::ClosedStream) => return Ok(0),
+            Err(ReadableError::IllegalOrderedRead) => return Err(io::ErrorKind::InvalidData.into()),
+        };
+        let mut nbytes = 0;
+        let result = loop {
+            if nbytes == data.len() {
+                break Ok(nbytes);
+            }
+            match chunks.next(data.len() - nbytes) {
+                Ok(Some(chunk)) => {
+                    data[nbytes..nbytes + chunk.bytes.len()].copy_from_slice(&chunk.bytes);
+                    nbytes += chunk.bytes.len();
+                }
+                //  End of stream.
+                Ok(None) => break Ok(nbytes),
+                Err(ReadError::Blocked) if nbytes > 0 => break Ok(nbytes),
+                Err(ReadError::Blocked) if quic_conn.lost.is_some() => {
+                    break Err(io::ErrorKind::ConnectionReset.into())
+                }
+                Err(ReadError::Blocked) => break Err(io::ErrorKind::WouldBlock.into()),
+                Err(ReadError::Reset(_)) => break Err(io::ErrorKind::ConnectionReset.into()),
+            }
+        };
+        //  Flow control credit to hand back to the peer.
+        if chunks.finalize().should_transmit() {
+            self.driver_bell.ring();
+        }
+        result
+    }
+
+    //  Equivalent of send(2) on the connection's stream.
+    pub fn write(&mut self, ch: ConnectionHandle, data: &[u8]) -> io::Result<usize> {
+        let quic_conn = self
+            .conns
+            .get_mut(&ch)
+            .ok_or_else(|| io::Error::from(io::ErrorKind::NotConnected))?;
+        if quic_conn.lost.is_some() {
+            return Err(io::ErrorKind::BrokenPipe.into());
+        }
+        let id = match quic_conn.stream {
+            Some(id) => id,
+            None => return Err(io::ErrorKind::WouldBlock.into()),
+        };
+        if !quic_conn.resend.is_empty() {
+            quic_conn.flush_resend();
+            if quic_conn.lost.is_some() {
+                return Err(io::ErrorKind::BrokenPipe.into());
+            }
+            if !quic_conn.resend.is_empty() {
+                return Err(io::ErrorKind::WouldBlock.into());
+            }
+        }
+
+        match quic_conn.conn.send_stream(id).write(data) {
+            Ok(nbytes) => {
+                if let Some(early_data) = quic_conn.early_data.as_mut() {
+                    early_data.extend_from_slice(&data[..nbytes]);
+                }
+                self.driver_bell.ring();
+                Ok(nbytes)
+            }
+            Err(WriteError::Blocked) => Err(io::ErrorKind::WouldBlock.into()),
+            Err(_) => Err(io::ErrorKind::BrokenPipe.into()),
+        }
+    }
+
+    //  The engine is done with the connection. It is closed and forgotten
+    //  once the peer has been told.
+    pub fn release(&mut self, ch: ConnectionHandle) {
+        if let Some(quic_conn) = self.conns.get_mut(&ch) {
+            quic_conn.released = true;
+            quic_conn
+                .conn
+                .close(Instant::now(), VarInt::from_u32(0), Bytes::new());
+            self.driver_bell.ring();
+        }
+    }
+
+    //  Closes every connection, e.g. because the listener goes away and
+    //  nobody would be left to run them.
+    pub fn close_all(&mut self) {
+        let now = Instant::now();
+        for quic_conn in self.conns.values_mut() {
+            quic_conn.conn.close(now, VarInt::from_u32(0), Bytes::new());
+            //  quinn-proto only reports connections the peer closed.
+            if quic_conn.lost.is_none() {
+                quic_conn.lost = Some("closed locally".to_string());
+            }
+            quic_conn.bell.ring();
+        }
+        self.drive();
+    }
+
+    pub fn remote_address(&self, ch: ConnectionHandle) -> Option<SocketAddr> {
+        self.conns
+            .get(&ch)
+            .map(|quic_conn| quic_conn.conn.remote_address())
+    }
+}
+
+impl std::fmt::Debug for ZmqQuicEndpoint {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ZmqQuicEndpoint")
+            .field("socket", &self.socket)
+            .field("local_addr", &self.local_addr)
+            .field("conns", &self.conns.len())
+            .finish()
+    }
+}
+
+impl Drop for ZmqQuicEndpoint {
+    fn drop(&mut self) {
+        unsafe { libc::close(self.socket) };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wss_engine::{WSS_TEST_CERT_PEM, WSS_TEST_KEY_PEM};
+    use rustls::pki_types::pem::PemObject;
+    use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+
+    fn server_config() -> Arc<ServerConfig> {
+        let certs = CertificateDer::pem_slice_iter(WSS_TEST_CERT_PEM.as_bytes())
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        let key = PrivateKeyDer::from_pem_slice(WSS_TEST_KEY_PEM.as_bytes()).unwrap();
+        let tls = rustls::ServerConfig::builder_with_provider(Arc::new(
+            rustls::crypto::ring::default_provider(),
+        ))
+        .with_safe_default_protocol_versions()
+        .unwrap()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .unwrap();
+        quic_server_config(&tls).unwrap()
+    }
+
+    fn client_config() -> ClientConfig {
+        let mut roots = rustls::RootCertStore::empty();
+        for cert in CertificateDer::pem_slice_iter(WSS_TEST_CERT_PEM.as_bytes()) {
+            roots.add(cert.unwrap()).unwrap();
+        }
+        let tls = rustls::ClientConfig::builder_with_provider(Arc::new(
+            rustls::crypto::ring::default_provider(),
+        ))
+        .with_safe_default_protocol_versions()
+        .unwrap()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+        quic_client_config(&tls).unwrap()
+    }
+
+    //  Drives both ends until 'done' returns true.
+    fn run(
+        server: &mut ZmqQuicEndpoint,
+        client: &mut ZmqQuicEndpoint,
+        mut done: impl FnMut(&mut ZmqQuicEndpoint, &mut ZmqQuicEndpoint) -> bool,
+    ) {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !done(server, client) {
+            assert!(Instant::now() < deadline, "timed out");
+            client.drive();
+            server.drive();
+            std::thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    //  Connects to 'server', writes 'greeting' as soon as the stream takes
+    //  it and returns what the server read, and whether it went as 0-RTT.
+    fn exchange(
+        server: &mut ZmqQuicEndpoint,
+        config: ClientConfig,
+        greeting: &[u8],
+    ) -> (Vec<u8>, bool) {
+        let (mut client, ch) = ZmqQuicEndpoint::connect(&server.local_addr, config, "localhost")
+            .unwrap();
+        let mut written = 0;
+        let mut received = vec![];
+        let mut peer = None;
+        run(server, &mut client, |server, client| {
+            if written < greeting.len() {
+                match client.write(ch, &greeting[written..]) {
+                    Ok(n) => written += n,
+                    Err(e) => assert_eq!(e.kind(), io::ErrorKind::WouldBlock),
+                }
+            }
+            if let Some(ch) = server.accepted.pop() {
+                peer = Some(ch);
+            }
+            if let Some(ch) = peer {
+                let mut buf = [0u8; 256];
+                if let Ok(n) = server.read(ch, &mut buf) {
+                    received.extend_from_slice(&buf[..n]);
+                }
+            }
+            received.len() >= greeting.len()
+        });
+        //  Let the session ticket arrive, then hang up.
+        let linger = Instant::now() + Duration::from_millis(50);
+        run(server, &mut client, |_, _| Instant::now() > linger);
+        let early = client.conns[&ch].conn.accepted_0rtt();
+        client.release(ch);
+        if let Some(ch) = peer {
+            server.release(ch);
+        }
+        client.drive();
+        server.drive();
+        (received, early)
+    }
+
+    #[test]
+    fn loopback_stream() {
+        let mut server = ZmqQuicEndpoint::bind(&"127.0.0.1:0".parse().unwrap(), server_config())
+            .unwrap();
+        let greeting: Vec<u8> = (0..=255).collect();
+        assert_eq!(exchange(&mut server, client_config(), &greeting), (greeting, false));
+    }
+
+    //  A resumed session sends the greeting as 0-RTT data. A server that
+    //  doesn't know the ticket turns it down, and the greeting arrives in
+    //  full all the same.
+    #[test]
+    fn early_data_accepted_or_resent() {
+        let config = client_config();
+        let mut server = ZmqQuicEndpoint::bind(&"127.0.0.1:0".parse().unwrap(), server_config())
+            .unwrap();
+        assert_eq!(exchange(&mut server, config.clone(), b"first").0, b"first");
+        assert_eq!(
+            exchange(&mut server, config.clone(), b"resumed"),
+            (b"resumed".to_vec(), true)
+        );
+
+        let mut other = ZmqQuicEndpoint::bind(&"127.0.0.1:0".parse().unwrap(), server_config())
+            .unwrap();
+        assert_eq!(
+            exchange(&mut other, config, b"rejected"),
+            (b"rejected".to_vec(), false)
+        );
+    }
+}
@@ -0,0 +1,197 @@
+//  Engine hooks of the quic:// transport.
+//
+//  A QUIC connection is a regular ZMTP stream engine whose reads and
+//  writes go to the connection's stream instead of a socket; greeting,
+//  mechanisms and framing are unchanged. What the engine polls is the
+//  connection's doorbell (see quic_endpoint.rs) rather than a socket. As an
+//  eventfd is always writable, waiting for output is done by remembering
+//  it and retrying whenever the doorbell rings, which it does once the
+//  stream has room again.
+//
+//  On the connect side the engine also owns the UDP socket: it polls it and
+//  runs the endpoint's timers.
+
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use quinn_proto::ConnectionHandle;
+
+use crate::address::ZmqAddress;
+use crate::defines::{ZmqFileDesc, ZmqHandle, RETIRED_FD};
+use crate::endpoint_uri::EndpointUriPair;
+use crate::engine::ZmqEngine;
+use crate::io_object::ZmqIoObject;
+use crate::quic_endpoint::ZmqQuicEndpoint;
+use crate::doorbell::ZmqDoorbell;
+
+//  Timer id used to run an endpoint's timers.
+pub const QUIC_DRIVE_TIMER_ID: i32 = 0x50;
+
+//  An engine's connection.
+#[derive(Debug, Clone)]
+pub struct ZmqQuicLink {
+    pub endpoint: Arc<Mutex<ZmqQuicEndpoint>>,
+    pub ch: ConnectionHandle,
+    pub bell: Arc<ZmqDoorbell>,
+    //  Whether the engine runs the endpoint (connect side).
+    pub driver: bool,
+}
+
+//  (Re)arms the timer running the endpoint, as asked for by drive().
+pub fn quic_schedule(io_object: &mut ZmqIoObject, has_timer: &mut bool, timeout: Option<Duration>) {
+    if *has_timer {
+        io_object.cancel_timer(QUIC_DRIVE_TIMER_ID);
+        *has_timer = false;
+    }
+    if let Some(timeout) = timeout {
+        let ms = timeout.as_millis().clamp(1, i32::MAX as u128) as i32;
+        io_object.add_timer(ms, QUIC_DRIVE_TIMER_ID);
+        *has_timer = true;
+    }
+}
+
+pub fn quic_init(
+    engine: &mut ZmqEngine,
+    link: ZmqQuicLink,
+    address: &ZmqAddress,
+    endpoint_uri_pair: EndpointUriPair,
+) {
+    {
+        let endpoint = link.endpoint.lock().unwrap();
+        if let Some(remote) = endpoint.remote_address(link.ch) {
+            engine.peer_address = remote.ip().to_string();
+        }
+    }
+    engine.fd = link.bell.fd;
+    engine.address = address.clone();
+    engine.endpoint_uri_pair = endpoint_uri_pair;
+    engine.quic = Some(link);
+    engine.quic_socket_handle = RETIRED_FD as ZmqHandle;
+    engine.quic_want_write = false;
+    engine.has_quic_timer = false;
+}
+
+//  Called by plug() once the doorbell is registered.
+pub fn quic_plug(engine: &mut ZmqEngine) {
+    let Some(link) = engine.quic.clone() else {
+        return;
+    };
+    engine.io_object.set_pollin(engine.handle);
+    if link.driver {
+        let socket = link.endpoint.lock().unwrap().socket;
+        engine.quic_socket_handle = engine.io_object.add_fd(socket);
+        engine.io_object.set_pollin(engine.quic_socket_handle);
+        quic_drive(engine);
+    }
+}
+
+//  Runs the endpoint on the connect side. Follows the socket if the
+//  connection migrated to a new one.
+fn quic_drive(engine: &mut ZmqEngine) {
+    let Some(link) = engine.quic.clone() else {
+        return;
+    };
+    if !link.driver {
+        return;
+    }
+    let (timeout, socket) = {
+        let mut endpoint = link.endpoint.lock().unwrap();
+        (endpoint.drive(), endpoint.socket)
+    };
+    if socket as ZmqHandle != engine.quic_socket_handle
+        && engine.quic_socket_handle != RETIRED_FD as ZmqHandle
+    {
+        engine.io_object.rm_fd(engine.quic_socket_handle);
+        engine.quic_socket_handle = engine.io_object.add_fd(socket);
+        engine.io_object.set_pollin(engine.quic_socket_handle);
+    }
+    quic_schedule(&mut engine.io_object, &mut engine.has_quic_timer, timeout);
+}
+
+pub fn quic_in_event(engine: &mut ZmqEngine) {
+    let Some(link) = engine.quic.clone() else {
+        return;
+    };
+    link.bell.drain();
+    quic_drive(engine);
+
+    if !engine.in_event_internal() {
+        return;
+    }
+    if engine.quic_want_write && engine.quic.is_some() {
+        engine.out_event();
+    }
+    //  Whatever the engine wrote goes out now rather than on the next
+    //  wakeup.
+    quic_drive(engine);
+}
+
+pub fn quic_timer_event(engine: &mut ZmqEngine) {
+    engine.has_quic_timer = false;
+    quic_drive(engine);
+}
+
+//  Same contract as tcp_read.
+pub fn quic_read(engine: &mut ZmqEngine, data: &mut [u8]) -> i32 {
+    let Some(link) = engine.quic.as_ref() else {
+        return 0;
+    };
+    let result = link.endpoint.lock().unwrap().read(link.ch, data);
+    match result {
+        Ok(nbytes) => nbytes as i32,
+        Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => -1,
+        //  The connection is gone; report it like a peer closing a TCP
+        //  connection.
+        Err(_) => 0,
+    }
+}
+
+//  Same contract as tcp_write.
+pub fn quic_write(engine: &mut ZmqEngine, data: &[u8]) -> i32 {
+    let Some(link) = engine.quic.as_ref() else {
+        return -1;
+    };
+    let result = link.endpoint.lock().unwrap().write(link.ch, data);
+    match result {
+        Ok(nbytes) => nbytes as i32,
+        Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => 0,
+        Err(_) => -1,
+    }
+}
+
+pub fn quic_set_pollout(engine: &mut ZmqEngine) {
+    engine.quic_want_write = true;
+}
+
+pub fn quic_reset_pollout(engine: &mut ZmqEngine) {
+    engine.quic_want_write = false;
+}
+
+pub fn quic_terminate(engine: &mut ZmqEngine) {
+    let Some(link) = engine.quic.take() else {
+        return engine.unplug();
+    };
+    {
+        let mut endpoint = link.endpoint.lock().unwrap();
+        endpoint.release(link.ch);
+        //  Get the close frame out before the socket goes away with us.
+        if link.driver {
+            endpoint.drive();
+        }
+    }
+    if engine.has_quic_timer {
+        engine.io_object.cancel_timer(QUIC_DRIVE_TIMER_ID);
+        engine.has_quic_timer = false;
+    }
+    if engine.quic_socket_handle != RETIRED_FD as ZmqHandle {
+        engine.io_object.rm_fd(engine.quic_socket_handle);
+        engine.quic_socket_handle = RETIRED_FD as ZmqHandle;
+    }
+    engine.unplug();
+    //  The doorbell is closed by whoever drops the last reference to it.
+    engine.fd = RETIRED_FD as ZmqFileDesc;
+}
+
+pub fn quic_get_endpoint(engine: &mut ZmqEngine) -> EndpointUriPair {
+    engine.endpoint_uri_pair.clone()
+}
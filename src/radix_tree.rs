@@ -0,0 +1,246 @@
+/*
+    Copyright (c) 2007-2016 Contributors as noted in the AUTHORS file
+
+    This file is part of libzmq, the ZeroMQ core engine in C+= 1.
+
+    libzmq is free software; you can redistribute it and/or modify it under
+    the terms of the GNU Lesser General Public License (LGPL) as published
+    by the Free Software Foundation; either version 3 of the License, or
+    (at your option) any later version.
+
+    As a special exception, the Contributors give you permission to link
+    this library with independent modules to produce an executable,
+    regardless of the license terms of these independent modules, and to
+    copy and distribute the resulting executable under terms of your choice,
+    provided that you also meet, for each linked independent module, the
+    terms and conditions of the license of that module. An independent
+    module is a module which is not derived from or based on this library.
+    If you modify this library, you must extend this exception to your
+    version of the library.
+
+    libzmq is distributed in the hope that it will be useful, but WITHOUT
+    ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+    FITNESS FOR A PARTICULAR PURPOSE. See the GNU Lesser General Public
+    License for more details.
+
+    You should have received a copy of the GNU Lesser General Public License
+    along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+// #include "precompiled.hpp"
+// #include "macros.hpp"
+// #include "err.hpp"
+// #include "radix_tree.hpp"
+
+//  Each node holds a run of key bytes (its prefix) and the reference count
+//  of the key that ends at it, 0 if no key ends there. The root always has
+//  an empty prefix. Children are kept sorted by the first byte of their
+//  prefix, which is unique among siblings.
+//
+//  None of the operations recurse: the depth of the tree is controlled by
+//  remote peers, so the walk is driven by explicit loops and stacks.
+#[derive(Default, Debug, Clone)]
+pub struct RadixNode {
+    pub prefix: Vec<u8>,
+    pub refcount: u32,
+    pub children: Vec<RadixNode>,
+}
+
+impl RadixNode {
+    fn leaf(prefix: &[u8]) -> Self {
+        Self {
+            prefix: prefix.to_vec(),
+            refcount: 1,
+            children: vec![],
+        }
+    }
+
+    //  Index of the child whose prefix starts with first_byte.
+    fn find_child(&self, first_byte: u8) -> Result<usize, usize> {
+        self.children
+            .binary_search_by_key(&first_byte, |child| child.prefix[0])
+    }
+
+    //  Splits the node so that it keeps only the first at bytes of its
+    //  prefix and a single child holding the remainder.
+    fn split(&mut self, at: usize) {
+        let child = RadixNode {
+            prefix: self.prefix.split_off(at),
+            refcount: self.refcount,
+            children: std::mem::take(&mut self.children),
+        };
+        self.refcount = 0;
+        self.children.push(child);
+    }
+
+    //  Folds the only child into this node, which must not hold a key.
+    fn merge_with_child(&mut self) {
+        let child = self.children.pop().unwrap();
+        self.prefix.extend_from_slice(&child.prefix);
+        self.refcount = child.refcount;
+        self.children = child.children;
+    }
+}
+
+fn common_prefix_len(a: &[u8], b: &[u8]) -> usize {
+    a.iter().zip(b.iter()).take_while(|(x, y)| x == y).count()
+}
+
+//  Mutable, reference-counted radix tree, used by XSUB to store the set of
+//  subscriptions it forwards upstream.
+#[derive(Default, Debug, Clone)]
+pub struct ZmqRadixTree {
+    pub root: RadixNode,
+    //  Number of distinct keys in the tree.
+    pub size: usize,
+}
+
+impl ZmqRadixTree {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    //  Add key to the tree. Returns true if this was a new key rather
+    //  than a duplicate.
+    pub fn add(&mut self, key: &[u8], key_size: usize) -> bool {
+        let mut key = &key[..key_size];
+        let mut node = &mut self.root;
+        loop {
+            let matched = common_prefix_len(&node.prefix, key);
+            if matched < node.prefix.len() {
+                node.split(matched);
+            }
+            key = &key[matched..];
+
+            if key.is_empty() {
+                node.refcount += 1;
+                if node.refcount == 1 {
+                    self.size += 1;
+                    return true;
+                }
+                return false;
+            }
+
+            match node.find_child(key[0]) {
+                Ok(idx) => node = &mut node.children[idx],
+                Err(idx) => {
+                    node.children.insert(idx, RadixNode::leaf(key));
+                    self.size += 1;
+                    return true;
+                }
+            }
+        }
+    }
+
+    //  Remove key from the tree. Returns true if the item is actually
+    //  removed from the tree.
+    pub fn rm(&mut self, key: &[u8], key_size: usize) -> bool {
+        //  Find the node holding the key, remembering the path to it.
+        let mut key = &key[..key_size];
+        let mut path: Vec<usize> = vec![];
+        let mut node = &self.root;
+        while !key.is_empty() {
+            match node.find_child(key[0]) {
+                Ok(idx) if key.starts_with(&node.children[idx].prefix) => {
+                    node = &node.children[idx];
+                    key = &key[node.prefix.len()..];
+                    path.push(idx);
+                }
+                _ => return false,
+            }
+        }
+        if node.refcount == 0 {
+            return false;
+        }
+
+        let (last, parent_path) = match path.split_last() {
+            Some((last, parent_path)) => (*last, parent_path),
+            None => {
+                self.root.refcount -= 1;
+                if self.root.refcount > 0 {
+                    return false;
+                }
+                self.size -= 1;
+                return true;
+            }
+        };
+
+        let parent = Self::node_at_mut(&mut self.root, parent_path);
+        let node = &mut parent.children[last];
+        node.refcount -= 1;
+        if node.refcount > 0 {
+            return false;
+        }
+        self.size -= 1;
+
+        //  Keep the tree compressed: drop the node if it is now a leaf,
+        //  or absorb its only child.
+        match node.children.len() {
+            0 => {
+                parent.children.remove(last);
+                if !parent_path.is_empty()
+                    && parent.refcount == 0
+                    && parent.children.len() == 1
+                {
+                    parent.merge_with_child();
+                }
+            }
+            1 => node.merge_with_child(),
+            _ => {}
+        }
+        true
+    }
+
+    //  Check whether particular key is in the tree, i.e. whether any of
+    //  the stored keys is a prefix of key.
+    pub fn check(&self, key: &[u8], key_size: usize) -> bool {
+        let mut key = &key[..key_size];
+        let mut node = &self.root;
+        loop {
+            if node.refcount > 0 {
+                return true;
+            }
+            if key.is_empty() {
+                return false;
+            }
+            match node.find_child(key[0]) {
+                Ok(idx) if key.starts_with(&node.children[idx].prefix) => {
+                    node = &node.children[idx];
+                    key = &key[node.prefix.len()..];
+                }
+                _ => return false,
+            }
+        }
+    }
+
+    //  Apply the function supplied to each key in the tree.
+    pub fn apply<F>(&self, mut func: F)
+    where
+        F: FnMut(&[u8], usize),
+    {
+        let mut buffer: Vec<u8> = vec![];
+        let mut stack: Vec<(&RadixNode, usize)> = vec![(&self.root, 0)];
+        while let Some((node, depth)) = stack.pop() {
+            buffer.truncate(depth);
+            buffer.extend_from_slice(&node.prefix);
+            if node.refcount > 0 {
+                func(&buffer, buffer.len());
+            }
+            for child in node.children.iter().rev() {
+                stack.push((child, buffer.len()));
+            }
+        }
+    }
+
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    fn node_at_mut<'a>(root: &'a mut RadixNode, path: &[usize]) -> &'a mut RadixNode {
+        let mut node = root;
+        for idx in path {
+            node = &mut node.children[*idx];
+        }
+        node
+    }
+}
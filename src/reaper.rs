@@ -2,7 +2,12 @@ use libc::{EAGAIN, EINTR};
 
 use crate::context::ZmqContext;
 use crate::defines::{ZmqHandle, RETIRED_FD};
+#[cfg(not(all(any(feature = "epoll", feature = "io-uring"), target_os = "linux")))]
 use crate::devpoll::ZmqPoller;
+#[cfg(all(feature = "epoll", not(feature = "io-uring"), target_os = "linux"))]
+use crate::epoll::ZmqPoller;
+#[cfg(all(feature = "io-uring", target_os = "linux"))]
+use crate::uring::ZmqPoller;
 use crate::mailbox::ZmqMailbox;
 use crate::thread_command::ZmqThreadCommand;
 
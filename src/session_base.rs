@@ -46,7 +46,7 @@
 // #include "norm_engine.hpp"
 // #include "udp_engine.hpp"
 
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
 use std::process::id;
 use std::ptr::null_mut;
 
@@ -67,9 +67,11 @@ use crate::endpoint::ZmqEndpoint;
 use crate::endpoint_uri::EndpointUriPair;
 use crate::engine::ZmqEngine;
 use crate::engine_interface::ZmqEngineInterface;
+use crate::http_connecter::{is_http_proxy, HttpConnecter};
 use crate::io_object::ZmqIoObject;
 use crate::ipc_connecter::IpcConnecter;
 use crate::message::{ZmqMessage, ZMQ_MSG_COMMAND, ZMQ_MSG_MORE, ZMQ_MSG_ROUTING_ID};
+use crate::metadata::ZmqMetadata;
 use crate::own::ZmqOwn;
 use crate::pgm_receiver::ZmqPgmReceiver;
 // use crate::pgm_sender::pgm_sender_t;
@@ -77,11 +79,15 @@ use crate::pipe::PipeState::active;
 use crate::pipe::ZmqPipe;
 use crate::radio_session::RadioSession;
 use crate::req::ReqSession;
+use crate::quic_connecter::QuicConnecter;
+use crate::shm_connecter::ShmConnecter;
 use crate::socket::ZmqSocket;
 use crate::socks_connecter::ZmqSocksConnector;
 use crate::tcp_connecter::ZmqTcpConnector;
 use crate::thread_context::ZmqThreadContext;
 use crate::tipc_connecter::ZmqTipcConnecter;
+use crate::tls_connecter::TlsConnecter;
+use crate::vsock_connecter::VsockConnecter;
 use crate::transport::ZmqTransport;
 use crate::vmci_connecter::ZmqVmciConnecter;
 use crate::ws_connecter::ZmqWsConnecter;
@@ -137,6 +143,9 @@ pub struct ZmqSessionBase<'a> {
     // #endif
     // // ZMQ_NON_COPYABLE_NOR_MOVABLE (ZmqSessionBase)
     pub reset_fn: Option<fn()>,
+    //  What the transport knows about the peer (e.g. its TLS certificate),
+    //  set by the engine and attached to ZAP requests.
+    pub peer_properties: HashMap<String, String>,
 }
 
 impl<'a> ZmqSessionBase<'a> {
@@ -186,6 +195,7 @@ impl<'a> ZmqSessionBase<'a> {
             addr: addr.clone(),
             wss_hostname: options.wss_hostname.clone(),
             reset_fn: None,
+            peer_properties: HashMap::new(),
         }
     }
 
@@ -393,6 +403,9 @@ impl<'a> ZmqSessionBase<'a> {
     //  The function takes ownership of the message.
     // int write_zap_msg (msg: &mut ZmqMessage);
     pub fn write_zap_msg(&mut self, msg: &mut ZmqMessage) -> anyhow::Result<()> {
+        if !self.peer_properties.is_empty() {
+            msg.set_metadata(&mut ZmqMetadata::new(&mut self.peer_properties));
+        }
         if (self.zap_pipe.is_none() || !self.zap_pipe.unwrap().write(msg)) {
             // errno = ENOTCONN;
             // return -1;
@@ -813,7 +826,9 @@ impl<'a> ZmqSessionBase<'a> {
         //  Create the connecter object.
         ZmqOwn * connecter = null_mut();
         if (_addr.protocol == ZmqTransport::ZmqTcp) {
-            if (!options.socks_proxy_address.empty()) {
+            if (is_http_proxy(&options.socks_proxy_address)) {
+                connecter = HttpConnecter::new(options, io_thread, this, _addr, wait_);
+            } else if (!options.socks_proxy_address.empty()) {
                 let mut proxy_address = ZmqAddress::new(
                     ZmqTransport::ZmqTcp,
                     self.options.socks_proxy_address,
@@ -838,6 +853,18 @@ impl<'a> ZmqSessionBase<'a> {
             connecter = IpcConnecter::new(options, io_thread, this, _addr, wait_);
         }
         // #endif
+        else if (_addr.protocol == ZmqTransport::ZmqShm) {
+            connecter = ShmConnecter::new(options, io_thread, this, _addr, wait_);
+        }
+        else if (_addr.protocol == ZmqTransport::ZmqQuic) {
+            connecter = QuicConnecter::new(options, io_thread, this, _addr, wait_);
+        }
+        else if (_addr.protocol == ZmqTransport::ZmqTls) {
+            connecter = TlsConnecter::new(options, io_thread, this, _addr, wait_);
+        }
+        else if (_addr.protocol == ZmqTransport::ZmqVsock) {
+            connecter = VsockConnecter::new(options, io_thread, this, _addr, wait_);
+        }
         // #if defined ZMQ_HAVE_TIPC
         else if (_addr.protocol == ZmqTransport::ZmqTipc) {
             connecter = ZmqTipcConnecter::new(io_thread, this, options, _addr, wait_);
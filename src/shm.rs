@@ -0,0 +1,173 @@
+//  Listening side of the shm:// transport.
+//
+//  Peers meet on a UNIX stream socket ("rendezvous socket") named after
+//  the endpoint: "shm:///run/app.shm" binds that filesystem path, any other
+//  name ("shm://feed") lives in the abstract namespace as "\0zmq-shm/feed".
+//  The connecting side hands over the shared memory rings and doorbells
+//  (see shm_ring.rs) and from then on messages travel through the rings
+//  only. The rendezvous socket is kept open to notice the peer going away.
+
+use std::mem;
+
+use anyhow::bail;
+use libc::{
+    accept4, bind, c_int, close, listen, sockaddr, sockaddr_un, socket, socklen_t, unlink,
+    AF_UNIX, SOCK_CLOEXEC, SOCK_NONBLOCK, SOCK_STREAM,
+};
+
+use crate::defines::{ZmqFileDesc, RETIRED_FD};
+use crate::endpoint::make_unconnected_bind_endpoint_pair;
+use crate::endpoint::EndpointType::Bind;
+use crate::endpoint_uri::EndpointUriPair;
+use crate::engine::ZmqEngine;
+use crate::listener::ZmqListener;
+use crate::session_base::ZmqSessionBase;
+use crate::shm_engine::{shm_init, ZmqShmState};
+
+pub fn shm_resolve_address(name: &str) -> anyhow::Result<(sockaddr_un, socklen_t)> {
+    let path: Vec<u8> = if name.starts_with('/') {
+        name.as_bytes().to_vec()
+    } else {
+        [b"\0zmq-shm/".as_slice(), name.as_bytes()].concat()
+    };
+
+    let mut address: sockaddr_un = unsafe { mem::zeroed() };
+    if name.is_empty() || path.len() >= address.sun_path.len() {
+        bail!("EINVAL: invalid shm endpoint {}", name);
+    }
+    address.sun_family = AF_UNIX as libc::sa_family_t;
+    for (dst, src) in address.sun_path.iter_mut().zip(path.iter()) {
+        *dst = *src as libc::c_char;
+    }
+    //  Abstract names are not NUL terminated; their length is part of the
+    //  address.
+    let len = mem::size_of::<libc::sa_family_t>() + path.len() + (name.starts_with('/') as usize);
+    Ok((address, len as socklen_t))
+}
+
+pub fn shm_set_local_address(listener: &mut ZmqListener, addr: &mut str) -> anyhow::Result<()> {
+    let (address, address_len) = shm_resolve_address(addr)?;
+
+    //  Get rid of a rendezvous socket left behind by a previous run.
+    let has_file = addr.starts_with('/');
+    if has_file {
+        let path = std::ffi::CString::new(addr.to_string())?;
+        unsafe { unlink(path.as_ptr()) };
+    }
+
+    let fd = unsafe { socket(AF_UNIX, SOCK_STREAM | SOCK_CLOEXEC | SOCK_NONBLOCK, 0) };
+    if fd < 0 {
+        bail!("failed to open socket: {}", std::io::Error::last_os_error());
+    }
+    listener.fd = fd as ZmqFileDesc;
+
+    let rc = unsafe {
+        bind(
+            fd,
+            &address as *const sockaddr_un as *const sockaddr,
+            address_len,
+        )
+    };
+    if rc != 0 {
+        let err = std::io::Error::last_os_error();
+        unsafe { close(fd) };
+        listener.fd = RETIRED_FD as ZmqFileDesc;
+        bail!("failed to bind socket: {}", err);
+    }
+
+    if unsafe { listen(fd, listener.socket.context.backlog) } != 0 {
+        let err = std::io::Error::last_os_error();
+        unsafe { close(fd) };
+        listener.fd = RETIRED_FD as ZmqFileDesc;
+        bail!("failed to listen on socket: {}", err);
+    }
+
+    listener.endpoint = format!("shm://{}", addr);
+    if has_file {
+        listener.filename = addr.to_string();
+        listener.has_file = true;
+    }
+    listener.socket.event_listening(
+        &make_unconnected_bind_endpoint_pair(&listener.endpoint),
+        listener.fd,
+    );
+    Ok(())
+}
+
+pub fn shm_in_event(listener: &mut ZmqListener) -> anyhow::Result<()> {
+    let fd = shm_accept(listener)?;
+
+    //  If connection was reset by the peer in the meantime, just ignore it.
+    if fd == RETIRED_FD as ZmqFileDesc {
+        listener
+            .socket
+            .event_accept_failed(&make_unconnected_bind_endpoint_pair(&listener.endpoint), -1);
+        return Ok(());
+    }
+
+    shm_create_engine(listener, fd)
+}
+
+pub fn shm_accept(listener: &mut ZmqListener) -> anyhow::Result<ZmqFileDesc> {
+    let sock = unsafe {
+        accept4(
+            listener.fd as c_int,
+            std::ptr::null_mut(),
+            std::ptr::null_mut(),
+            SOCK_CLOEXEC | SOCK_NONBLOCK,
+        )
+    };
+    if sock < 0 {
+        return Ok(RETIRED_FD as ZmqFileDesc);
+    }
+    Ok(sock as ZmqFileDesc)
+}
+
+pub fn shm_close(listener: &mut ZmqListener) -> anyhow::Result<()> {
+    let fd_for_event = listener.fd;
+    let mut rc = unsafe { close(listener.fd as c_int) };
+    listener.fd = RETIRED_FD as ZmqFileDesc;
+
+    if rc == 0 && listener.has_file {
+        let path = std::ffi::CString::new(listener.filename.clone())?;
+        rc = unsafe { unlink(path.as_ptr()) };
+    }
+    if rc != 0 {
+        listener
+            .socket
+            .event_close_failed(&make_unconnected_bind_endpoint_pair(&listener.endpoint), -1);
+        bail!("failed to close socket")
+    }
+
+    listener.socket.event_closed(
+        &make_unconnected_bind_endpoint_pair(&listener.endpoint),
+        fd_for_event,
+    );
+    Ok(())
+}
+
+pub fn shm_create_engine(listener: &mut ZmqListener, fd: ZmqFileDesc) -> anyhow::Result<()> {
+    let endpoint_pair = EndpointUriPair::new(&listener.endpoint, "", Bind);
+
+    let mut engine = ZmqEngine::default();
+    shm_init(
+        &mut engine,
+        fd,
+        &listener.address,
+        ZmqShmState::Accepting,
+        endpoint_pair.clone(),
+    );
+
+    //  Choose I/O thread to run the session in. Given that we are already
+    //  running in an I/O thread, there must be at least one available.
+    let io_thread = listener.choose_io_thread(listener.socket.context.affinity).unwrap();
+
+    //  Create and launch a session object.
+    let mut session = ZmqSessionBase::create(io_thread, false, listener.socket, None)?;
+    session.inc_seqnum();
+    listener.own.launch_child(session);
+    listener.own.send_attach(&mut session, engine, false);
+
+    listener.socket.event_accepted(&endpoint_pair, fd);
+    Ok(())
+}
@@ -0,0 +1,189 @@
+use anyhow::anyhow;
+use libc::{
+    c_int, connect, sockaddr, socket, AF_UNIX, EAGAIN, ECONNREFUSED, ENOENT, SOCK_CLOEXEC,
+    SOCK_NONBLOCK, SOCK_STREAM,
+};
+
+use crate::address::ZmqAddress;
+use crate::context::ZmqContext;
+use crate::defines::{
+    ZmqFileDesc, RETIRED_FD, ZMQ_RECONNECT_STOP_AFTER_DISCONNECT, ZMQ_RECONNECT_STOP_CONN_REFUSED,
+};
+use crate::endpoint::EndpointType::Connect;
+use crate::endpoint_uri::EndpointUriPair;
+use crate::engine::ZmqEngine;
+use crate::err::ZmqError;
+use crate::session_base::ZmqSessionBase;
+use crate::shm::shm_resolve_address;
+use crate::shm_engine::{shm_init, ZmqShmState};
+use crate::shm_ring::{ZmqShmHello, ZmqShmOffer};
+use crate::stream_connecter_base::StreamConnecterBase;
+use crate::thread_context::ZmqThreadContext;
+
+//  Connects to an shm:// listener and hands it the rings of the new
+//  connection; see shm.rs.
+pub struct ShmConnecter<'a> {
+    pub base: StreamConnecterBase<'a>,
+    //  What we tell the listener about ourselves.
+    pub hello: ZmqShmHello,
+    pub reconnect_stop: i32,
+}
+
+impl<'a> ShmConnecter<'a> {
+    //  If 'delayed_start' is true connecter first waits for a while,
+    //  then starts connection process.
+    pub fn new(
+        ctx: &mut ZmqContext,
+        io_thread_: &mut ZmqThreadContext,
+        session: &mut ZmqSessionBase,
+        addr: &mut ZmqAddress,
+        delayed_start_: bool,
+    ) -> Self {
+        // zmq_assert (_addr.protocol == ZmqTransport::ZmqShm);
+        Self {
+            base: StreamConnecterBase::new(io_thread_, session, ctx, addr, delayed_start_),
+            hello: ZmqShmHello {
+                ring_size: ctx.shm_ring_size,
+                socket_type: ctx.type_,
+                routing_id: ctx.routing_id.as_bytes().to_vec(),
+            },
+            reconnect_stop: ctx.reconnect_stop,
+        }
+    }
+
+    //  Handlers for I/O events.
+    pub fn out_event(&mut self) -> anyhow::Result<()> {
+        let fd = self.connect();
+        self.base.rm_handle();
+
+        //  Handle the error condition by attempt to reconnect.
+        let fd = match fd {
+            Ok(fd) => fd,
+            Err(_) => {
+                self.base.close();
+                self.base.add_reconnect_timer();
+                return Ok(());
+            }
+        };
+
+        //  Hand the rings over; the engine waits for the listener's answer.
+        match ZmqShmOffer::send(fd, &self.hello) {
+            Ok(offer) => {
+                self.base._s = RETIRED_FD as ZmqFileDesc;
+                self.create_engine(fd, offer);
+            }
+            Err(_) => {
+                self.base.close();
+                self.base.add_reconnect_timer();
+            }
+        }
+        Ok(())
+    }
+
+    //  Internal function to start the actual connection establishment.
+    pub fn start_connecting(&mut self) -> anyhow::Result<()> {
+        //  Open the connecting socket.
+        match self.open() {
+            //  Connect was successful immediately.
+            Ok(_) => {
+                self.base._handle = Some(self.base.io_object.add_fd(self.base._s));
+                self.out_event()
+            }
+            //  The listener's backlog is full; wait until it accepts.
+            Err(ZmqError::InProgress(_)) => {
+                self.base._handle = Some(self.base.io_object.add_fd(self.base._s));
+                self.base.io_object.set_pollout(self.base._handle.unwrap());
+                Ok(())
+            }
+            Err(ZmqError::ConnectionRefused(_)) => {
+                self.base.close();
+                let stop = (self.reconnect_stop & ZMQ_RECONNECT_STOP_CONN_REFUSED as i32) != 0
+                    || ((self.reconnect_stop & ZMQ_RECONNECT_STOP_AFTER_DISCONNECT as i32) != 0
+                        && self.base._socket.is_disconnected());
+                if !stop {
+                    self.base.add_reconnect_timer();
+                }
+                Ok(())
+            }
+            Err(_) => {
+                self.base.close();
+                self.base.add_reconnect_timer();
+                Ok(())
+            }
+        }
+    }
+
+    //  Open the rendezvous socket and start connecting it.
+    pub fn open(&mut self) -> Result<(), ZmqError> {
+        let (address, address_len) = shm_resolve_address(&self.base._addr.addr_str)
+            .map_err(|e| ZmqError::InvalidInput(e.to_string()))?;
+
+        let fd = unsafe { socket(AF_UNIX, SOCK_STREAM | SOCK_CLOEXEC | SOCK_NONBLOCK, 0) };
+        if fd < 0 {
+            return Err(ZmqError::ConnectSocketFailed("failed to create socket".to_string()));
+        }
+        self.base._s = fd as ZmqFileDesc;
+
+        let rc = unsafe {
+            connect(
+                fd,
+                &address as *const libc::sockaddr_un as *const sockaddr,
+                address_len,
+            )
+        };
+        if rc == 0 {
+            return Ok(());
+        }
+
+        match std::io::Error::last_os_error().raw_os_error() {
+            Some(EAGAIN) => Err(ZmqError::InProgress("connect not finished yet".to_string())),
+            //  Nobody is bound to the name (yet).
+            Some(ECONNREFUSED) | Some(ENOENT) => {
+                Err(ZmqError::ConnectionRefused("connection refused".to_string()))
+            }
+            _ => Err(ZmqError::ConnectSocketFailed("failed to connect to socket".to_string())),
+        }
+    }
+
+    //  Get the file descriptor of newly created connection.
+    pub fn connect(&mut self) -> anyhow::Result<ZmqFileDesc> {
+        let mut err: c_int = 0;
+        let mut len = std::mem::size_of::<c_int>() as libc::socklen_t;
+        let rc = unsafe {
+            libc::getsockopt(
+                self.base._s as c_int,
+                libc::SOL_SOCKET,
+                libc::SO_ERROR,
+                &mut err as *mut c_int as *mut libc::c_void,
+                &mut len,
+            )
+        };
+        if rc == -1 || err != 0 {
+            return Err(anyhow!("failed to connect to socket"));
+        }
+        Ok(self.base._s)
+    }
+
+    fn create_engine(&mut self, fd: ZmqFileDesc, offer: ZmqShmOffer) {
+        let endpoint = format!("shm://{}", self.base._addr.addr_str);
+        let endpoint_pair = EndpointUriPair::new("", &endpoint, Connect);
+
+        //  Create the engine object for this connection.
+        let mut engine = ZmqEngine::default();
+        shm_init(
+            &mut engine,
+            fd,
+            self.base._addr,
+            ZmqShmState::Offered(offer),
+            endpoint_pair.clone(),
+        );
+
+        //  Attach the engine to the corresponding session object.
+        self.base.own.send_attach(self.base._session, engine, true);
+
+        //  Shut the connecter down.
+        self.base.own.terminate();
+
+        self.base._socket.event_connected(&endpoint_pair, fd);
+    }
+}
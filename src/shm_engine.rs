@@ -0,0 +1,310 @@
+//  Engine of the shm:// transport.
+//
+//  There is no ZMTP on the wire: socket types and routing ids are swapped
+//  in the rendezvous hellos, and every frame carries its own message flags
+//  (more, command, subscribe, cancel) so multipart messages and
+//  subscriptions survive unchanged. HWM works as with stream engines:
+//  input stops when the session refuses a message, which leaves the ring
+//  to fill up and stalls the sender.
+
+use std::mem;
+use std::sync::{Arc, Mutex};
+
+use anyhow::bail;
+use libc::{c_int, close};
+
+use crate::address::ZmqAddress;
+use crate::defines::{ZmqFileDesc, ZmqHandle, RETIRED_FD, ZMQ_CHANNEL};
+use crate::endpoint_uri::EndpointUriPair;
+use crate::engine::ZmqEngine;
+use crate::engine_interface::ZmqErrorReason;
+use crate::mechanism::{socket_type_string, socket_types_compatible};
+use crate::message::{ZmqMessage, ZMQ_MSG_ROUTING_ID};
+use crate::session_base::ZmqSessionBase;
+use crate::shm_ring::{ZmqShmChannel, ZmqShmHello, ZmqShmOffer};
+use crate::thread_context::ZmqThreadContext;
+
+//  Where an shm engine is in the rendezvous.
+#[derive(Debug)]
+pub enum ZmqShmState {
+    //  Listening side, waiting for the connecting side's hello.
+    Accepting,
+    //  Connecting side, waiting for the listener's hello.
+    Offered(ZmqShmOffer),
+    Ready(ZmqShmChannel),
+    Closed,
+}
+
+pub fn shm_hello(engine: &ZmqEngine) -> ZmqShmHello {
+    ZmqShmHello {
+        ring_size: engine.context.shm_ring_size,
+        socket_type: engine.context.type_,
+        routing_id: engine.context.routing_id.as_bytes().to_vec(),
+    }
+}
+
+pub fn shm_init(
+    engine: &mut ZmqEngine,
+    fd: ZmqFileDesc,
+    address: &ZmqAddress,
+    state: ZmqShmState,
+    endpoint_uri_pair: EndpointUriPair,
+) {
+    engine.fd = fd;
+    engine.address = address.clone();
+    engine.endpoint_uri_pair = endpoint_uri_pair;
+    engine.shm = Some(Arc::new(Mutex::new(state)));
+    engine.shm_bell_handle = RETIRED_FD as ZmqHandle;
+    engine.shm_tx_offset = None;
+    engine.shm_rx_msg = None;
+    engine.handshaking = true;
+    engine.has_handshake_stage = true;
+}
+
+pub fn shm_plug(
+    engine: &mut ZmqEngine,
+    io_thread: &mut ZmqThreadContext,
+    session: &mut ZmqSessionBase,
+) {
+    engine.plugged = true;
+    engine.session = Some(session);
+
+    //  Connect to I/O threads poller object. The rendezvous socket carries
+    //  the peer's hello and, later on, tells us when the peer went away.
+    engine.io_object.plug(io_thread);
+    engine.handle = engine.io_object.add_fd(engine.fd);
+    engine.io_object.set_pollin(engine.handle);
+    engine.io_error = false;
+
+    //  The peer may have sent its hello already.
+    shm_in_event(engine);
+}
+
+pub fn shm_terminate(engine: &mut ZmqEngine) {
+    shm_unplug(engine);
+    engine.shm = None;
+    engine.shm_rx_msg = None;
+    if engine.shm_tx_offset.take().is_some() {
+        let _ = engine.tx_msg.close();
+    }
+    if engine.fd != RETIRED_FD as ZmqFileDesc {
+        unsafe { close(engine.fd as c_int) };
+        engine.fd = RETIRED_FD as ZmqFileDesc;
+    }
+}
+
+fn shm_unplug(engine: &mut ZmqEngine) {
+    if !engine.plugged {
+        return;
+    }
+    engine.plugged = false;
+    engine.io_object.rm_fd(engine.handle);
+    if engine.shm_bell_handle != RETIRED_FD as ZmqHandle {
+        engine.io_object.rm_fd(engine.shm_bell_handle);
+        engine.shm_bell_handle = RETIRED_FD as ZmqHandle;
+    }
+    engine.io_object.unplug();
+}
+
+fn shm_error(engine: &mut ZmqEngine, reason: ZmqErrorReason) {
+    let handshaked = !engine.handshaking;
+    if let Some(state) = engine.shm.as_ref() {
+        *state.lock().unwrap() = ZmqShmState::Closed;
+    }
+    shm_unplug(engine);
+    if let Some(session) = engine.session.take() {
+        session.engine_error(handshaked, reason);
+    }
+    shm_terminate(engine);
+}
+
+pub fn shm_in_event(engine: &mut ZmqEngine) {
+    let Some(state) = engine.shm.clone() else {
+        return;
+    };
+    let mut state = state.lock().unwrap();
+
+    let result = match &mut *state {
+        ZmqShmState::Ready(channel) => shm_receive(engine, channel),
+        ZmqShmState::Closed => return,
+        _ => shm_handshake(engine, &mut state),
+    };
+    match result {
+        Ok(true) => {}
+        Ok(false) => {
+            drop(state);
+            shm_error(engine, ZmqErrorReason::ConnectionError);
+            return;
+        }
+        Err(e) => {
+            drop(state);
+            let error = e.to_string();
+            let reason = if error.starts_with("EPROTO") || error.starts_with("EMSGSIZE") {
+                ZmqErrorReason::ProtocolError
+            } else {
+                ZmqErrorReason::ConnectionError
+            };
+            shm_error(engine, reason);
+            return;
+        }
+    }
+
+    //  The doorbell also rings when the peer made room in the ring.
+    let output_pending = engine.shm_tx_offset.is_some();
+    drop(state);
+    if output_pending {
+        shm_out_event(engine);
+    }
+}
+
+//  Completes the rendezvous once the peer's hello is in. Like
+//  shm_receive, returns false once the connection is gone.
+fn shm_handshake(engine: &mut ZmqEngine, state: &mut ZmqShmState) -> anyhow::Result<bool> {
+    let mut channel = match state {
+        ZmqShmState::Accepting => {
+            match ZmqShmChannel::accept(engine.fd, &shm_hello(engine))? {
+                Some(channel) => channel,
+                None => return Ok(true),
+            }
+        }
+        ZmqShmState::Offered(offer) => {
+            let peer = match offer.poll_reply()? {
+                Some(peer) => peer,
+                None => return Ok(true),
+            };
+            match mem::replace(state, ZmqShmState::Closed) {
+                ZmqShmState::Offered(offer) => offer.into_channel(peer),
+                _ => unreachable!(),
+            }
+        }
+        _ => return Ok(true),
+    };
+
+    channel.set_max_msg_size(engine.context.maxmsgsize);
+
+    let peer_type = channel.peer.socket_type;
+    if !(0..=ZMQ_CHANNEL).contains(&peer_type)
+        || !socket_types_compatible(engine.context.type_, &socket_type_string(peer_type))
+    {
+        bail!("EPROTO: incompatible shm peer socket type {}", peer_type);
+    }
+
+    //  From now on the doorbell tells us about data and free space.
+    engine.shm_bell_handle = engine.io_object.add_fd(channel.bell.fd);
+    engine.io_object.set_pollin(engine.shm_bell_handle);
+    engine.handshaking = false;
+
+    let session = engine.session.as_mut().unwrap();
+    if engine.context.recv_routing_id {
+        let mut routing_id = ZmqMessage::default();
+        routing_id.init_size(channel.peer.routing_id.len())?;
+        routing_id
+            .data_mut()
+            .copy_from_slice(&channel.peer.routing_id);
+        routing_id.set_flags(ZMQ_MSG_ROUTING_ID);
+        session.push_msg(&mut routing_id)?;
+        session.flush()?;
+    }
+    session.engine_ready();
+
+    *state = ZmqShmState::Ready(channel);
+    if let ZmqShmState::Ready(channel) = state {
+        return shm_receive(engine, channel);
+    }
+    Ok(true)
+}
+
+//  Moves frames from the ring into the session until either runs dry.
+//  Returns false once the peer is gone and everything it sent has been
+//  delivered.
+fn shm_receive(engine: &mut ZmqEngine, channel: &mut ZmqShmChannel) -> anyhow::Result<bool> {
+    channel.bell.drain();
+    if engine.input_stopped {
+        return Ok(true);
+    }
+
+    let session = engine.session.as_mut().unwrap();
+    loop {
+        let mut msg = match engine.shm_rx_msg.take() {
+            Some(msg) => msg,
+            None => match channel.read_frame()? {
+                Some((flags, data)) => {
+                    let mut msg = ZmqMessage::default();
+                    msg.init_bytes(data)?;
+                    msg.set_flags(flags);
+                    msg
+                }
+                None => break,
+            },
+        };
+        if session.push_msg(&mut msg).is_err() {
+            //  The pipe is full. Hold on to the message and stop reading;
+            //  the writer stalls once the ring fills up behind it. The
+            //  doorbell stays armed as it also reports free space for our
+            //  own output.
+            engine.shm_rx_msg = Some(msg);
+            engine.input_stopped = true;
+            engine.io_object.reset_pollin(engine.handle);
+            break;
+        }
+    }
+    session.flush()?;
+
+    Ok(engine.input_stopped || !channel.peer_closed())
+}
+
+pub fn shm_restart_input(engine: &mut ZmqEngine) -> bool {
+    if !engine.input_stopped {
+        return true;
+    }
+    engine.input_stopped = false;
+    engine.io_object.set_pollin(engine.handle);
+    shm_in_event(engine);
+    engine.plugged
+}
+
+pub fn shm_out_event(engine: &mut ZmqEngine) {
+    let Some(state) = engine.shm.clone() else {
+        return;
+    };
+    let mut state = state.lock().unwrap();
+    let ZmqShmState::Ready(channel) = &mut *state else {
+        return;
+    };
+
+    let session = engine.session.as_mut().unwrap();
+    loop {
+        let mut offset = match engine.shm_tx_offset.take() {
+            Some(offset) => offset,
+            None => {
+                if session.pull_msg(&mut engine.tx_msg).is_err() {
+                    engine.output_stopped = true;
+                    break;
+                }
+                0
+            }
+        };
+        let msg = &mut engine.tx_msg;
+        if !channel.write_frame(msg.flags(), msg.data(), &mut offset) {
+            //  The ring is full; the peer rings our doorbell once it has
+            //  made room.
+            engine.shm_tx_offset = Some(offset);
+            engine.output_stopped = true;
+            break;
+        }
+        let _ = msg.close();
+        msg.init2().ok();
+    }
+}
+
+pub fn shm_restart_output(engine: &mut ZmqEngine) {
+    if engine.io_error {
+        return;
+    }
+    engine.output_stopped = false;
+    shm_out_event(engine);
+}
+
+pub fn shm_get_endpoint(engine: &mut ZmqEngine) -> EndpointUriPair {
+    engine.endpoint_uri_pair.clone()
+}
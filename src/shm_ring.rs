@@ -0,0 +1,845 @@
+//  Shared memory channel used by the shm:// transport.
+//
+//  A connection owns one memfd holding two single-producer/single-consumer
+//  ring buffers, one per direction, plus one eventfd per side used as a
+//  doorbell. The memfd and both eventfds are created by the connecting side
+//  and handed to the listener over the rendezvous UNIX socket (SCM_RIGHTS).
+//  The rendezvous socket stays open for the lifetime of the connection so
+//  that either side notices when its peer goes away.
+//
+//  Each ring carries records of the form
+//
+//      [len: u32][flags: u32][released: u32][reserved: u32][payload]
+//
+//  aligned to 16 bytes. Frames up to a quarter of the ring are written as a
+//  single record; larger ones are split into fragments and reassembled by
+//  the reader. Single-record payloads of SHM_ZERO_COPY_THRESHOLD bytes or
+//  more are not copied out of the ring: the receiver gets a `Bytes` that
+//  points at the record, and the space is returned to the writer when the
+//  last handle to it is dropped. Smaller payloads are copied and released
+//  straight away. Space is reclaimed in ring order, so an application that
+//  keeps such messages around indefinitely eventually stalls the sender.
+//
+//  The doorbell is only rung when the other side announced that it is
+//  waiting, either for data (reader_waiting) or for space (writer_waiting).
+
+use std::collections::VecDeque;
+use std::mem;
+use std::ptr;
+use std::sync::atomic::{fence, AtomicU32, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use anyhow::bail;
+use bytes::{Bytes, BytesMut};
+
+use crate::defines::{ZmqFileDesc, ZMQ_SHM_RING_SIZE_MIN};
+use crate::doorbell::ZmqDoorbell;
+
+pub const SHM_MAGIC: u32 = 0x5a53_484d; // "ZSHM"
+pub const SHM_VERSION: u32 = 1;
+
+//  Payloads at least this large are handed to the receiver in place.
+pub const SHM_ZERO_COPY_THRESHOLD: usize = 8192;
+
+//  Message flags that travel with a frame: more, command and the command
+//  type bits (subscribe, cancel, ...).
+pub const SHM_FLAGS_MASK: u8 = 0x1f;
+
+const RING_HEADER_SIZE: usize = 256;
+const RECORD_HEADER_SIZE: usize = 16;
+const RECORD_ALIGN: u64 = 16;
+
+//  Record flags above the message flags byte.
+const RECORD_PAD: u32 = 1 << 8;
+const RECORD_FRAGMENT: u32 = 1 << 9;
+
+//  Longest routing id carried by the rendezvous messages.
+const MAX_ROUTING_ID_SIZE: usize = 255;
+
+#[repr(C)]
+struct ZmqShmRingHeader {
+    magic: u32,
+    version: u32,
+    capacity: u64,
+    _pad0: [u8; 48],
+    //  Written by the producer.
+    head: AtomicU64,
+    reader_waiting: AtomicU32,
+    _pad1: [u8; 52],
+    //  Written by the consumer.
+    tail: AtomicU64,
+    writer_waiting: AtomicU32,
+    _pad2: [u8; 52],
+}
+
+#[repr(C)]
+struct ZmqShmRecordHeader {
+    len: u32,
+    flags: u32,
+    released: AtomicU32,
+    _reserved: u32,
+}
+
+//  The memfd mapping shared by both rings of a connection.
+#[derive(Debug)]
+struct ZmqShmMapping {
+    base: *mut u8,
+    len: usize,
+}
+
+unsafe impl Send for ZmqShmMapping {}
+unsafe impl Sync for ZmqShmMapping {}
+
+impl ZmqShmMapping {
+    fn map(fd: ZmqFileDesc, len: usize) -> anyhow::Result<Self> {
+        let base = unsafe {
+            libc::mmap(
+                ptr::null_mut(),
+                len,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_SHARED,
+                fd as libc::c_int,
+                0,
+            )
+        };
+        if base == libc::MAP_FAILED {
+            bail!("shm: mmap failed: {}", std::io::Error::last_os_error());
+        }
+        Ok(Self {
+            base: base as *mut u8,
+            len,
+        })
+    }
+}
+
+impl Drop for ZmqShmMapping {
+    fn drop(&mut self) {
+        unsafe { libc::munmap(self.base as *mut libc::c_void, self.len) };
+    }
+}
+
+//  One direction of a connection.
+#[derive(Debug, Clone)]
+struct ZmqShmRing {
+    map: Arc<ZmqShmMapping>,
+    offset: usize,
+    capacity: u64,
+}
+
+impl ZmqShmRing {
+    fn header(&self) -> &ZmqShmRingHeader {
+        unsafe { &*(self.map.base.add(self.offset) as *const ZmqShmRingHeader) }
+    }
+
+    fn data(&self) -> *mut u8 {
+        unsafe { self.map.base.add(self.offset + RING_HEADER_SIZE) }
+    }
+
+    fn record(&self, pos: u64) -> &ZmqShmRecordHeader {
+        let at = (pos % self.capacity) as usize;
+        unsafe { &*(self.data().add(at) as *const ZmqShmRecordHeader) }
+    }
+
+    fn record_mut(&self, pos: u64) -> *mut ZmqShmRecordHeader {
+        let at = (pos % self.capacity) as usize;
+        unsafe { self.data().add(at) as *mut ZmqShmRecordHeader }
+    }
+
+    //  The caller makes sure the payload does not run past the end of the
+    //  buffer, see fits().
+    fn payload(&self, pos: u64, len: usize) -> *mut u8 {
+        debug_assert!(self.fits(pos, len));
+        let at = (pos % self.capacity) as usize + RECORD_HEADER_SIZE;
+        unsafe { self.data().add(at) }
+    }
+
+    //  Whether a record of 'len' bytes at 'pos' lies within the buffer
+    //  without wrapping. Positions are record aligned, so the header
+    //  always does.
+    fn fits(&self, pos: u64, len: usize) -> bool {
+        let at = (pos % self.capacity) as usize + RECORD_HEADER_SIZE;
+        len <= self.capacity as usize - at
+    }
+
+    //  Largest payload written as a single record.
+    fn max_record_payload(&self) -> usize {
+        self.capacity as usize / 4 - RECORD_HEADER_SIZE
+    }
+}
+
+fn record_size(len: usize) -> u64 {
+    (RECORD_HEADER_SIZE as u64 + len as u64 + RECORD_ALIGN - 1) & !(RECORD_ALIGN - 1)
+}
+
+//  Producer side of a ring.
+#[derive(Debug)]
+struct ZmqShmWriter {
+    ring: ZmqShmRing,
+    head: u64,
+    peer_bell: Arc<ZmqDoorbell>,
+}
+
+impl ZmqShmWriter {
+    //  Writes one record if there is room for it; false if the ring is
+    //  full, in which case the peer rings our doorbell once it has freed
+    //  some space.
+    fn write_record(&mut self, flags: u32, payload: &[u8]) -> bool {
+        let header = self.ring.header();
+        let size = record_size(payload.len());
+        let at = self.head % self.ring.capacity;
+        let to_end = self.ring.capacity - at;
+        let needed = if to_end < size { to_end + size } else { size };
+
+        if !self.has_room(needed) {
+            header.writer_waiting.store(1, Ordering::SeqCst);
+            fence(Ordering::SeqCst);
+            //  The reader may have released space in the meantime.
+            if !self.has_room(needed) {
+                return false;
+            }
+            header.writer_waiting.store(0, Ordering::Relaxed);
+        }
+
+        if to_end < size {
+            //  Records are contiguous; skip the tail end of the buffer.
+            unsafe {
+                let pad = self.ring.record_mut(self.head);
+                (*pad).len = (to_end as usize - RECORD_HEADER_SIZE) as u32;
+                (*pad).flags = RECORD_PAD;
+                (*pad).released.store(0, Ordering::Relaxed);
+            }
+            self.head += to_end;
+        }
+
+        unsafe {
+            ptr::copy_nonoverlapping(
+                payload.as_ptr(),
+                self.ring.payload(self.head, payload.len()),
+                payload.len(),
+            );
+            let record = self.ring.record_mut(self.head);
+            (*record).len = payload.len() as u32;
+            (*record).flags = flags;
+            (*record).released.store(0, Ordering::Relaxed);
+        }
+        self.head += size;
+        header.head.store(self.head, Ordering::Release);
+
+        fence(Ordering::SeqCst);
+        if header.reader_waiting.swap(0, Ordering::SeqCst) != 0 {
+            self.peer_bell.ring();
+        }
+        true
+    }
+
+    fn has_room(&self, needed: u64) -> bool {
+        let tail = self.ring.header().tail.load(Ordering::Acquire);
+        self.ring.capacity - (self.head - tail) >= needed
+    }
+}
+
+//  Records read but not reclaimed yet.
+#[derive(Debug)]
+struct ZmqShmTail {
+    pos: u64,
+    //  Sizes of the records from pos on, as validated when they were read;
+    //  the peer may have changed the headers since.
+    sizes: VecDeque<u64>,
+}
+
+//  State shared between the consumer and the `Bytes` handed out in place.
+#[derive(Debug)]
+struct ZmqShmReleaser {
+    ring: ZmqShmRing,
+    tail: Mutex<ZmqShmTail>,
+    peer_bell: Arc<ZmqDoorbell>,
+}
+
+impl ZmqShmReleaser {
+    //  Notes a record of 'size' bytes as read; it is reclaimed once
+    //  released.
+    fn consumed(&self, size: u64) {
+        self.tail.lock().unwrap().sizes.push_back(size);
+    }
+
+    fn release(&self, pos: u64) {
+        self.ring.record(pos).released.store(1, Ordering::Release);
+        self.reclaim();
+    }
+
+    //  Advances the tail over every released record at its front.
+    fn reclaim(&self) {
+        let header = self.ring.header();
+        let mut tail = self.tail.lock().unwrap();
+        let start = tail.pos;
+        while let Some(&size) = tail.sizes.front() {
+            if self.ring.record(tail.pos).released.load(Ordering::Acquire) == 0 {
+                break;
+            }
+            tail.pos += size;
+            tail.sizes.pop_front();
+        }
+        if tail.pos == start {
+            return;
+        }
+        header.tail.store(tail.pos, Ordering::Release);
+
+        fence(Ordering::SeqCst);
+        if header.writer_waiting.swap(0, Ordering::SeqCst) != 0 {
+            self.peer_bell.ring();
+        }
+    }
+}
+
+//  Keeps a record alive while a message refers to it.
+struct ZmqShmRecordOwner {
+    releaser: Arc<ZmqShmReleaser>,
+    pos: u64,
+    data: *const u8,
+    len: usize,
+}
+
+unsafe impl Send for ZmqShmRecordOwner {}
+unsafe impl Sync for ZmqShmRecordOwner {}
+
+impl AsRef<[u8]> for ZmqShmRecordOwner {
+    fn as_ref(&self) -> &[u8] {
+        unsafe { std::slice::from_raw_parts(self.data, self.len) }
+    }
+}
+
+impl Drop for ZmqShmRecordOwner {
+    fn drop(&mut self) {
+        self.releaser.release(self.pos);
+    }
+}
+
+//  Consumer side of a ring.
+#[derive(Debug)]
+struct ZmqShmReader {
+    releaser: Arc<ZmqShmReleaser>,
+    read_pos: u64,
+    partial: Option<BytesMut>,
+    //  ZMQ_MAXMSGSIZE, -1 for no limit.
+    max_msg_size: i64,
+}
+
+impl ZmqShmReader {
+    //  Returns the next complete frame, or None if the ring is empty. When
+    //  None is returned the writer rings our doorbell on its next write.
+    fn read_frame(&mut self) -> anyhow::Result<Option<(u8, Bytes)>> {
+        loop {
+            let ring = &self.releaser.ring;
+            let header = ring.header();
+            let mut head = header.head.load(Ordering::Acquire);
+            if self.read_pos == head {
+                header.reader_waiting.store(1, Ordering::SeqCst);
+                fence(Ordering::SeqCst);
+                head = header.head.load(Ordering::Acquire);
+                if self.read_pos == head {
+                    return Ok(None);
+                }
+                header.reader_waiting.store(0, Ordering::Relaxed);
+            }
+
+            //  The header is written by the peer: read it once and check it
+            //  before going anywhere near the payload.
+            let pos = self.read_pos;
+            let record = ring.record(pos);
+            let len = record.len as usize;
+            let flags = record.flags;
+            if head < pos || !ring.fits(pos, len) || record_size(len) > head - pos {
+                bail!("EPROTO: corrupt shm record");
+            }
+            let size = record_size(len);
+            self.read_pos += size;
+            self.releaser.consumed(size);
+
+            if (flags & RECORD_PAD) != 0 {
+                self.releaser.release(pos);
+                continue;
+            }
+
+            let msg_flags = (flags as u8) & SHM_FLAGS_MASK;
+            let payload = ring.payload(pos, len);
+
+            let frame_size = self.partial.as_ref().map_or(0, |partial| partial.len()) + len;
+            if self.max_msg_size >= 0 && frame_size as u64 > self.max_msg_size as u64 {
+                self.releaser.release(pos);
+                self.partial = None;
+                bail!("EMSGSIZE: shm frame larger than ZMQ_MAXMSGSIZE");
+            }
+
+            if (flags & RECORD_FRAGMENT) != 0 || self.partial.is_some() {
+                let partial = self.partial.get_or_insert_with(BytesMut::new);
+                partial.extend_from_slice(unsafe { std::slice::from_raw_parts(payload, len) });
+                self.releaser.release(pos);
+                if (flags & RECORD_FRAGMENT) != 0 {
+                    continue;
+                }
+                let frame = self.partial.take().unwrap().freeze();
+                return Ok(Some((msg_flags, frame)));
+            }
+
+            if len >= SHM_ZERO_COPY_THRESHOLD {
+                let owner = ZmqShmRecordOwner {
+                    releaser: self.releaser.clone(),
+                    pos,
+                    data: payload,
+                    len,
+                };
+                return Ok(Some((msg_flags, Bytes::from_owner(owner))));
+            }
+
+            let frame = Bytes::copy_from_slice(unsafe { std::slice::from_raw_parts(payload, len) });
+            self.releaser.release(pos);
+            return Ok(Some((msg_flags, frame)));
+        }
+    }
+}
+
+//  What each side tells the other during the rendezvous.
+#[derive(Default, Debug, Clone)]
+pub struct ZmqShmHello {
+    pub ring_size: u64,
+    pub socket_type: i32,
+    pub routing_id: Vec<u8>,
+}
+
+impl ZmqShmHello {
+    const SIZE: usize = 4 + 4 + 8 + 4 + 1 + MAX_ROUTING_ID_SIZE;
+
+    fn encode(&self) -> [u8; Self::SIZE] {
+        let mut buf = [0u8; Self::SIZE];
+        let id_len = self.routing_id.len().min(MAX_ROUTING_ID_SIZE);
+        buf[0..4].copy_from_slice(&SHM_MAGIC.to_le_bytes());
+        buf[4..8].copy_from_slice(&SHM_VERSION.to_le_bytes());
+        buf[8..16].copy_from_slice(&self.ring_size.to_le_bytes());
+        buf[16..20].copy_from_slice(&self.socket_type.to_le_bytes());
+        buf[20] = id_len as u8;
+        buf[21..21 + id_len].copy_from_slice(&self.routing_id[..id_len]);
+        buf
+    }
+
+    fn decode(buf: &[u8]) -> anyhow::Result<Self> {
+        if buf.len() != Self::SIZE
+            || buf[0..4] != SHM_MAGIC.to_le_bytes()
+            || buf[4..8] != SHM_VERSION.to_le_bytes()
+        {
+            bail!("EPROTO: invalid shm hello");
+        }
+        let id_len = buf[20] as usize;
+        Ok(Self {
+            ring_size: u64::from_le_bytes(buf[8..16].try_into().unwrap()),
+            socket_type: i32::from_le_bytes(buf[16..20].try_into().unwrap()),
+            routing_id: buf[21..21 + id_len].to_vec(),
+        })
+    }
+}
+
+//  Connecting side of a connection whose rings have been handed to the
+//  listener but which has not seen the listener's hello yet.
+#[derive(Debug)]
+pub struct ZmqShmOffer {
+    conn: ZmqFileDesc,
+    map: Arc<ZmqShmMapping>,
+    ring_size: usize,
+    own_bell: ZmqDoorbell,
+    peer_bell: ZmqDoorbell,
+}
+
+impl ZmqShmOffer {
+    //  Creates the rings and doorbells and sends them, along with our
+    //  hello, over the freshly connected rendezvous socket.
+    pub fn send(conn: ZmqFileDesc, hello: &ZmqShmHello) -> anyhow::Result<Self> {
+        let ring_size = (hello.ring_size as usize)
+            .max(ZMQ_SHM_RING_SIZE_MIN as usize)
+            .next_power_of_two();
+        let len = 2 * (RING_HEADER_SIZE + ring_size);
+
+        let memfd = unsafe {
+            libc::memfd_create(c"zmq-shm".as_ptr(), libc::MFD_CLOEXEC)
+        };
+        if memfd < 0 {
+            bail!("shm: memfd_create failed: {}", std::io::Error::last_os_error());
+        }
+        let result = (|| {
+            if unsafe { libc::ftruncate(memfd, len as libc::off_t) } != 0 {
+                bail!("shm: ftruncate failed: {}", std::io::Error::last_os_error());
+            }
+            let map = Arc::new(ZmqShmMapping::map(memfd as ZmqFileDesc, len)?);
+            for i in 0..2 {
+                let header = unsafe {
+                    &mut *(map.base.add(i * (RING_HEADER_SIZE + ring_size)) as *mut ZmqShmRingHeader)
+                };
+                header.magic = SHM_MAGIC;
+                header.version = SHM_VERSION;
+                header.capacity = ring_size as u64;
+            }
+
+            let own_bell = ZmqDoorbell::new()?;
+            let peer_bell = ZmqDoorbell::new()?;
+
+            let mut out_hello = hello.clone();
+            out_hello.ring_size = ring_size as u64;
+            send_with_fds(
+                conn,
+                &out_hello.encode(),
+                &[memfd as ZmqFileDesc, peer_bell.fd, own_bell.fd],
+            )?;
+            Ok(Self {
+                conn,
+                map,
+                ring_size,
+                own_bell,
+                peer_bell,
+            })
+        })();
+        unsafe { libc::close(memfd) };
+        result
+    }
+
+    //  Returns the listener's hello once it has arrived.
+    pub fn poll_reply(&self) -> anyhow::Result<Option<ZmqShmHello>> {
+        match recv_with_fds(self.conn, ZmqShmHello::SIZE, 0)? {
+            Some((buf, _)) => Ok(Some(ZmqShmHello::decode(&buf)?)),
+            None => Ok(None),
+        }
+    }
+
+    pub fn into_channel(self, peer: ZmqShmHello) -> ZmqShmChannel {
+        //  Ring 0 carries connecter -> listener traffic.
+        ZmqShmChannel::new(
+            self.conn,
+            self.map,
+            self.ring_size,
+            0,
+            self.own_bell,
+            self.peer_bell,
+            peer,
+        )
+    }
+}
+
+//  Both directions of an established shm connection.
+#[derive(Debug)]
+pub struct ZmqShmChannel {
+    //  Rendezvous socket, kept open to detect the peer going away. It is
+    //  owned, and eventually closed, by the engine.
+    pub conn: ZmqFileDesc,
+    //  Our doorbell; poll it for input and for space freed by the peer.
+    pub bell: ZmqDoorbell,
+    pub peer: ZmqShmHello,
+    writer: ZmqShmWriter,
+    reader: ZmqShmReader,
+}
+
+impl ZmqShmChannel {
+    //  Listening side: picks up the rings and doorbells sent by the
+    //  connecting side and answers with our hello. Returns None if the
+    //  connecting side's hello has not arrived yet.
+    pub fn accept(conn: ZmqFileDesc, hello: &ZmqShmHello) -> anyhow::Result<Option<Self>> {
+        let (buf, fds) = match recv_with_fds(conn, ZmqShmHello::SIZE, 3)? {
+            Some(received) => received,
+            None => return Ok(None),
+        };
+        let close_fds = || {
+            for fd in &fds {
+                unsafe { libc::close(*fd as libc::c_int) };
+            }
+        };
+        let peer = match ZmqShmHello::decode(&buf) {
+            Ok(peer) if fds.len() == 3 => peer,
+            Ok(_) => {
+                close_fds();
+                bail!("EPROTO: shm hello without descriptors");
+            }
+            Err(e) => {
+                close_fds();
+                return Err(e);
+            }
+        };
+        let ring_size = peer.ring_size as usize;
+        let own_bell = ZmqDoorbell { fd: fds[1] };
+        let peer_bell = ZmqDoorbell { fd: fds[2] };
+        if ring_size < ZMQ_SHM_RING_SIZE_MIN as usize || !ring_size.is_power_of_two() {
+            unsafe { libc::close(fds[0] as libc::c_int) };
+            bail!("EPROTO: invalid shm ring size {}", ring_size);
+        }
+
+        let map = ZmqShmMapping::map(fds[0], 2 * (RING_HEADER_SIZE + ring_size));
+        unsafe { libc::close(fds[0] as libc::c_int) };
+        let map = Arc::new(map?);
+        for i in 0..2 {
+            let header = unsafe {
+                &*(map.base.add(i * (RING_HEADER_SIZE + ring_size)) as *const ZmqShmRingHeader)
+            };
+            if header.magic != SHM_MAGIC
+                || header.capacity != ring_size as u64
+                || header.head.load(Ordering::Acquire) % RECORD_ALIGN != 0
+                || header.tail.load(Ordering::Acquire) % RECORD_ALIGN != 0
+            {
+                bail!("EPROTO: invalid shm ring header");
+            }
+        }
+
+        let mut out_hello = hello.clone();
+        out_hello.ring_size = ring_size as u64;
+        send_with_fds(conn, &out_hello.encode(), &[])?;
+
+        Ok(Some(Self::new(conn, map, ring_size, 1, own_bell, peer_bell, peer)))
+    }
+
+    fn new(
+        conn: ZmqFileDesc,
+        map: Arc<ZmqShmMapping>,
+        ring_size: usize,
+        out_ring: usize,
+        own_bell: ZmqDoorbell,
+        peer_bell: ZmqDoorbell,
+        peer: ZmqShmHello,
+    ) -> Self {
+        let ring = |i: usize| ZmqShmRing {
+            map: map.clone(),
+            offset: i * (RING_HEADER_SIZE + ring_size),
+            capacity: ring_size as u64,
+        };
+        let peer_bell = Arc::new(peer_bell);
+        let in_ring = ring(1 - out_ring);
+        let read_pos = in_ring.header().tail.load(Ordering::Acquire);
+        let out_ring = ring(out_ring);
+        let head = out_ring.header().head.load(Ordering::Acquire);
+        Self {
+            conn,
+            bell: own_bell,
+            peer,
+            writer: ZmqShmWriter {
+                ring: out_ring,
+                head,
+                peer_bell: peer_bell.clone(),
+            },
+            reader: ZmqShmReader {
+                releaser: Arc::new(ZmqShmReleaser {
+                    ring: in_ring,
+                    tail: Mutex::new(ZmqShmTail {
+                        pos: read_pos,
+                        sizes: VecDeque::new(),
+                    }),
+                    peer_bell,
+                }),
+                read_pos,
+                partial: None,
+                max_msg_size: -1,
+            },
+        }
+    }
+
+    //  Writes the frame starting at `*offset`. Returns true once the whole
+    //  frame is in the ring; false if the ring filled up first, in which
+    //  case `*offset` tells where to resume.
+    pub fn write_frame(&mut self, flags: u8, data: &[u8], offset: &mut usize) -> bool {
+        let flags = (flags & SHM_FLAGS_MASK) as u32;
+        let max = self.writer.ring.max_record_payload();
+        if data.len() <= max && *offset == 0 {
+            return self.writer.write_record(flags, data);
+        }
+        while *offset < data.len() {
+            let end = (*offset + max).min(data.len());
+            let record_flags = if end < data.len() {
+                flags | RECORD_FRAGMENT
+            } else {
+                flags
+            };
+            if !self.writer.write_record(record_flags, &data[*offset..end]) {
+                return false;
+            }
+            *offset = end;
+        }
+        true
+    }
+
+    pub fn read_frame(&mut self) -> anyhow::Result<Option<(u8, Bytes)>> {
+        self.reader.read_frame()
+    }
+
+    //  Frames longer than this fail read_frame with EMSGSIZE; -1 for no
+    //  limit.
+    pub fn set_max_msg_size(&mut self, max_msg_size: i64) {
+        self.reader.max_msg_size = max_msg_size;
+    }
+
+    //  True once the peer has closed its end of the rendezvous socket.
+    pub fn peer_closed(&self) -> bool {
+        let mut byte = 0u8;
+        let rc = unsafe {
+            libc::recv(
+                self.conn as libc::c_int,
+                &mut byte as *mut u8 as *mut libc::c_void,
+                1,
+                libc::MSG_PEEK | libc::MSG_DONTWAIT,
+            )
+        };
+        rc == 0 || (rc < 0 && !would_block())
+    }
+}
+
+impl Drop for ZmqShmChannel {
+    fn drop(&mut self) {
+        //  Wake the peer so that it notices the hang-up.
+        self.writer.peer_bell.ring();
+    }
+}
+
+fn would_block() -> bool {
+    matches!(
+        std::io::Error::last_os_error().raw_os_error(),
+        Some(libc::EAGAIN) | Some(libc::EINTR)
+    )
+}
+
+fn send_with_fds(conn: ZmqFileDesc, data: &[u8], fds: &[ZmqFileDesc]) -> anyhow::Result<()> {
+    let fds: Vec<libc::c_int> = fds.iter().map(|fd| *fd as libc::c_int).collect();
+    let fds_len = mem::size_of_val(fds.as_slice()) as u32;
+    let mut cmsg_buf = vec![0u8; unsafe { libc::CMSG_SPACE(fds_len.max(1)) } as usize];
+    let mut iov = libc::iovec {
+        iov_base: data.as_ptr() as *mut libc::c_void,
+        iov_len: data.len(),
+    };
+    let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    if !fds.is_empty() {
+        msg.msg_control = cmsg_buf.as_mut_ptr() as *mut libc::c_void;
+        msg.msg_controllen = unsafe { libc::CMSG_SPACE(fds_len) } as _;
+        unsafe {
+            let cmsg = libc::CMSG_FIRSTHDR(&msg);
+            (*cmsg).cmsg_level = libc::SOL_SOCKET;
+            (*cmsg).cmsg_type = libc::SCM_RIGHTS;
+            (*cmsg).cmsg_len = libc::CMSG_LEN(fds_len) as _;
+            ptr::copy_nonoverlapping(
+                fds.as_ptr(),
+                libc::CMSG_DATA(cmsg) as *mut libc::c_int,
+                fds.len(),
+            );
+        }
+    }
+    //  The hello is far below the socket buffer size and the socket is
+    //  fresh, so a non-blocking send goes through in one piece.
+    let rc = unsafe {
+        libc::sendmsg(conn as libc::c_int, &msg, libc::MSG_NOSIGNAL | libc::MSG_DONTWAIT)
+    };
+    if rc != data.len() as isize {
+        bail!("shm: sendmsg failed: {}", std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+//  Receives one hello and any descriptors attached to it; None if nothing
+//  has arrived yet.
+fn recv_with_fds(
+    conn: ZmqFileDesc,
+    size: usize,
+    max_fds: usize,
+) -> anyhow::Result<Option<(Vec<u8>, Vec<ZmqFileDesc>)>> {
+    let mut buf = vec![0u8; size];
+    let fds_len = (max_fds.max(1) * mem::size_of::<libc::c_int>()) as u32;
+    let mut cmsg_buf = vec![0u8; unsafe { libc::CMSG_SPACE(fds_len) } as usize];
+    let mut iov = libc::iovec {
+        iov_base: buf.as_mut_ptr() as *mut libc::c_void,
+        iov_len: size,
+    };
+    let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = cmsg_buf.as_mut_ptr() as *mut libc::c_void;
+    msg.msg_controllen = cmsg_buf.len() as _;
+
+    let rc = unsafe {
+        libc::recvmsg(
+            conn as libc::c_int,
+            &mut msg,
+            libc::MSG_CMSG_CLOEXEC | libc::MSG_DONTWAIT,
+        )
+    };
+    if rc < 0 {
+        if would_block() {
+            return Ok(None);
+        }
+        bail!("shm: recvmsg failed: {}", std::io::Error::last_os_error());
+    }
+    if rc == 0 {
+        bail!("ECONNRESET: shm peer closed the connection");
+    }
+
+    let mut fds = Vec::new();
+    unsafe {
+        let mut cmsg = libc::CMSG_FIRSTHDR(&msg);
+        while !cmsg.is_null() {
+            if (*cmsg).cmsg_level == libc::SOL_SOCKET && (*cmsg).cmsg_type == libc::SCM_RIGHTS {
+                let count = ((*cmsg).cmsg_len as usize - libc::CMSG_LEN(0) as usize)
+                    / mem::size_of::<libc::c_int>();
+                let data = libc::CMSG_DATA(cmsg) as *const libc::c_int;
+                for i in 0..count {
+                    fds.push(*data.add(i) as ZmqFileDesc);
+                }
+            }
+            cmsg = libc::CMSG_NXTHDR(&msg, cmsg);
+        }
+    }
+    //  The peer sends its hello with a single sendmsg, so anything short
+    //  of that is a protocol error.
+    if rc as usize != size || (msg.msg_flags & libc::MSG_CTRUNC) != 0 {
+        for fd in &fds {
+            unsafe { libc::close(*fd as libc::c_int) };
+        }
+        bail!("EPROTO: short shm hello");
+    }
+    Ok(Some((buf, fds)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::fd::IntoRawFd;
+    use std::os::unix::net::UnixStream;
+
+    fn channel_pair() -> (ZmqShmChannel, ZmqShmChannel) {
+        let (a, b) = UnixStream::pair().unwrap();
+        let hello = |socket_type| ZmqShmHello {
+            ring_size: ZMQ_SHM_RING_SIZE_MIN,
+            socket_type,
+            routing_id: vec![],
+        };
+        let offer = ZmqShmOffer::send(a.into_raw_fd() as ZmqFileDesc, &hello(8)).unwrap();
+        let server = ZmqShmChannel::accept(b.into_raw_fd() as ZmqFileDesc, &hello(7))
+            .unwrap()
+            .unwrap();
+        let peer = offer.poll_reply().unwrap().unwrap();
+        (offer.into_channel(peer), server)
+    }
+
+    #[test]
+    fn flags_are_masked_on_read() {
+        let (mut client, mut server) = channel_pair();
+        assert!(client.writer.write_record(0xff, b"abc"));
+        let (flags, data) = server.read_frame().unwrap().unwrap();
+        assert_eq!(flags, SHM_FLAGS_MASK);
+        assert_eq!(&data[..], b"abc");
+    }
+
+    #[test]
+    fn oversize_frames_fail_with_emsgsize() {
+        let (mut client, mut server) = channel_pair();
+        server.set_max_msg_size(100);
+        let mut offset = 0;
+        assert!(client.write_frame(0, &[1; 100], &mut offset));
+        assert_eq!(server.read_frame().unwrap().unwrap().1.len(), 100);
+
+        //  Fragmented frames are checked while they are reassembled.
+        let frame = vec![2; client.writer.ring.max_record_payload() * 2];
+        offset = 0;
+        assert!(client.write_frame(0, &frame, &mut offset));
+        let error = server.read_frame().unwrap_err();
+        assert!(error.to_string().starts_with("EMSGSIZE"));
+    }
+}
@@ -29,7 +29,12 @@ use crate::defines::{
     ZMQ_RCVHWM, ZMQ_RECONNECT_STOP_AFTER_DISCONNECT, ZMQ_REQ, ZMQ_SNDHWM, ZMQ_SNDMORE, ZMQ_SUB,
     ZMQ_ZERO_COPY_RECV,
 };
+#[cfg(not(all(any(feature = "epoll", feature = "io-uring"), target_os = "linux")))]
 use crate::devpoll::ZmqPoller;
+#[cfg(all(feature = "epoll", not(feature = "io-uring"), target_os = "linux"))]
+use crate::epoll::ZmqPoller;
+#[cfg(all(feature = "io-uring", target_os = "linux"))]
+use crate::uring::ZmqPoller;
 use crate::dgram::dgram_xrecv;
 use crate::dish::dish_xrecv;
 use crate::endpoint::{
@@ -42,6 +47,7 @@ use crate::mailbox::ZmqMailbox;
 use crate::mailbox_interface::ZmqMailboxInterface;
 use crate::mailbox_safe::ZmqMailboxSafe;
 use crate::message::{ZmqMessage, ZMQ_MSG_MORE};
+use crate::mtrie::ZmqMtrie;
 use crate::ops::{
     zmq_bind, zmq_close, zmq_msg_init_size, zmq_msg_send, zmq_setsockopt, zmq_socket,
 };
@@ -52,6 +58,7 @@ use crate::pair::pair_xrecv;
 use crate::pipe::ZmqPipe;
 use crate::pull::pull_xrecv;
 use crate::radio::radio_xrecv;
+use crate::radix_tree::ZmqRadixTree;
 use crate::rep::rep_xrecv;
 use crate::req::req_xrecv;
 use crate::router::router_xrecv;
@@ -97,6 +104,9 @@ pub struct ZmqSocket<'a> {
     pub context: &'a mut ZmqContext<'a>,
     //  Map of open endpoints.
     pub endpoints: HashMap<String, ZmqEndpoint<'a>>,
+    //  Listening descriptors by bound endpoint, for handing them over to
+    //  another process; see fd.rs.
+    pub listener_fds: HashMap<String, ZmqFileDesc>,
     // id of the parent context's thread that performs I/O for this socket
     pub thread_id: i32,
     pub sent_seqnum: u64,
@@ -144,6 +154,12 @@ pub struct ZmqSocket<'a> {
     // // ZMQ_NON_COPYABLE_NOR_MOVABLE (ZmqSocketBase)
     // Add a flag for mark disconnect action
     pub disconnected: bool,
+    //  XSUB: the repository of subscriptions forwarded upstream.
+    pub _xsub_subscriptions: ZmqRadixTree,
+    //  XPUB: list of all subscriptions mapped to corresponding pipes.
+    pub _subscriptions: ZmqMtrie,
+    //  XPUB: list of manual subscriptions mapped to corresponding pipes.
+    pub _manual_subscriptions: ZmqMtrie,
 }
 
 impl<'a> ZmqSocket<'a> {
@@ -533,11 +549,12 @@ impl<'a> ZmqSocket<'a> {
                 //  TODO shouldn't this use _last_endpoint instead of endpoint_uri_? as in the other cases
                 let mut ep = EndpointUriPair::new(endpoint_uri, "", EndpointType::None);
                 // todo add trait ZmqOwn to ZmqSessionBase
-                self.add_endpoint(&ep, &mut session, &mut newpipe);
+                self.add_endpoint(&ep, &mut session, Some(&mut newpipe));
 
                 Ok(())
             }
-            ZmqTransport::ZmqTcp => {
+            ZmqTransport::ZmqTcp | ZmqTransport::ZmqIpc => {
+                self.destination.protocol = protocol;
                 let mut listener = ZmqListener::new(&mut io_thread, self);
                 // alloc_assert (listener);
                 if listener.set_local_address(address.c_str()).is_err() {
@@ -548,15 +565,55 @@ impl<'a> ZmqSocket<'a> {
 
                 // Save last endpoint URI
                 listener.get_local_address(&mut self.last_endpoint);
+                //  A wildcard ipc listener removes its file when it closes,
+                //  which would leave a successor unreachable.
+                if listener.tmp_socket_dirname.is_empty() {
+                    self.listener_fds
+                        .insert(self.last_endpoint.clone(), listener.fd);
+                }
 
                 self.add_endpoint(
                     &make_unconnected_bind_endpoint_pair(self._last_endpoint),
                     listener,
-                    null_mut(),
+                    None,
                 );
                 options.connected = true;
                 Ok(())
             }
+            ZmqTransport::ZmqShm
+            | ZmqTransport::ZmqQuic
+            | ZmqTransport::ZmqTls
+            | ZmqTransport::ZmqVsock
+            | ZmqTransport::ZmqFd
+            | ZmqTransport::ZmqWs
+            | ZmqTransport::ZmqWss => {
+                //  The listener picks its transport from the destination.
+                self.destination.protocol = protocol;
+                let mut listener = ZmqListener::new(&mut io_thread, self);
+                listener.wss = protocol == ZmqTransport::ZmqWss;
+                if let Err(e) = listener.set_local_address(&mut address) {
+                    let errno = std::io::Error::last_os_error()
+                        .raw_os_error()
+                        .unwrap_or(0);
+                    self.event_bind_failed(&make_unconnected_bind_endpoint_pair(endpoint_uri), errno);
+                    return Err(e);
+                }
+
+                //  Save last endpoint URI. These listeners report the
+                //  endpoint that was bound, not the name of their socket.
+                listener.get_local_address(&mut self.last_endpoint)?;
+                //  Only adopted descriptors are worth passing on; the others
+                //  carry transport state besides the socket.
+                if protocol == ZmqTransport::ZmqFd {
+                    self.listener_fds
+                        .insert(self.last_endpoint.clone(), listener.fd);
+                }
+
+                let endpoint_pair = make_unconnected_bind_endpoint_pair(&self.last_endpoint);
+                self.add_endpoint(&endpoint_pair, &mut listener, None);
+                self.context.connected = true;
+                Ok(())
+            }
             _ => {
                 bail!("Bind failed")
             }
@@ -735,6 +792,7 @@ impl<'a> ZmqSocket<'a> {
         }
         // _endpoints.erase (range.first, range.second);
         self.endpoints.clear()?;
+        self.listener_fds.remove(&resolved_endpoint_uri);
 
         if options.reconnect_stop & ZMQ_RECONNECT_STOP_AFTER_DISCONNECT {
             self.disconnected = true;
@@ -1879,7 +1937,7 @@ impl<'a> ZmqSocket<'a> {
         self.add_endpoint(
             &make_unconnected_connect_endpoint_pair(endpoint_uri),
             &session,
-            &mut newpipe,
+            Some(&mut newpipe),
         );
         Ok(())
     }
@@ -2059,7 +2117,7 @@ impl<'a> ZmqSocket<'a> {
         &mut self,
         endpoint_pair: &EndpointUriPair,
         endpoint: &mut ZmqSessionBase,
-        pipe: &mut ZmqPipe,
+        pipe: Option<&mut ZmqPipe>,
     ) {
         //  Activate the session. Make it a child of this socket.
         self.launch_child(endpoint);
@@ -2070,7 +2128,7 @@ impl<'a> ZmqSocket<'a> {
         //     endpoint_pipe_t::new(endpoint, pipe),
         // );
 
-        if pipe != null_mut() {
+        if let Some(pipe) = pipe {
             pipe.set_endpoint_pair(endpoint_pair.clone());
         }
     }
@@ -2141,26 +2199,22 @@ impl<'a> ZmqSocket<'a> {
 
     pub fn check_protocol(&mut self, protocol_: &str) -> anyhow::Result<()> {
         //  First check out whether the protocol is something we are aware of.
-        if protocol_ != ZmqTransport::ZmqInproc {
-            // #if defined ZMQ_HAVE_IPC && protocol_ != protocol_name::ipc
-            // #endif && protocol_ != protocol_name::tcp
-            // #ifdef ZMQ_HAVE_WS && protocol_ != protocol_name::ws
-            // #endif
-            // #ifdef ZMQ_HAVE_WSS && protocol_ != protocol_name::wss
-            // #endif
-            // #if defined ZMQ_HAVE_OPENPGM
-            //  pgm/epgm transports only available if 0MQ is compiled with OpenPGM. && protocol_ != protocol_name::pgm && protocol_ != protocol_name::epgm
-            // #endif
-            // #if defined ZMQ_HAVE_TIPC
-            // TIPC transport is only available on Linux. && protocol_ != protocol_name::tipc
-            // #endif
-            // #if defined ZMQ_HAVE_NORM && protocol_ != protocol_name::norm
-            // #endif
-            // #if defined ZMQ_HAVE_VMCI && protocol_ != protocol_name::vmci
-            // #endif && protocol_ != protocol_name::udp {
-            // errno = EPROTONOSUPPORT;
-            // return -1;
-            bail!("udp protocol not supported")
+        //  pgm, epgm, norm and tipc are not built into this library, vmci
+        //  only with the vmci feature.
+        if protocol_ != ZmqTransport::ZmqInproc
+            && protocol_ != ZmqTransport::ZmqIpc
+            && protocol_ != ZmqTransport::ZmqTcp
+            && protocol_ != ZmqTransport::ZmqShm
+            && protocol_ != ZmqTransport::ZmqQuic
+            && protocol_ != ZmqTransport::ZmqTls
+            && protocol_ != ZmqTransport::ZmqVsock
+            && protocol_ != ZmqTransport::ZmqFd
+            && protocol_ != ZmqTransport::ZmqWs
+            && protocol_ != ZmqTransport::ZmqWss
+            && !(cfg!(feature = "vmci") && protocol_ == ZmqTransport::ZmqVmci)
+            && protocol_ != ZmqTransport::ZmqUdp
+        {
+            bail!("EPROTONOSUPPORT: protocol {} not supported", protocol_)
         }
 
         //  Check whether socket type and transport protocol match.
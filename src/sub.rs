@@ -31,7 +31,7 @@ use bincode::options;
 use libc::{EINVAL, ENOTSUP};
 use crate::context::ZmqContext;
 use crate::decoder_allocators::data;
-use crate::defines::{ZMQ_SUB, ZMQ_UNSUBSCRIBE};
+use crate::defines::{ZMQ_SUB, ZMQ_SUBSCRIBE, ZMQ_UNSUBSCRIBE};
 use crate::message::{close_and_return, ZmqMessage};
 
 use crate::xsub::XSub;
@@ -75,24 +75,26 @@ impl ZmqSub {
                         optval_: &mut [u8],
                         optvallen_: usize) -> i32
     {
-        if option_ != ZMQ_SUBSCRIBE && option_ != ZMQ_UNSUBSCRIBE {
+        if option_ != ZMQ_SUBSCRIBE as i32 && option_ != ZMQ_UNSUBSCRIBE as i32 {
           // errno = EINVAL;
             return -1;
         }
 
         //  Create the subscription message.
-    let mut msg = ZmqMessage::default();
-        rc: i32;
-        let data = (optval_);
-        if option_ == ZMQ_SUBSCRIBE {
-            rc = msg.init_subscribe (optvallen_, data);
+        //  The subscription travels as a SUBSCRIBE/CANCEL message; each
+        //  connection's encoder decides how it looks on the wire.
+        let mut msg = ZmqMessage::default();
+        let data = &optval_[..optvallen_];
+        let rc = if option_ == ZMQ_SUBSCRIBE as i32 {
+            msg.init_subscribe (optvallen_, data)
         } else {
-            rc = msg.init_cancel (optvallen_, data);
+            msg.init_cancel (optvallen_, data)
+        };
+        if rc.is_err() {
+            return -1;
         }
-        // errno_assert (rc == 0);
 
-        //  Pass it further on in the stack.
-        rc = self.xsub.xsend (&mut msg);
+        let rc = self.xsub.xsend (&mut msg);
         return close_and_return (&mut msg, rc);
     }
 
@@ -1,19 +1,27 @@
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
 use std::mem;
+use std::net::{IpAddr, SocketAddr};
 use std::ptr::null_mut;
-use libc::{accept, bind, c_int, close, EAFNOSUPPORT, EAGAIN, EFAULT, EINTR, EISCONN, EMSGSIZE, ENOMEM, ENOTSOCK, EOPNOTSUPP, EWOULDBLOCK, listen, setsockopt, sockaddr, SOCKET, ssize_t};
+use std::sync::{Arc, Mutex};
+use libc::{accept, bind, c_int, close, EACCES, ECONNREFUSED, ENOTCONN, EAFNOSUPPORT, EAGAIN, EFAULT, EINTR, EISCONN, EMSGSIZE, ENOMEM, ENOTSOCK, EOPNOTSUPP, EWOULDBLOCK, listen, setsockopt, sockaddr, SOCKET, ssize_t};
 use windows::Win32::Networking::WinSock::{closesocket, IPPROTO_TCP, recv, send, SEND_RECV_FLAGS, SIO_KEEPALIVE_VALS, SIO_LOOPBACK_FAST_PATH, SO_KEEPALIVE, SO_RCVBUF, SO_REUSEADDR, SO_SNDBUF, SOCK_STREAM, SOCKADDR_STORAGE, SOCKET_ERROR, SOL_SOCKET, tcp_keepalive, TCP_KEEPALIVE, TCP_KEEPCNT, TCP_KEEPIDLE, TCP_KEEPINTVL, TCP_MAXRT, TCP_NODELAY, WSA_ERROR, WSAECONNABORTED, WSAECONNREFUSED, WSAECONNRESET, WSAEHOSTUNREACH, WSAENETDOWN, WSAENETRESET, WSAENOBUFS, WSAENOTCONN, WSAEOPNOTSUPP, WSAETIMEDOUT, WSAEWOULDBLOCK, WSAGetLastError};
-use std::mem::size_of_val;
 use anyhow::bail;
+use ipnet::IpNet;
 use bincode::options;
-use crate::address::{get_socket_name, ZmqAddress, ZmqSocketEnd};
+use crate::address::{from_sockaddr, get_socket_name, ZmqAddress, ZmqSocketEnd};
 use crate::address_family::{AF_INET, AF_INET6};
 use crate::context::ZmqContext;
 use crate::defines::RETIRED_FD;
 use crate::endpoint::make_unconnected_bind_endpoint_pair;
-use crate::err::wsa_error_to_errno;
+use crate::endpoint::EndpointType::Bind;
+use crate::endpoint_uri::EndpointUriPair;
+use crate::engine::ZmqEngine;
+use crate::err::{set_errno, wsa_error_to_errno};
 use crate::defines::ZmqFileDesc;
 use crate::ip::{assert_success_or_recoverable, bind_to_device, enable_ipv4_mapping, make_socket_noninheritable, ip_open_socket, set_ip_type_of_service, set_nosigpipe, set_socket_priority};
 use crate::listener::ZmqListener;
+use crate::session_base::ZmqSessionBase;
 
 use crate::tcp_address::TcpAddress;
 
@@ -441,21 +449,20 @@ pub fn tcp_open_socket (address: &mut str,
 
 
 pub fn tcp_in_event(listener: &mut ZmqListener) -> anyhow::Result<()> {
-    let mut tcp_sockaddr = sockaddr { sa_family: AF_INET, sa_data: [0; 14] };
-    tcp_sockaddr.sa_data = listener.address.addr_sockaddr.sa_data;
-    let mut tcp_sockaddr_len = size_of_val(&tcp_sockaddr) as c_int;
-    let mut fd = unsafe {
-        accept(listener.fd,
-               &mut listener.address.addr_sockaddr as *mut sockaddr,
-               &mut tcp_sockaddr_len)
-    };
+    let mut fd = tcp_accept(listener)?;
 
-    //  If connection was reset by the peer in the meantime, just ignore it.
+    //  If connection was reset by the peer in the meantime, or it was
+    //  turned away, just ignore it.
     //  TODO: Handle specific errors like ENFILE/EMFILE etc.
     if (fd == RETIRED_FD as usize) {
+        //  Nothing to accept yet, e.g. the ring's accept is still in
+        //  flight; that is no failure.
+        if std::io::Error::last_os_error().raw_os_error() == Some(EAGAIN) {
+            return Ok(());
+        }
         listener.socket
             .event_accept_failed(&make_unconnected_bind_endpoint_pair(&listener.endpoint), zmq_errno());
-        bail!("tcp_in_event failed");
+        return Ok(());
     }
 
     let mut rc = tune_tcp_socket(&mut fd);
@@ -471,12 +478,12 @@ pub fn tcp_in_event(listener: &mut ZmqListener) -> anyhow::Result<()> {
     if (rc != 0) {
         listener.socket
             .event_accept_failed(&make_unconnected_bind_endpoint_pair(&listener.endpoint), zmq_errno());
+        unsafe { close(fd as c_int) };
         bail!("tcp_in_event failed");
     }
 
     //  Create the engine object for this connection.
-    listener.create_engine();
-    Ok(())
+    tcp_create_engine(listener, fd)
 }
 
 pub fn tcp_create_socket(listener: &mut ZmqListener, addr_: &mut str) -> anyhow::Result<()> {
@@ -594,7 +601,24 @@ pub fn tcp_accept(listener: &mut ZmqListener) -> anyhow::Result<ZmqFileDesc> {
     // #if defined ZMQ_HAVE_SOCK_CLOEXEC && defined HAVE_ACCEPT4
     // let mut sock: ZmqFileDesc = ::accept4(listener.fd, (&ss), &ss_len, SOCK_CLOEXEC);
     // #else
+    #[cfg(not(all(feature = "io-uring", target_os = "linux")))]
     let sock = unsafe { accept(listener.fd, (&mut ss) as *mut sockaddr, &mut ss_len) };
+    //  The accept was handed to the I/O thread's ring; pick up its result.
+    #[cfg(all(feature = "io-uring", target_os = "linux"))]
+    let sock = match listener.io_object.take_accept(listener.handle) {
+        Some((fd, addr, addr_len)) => {
+            unsafe {
+                std::ptr::copy_nonoverlapping(
+                    &addr as *const libc::sockaddr_storage as *const u8,
+                    &mut ss as *mut SOCKADDR_STORAGE as *mut u8,
+                    mem::size_of_val(&ss).min(addr_len as usize),
+                )
+            };
+            ss_len = addr_len as c_int;
+            fd
+        }
+        None => RETIRED_FD,
+    };
     // #endif
 
     unsafe {
@@ -615,30 +639,12 @@ pub fn tcp_accept(listener: &mut ZmqListener) -> anyhow::Result<ZmqFileDesc> {
 
     make_socket_noninheritable(sock);
 
-    if (!options.tcp_accept_filters.empty()) {
-        let mut matched = false;
-        // for (ZmqOptions::tcp_accept_filters_t::size_type
-        //        i = 0,
-        //        size = options.tcp_accept_filters.size ();
-        //      i != size; += 1i)
-        for i in 0..options.tcp_accept_filters.len() {
-            if (options.tcp_accept_filters[i].match_address((&ss), ss_len)) {
-                matched = true;
-                break;
-            }
-        }
-        unsafe {
-            if (!matched) {
-                // #ifdef ZMQ_HAVE_WINDOWS
-                let rc: i32 = closesocket(sock);
-                // wsa_assert (rc != SOCKET_ERROR);
-                // #else
-                //             int rc = ::close (sock);
-                // errno_assert (rc == 0);
-                // #endif
-                return Ok(RETIRED_FD as ZmqFileDesc);
-            }
-        }
+    //  Apply the accept filters and connection caps. The caller reports
+    //  the rejection with errno.
+    if let Err(errno) = tcp_admit(listener, sock) {
+        unsafe { close(sock as c_int) };
+        set_errno(errno);
+        return Ok(RETIRED_FD as ZmqFileDesc);
     }
 
     unsafe {
@@ -666,3 +672,188 @@ pub fn tcp_accept(listener: &mut ZmqListener) -> anyhow::Result<ZmqFileDesc> {
 
     return Ok(sock as ZmqFileDesc);
 }
+
+//  Live connections accepted by a TCP listener, counted for the
+//  ZMQ_TCP_MAX_CONNECTIONS and ZMQ_TCP_MAX_CONNECTIONS_PER_IP caps.
+#[derive(Debug, Default)]
+pub struct ZmqTcpConnections {
+    pub total: usize,
+    pub per_ip: HashMap<IpAddr, usize>,
+}
+
+//  One accepted connection's share of the count. The connection's engine
+//  holds on to it, so the connection is counted until the engine is gone.
+#[derive(Debug)]
+pub struct ZmqTcpConnectionSlot {
+    connections: Arc<Mutex<ZmqTcpConnections>>,
+    ip: IpAddr,
+}
+
+impl Drop for ZmqTcpConnectionSlot {
+    fn drop(&mut self) {
+        let mut connections = self.connections.lock().unwrap();
+        connections.total -= 1;
+        if let Entry::Occupied(mut count) = connections.per_ip.entry(self.ip) {
+            *count.get_mut() -= 1;
+            if *count.get() == 0 {
+                count.remove();
+            }
+        }
+    }
+}
+
+//  Takes a slot for a connection from 'ip', unless that would go over one
+//  of the caps (0 meaning no cap).
+pub fn tcp_acquire_slot(
+    connections: &Arc<Mutex<ZmqTcpConnections>>,
+    ip: IpAddr,
+    max_total: i32,
+    max_per_ip: i32,
+) -> Option<ZmqTcpConnectionSlot> {
+    let mut guard = connections.lock().unwrap();
+    let from_ip = guard.per_ip.get(&ip).copied().unwrap_or(0);
+    if (max_total > 0 && guard.total >= max_total as usize)
+        || (max_per_ip > 0 && from_ip >= max_per_ip as usize)
+    {
+        return None;
+    }
+    guard.total += 1;
+    *guard.per_ip.entry(ip).or_insert(0) += 1;
+    Some(ZmqTcpConnectionSlot {
+        connections: connections.clone(),
+        ip,
+    })
+}
+
+//  Address of the peer, with IPv4-mapped IPv6 addresses turned back into
+//  IPv4 ones so that IPv4 filters apply to them.
+pub fn tcp_peer_ip(fd: ZmqFileDesc) -> Option<IpAddr> {
+    tcp_peer_address(fd).map(|addr| addr.ip().to_canonical())
+}
+
+pub fn tcp_peer_address(fd: ZmqFileDesc) -> Option<SocketAddr> {
+    let mut storage: libc::sockaddr_storage = unsafe { mem::zeroed() };
+    let mut len = mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+    let rc = unsafe {
+        libc::getpeername(
+            fd as c_int,
+            &mut storage as *mut libc::sockaddr_storage as *mut sockaddr,
+            &mut len,
+        )
+    };
+    if rc != 0 {
+        return None;
+    }
+    from_sockaddr(&storage)
+}
+
+pub fn tcp_local_address(fd: ZmqFileDesc) -> Option<SocketAddr> {
+    let mut storage: libc::sockaddr_storage = unsafe { mem::zeroed() };
+    let mut len = mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+    let rc = unsafe {
+        libc::getsockname(
+            fd as c_int,
+            &mut storage as *mut libc::sockaddr_storage as *mut sockaddr,
+            &mut len,
+        )
+    };
+    if rc != 0 {
+        return None;
+    }
+    from_sockaddr(&storage)
+}
+
+//  Checks an accepted connection against ZMQ_TCP_ACCEPT_FILTER and the
+//  connection caps, and takes its slot (see create_engine). Returns the
+//  errno to report a rejection with: EACCES if no filter matched,
+//  ECONNREFUSED if a cap was reached.
+//
+//  With ZMQ_TCP_PROXY_PROTOCOL the peer is the load balancer, so only the
+//  total cap is applied here. The filters and the per-IP cap wait for the
+//  client address in the PROXY header; see tcp_readmit.
+fn tcp_admit(listener: &mut ZmqListener, fd: ZmqFileDesc) -> Result<(), i32> {
+    let ctx = &listener.socket.context;
+    let counted = ctx.tcp_max_connections > 0 || ctx.tcp_max_connections_per_ip > 0;
+    if ctx.tcp_accept_filters.is_empty() && !counted {
+        return Ok(());
+    }
+
+    let ip = tcp_peer_ip(fd).ok_or(ENOTCONN)?;
+    let proxied = ctx.tcp_proxy_protocol;
+    if !proxied && !tcp_filters_allow(&ctx.tcp_accept_filters, ip) {
+        return Err(EACCES);
+    }
+
+    if counted {
+        let max_per_ip = if proxied {
+            0
+        } else {
+            ctx.tcp_max_connections_per_ip
+        };
+        let slot = tcp_acquire_slot(
+            &listener.tcp_connections,
+            ip,
+            ctx.tcp_max_connections,
+            max_per_ip,
+        )
+        .ok_or(ECONNREFUSED)?;
+        listener.tcp_slot = Some(Arc::new(slot));
+    }
+    Ok(())
+}
+
+//  Admits a connection again for the client address its PROXY header
+//  names, moving its slot over to that address. Errors as tcp_admit.
+pub fn tcp_readmit(
+    slot: &mut Option<Arc<ZmqTcpConnectionSlot>>,
+    filters: &[IpNet],
+    max_total: i32,
+    max_per_ip: i32,
+    ip: IpAddr,
+) -> Result<(), i32> {
+    //  Give up the balancer's slot first so it is not counted twice.
+    let connections = slot.take().map(|slot| slot.connections.clone());
+    if !tcp_filters_allow(filters, ip) {
+        return Err(EACCES);
+    }
+    if let Some(connections) = connections {
+        let new_slot =
+            tcp_acquire_slot(&connections, ip, max_total, max_per_ip).ok_or(ECONNREFUSED)?;
+        *slot = Some(Arc::new(new_slot));
+    }
+    Ok(())
+}
+
+//  No filters means every address is allowed.
+fn tcp_filters_allow(filters: &[IpNet], ip: IpAddr) -> bool {
+    filters.is_empty() || filters.iter().any(|net| net.contains(&ip))
+}
+
+pub fn tcp_create_engine(listener: &mut ZmqListener, fd: ZmqFileDesc) -> anyhow::Result<()> {
+    let remote = get_socket_name(fd, ZmqSocketEnd::SocketEndRemote).unwrap_or_default();
+    let endpoint_pair = EndpointUriPair::new(&listener.endpoint, &remote, Bind);
+
+    let mut engine = ZmqEngine::default();
+    engine.fd = fd;
+    engine.address = listener.address.clone();
+    engine.endpoint_uri_pair = endpoint_pair.clone();
+    engine.peer_address = tcp_peer_ip(fd).map(|ip| ip.to_string()).unwrap_or_default();
+    engine.tcp_slot = listener.tcp_slot.take();
+    //  The peer address above is the balancer's until the header is read.
+    engine.proxy_header_pending = listener.socket.context.tcp_proxy_protocol;
+
+    //  Choose I/O thread to run the session in. Given that we are already
+    //  running in an I/O thread, there must be at least one available.
+    let io_thread = listener
+        .choose_io_thread(listener.socket.context.affinity)
+        .unwrap();
+
+    //  Create and launch a session object.
+    let mut session = ZmqSessionBase::create(io_thread, false, listener.socket, None)?;
+    session.inc_seqnum();
+    listener.own.launch_child(session);
+    listener.own.send_attach(&mut session, engine, false);
+
+    listener.socket.event_accepted(&endpoint_pair, fd);
+    Ok(())
+}
@@ -1,7 +1,12 @@
 use crate::thread_command::ZmqThreadCommand;
 use crate::context::ZmqContext;
 use crate::defines::ZmqHandle;
+#[cfg(not(all(any(feature = "epoll", feature = "io-uring"), target_os = "linux")))]
 use crate::devpoll::ZmqPoller;
+#[cfg(all(feature = "epoll", not(feature = "io-uring"), target_os = "linux"))]
+use crate::epoll::ZmqPoller;
+#[cfg(all(feature = "io-uring", target_os = "linux"))]
+use crate::uring::ZmqPoller;
 use crate::endpoint::ZmqEndpoint;
 use crate::mailbox::ZmqMailbox;
 use crate::object::ZmqObject;
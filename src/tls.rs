@@ -0,0 +1,113 @@
+//  Listening side of the tls:// transport: a TCP listener whose
+//  connections get a TLS session on top (see tls_engine.rs).
+
+use anyhow::bail;
+use libc::{c_int, close};
+
+use crate::address::{get_socket_name, ZmqSocketEnd};
+use crate::defines::{ZmqFileDesc, RETIRED_FD};
+use crate::endpoint::make_unconnected_bind_endpoint_pair;
+use crate::endpoint::EndpointType::Bind;
+use crate::endpoint_uri::EndpointUriPair;
+use crate::engine::ZmqEngine;
+use crate::listener::ZmqListener;
+use crate::session_base::ZmqSessionBase;
+use crate::tcp::{tcp_accept, tcp_set_local_address, tune_tcp_socket};
+use crate::tls_engine::{tls_init, tls_server_config, tls_server_session};
+
+pub fn tls_set_local_address(listener: &mut ZmqListener, addr: &mut str) -> anyhow::Result<()> {
+    //  Refuse to listen without usable credentials rather than failing
+    //  every handshake later on.
+    listener.tls_cred = Some(tls_server_config(listener.socket.context)?);
+
+    tcp_set_local_address(listener, addr)?;
+    listener.endpoint = format!("tls://{}", listener.endpoint.trim_start_matches("tcp://"));
+    Ok(())
+}
+
+pub fn tls_in_event(listener: &mut ZmqListener) -> anyhow::Result<()> {
+    let mut fd = tcp_accept(listener)?;
+
+    //  If connection was reset by the peer in the meantime, or it was
+    //  turned away, just ignore it.
+    if fd == RETIRED_FD as ZmqFileDesc {
+        let errno = std::io::Error::last_os_error().raw_os_error().unwrap_or(-1);
+        listener
+            .socket
+            .event_accept_failed(&make_unconnected_bind_endpoint_pair(&listener.endpoint), errno);
+        return Ok(());
+    }
+    tune_tcp_socket(&mut fd);
+
+    tls_create_engine(listener, fd)
+}
+
+pub fn tls_close(listener: &mut ZmqListener) -> anyhow::Result<()> {
+    let fd_for_event = listener.fd;
+    let rc = unsafe { close(listener.fd as c_int) };
+    listener.fd = RETIRED_FD as ZmqFileDesc;
+    listener.tls_cred = None;
+
+    if rc != 0 {
+        listener
+            .socket
+            .event_close_failed(&make_unconnected_bind_endpoint_pair(&listener.endpoint), -1);
+        bail!("failed to close socket")
+    }
+
+    listener.socket.event_closed(
+        &make_unconnected_bind_endpoint_pair(&listener.endpoint),
+        fd_for_event,
+    );
+    Ok(())
+}
+
+pub fn tls_create_engine(listener: &mut ZmqListener, fd: ZmqFileDesc) -> anyhow::Result<()> {
+    let tcp_slot = listener.tcp_slot.take();
+    let remote = get_socket_name(fd, ZmqSocketEnd::SocketEndRemote).unwrap_or_default();
+    let endpoint_pair = EndpointUriPair::new(
+        &listener.endpoint,
+        &format!("tls://{}", remote.trim_start_matches("tcp://")),
+        Bind,
+    );
+
+    let config = match listener.tls_cred.clone() {
+        Some(config) => config,
+        None => {
+            unsafe { close(fd as c_int) };
+            bail!("EINVAL: tls listener has no credentials");
+        }
+    };
+    let session = match tls_server_session(config) {
+        Ok(session) => session,
+        Err(e) => {
+            unsafe { close(fd as c_int) };
+            return Err(e);
+        }
+    };
+
+    let mut engine = ZmqEngine::default();
+    engine.tcp_slot = tcp_slot;
+    tls_init(
+        &mut engine,
+        fd,
+        &listener.address,
+        session,
+        endpoint_pair.clone(),
+    );
+
+    //  Choose I/O thread to run the session in. Given that we are already
+    //  running in an I/O thread, there must be at least one available.
+    let io_thread = listener
+        .choose_io_thread(listener.socket.context.affinity)
+        .unwrap();
+
+    //  Create and launch a session object.
+    let mut session = ZmqSessionBase::create(io_thread, false, listener.socket, None)?;
+    session.inc_seqnum();
+    listener.own.launch_child(session);
+    listener.own.send_attach(&mut session, engine, false);
+
+    listener.socket.event_accepted(&endpoint_pair, fd);
+    Ok(())
+}
@@ -0,0 +1,206 @@
+use std::sync::Arc;
+
+use anyhow::{anyhow, bail};
+use libc::{
+    c_int, connect, sockaddr, socket, EINPROGRESS, SOCK_CLOEXEC, SOCK_NONBLOCK, SOCK_STREAM,
+};
+use rustls::{ClientConfig, Connection};
+
+use crate::address::{resolve_address, to_sockaddr, ZmqAddress};
+use crate::context::ZmqContext;
+use crate::defines::{ZmqFileDesc, RETIRED_FD};
+use crate::endpoint::EndpointType::Connect;
+use crate::endpoint_uri::EndpointUriPair;
+use crate::engine::ZmqEngine;
+use crate::err::ZmqError;
+use crate::session_base::ZmqSessionBase;
+use crate::stream_connecter_base::StreamConnecterBase;
+use crate::tcp::tune_tcp_socket;
+use crate::thread_context::ZmqThreadContext;
+use crate::tls_engine::{tls_client_config, tls_client_session, tls_init};
+
+//  Connects to a tls:// listener. The TCP connection is established as for
+//  tcp://; the TLS handshake is then run by the engine ahead of the ZMTP
+//  one.
+pub struct TlsConnecter<'a> {
+    pub base: StreamConnecterBase<'a>,
+    //  None if the TLS options are unusable.
+    pub config: Option<Arc<ClientConfig>>,
+    //  Name the server's certificate is checked against.
+    pub server_name: String,
+    pub ipv6: bool,
+}
+
+impl<'a> TlsConnecter<'a> {
+    //  If 'delayed_start' is true connecter first waits for a while,
+    //  then starts connection process.
+    pub fn new(
+        ctx: &mut ZmqContext,
+        io_thread_: &mut ZmqThreadContext,
+        session: &mut ZmqSessionBase,
+        addr: &mut ZmqAddress,
+        delayed_start_: bool,
+    ) -> Self {
+        // zmq_assert (_addr.protocol == ZmqTransport::ZmqTls);
+        let server_name = if ctx.wss_hostname.is_empty() {
+            let (host, _) = addr.addr_str.rsplit_once(':').unwrap_or_default();
+            host.trim_start_matches('[')
+                .trim_end_matches(']')
+                .to_string()
+        } else {
+            ctx.wss_hostname.clone()
+        };
+
+        Self {
+            config: tls_client_config(ctx).ok(),
+            server_name,
+            ipv6: ctx.ipv6,
+            base: StreamConnecterBase::new(io_thread_, session, ctx, addr, delayed_start_),
+        }
+    }
+
+    //  Handlers for I/O events.
+    pub fn out_event(&mut self) -> anyhow::Result<()> {
+        let fd = self.connect();
+        self.base.rm_handle();
+
+        //  Handle the error condition by attempt to reconnect.
+        let mut fd = match fd {
+            Ok(fd) => fd,
+            Err(_) => {
+                self.base.close();
+                self.base.add_reconnect_timer();
+                return Ok(());
+            }
+        };
+
+        //  Bad TLS options won't get any better by retrying, but this is
+        //  what the other transports do with connect-time errors.
+        match self.session() {
+            Ok(session) => {
+                self.base._s = RETIRED_FD as ZmqFileDesc;
+                tune_tcp_socket(&mut fd);
+                self.create_engine(fd, session);
+            }
+            Err(_) => {
+                self.base.close();
+                self.base.add_reconnect_timer();
+            }
+        }
+        Ok(())
+    }
+
+    //  Internal function to start the actual connection establishment.
+    pub fn start_connecting(&mut self) -> anyhow::Result<()> {
+        //  Open the connecting socket.
+        match self.open() {
+            //  Connect was successful immediately.
+            Ok(_) => {
+                self.base._handle = Some(self.base.io_object.add_fd(self.base._s));
+                self.out_event()
+            }
+            //  Connection establishment may be delayed. Poll for its
+            //  completion.
+            Err(ZmqError::InProgress(_)) => {
+                self.base._handle = Some(self.base.io_object.add_fd(self.base._s));
+                self.base.io_object.set_pollout(self.base._handle.unwrap());
+                Ok(())
+            }
+            //  Handle any other error condition by eventual reconnect.
+            Err(_) => {
+                self.base.close();
+                self.base.add_reconnect_timer();
+                Ok(())
+            }
+        }
+    }
+
+    //  Open the TCP socket and start connecting it.
+    pub fn open(&mut self) -> Result<(), ZmqError> {
+        let remote = resolve_address(&self.base._addr.addr_str, self.ipv6)
+            .map_err(|e| ZmqError::InvalidInput(e.to_string()))?;
+        let (address, address_len) = to_sockaddr(&remote);
+
+        let fd = unsafe {
+            socket(
+                address.ss_family as c_int,
+                SOCK_STREAM | SOCK_CLOEXEC | SOCK_NONBLOCK,
+                0,
+            )
+        };
+        if fd < 0 {
+            return Err(ZmqError::ConnectSocketFailed(
+                "failed to create socket".to_string(),
+            ));
+        }
+        self.base._s = fd as ZmqFileDesc;
+
+        let rc = unsafe {
+            connect(
+                fd,
+                &address as *const libc::sockaddr_storage as *const sockaddr,
+                address_len,
+            )
+        };
+        if rc == 0 {
+            return Ok(());
+        }
+
+        match std::io::Error::last_os_error().raw_os_error() {
+            Some(EINPROGRESS) => Err(ZmqError::InProgress("connect not finished yet".to_string())),
+            _ => Err(ZmqError::ConnectSocketFailed(
+                "failed to connect to socket".to_string(),
+            )),
+        }
+    }
+
+    //  Get the file descriptor of newly created connection.
+    pub fn connect(&mut self) -> anyhow::Result<ZmqFileDesc> {
+        let mut err: c_int = 0;
+        let mut len = std::mem::size_of::<c_int>() as libc::socklen_t;
+        let rc = unsafe {
+            libc::getsockopt(
+                self.base._s as c_int,
+                libc::SOL_SOCKET,
+                libc::SO_ERROR,
+                &mut err as *mut c_int as *mut libc::c_void,
+                &mut len,
+            )
+        };
+        if rc == -1 || err != 0 {
+            return Err(anyhow!("failed to connect to socket"));
+        }
+        Ok(self.base._s)
+    }
+
+    //  The client side of the TLS session, built from the wss options.
+    fn session(&mut self) -> anyhow::Result<Connection> {
+        let Some(config) = self.config.clone() else {
+            bail!("EINVAL: invalid TLS options for tls");
+        };
+        tls_client_session(config, &self.server_name)
+    }
+
+    fn create_engine(&mut self, fd: ZmqFileDesc, session: Connection) {
+        let endpoint = format!("tls://{}", self.base._addr.addr_str);
+        let endpoint_pair = EndpointUriPair::new("", &endpoint, Connect);
+
+        //  Create the engine object for this connection.
+        let mut engine = ZmqEngine::default();
+        tls_init(
+            &mut engine,
+            fd,
+            self.base._addr,
+            session,
+            endpoint_pair.clone(),
+        );
+
+        //  Attach the engine to the corresponding session object.
+        self.base.own.send_attach(self.base._session, engine, true);
+
+        //  Shut the connecter down.
+        self.base.own.terminate();
+
+        self.base._socket.event_connected(&endpoint_pair, fd);
+    }
+}
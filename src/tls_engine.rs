@@ -0,0 +1,445 @@
+//  Engine hooks of the tls:// transport.
+//
+//  tls:// is ZMTP over TLS over TCP, without the HTTP upgrade and framing
+//  of wss://. The engine is a regular ZMTP stream engine whose reads and
+//  writes go through the TLS session (the tls_* helpers of wss_engine.rs);
+//  the TLS handshake runs first, anything ZMTP writes meanwhile is held back
+//  by rustls until it completes.
+//
+//  Credentials are the WSS ones. In addition to wss://, a listener with
+//  trust anchors asks clients for a certificate, and a client with a
+//  certificate presents it. The peer's certificate, if any, is exposed as
+//  the TLS-Peer-Subject and TLS-Peer-Fingerprint message properties, and
+//  attached to the ZAP requests for the connection.
+
+use std::collections::HashMap;
+use std::fmt::Write as _;
+use std::sync::{Arc, Mutex};
+
+use anyhow::{anyhow, bail};
+use libc::{c_int, close, EPIPE, EPROTO};
+use rustls::pki_types::{CertificateDer, ServerName};
+use rustls::server::WebPkiClientVerifier;
+use rustls::{ClientConfig, ClientConnection, Connection, ServerConfig, ServerConnection};
+
+use crate::address::ZmqAddress;
+use crate::context::ZmqContext;
+use crate::defines::{
+    ZmqFileDesc, RETIRED_FD, ZMQ_MSG_PROPERTY_TLS_PEER_FINGERPRINT,
+    ZMQ_MSG_PROPERTY_TLS_PEER_SUBJECT,
+};
+use crate::endpoint_uri::EndpointUriPair;
+use crate::engine::ZmqEngine;
+use crate::engine_interface::ZmqErrorReason;
+use crate::wss_engine::{
+    tls_flush, tls_handshake, tls_read, tls_write, wss_certificate, wss_trust_roots,
+    ZmqTlsHandshakeStatus, ZmqWssStream,
+};
+
+//  TLS state of a tls:// connection.
+#[derive(Debug)]
+pub struct ZmqTlsState {
+    pub session: Connection,
+    pub stream: ZmqWssStream,
+    pub established: bool,
+}
+
+fn crypto_provider() -> Arc<rustls::crypto::CryptoProvider> {
+    Arc::new(rustls::crypto::ring::default_provider())
+}
+
+//  Listener credentials. Client certificates are requested, and checked
+//  against the trust anchors, only if there are any; clients without one
+//  are let in, it is up to ZAP to turn them away.
+pub fn tls_server_config(ctx: &ZmqContext) -> anyhow::Result<Arc<ServerConfig>> {
+    let Some((certs, key)) = wss_certificate(ctx)? else {
+        bail!("EINVAL: ZMQ_WSS_CERT_PEM is not set");
+    };
+    let roots = wss_trust_roots(ctx)?;
+
+    let builder = ServerConfig::builder_with_provider(crypto_provider())
+        .with_safe_default_protocol_versions()?;
+    let builder = if roots.is_empty() {
+        builder.with_no_client_auth()
+    } else {
+        let verifier =
+            WebPkiClientVerifier::builder_with_provider(Arc::new(roots), crypto_provider())
+                .allow_unauthenticated()
+                .build()
+                .map_err(|e| anyhow!("EINVAL: {}", e))?;
+        builder.with_client_cert_verifier(verifier)
+    };
+    let config = builder
+        .with_single_cert(certs, key)
+        .map_err(|e| anyhow!("EINVAL: {}", e))?;
+    Ok(Arc::new(config))
+}
+
+//  Client configuration; presents ZMQ_WSS_CERT_PEM if set.
+pub fn tls_client_config(ctx: &ZmqContext) -> anyhow::Result<Arc<ClientConfig>> {
+    let roots = wss_trust_roots(ctx)?;
+    if roots.is_empty() {
+        bail!("EINVAL: no tls trust anchors, set ZMQ_WSS_TRUST_PEM or ZMQ_WSS_TRUST_SYSTEM");
+    }
+
+    let builder = ClientConfig::builder_with_provider(crypto_provider())
+        .with_safe_default_protocol_versions()?
+        .with_root_certificates(roots);
+    let config = match wss_certificate(ctx)? {
+        Some((certs, key)) => builder
+            .with_client_auth_cert(certs, key)
+            .map_err(|e| anyhow!("EINVAL: {}", e))?,
+        None => builder.with_no_client_auth(),
+    };
+    Ok(Arc::new(config))
+}
+
+pub fn tls_client_session(config: Arc<ClientConfig>, hostname: &str) -> anyhow::Result<Connection> {
+    if hostname.is_empty() {
+        bail!("EINVAL: ZMQ_WSS_HOSTNAME is not set");
+    }
+    let server_name = ServerName::try_from(hostname.to_string())
+        .map_err(|_| anyhow!("EINVAL: invalid tls hostname {}", hostname))?;
+    Ok(ClientConnection::new(config, server_name)?.into())
+}
+
+pub fn tls_server_session(config: Arc<ServerConfig>) -> anyhow::Result<Connection> {
+    Ok(ServerConnection::new(config)?.into())
+}
+
+//  Splits a DER TLV off the front of 'der'. Returns the tag, the contents
+//  and what follows.
+fn der_next(der: &[u8]) -> Option<(u8, &[u8], &[u8])> {
+    let (&tag, rest) = der.split_first()?;
+    let (&first, mut rest) = rest.split_first()?;
+    let len = if first < 0x80 {
+        first as usize
+    } else {
+        let count = (first & 0x7f) as usize;
+        if count == 0 || count > 4 || rest.len() < count {
+            return None;
+        }
+        let len = rest[..count]
+            .iter()
+            .fold(0usize, |len, &b| (len << 8) | b as usize);
+        rest = &rest[count..];
+        len
+    };
+    if rest.len() < len {
+        return None;
+    }
+    Some((tag, &rest[..len], &rest[len..]))
+}
+
+fn oid_to_string(oid: &[u8]) -> String {
+    let mut arcs: Vec<u64> = vec![];
+    let mut value = 0u64;
+    for &b in oid {
+        value = (value << 7) | (b & 0x7f) as u64;
+        if b & 0x80 == 0 {
+            if arcs.is_empty() {
+                let first = (value / 40).min(2);
+                arcs.push(first);
+                arcs.push(value - first * 40);
+            } else {
+                arcs.push(value);
+            }
+            value = 0;
+        }
+    }
+    arcs.iter()
+        .map(|arc| arc.to_string())
+        .collect::<Vec<_>>()
+        .join(".")
+}
+
+fn attribute_name(oid: &str) -> Option<&'static str> {
+    Some(match oid {
+        "2.5.4.3" => "CN",
+        "2.5.4.5" => "serialNumber",
+        "2.5.4.6" => "C",
+        "2.5.4.7" => "L",
+        "2.5.4.8" => "ST",
+        "2.5.4.9" => "STREET",
+        "2.5.4.10" => "O",
+        "2.5.4.11" => "OU",
+        "0.9.2342.19200300.100.1.1" => "UID",
+        "0.9.2342.19200300.100.1.25" => "DC",
+        "1.2.840.113549.1.9.1" => "emailAddress",
+        _ => return None,
+    })
+}
+
+//  Escapes an attribute value as per RFC 4514.
+fn escape_value(value: &str, out: &mut String) {
+    let last = value.chars().count().saturating_sub(1);
+    for (i, c) in value.chars().enumerate() {
+        let special = matches!(c, ',' | '+' | '"' | '\\' | '<' | '>' | ';' | '=')
+            || (i == 0 && (c == ' ' || c == '#'))
+            || (i == last && c == ' ');
+        if special {
+            out.push('\\');
+        }
+        out.push(c);
+    }
+}
+
+//  Subject of a DER encoded X.509 certificate in RFC 4514 form, e.g.
+//  "CN=client1,O=Example". Attributes without a well-known name are
+//  written as "<oid>=#<hex of the DER value>".
+pub fn tls_certificate_subject(cert: &[u8]) -> Option<String> {
+    let (_, certificate, _) = der_next(cert)?;
+    let (_, tbs, _) = der_next(certificate)?;
+
+    //  version (optional, [0]), serialNumber, signature, issuer,
+    //  validity, subject
+    let mut rest = tbs;
+    let (tag, _, after) = der_next(rest)?;
+    if tag == 0xa0 {
+        rest = after;
+    }
+    for _ in 0..4 {
+        rest = der_next(rest)?.2;
+    }
+    let (_, mut rdns, _) = der_next(rest)?;
+
+    let mut names: Vec<String> = vec![];
+    while !rdns.is_empty() {
+        let (_, mut set, after) = der_next(rdns)?;
+        rdns = after;
+        let mut rdn = String::new();
+        while !set.is_empty() {
+            let (_, attribute, after) = der_next(set)?;
+            set = after;
+            let (_, oid, value) = der_next(attribute)?;
+            let (tag, contents, _) = der_next(value)?;
+            let oid = oid_to_string(oid);
+
+            if !rdn.is_empty() {
+                rdn.push('+');
+            }
+            //  UTF8String, PrintableString, IA5String, TeletexString
+            let text = match tag {
+                0x0c | 0x13 | 0x16 | 0x14 => std::str::from_utf8(contents).ok(),
+                _ => None,
+            };
+            match (attribute_name(&oid), text) {
+                (Some(name), Some(text)) => {
+                    rdn.push_str(name);
+                    rdn.push('=');
+                    escape_value(text, &mut rdn);
+                }
+                _ => {
+                    rdn.push_str(&oid);
+                    rdn.push_str("=#");
+                    let der_len = value.len() - der_next(value)?.2.len();
+                    for b in &value[..der_len] {
+                        let _ = write!(rdn, "{:02x}", b);
+                    }
+                }
+            }
+        }
+        names.push(rdn);
+    }
+    //  RFC 4514 lists the most specific RDN first.
+    names.reverse();
+    Some(names.join(","))
+}
+
+//  SHA-256 of the DER encoded certificate, in lower case hex.
+pub fn tls_certificate_fingerprint(cert: &[u8]) -> String {
+    let digest = ring::digest::digest(&ring::digest::SHA256, cert);
+    let mut fingerprint = String::with_capacity(64);
+    for b in digest.as_ref() {
+        let _ = write!(fingerprint, "{:02x}", b);
+    }
+    fingerprint
+}
+
+//  Message properties describing the peer's certificate, if it sent one.
+pub fn tls_peer_properties(session: &Connection) -> HashMap<String, String> {
+    let mut properties = HashMap::new();
+    let Some(cert) = session
+        .peer_certificates()
+        .and_then(|certs: &[CertificateDer]| certs.first())
+    else {
+        return properties;
+    };
+    if let Some(subject) = tls_certificate_subject(cert) {
+        properties.insert(ZMQ_MSG_PROPERTY_TLS_PEER_SUBJECT.to_string(), subject);
+    }
+    properties.insert(
+        ZMQ_MSG_PROPERTY_TLS_PEER_FINGERPRINT.to_string(),
+        tls_certificate_fingerprint(cert),
+    );
+    properties
+}
+
+pub fn tls_init(
+    engine: &mut ZmqEngine,
+    fd: ZmqFileDesc,
+    address: &ZmqAddress,
+    session: Connection,
+    endpoint_uri_pair: EndpointUriPair,
+) {
+    engine.fd = fd;
+    engine.address = address.clone();
+    engine.endpoint_uri_pair = endpoint_uri_pair;
+    engine.tls = Some(Arc::new(Mutex::new(ZmqTlsState {
+        session,
+        stream: ZmqWssStream { fd },
+        established: false,
+    })));
+    engine.tls_properties = HashMap::new();
+}
+
+//  Called by plug() once the socket is registered; the client sends its
+//  hello right away.
+pub fn tls_plug(engine: &mut ZmqEngine) {
+    engine.io_object.set_pollin(engine.handle);
+    tls_do_handshake(engine);
+}
+
+//  Advances the TLS handshake. Returns true once it is complete.
+fn tls_do_handshake(engine: &mut ZmqEngine) -> bool {
+    let Some(tls) = engine.tls.clone() else {
+        return false;
+    };
+    let mut tls = tls.lock().unwrap();
+    if tls.established {
+        return true;
+    }
+    let ZmqTlsState {
+        session, stream, ..
+    } = &mut *tls;
+    let rc = tls_handshake(session, stream);
+
+    engine.io_object.reset_pollout(engine.handle);
+    match rc {
+        Ok(ZmqTlsHandshakeStatus::Complete) => {
+            tls.established = true;
+            engine.tls_properties = tls_peer_properties(&tls.session);
+            if let Some(session) = engine.session.as_mut() {
+                session.peer_properties = engine.tls_properties.clone();
+            }
+            //  Whatever ZMTP wrote during the handshake is ready to go.
+            engine.io_object.set_pollout(engine.handle);
+            true
+        }
+        Ok(ZmqTlsHandshakeStatus::WantWrite) => {
+            engine.io_object.set_pollout(engine.handle);
+            false
+        }
+        Ok(ZmqTlsHandshakeStatus::WantRead) => false,
+        Err(e) => {
+            //  Covers certificate and hostname verification failures too.
+            drop(tls);
+            tls_error(engine, &e);
+            false
+        }
+    }
+}
+
+//  error (connection_error) of the stream engine: reports a failed
+//  handshake, hands the connection back to the session and closes it.
+fn tls_error(engine: &mut ZmqEngine, e: &anyhow::Error) {
+    let handshaked = engine
+        .tls
+        .as_ref()
+        .is_some_and(|tls| tls.lock().unwrap().established);
+    let errno = if e.to_string().starts_with("EPROTO") {
+        EPROTO
+    } else {
+        EPIPE
+    };
+    if let Some(mut session) = engine.session.take() {
+        if !handshaked {
+            session
+                .get_socket()
+                .event_handshake_failed_no_detail(&engine.endpoint_uri_pair, errno);
+        }
+        session.engine_error(handshaked, ZmqErrorReason::ConnectionError);
+    }
+    tls_terminate(engine);
+}
+
+pub fn tls_in_event(engine: &mut ZmqEngine) {
+    if !tls_do_handshake(engine) {
+        return;
+    }
+    engine.in_event_internal();
+}
+
+//  Runs ahead of out_event. Returns false if the encoder should not run,
+//  because the handshake is not done or earlier records are still waiting
+//  for the socket.
+pub fn tls_out_event(engine: &mut ZmqEngine) -> bool {
+    if !tls_do_handshake(engine) {
+        return false;
+    }
+    let Some(tls) = engine.tls.clone() else {
+        return false;
+    };
+    let mut tls = tls.lock().unwrap();
+    let ZmqTlsState {
+        session, stream, ..
+    } = &mut *tls;
+    match tls_flush(session, stream) {
+        Ok(true) => true,
+        Ok(false) => false,
+        Err(e) => {
+            drop(tls);
+            tls_error(engine, &e);
+            false
+        }
+    }
+}
+
+pub fn tls_engine_read(engine: &mut ZmqEngine, data: &mut [u8]) -> i32 {
+    let Some(tls) = engine.tls.clone() else {
+        return -1;
+    };
+    let mut tls = tls.lock().unwrap();
+    let ZmqTlsState {
+        session, stream, ..
+    } = &mut *tls;
+    tls_read(session, stream, data)
+}
+
+pub fn tls_engine_write(engine: &mut ZmqEngine, data: &[u8]) -> i32 {
+    let Some(tls) = engine.tls.clone() else {
+        return -1;
+    };
+    let mut tls = tls.lock().unwrap();
+    let ZmqTlsState {
+        session, stream, ..
+    } = &mut *tls;
+    let rc = tls_write(session, stream, data);
+    if rc >= 0 && session.wants_write() {
+        //  Make sure the tail of the last record gets flushed even if the
+        //  encoder has nothing more to send.
+        engine.io_object.set_pollout(engine.handle);
+    }
+    rc
+}
+
+pub fn tls_terminate(engine: &mut ZmqEngine) {
+    if let Some(tls) = engine.tls.take() {
+        //  Best effort; the peer learns about the close either way.
+        let mut tls = tls.lock().unwrap();
+        let ZmqTlsState {
+            session, stream, ..
+        } = &mut *tls;
+        session.send_close_notify();
+        let _ = tls_flush(session, stream);
+    }
+    engine.unplug();
+    if engine.fd != RETIRED_FD as ZmqFileDesc {
+        unsafe { close(engine.fd as c_int) };
+        engine.fd = RETIRED_FD as ZmqFileDesc;
+    }
+}
+
+pub fn tls_get_endpoint(engine: &mut ZmqEngine) -> EndpointUriPair {
+    engine.endpoint_uri_pair.clone()
+}
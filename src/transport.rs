@@ -1,6 +1,7 @@
 pub enum ZmqTransport {
     ZmqTcp,
     ZmqIpc,
+    ZmqShm,
     ZmqInproc,
     ZmqTipc,
     ZmqVmci,
@@ -10,4 +11,65 @@ pub enum ZmqTransport {
     ZmqWss,
     ZmqEpgm,
     ZmqNorm,
-}
\ No newline at end of file
+    ZmqQuic,
+    ZmqTls,
+    ZmqVsock,
+    ZmqFd,
+}
+impl ZmqTransport {
+    //  The scheme naming the transport in endpoint URIs.
+    pub fn name(&self) -> &'static str {
+        match self {
+            ZmqTransport::ZmqTcp => "tcp",
+            ZmqTransport::ZmqIpc => "ipc",
+            ZmqTransport::ZmqShm => "shm",
+            ZmqTransport::ZmqInproc => "inproc",
+            ZmqTransport::ZmqTipc => "tipc",
+            ZmqTransport::ZmqVmci => "vmci",
+            ZmqTransport::ZmqUdp => "udp",
+            ZmqTransport::ZmqPgm => "pgm",
+            ZmqTransport::ZmqWs => "ws",
+            ZmqTransport::ZmqWss => "wss",
+            ZmqTransport::ZmqEpgm => "epgm",
+            ZmqTransport::ZmqNorm => "norm",
+            ZmqTransport::ZmqQuic => "quic",
+            ZmqTransport::ZmqTls => "tls",
+            ZmqTransport::ZmqVsock => "vsock",
+            ZmqTransport::ZmqFd => "fd",
+        }
+    }
+}
+
+//  Protocols parsed out of endpoint URIs compare against transports by
+//  name.
+impl PartialEq<ZmqTransport> for str {
+    fn eq(&self, other: &ZmqTransport) -> bool {
+        self == other.name()
+    }
+}
+
+impl PartialEq<ZmqTransport> for &str {
+    fn eq(&self, other: &ZmqTransport) -> bool {
+        *self == other.name()
+    }
+}
+
+impl PartialEq<ZmqTransport> for String {
+    fn eq(&self, other: &ZmqTransport) -> bool {
+        self == other.name()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn protocols_match_transports_by_name() {
+        let protocol = String::from("quic");
+        assert!(protocol == ZmqTransport::ZmqQuic);
+        assert!("quic" == ZmqTransport::ZmqQuic);
+        assert!("tcp" != ZmqTransport::ZmqQuic);
+        assert!("wss" != ZmqTransport::ZmqWs);
+    }
+}
@@ -0,0 +1,299 @@
+//  Typed front-end over ZmqSocket.
+//
+//  ZmqContext::create_socket takes the socket type as an integer and every
+//  operation is available on every socket, so using an operation the
+//  pattern does not support (e.g. subscribing on a PUB socket, or
+//  receiving on a PUSH socket) is only reported at runtime. The wrappers
+//  below bind the socket type at construction and only expose the
+//  operations that are valid for that pattern:
+//
+//  * every socket implements ZmqTypedSocket (bind, connect, close, ...),
+//  * sockets that can send implement ZmqSendSocket,
+//  * sockets that can receive implement ZmqRecvSocket,
+//  * pattern specific options (subscribe, join, router_mandatory, ...)
+//    are inherent methods of the wrapper that supports them.
+
+use crate::context::ZmqContext;
+use crate::defines::{
+    ZMQ_CHANNEL, ZMQ_CLIENT, ZMQ_CONFLATE, ZMQ_DEALER, ZMQ_DGRAM, ZMQ_DISH, ZMQ_GATHER,
+    ZMQ_INVERT_MATCHING, ZMQ_LINGER, ZMQ_PAIR, ZMQ_PEER, ZMQ_PROBE_ROUTER, ZMQ_PUB, ZMQ_PULL,
+    ZMQ_PUSH, ZMQ_RADIO, ZMQ_RCVHWM, ZMQ_RCVTIMEO, ZMQ_REP, ZMQ_REQ, ZMQ_REQ_CORRELATE,
+    ZMQ_REQ_RELAXED, ZMQ_ROUTER, ZMQ_ROUTER_HANDOVER, ZMQ_ROUTER_MANDATORY, ZMQ_ROUTING_ID,
+    ZMQ_SCATTER, ZMQ_SERVER, ZMQ_SNDHWM, ZMQ_SNDTIMEO, ZMQ_STREAM, ZMQ_SUB, ZMQ_SUBSCRIBE,
+    ZMQ_UNSUBSCRIBE, ZMQ_XPUB, ZMQ_XPUB_MANUAL,
+    ZMQ_XPUB_NODROP, ZMQ_XPUB_VERBOSE, ZMQ_XPUB_VERBOSER, ZMQ_XPUB_WELCOME_MSG, ZMQ_XSUB,
+};
+use crate::message::ZmqMessage;
+use crate::socket::ZmqSocket;
+
+//  Operations common to all socket types.
+pub trait ZmqTypedSocket<'a> {
+    //  The ZMQ_* socket type the wrapper was created with.
+    const SOCKET_TYPE: i32;
+
+    fn socket(&mut self) -> &mut ZmqSocket<'a>;
+
+    fn bind(&mut self, endpoint_uri: &str) -> anyhow::Result<()> {
+        self.socket().bind(endpoint_uri)
+    }
+
+    fn connect(&mut self, endpoint_uri: &str) -> anyhow::Result<()> {
+        self.socket().connect(endpoint_uri)
+    }
+
+    fn unbind(&mut self, endpoint_uri: &str) -> anyhow::Result<()> {
+        self.socket().term_endpoint(endpoint_uri)
+    }
+
+    fn disconnect(&mut self, endpoint_uri: &str) -> anyhow::Result<()> {
+        self.socket().term_endpoint(endpoint_uri)
+    }
+
+    //  Options valid for every socket type. Anything else goes through
+    //  into_inner.
+    fn set_linger(&mut self, linger_ms: i32) -> anyhow::Result<()> {
+        set_int_option(self.socket(), ZMQ_LINGER, linger_ms)
+    }
+
+    fn set_sndhwm(&mut self, hwm: i32) -> anyhow::Result<()> {
+        set_int_option(self.socket(), ZMQ_SNDHWM, hwm)
+    }
+
+    fn set_rcvhwm(&mut self, hwm: i32) -> anyhow::Result<()> {
+        set_int_option(self.socket(), ZMQ_RCVHWM as i32, hwm)
+    }
+
+    fn set_sndtimeo(&mut self, timeout_ms: i32) -> anyhow::Result<()> {
+        set_int_option(self.socket(), ZMQ_SNDTIMEO as i32, timeout_ms)
+    }
+
+    fn set_rcvtimeo(&mut self, timeout_ms: i32) -> anyhow::Result<()> {
+        set_int_option(self.socket(), ZMQ_RCVTIMEO as i32, timeout_ms)
+    }
+
+    fn close(&mut self) -> anyhow::Result<()> {
+        self.socket().close()
+    }
+}
+
+//  Sockets that can send messages.
+pub trait ZmqSendSocket<'a>: ZmqTypedSocket<'a> {
+    fn send(&mut self, msg: &mut ZmqMessage, flags: i32) -> anyhow::Result<()> {
+        self.socket().send(msg, flags)
+    }
+}
+
+//  Sockets that can receive messages.
+pub trait ZmqRecvSocket<'a>: ZmqTypedSocket<'a> {
+    fn recv(&mut self, flags: i32) -> anyhow::Result<ZmqMessage> {
+        self.socket().recv(flags)
+    }
+}
+
+fn set_int_option(socket: &mut ZmqSocket, option: i32, value: i32) -> anyhow::Result<()> {
+    let optval = value.to_le_bytes();
+    socket.setsockopt(option, &optval, optval.len())
+}
+
+fn set_bool_option(socket: &mut ZmqSocket, option: i32, value: bool) -> anyhow::Result<()> {
+    set_int_option(socket, option, value as i32)
+}
+
+macro_rules! typed_socket {
+    ($(#[$doc:meta])* $name:ident, $socket_type:expr) => {
+        $(#[$doc])*
+        pub struct $name<'a> {
+            inner: ZmqSocket<'a>,
+        }
+
+        impl<'a> $name<'a> {
+            pub fn new(ctx: &mut ZmqContext) -> anyhow::Result<Self> {
+                Ok(Self {
+                    inner: ctx.create_socket($socket_type as i32)?,
+                })
+            }
+
+            //  Gives up the typed view, e.g. to hand the socket to zmq_poll.
+            pub fn into_inner(self) -> ZmqSocket<'a> {
+                self.inner
+            }
+        }
+
+        impl<'a> ZmqTypedSocket<'a> for $name<'a> {
+            const SOCKET_TYPE: i32 = $socket_type as i32;
+
+            fn socket(&mut self) -> &mut ZmqSocket<'a> {
+                &mut self.inner
+            }
+        }
+    };
+}
+
+typed_socket!(PairSocket, ZMQ_PAIR);
+typed_socket!(PubSocket, ZMQ_PUB);
+typed_socket!(SubSocket, ZMQ_SUB);
+typed_socket!(ReqSocket, ZMQ_REQ);
+typed_socket!(RepSocket, ZMQ_REP);
+typed_socket!(DealerSocket, ZMQ_DEALER);
+typed_socket!(RouterSocket, ZMQ_ROUTER);
+typed_socket!(PullSocket, ZMQ_PULL);
+typed_socket!(PushSocket, ZMQ_PUSH);
+typed_socket!(XPubSocket, ZMQ_XPUB);
+typed_socket!(XSubSocket, ZMQ_XSUB);
+typed_socket!(StreamSocket, ZMQ_STREAM);
+typed_socket!(ServerSocket, ZMQ_SERVER);
+typed_socket!(ClientSocket, ZMQ_CLIENT);
+typed_socket!(RadioSocket, ZMQ_RADIO);
+typed_socket!(DishSocket, ZMQ_DISH);
+typed_socket!(GatherSocket, ZMQ_GATHER);
+typed_socket!(ScatterSocket, ZMQ_SCATTER);
+typed_socket!(DgramSocket, ZMQ_DGRAM);
+typed_socket!(PeerSocket, ZMQ_PEER);
+typed_socket!(ChannelSocket, ZMQ_CHANNEL);
+
+impl<'a> ZmqSendSocket<'a> for PairSocket<'a> {}
+impl<'a> ZmqRecvSocket<'a> for PairSocket<'a> {}
+
+impl<'a> ZmqSendSocket<'a> for PubSocket<'a> {}
+
+impl<'a> ZmqRecvSocket<'a> for SubSocket<'a> {}
+
+impl<'a> SubSocket<'a> {
+    pub fn subscribe(&mut self, topic: &[u8]) -> anyhow::Result<()> {
+        self.inner
+            .setsockopt(ZMQ_SUBSCRIBE as i32, topic, topic.len())
+    }
+
+    pub fn unsubscribe(&mut self, topic: &[u8]) -> anyhow::Result<()> {
+        self.inner
+            .setsockopt(ZMQ_UNSUBSCRIBE as i32, topic, topic.len())
+    }
+
+    pub fn set_invert_matching(&mut self, value: bool) -> anyhow::Result<()> {
+        set_bool_option(&mut self.inner, ZMQ_INVERT_MATCHING as i32, value)
+    }
+
+    pub fn set_conflate(&mut self, value: bool) -> anyhow::Result<()> {
+        set_bool_option(&mut self.inner, ZMQ_CONFLATE as i32, value)
+    }
+}
+
+impl<'a> ZmqSendSocket<'a> for ReqSocket<'a> {}
+impl<'a> ZmqRecvSocket<'a> for ReqSocket<'a> {}
+
+impl<'a> ReqSocket<'a> {
+    pub fn set_correlate(&mut self, value: bool) -> anyhow::Result<()> {
+        set_bool_option(&mut self.inner, ZMQ_REQ_CORRELATE as i32, value)
+    }
+
+    pub fn set_relaxed(&mut self, value: bool) -> anyhow::Result<()> {
+        set_bool_option(&mut self.inner, ZMQ_REQ_RELAXED as i32, value)
+    }
+}
+
+impl<'a> ZmqSendSocket<'a> for RepSocket<'a> {}
+impl<'a> ZmqRecvSocket<'a> for RepSocket<'a> {}
+
+impl<'a> ZmqSendSocket<'a> for DealerSocket<'a> {}
+impl<'a> ZmqRecvSocket<'a> for DealerSocket<'a> {}
+
+impl<'a> DealerSocket<'a> {
+    pub fn set_routing_id(&mut self, routing_id: &[u8]) -> anyhow::Result<()> {
+        self.inner
+            .setsockopt(ZMQ_ROUTING_ID as i32, routing_id, routing_id.len())
+    }
+}
+
+impl<'a> ZmqSendSocket<'a> for RouterSocket<'a> {}
+impl<'a> ZmqRecvSocket<'a> for RouterSocket<'a> {}
+
+impl<'a> RouterSocket<'a> {
+    pub fn set_routing_id(&mut self, routing_id: &[u8]) -> anyhow::Result<()> {
+        self.inner
+            .setsockopt(ZMQ_ROUTING_ID as i32, routing_id, routing_id.len())
+    }
+
+    pub fn set_router_mandatory(&mut self, value: bool) -> anyhow::Result<()> {
+        set_bool_option(&mut self.inner, ZMQ_ROUTER_MANDATORY as i32, value)
+    }
+
+    pub fn set_router_handover(&mut self, value: bool) -> anyhow::Result<()> {
+        set_bool_option(&mut self.inner, ZMQ_ROUTER_HANDOVER as i32, value)
+    }
+
+    pub fn set_probe_router(&mut self, value: bool) -> anyhow::Result<()> {
+        set_bool_option(&mut self.inner, ZMQ_PROBE_ROUTER as i32, value)
+    }
+}
+
+impl<'a> ZmqRecvSocket<'a> for PullSocket<'a> {}
+
+impl<'a> ZmqSendSocket<'a> for PushSocket<'a> {}
+
+//  XPUB receives the subscriptions of its peers as messages.
+impl<'a> ZmqSendSocket<'a> for XPubSocket<'a> {}
+impl<'a> ZmqRecvSocket<'a> for XPubSocket<'a> {}
+
+impl<'a> XPubSocket<'a> {
+    pub fn set_verbose(&mut self, value: bool) -> anyhow::Result<()> {
+        set_bool_option(&mut self.inner, ZMQ_XPUB_VERBOSE as i32, value)
+    }
+
+    pub fn set_verboser(&mut self, value: bool) -> anyhow::Result<()> {
+        set_bool_option(&mut self.inner, ZMQ_XPUB_VERBOSER as i32, value)
+    }
+
+    pub fn set_nodrop(&mut self, value: bool) -> anyhow::Result<()> {
+        set_bool_option(&mut self.inner, ZMQ_XPUB_NODROP as i32, value)
+    }
+
+    pub fn set_manual(&mut self, value: bool) -> anyhow::Result<()> {
+        set_bool_option(&mut self.inner, ZMQ_XPUB_MANUAL as i32, value)
+    }
+
+    pub fn set_welcome_msg(&mut self, welcome_msg: &[u8]) -> anyhow::Result<()> {
+        self.inner
+            .setsockopt(ZMQ_XPUB_WELCOME_MSG as i32, welcome_msg, welcome_msg.len())
+    }
+}
+
+//  XSUB sends its subscriptions upstream as messages.
+impl<'a> ZmqSendSocket<'a> for XSubSocket<'a> {}
+impl<'a> ZmqRecvSocket<'a> for XSubSocket<'a> {}
+
+impl<'a> ZmqSendSocket<'a> for StreamSocket<'a> {}
+impl<'a> ZmqRecvSocket<'a> for StreamSocket<'a> {}
+
+impl<'a> ZmqSendSocket<'a> for ServerSocket<'a> {}
+impl<'a> ZmqRecvSocket<'a> for ServerSocket<'a> {}
+
+impl<'a> ZmqSendSocket<'a> for ClientSocket<'a> {}
+impl<'a> ZmqRecvSocket<'a> for ClientSocket<'a> {}
+
+//  RADIO messages carry their group, see ZmqMessage::set_group.
+impl<'a> ZmqSendSocket<'a> for RadioSocket<'a> {}
+
+impl<'a> ZmqRecvSocket<'a> for DishSocket<'a> {}
+
+impl<'a> DishSocket<'a> {
+    pub fn join(&mut self, group: &str) -> anyhow::Result<()> {
+        self.inner.join(group)
+    }
+
+    pub fn leave(&mut self, group: &str) -> anyhow::Result<()> {
+        self.inner.leave(group)
+    }
+}
+
+impl<'a> ZmqRecvSocket<'a> for GatherSocket<'a> {}
+
+impl<'a> ZmqSendSocket<'a> for ScatterSocket<'a> {}
+
+impl<'a> ZmqSendSocket<'a> for DgramSocket<'a> {}
+impl<'a> ZmqRecvSocket<'a> for DgramSocket<'a> {}
+
+impl<'a> ZmqSendSocket<'a> for PeerSocket<'a> {}
+impl<'a> ZmqRecvSocket<'a> for PeerSocket<'a> {}
+
+impl<'a> ZmqSendSocket<'a> for ChannelSocket<'a> {}
+impl<'a> ZmqRecvSocket<'a> for ChannelSocket<'a> {}
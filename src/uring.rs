@@ -0,0 +1,845 @@
+//  io_uring(7) I/O thread poller.
+//
+//  Out of the box it behaves like ZmqEpoll: the interest set with
+//  set_pollin/set_pollout becomes one-shot IORING_OP_POLL_ADD requests and
+//  readiness is reported through in_event/out_event, so objects that do
+//  their own recv/send/accept keep working unchanged.
+//
+//  Engines and listeners can additionally hand the I/O itself to the kernel
+//  with take_read, submit_write and take_accept. Those queue READ_FIXED,
+//  WRITE and ACCEPT requests whose completions are reported through the same
+//  in_event/out_event callbacks. Everything queued during one loop iteration
+//  reaches the kernel with a single io_uring_enter(2).
+//
+//  The descriptors are non-blocking and io_uring honours that, so each of
+//  these requests is linked behind a POLL_ADD for the matching direction
+//  rather than failing with EAGAIN when nothing is ready yet.
+//
+//  Reads land in a registered_buffer_pool (decoder_allocators.rs) whose
+//  buffers are registered with the ring once, when the poller is created.
+//  take_read leases the filled buffer to the engine, whose decoder works on
+//  it in place; the buffer goes back to the pool when the lease is dropped.
+//  Descriptors that find every buffer leased out wait for one in 'starved'.
+//  Writes are copied into a buffer owned by the request, which is kept for
+//  the next write of the descriptor.
+//
+//  Kernels without io_uring, or with it disabled, get ZmqEpoll instead; see
+//  ZmqPoller at the end of this file.
+
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::mem;
+use std::rc::Rc;
+use std::time::Duration;
+
+use io_uring::{opcode, squeue, types, IoUring};
+use libc::{
+    accept4, c_void, close, send, sockaddr, sockaddr_storage, socklen_t, EAGAIN, EBUSY, ECANCELED,
+    EINTR, EIO, ETIME, MSG_DONTWAIT, MSG_NOSIGNAL, POLLERR, POLLHUP, POLLIN, POLLOUT, SOCK_CLOEXEC,
+    SOCK_NONBLOCK,
+};
+
+use crate::config::{MAX_IO_EVENTS, URING_REGISTERED_BUFFERS, URING_REGISTERED_BUFFER_SIZE};
+use crate::context::ZmqContext;
+use crate::decoder_allocators::{registered_buffer, registered_buffer_pool};
+use crate::defines::{ZmqFileDesc, ZmqHandle, RETIRED_FD};
+use crate::epoll::ZmqEpoll;
+use crate::err::set_errno;
+use crate::events::ZmqEvents;
+use crate::poller_base::WorkerPollerBase;
+
+//  What a submitted request is about. The request owns whatever memory the
+//  kernel may still be writing to or reading from, so removing a descriptor
+//  with requests in flight never leaves the kernel with a dangling pointer.
+pub enum UringOpKind {
+    Poll,
+    Read(u16),
+    Write(Vec<u8>, usize),
+    Accept(Box<(sockaddr_storage, socklen_t)>),
+    //  Cancellations and the polls linked in front of completion requests;
+    //  nothing to do when they complete.
+    Internal,
+}
+
+pub struct UringOp {
+    pub fd: ZmqFileDesc,
+    pub generation: u32,
+    pub kind: UringOpKind,
+}
+
+//  A completed read not yet consumed by take_read.
+pub struct UringRead {
+    pub buf_index: u16,
+    //  Bytes read, 0 on end of stream or -errno.
+    pub result: i32,
+}
+
+pub struct UringEntry {
+    pub fd: ZmqFileDesc,
+    pub reactor: ZmqEvents,
+    //  Bumped every time the slot is (re)used, see ZmqEpoll.
+    pub generation: u32,
+    pub pollin: bool,
+    pub pollout: bool,
+    //  The POLL_ADD request in flight and the events it waits for.
+    pub poll_token: Option<u64>,
+    pub poll_events: u32,
+    pub read_token: Option<u64>,
+    pub reads: VecDeque<UringRead>,
+    //  A read is waiting in ZmqUring::starved for a buffer to come back.
+    pub starved: bool,
+    pub write_token: Option<u64>,
+    //  Failure of a write already reported as successful to the caller.
+    pub write_error: Option<i32>,
+    //  Buffer of the last completed write, reused by the next one.
+    pub write_buf: Vec<u8>,
+    pub accept_token: Option<u64>,
+    pub accepted: VecDeque<(ZmqFileDesc, sockaddr_storage, socklen_t)>,
+}
+
+impl Default for UringEntry {
+    fn default() -> Self {
+        Self {
+            fd: RETIRED_FD,
+            reactor: ZmqEvents {},
+            generation: 0,
+            pollin: false,
+            pollout: false,
+            poll_token: None,
+            poll_events: 0,
+            read_token: None,
+            reads: VecDeque::new(),
+            starved: false,
+            write_token: None,
+            write_error: None,
+            write_buf: vec![],
+            accept_token: None,
+            accepted: VecDeque::new(),
+        }
+    }
+}
+
+pub struct ZmqUring<'a> {
+    pub ring: IoUring,
+    //  Requests in flight, indexed by their user_data.
+    pub ops: Vec<Option<UringOp>>,
+    pub free_tokens: Vec<u64>,
+    //  Registered descriptors, indexed by the descriptor itself.
+    pub fd_table: Vec<UringEntry>,
+    //  Receive buffers registered with the ring, shared with the leases
+    //  handed out by take_read.
+    pub pool: Rc<RefCell<registered_buffer_pool>>,
+    //  Descriptors (with their generation) waiting for a receive buffer.
+    pub starved: VecDeque<(ZmqFileDesc, u32)>,
+    //  Completions not yet dispatched: reaped early to make room in the
+    //  completion queue, or made up for requests the kernel refused.
+    pub backlog: Vec<(u64, i32)>,
+    pub base: WorkerPollerBase<'a>,
+}
+
+impl<'a> ZmqUring<'a> {
+    //  Sets up a ring and the receive buffers registered with it. Fails
+    //  where the kernel has no io_uring or does not let us use it.
+    pub fn setup_ring() -> anyhow::Result<(IoUring, registered_buffer_pool)> {
+        let ring = IoUring::new(MAX_IO_EVENTS as u32)?;
+        let mut pool =
+            registered_buffer_pool::new(URING_REGISTERED_BUFFERS, URING_REGISTERED_BUFFER_SIZE);
+        //  The buffers are owned by the pool, which outlives the ring. Their
+        //  heap memory stays put when the pool is moved.
+        unsafe { ring.submitter().register_buffers(&pool.iovecs()) }?;
+        Ok((ring, pool))
+    }
+
+    pub fn new(ctx: &mut ZmqContext, ring: IoUring, pool: registered_buffer_pool) -> Self {
+        Self {
+            ring,
+            ops: vec![],
+            free_tokens: vec![],
+            fd_table: vec![],
+            pool: Rc::new(RefCell::new(pool)),
+            starved: VecDeque::new(),
+            backlog: vec![],
+            base: WorkerPollerBase::new(&mut ctx.thread_ctx),
+        }
+    }
+
+    fn alloc_token(&mut self, op: UringOp) -> u64 {
+        match self.free_tokens.pop() {
+            Some(token) => {
+                self.ops[token as usize] = Some(op);
+                token
+            }
+            None => {
+                self.ops.push(Some(op));
+                (self.ops.len() - 1) as u64
+            }
+        }
+    }
+
+    //  Queues a chain of requests; they are handed to the kernel on the next
+    //  loop iteration together with everything else queued until then.
+    //  Returns the token of the last request of the chain.
+    //
+    //  If the submission queue is full and the kernel will not take it,
+    //  the requests of the chain complete with the error on the next loop
+    //  iteration instead, and their owners see it through in_event or
+    //  out_event as they would any other failed request.
+    fn push(&mut self, chain: Vec<(UringOp, squeue::Entry)>) -> u64 {
+        //  A chain must not be split across two submissions.
+        loop {
+            let room = {
+                let sq = self.ring.submission();
+                sq.capacity() - sq.len()
+            };
+            if room >= chain.len() {
+                break;
+            }
+            match self.ring.submit() {
+                Ok(_) => {}
+                Err(e) if e.raw_os_error() == Some(EINTR) => {}
+                //  The completion queue is full; empty it and try again.
+                Err(e) if e.raw_os_error() == Some(EBUSY) => self.reap(),
+                Err(e) => {
+                    let errno = e.raw_os_error().unwrap_or(EIO);
+                    let mut token = 0;
+                    for (op, _) in chain {
+                        token = self.alloc_token(op);
+                        self.backlog.push((token, -errno));
+                    }
+                    return token;
+                }
+            }
+        }
+
+        let mut token = 0;
+        for (op, sqe) in chain {
+            token = self.alloc_token(op);
+            let sqe = sqe.user_data(token);
+            unsafe { self.ring.submission().push(&sqe) }.unwrap();
+        }
+        token
+    }
+
+    //  Queues a completion request to run once the descriptor is ready for
+    //  'events'.
+    fn push_after_poll(&mut self, events: u32, op: UringOp, sqe: squeue::Entry) -> u64 {
+        let poll = opcode::PollAdd::new(types::Fd(op.fd), events)
+            .build()
+            .flags(squeue::Flags::IO_LINK);
+        let poll_op = UringOp {
+            fd: op.fd,
+            generation: op.generation,
+            kind: UringOpKind::Internal,
+        };
+        self.push(vec![(poll_op, poll), (op, sqe)])
+    }
+
+    fn new_op(&self, handle_: &ZmqHandle, kind: UringOpKind) -> UringOp {
+        let entry = &self.fd_table[*handle_ as usize];
+        UringOp {
+            fd: entry.fd,
+            generation: entry.generation,
+            kind,
+        }
+    }
+
+    fn cancel(&mut self, token: u64) {
+        let op = UringOp {
+            fd: RETIRED_FD,
+            generation: 0,
+            kind: UringOpKind::Internal,
+        };
+        self.push(vec![(op, opcode::AsyncCancel::new(token).build())]);
+    }
+
+    //  Brings the POLL_ADD request of the entry in line with its interest
+    //  set. Directions with completion requests in flight are not polled
+    //  for; their completions wake the reactor instead. Neither is input
+    //  while a read waits for a buffer, as nothing could be read anyway.
+    fn update_poll(&mut self, handle_: &ZmqHandle) {
+        let entry = &self.fd_table[*handle_ as usize];
+        let mut events = 0u32;
+        if entry.pollin
+            && entry.read_token.is_none()
+            && entry.accept_token.is_none()
+            && !entry.starved
+        {
+            events |= POLLIN as u32;
+        }
+        if entry.pollout && entry.write_token.is_none() {
+            events |= POLLOUT as u32;
+        }
+        if entry.poll_token.is_some() && entry.poll_events == events {
+            return;
+        }
+
+        if let Some(token) = self.fd_table[*handle_ as usize].poll_token.take() {
+            self.cancel(token);
+        }
+        if events != 0 {
+            let op = self.new_op(handle_, UringOpKind::Poll);
+            let sqe = opcode::PollAdd::new(types::Fd(*handle_), events).build();
+            let token = self.push(vec![(op, sqe)]);
+            let entry = &mut self.fd_table[*handle_ as usize];
+            entry.poll_token = Some(token);
+            entry.poll_events = events;
+        }
+    }
+
+    pub fn add_fd(&mut self, fd: &ZmqHandle, reactor_: &mut ZmqEvents) -> ZmqHandle {
+        self.base.check_thread();
+        // zmq_assert (fd != retired_fd);
+
+        //  If the file descriptor table is too small expand it.
+        let idx = *fd as usize;
+        if self.fd_table.len() <= idx {
+            self.fd_table.resize_with(idx + 1, UringEntry::default);
+        }
+
+        let generation = self.fd_table[idx].generation.wrapping_add(1);
+        self.fd_table[idx] = UringEntry {
+            fd: *fd,
+            reactor: mem::replace(reactor_, ZmqEvents {}),
+            generation,
+            ..Default::default()
+        };
+
+        //  Increase the load metric of the thread.
+        self.base.base.adjust_load(1);
+
+        return *fd;
+    }
+
+    pub fn rm_fd(&mut self, handle_: &ZmqHandle) {
+        self.base.check_thread();
+        let entry = &mut self.fd_table[*handle_ as usize];
+        let (fd, generation) = (entry.fd, entry.generation);
+        let reads: Vec<u16> = entry.reads.drain(..).map(|read| read.buf_index).collect();
+        let accepted: Vec<ZmqFileDesc> = entry.accepted.drain(..).map(|a| a.0).collect();
+        entry.fd = RETIRED_FD;
+
+        //  Everything in flight for the descriptor, polls linked in front of
+        //  completion requests included, is cancelled; the completions are
+        //  then dropped as stale.
+        let tokens: Vec<u64> = (0..self.ops.len() as u64)
+            .filter(|&token| match &self.ops[token as usize] {
+                Some(op) => op.fd == fd && op.generation == generation,
+                None => false,
+            })
+            .collect();
+        for token in tokens {
+            self.cancel(token);
+        }
+        for buf_index in reads {
+            self.pool.borrow_mut().release(buf_index);
+        }
+        for fd in accepted {
+            unsafe { close(fd) };
+        }
+
+        //  Decrease the load metric of the thread.
+        self.base.base.adjust_load(-1);
+    }
+
+    pub fn set_pollin(&mut self, handle_: &ZmqHandle) {
+        self.base.check_thread();
+        self.fd_table[*handle_ as usize].pollin = true;
+        self.update_poll(handle_);
+    }
+
+    pub fn reset_pollin(&mut self, handle_: &ZmqHandle) {
+        self.base.check_thread();
+        self.fd_table[*handle_ as usize].pollin = false;
+        self.update_poll(handle_);
+    }
+
+    pub fn set_pollout(&mut self, handle_: &ZmqHandle) {
+        self.base.check_thread();
+        self.fd_table[*handle_ as usize].pollout = true;
+        self.update_poll(handle_);
+    }
+
+    pub fn reset_pollout(&mut self, handle_: &ZmqHandle) {
+        self.base.check_thread();
+        self.fd_table[*handle_ as usize].pollout = false;
+        self.update_poll(handle_);
+    }
+
+    //  Completion based counterpart of tcp_read: hands out the registered
+    //  buffer the kernel has read into, without copying. An empty buffer
+    //  means end of stream. If nothing has been read yet, a read is
+    //  submitted and EAGAIN returned; in_event fires once it completes.
+    pub fn take_read(&mut self, handle_: &ZmqHandle) -> Result<registered_buffer, i32> {
+        self.base.check_thread();
+        let entry = &mut self.fd_table[*handle_ as usize];
+        if let Some(read) = entry.reads.pop_front() {
+            let buf = registered_buffer::new(
+                self.pool.clone(),
+                read.buf_index,
+                read.result.max(0) as usize,
+            );
+            if read.result < 0 {
+                return Err(-read.result);
+            }
+            return Ok(buf);
+        }
+
+        if entry.read_token.is_none() && !entry.starved {
+            let buf_index = self.pool.borrow_mut().acquire();
+            match buf_index {
+                Some(buf_index) => self.push_read(handle_, buf_index),
+                //  Every registered buffer is leased out; the read is
+                //  submitted once one comes back.
+                None => {
+                    let entry = &mut self.fd_table[*handle_ as usize];
+                    entry.starved = true;
+                    self.starved.push_back((entry.fd, entry.generation));
+                    self.update_poll(handle_);
+                }
+            }
+        }
+        Err(EAGAIN)
+    }
+
+    //  Submits the reads that have been waiting for a receive buffer, as
+    //  far as buffers have come back.
+    fn resume_starved(&mut self) {
+        while let Some(&(fd, generation)) = self.starved.front() {
+            let buf_index = match self.pool.borrow_mut().acquire() {
+                Some(buf_index) => buf_index,
+                None => break,
+            };
+            self.starved.pop_front();
+            match self.live_entry(fd, generation) {
+                Some(entry) if entry.starved => {
+                    entry.starved = false;
+                    let handle = fd as ZmqHandle;
+                    self.push_read(&handle, buf_index);
+                }
+                _ => self.pool.borrow_mut().release(buf_index),
+            }
+        }
+    }
+
+    fn push_read(&mut self, handle_: &ZmqHandle, buf_index: u16) {
+        let buf = self.pool.borrow_mut().buffer(buf_index).as_mut_ptr();
+        let sqe = opcode::ReadFixed::new(
+            types::Fd(*handle_),
+            buf,
+            URING_REGISTERED_BUFFER_SIZE as u32,
+            buf_index,
+        )
+        .build();
+        let op = self.new_op(handle_, UringOpKind::Read(buf_index));
+        let token = self.push_after_poll(POLLIN as u32, op, sqe);
+        self.fd_table[*handle_ as usize].read_token = Some(token);
+        self.update_poll(handle_);
+    }
+
+    //  Completion based counterpart of tcp_write. The data is copied and
+    //  handed to the kernel, which counts as written; out_event fires once
+    //  the kernel is done with it. Returns 0 while a previous write is still
+    //  in flight, and -1 with errno set if a previous write failed.
+    //
+    //  The copy goes into the descriptor's recycled write buffer rather
+    //  than being read from 'data' in place: the caller's buffer may be
+    //  refilled or freed (rm_fd does not wait for cancellations) while the
+    //  kernel is still sending from it.
+    pub fn submit_write(&mut self, handle_: &ZmqHandle, data: &[u8]) -> i32 {
+        self.base.check_thread();
+        let entry = &mut self.fd_table[*handle_ as usize];
+        if let Some(err) = entry.write_error.take() {
+            set_errno(err);
+            return -1;
+        }
+        if entry.write_token.is_some() {
+            return 0;
+        }
+        let mut buf = mem::take(&mut entry.write_buf);
+        buf.clear();
+        buf.extend_from_slice(data);
+        self.push_write(handle_, buf, 0);
+        data.len() as i32
+    }
+
+    fn push_write(&mut self, handle_: &ZmqHandle, data: Vec<u8>, written: usize) {
+        let remaining = &data[written..];
+        let sqe = opcode::Write::new(
+            types::Fd(*handle_),
+            remaining.as_ptr(),
+            remaining.len() as u32,
+        )
+        .build();
+        //  Moving the Vec into the op does not move its heap buffer.
+        let op = self.new_op(handle_, UringOpKind::Write(data, written));
+        let token = self.push_after_poll(POLLOUT as u32, op, sqe);
+        self.fd_table[*handle_ as usize].write_token = Some(token);
+        self.update_poll(handle_);
+    }
+
+    //  Completion based counterpart of accept(2): returns a connection the
+    //  kernel has accepted, with its peer address. There is always one
+    //  accept kept in flight; in_event fires when it completes. Until then
+    //  None is returned with errno set to EAGAIN, as accept(2) would.
+    pub fn take_accept(
+        &mut self,
+        handle_: &ZmqHandle,
+    ) -> Option<(ZmqFileDesc, sockaddr_storage, socklen_t)> {
+        self.base.check_thread();
+        let accepted = self.fd_table[*handle_ as usize].accepted.pop_front();
+        if self.fd_table[*handle_ as usize].accept_token.is_none() {
+            self.push_accept(handle_);
+        }
+        if accepted.is_none() {
+            set_errno(EAGAIN);
+        }
+        accepted
+    }
+
+    fn push_accept(&mut self, handle_: &ZmqHandle) {
+        let mut addr: Box<(sockaddr_storage, socklen_t)> = Box::new(unsafe { mem::zeroed() });
+        addr.1 = mem::size_of::<sockaddr_storage>() as socklen_t;
+        let sqe = opcode::Accept::new(
+            types::Fd(*handle_),
+            &mut addr.0 as *mut sockaddr_storage as *mut libc::sockaddr,
+            &mut addr.1,
+        )
+        .flags(SOCK_CLOEXEC | SOCK_NONBLOCK)
+        .build();
+        //  Moving the Box into the op does not move the address it points to.
+        let op = self.new_op(handle_, UringOpKind::Accept(addr));
+        let token = self.push_after_poll(POLLIN as u32, op, sqe);
+        self.fd_table[*handle_ as usize].accept_token = Some(token);
+        self.update_poll(handle_);
+    }
+
+    pub fn start(&mut self, name: &str) {
+        self.base.start(name);
+    }
+
+    pub fn stop(&mut self) {
+        self.base.check_thread();
+        //  no-op... thread is stopped when no more fds or timers are registered
+    }
+
+    pub fn max_fds(&mut self) -> i32 {
+        return -1;
+    }
+
+    //  Returns the entry a request was submitted for, or None if the
+    //  descriptor has been removed (or removed and re-added) since.
+    fn live_entry(&mut self, fd: ZmqFileDesc, generation: u32) -> Option<&mut UringEntry> {
+        match self.fd_table.get_mut(fd as usize) {
+            Some(entry) if entry.fd != RETIRED_FD && entry.generation == generation => Some(entry),
+            _ => None,
+        }
+    }
+
+    fn complete(&mut self, token: u64, result: i32) {
+        let op = self.ops[token as usize].take().unwrap();
+        self.free_tokens.push(token);
+        let fd = op.fd;
+
+        match op.kind {
+            UringOpKind::Internal => return,
+            UringOpKind::Poll => {
+                let entry = match self.live_entry(fd, op.generation) {
+                    Some(entry) if entry.poll_token == Some(token) => entry,
+                    _ => return,
+                };
+                entry.poll_token = None;
+                if result == -ECANCELED {
+                    //  Superseded by a request with a different event mask.
+                } else if result < 0 || result & (POLLERR | POLLHUP) as i32 != 0 {
+                    entry.reactor.in_event();
+                } else {
+                    if result & POLLOUT as i32 != 0 {
+                        entry.reactor.out_event();
+                    }
+                    //  A callback may retire (or replace) the descriptor, so
+                    //  the entry is looked up again before every dispatch.
+                    if result & POLLIN as i32 != 0 {
+                        if let Some(entry) = self.live_entry(fd, op.generation) {
+                            entry.reactor.in_event();
+                        }
+                    }
+                }
+            }
+            UringOpKind::Read(buf_index) => match self.live_entry(fd, op.generation) {
+                //  Lost a race with another reader of the descriptor.
+                Some(_) if result == -EAGAIN => {
+                    let handle = fd as ZmqHandle;
+                    self.push_read(&handle, buf_index);
+                    return;
+                }
+                Some(entry) => {
+                    entry.read_token = None;
+                    entry.reads.push_back(UringRead { buf_index, result });
+                    entry.reactor.in_event();
+                }
+                None => self.pool.borrow_mut().release(buf_index),
+            },
+            UringOpKind::Write(data, written) => {
+                let entry = match self.live_entry(fd, op.generation) {
+                    Some(entry) => entry,
+                    None => return,
+                };
+                entry.write_token = None;
+                if result == -EAGAIN {
+                    let handle = fd as ZmqHandle;
+                    self.push_write(&handle, data, written);
+                    return;
+                } else if result < 0 {
+                    entry.write_error = Some(-result);
+                } else if written + (result as usize) < data.len() {
+                    //  Short write; the rest goes out before anything new.
+                    let handle = fd as ZmqHandle;
+                    self.push_write(&handle, data, written + result as usize);
+                    return;
+                }
+                entry.write_buf = data;
+                entry.reactor.out_event();
+            }
+            UringOpKind::Accept(addr) => match self.live_entry(fd, op.generation) {
+                Some(entry) => {
+                    entry.accept_token = None;
+                    if result >= 0 {
+                        entry.accepted.push_back((result as ZmqFileDesc, addr.0, addr.1));
+                        entry.reactor.in_event();
+                    } else {
+                        //  The connection went away before it was accepted
+                        //  (or resources ran out); wait for the next one.
+                        let handle = fd as ZmqHandle;
+                        self.push_accept(&handle);
+                        return;
+                    }
+                }
+                None => {
+                    if result >= 0 {
+                        unsafe { close(result) };
+                    }
+                }
+            },
+        }
+
+        if let Some(entry) = self.live_entry(fd, op.generation) {
+            let handle = entry.fd as ZmqHandle;
+            self.update_poll(&handle);
+        }
+    }
+
+    //  Moves whatever has completed out of the completion queue.
+    fn reap(&mut self) {
+        self.backlog.extend(
+            self.ring
+                .completion()
+                .map(|cqe| (cqe.user_data(), cqe.result())),
+        );
+    }
+
+    //  Hands over everything queued since the last round, waits up to
+    //  'timeout' ms (0 meaning forever) for at least one completion and
+    //  dispatches whatever has completed.
+    pub fn wait_and_dispatch(&mut self, timeout: u64) {
+        self.resume_starved();
+
+        //  Completions already at hand are not waited for; whatever is
+        //  queued goes to the kernel with the next round.
+        if self.backlog.is_empty() {
+            let rc = if timeout != 0 {
+                let ts = types::Timespec::from(Duration::from_millis(timeout));
+                let args = types::SubmitArgs::new().timespec(&ts);
+                self.ring.submitter().submit_with_args(1, &args)
+            } else {
+                self.ring.submit_and_wait(1)
+            };
+            if let Err(e) = rc {
+                // errno_assert (errno == EINTR || errno == ETIME || errno == EBUSY);
+                let errno = e.raw_os_error();
+                assert!(
+                    errno == Some(EINTR) || errno == Some(ETIME) || errno == Some(EBUSY),
+                    "io_uring_enter failed: {}",
+                    e
+                );
+            }
+        }
+
+        //  Completing a request may queue new ones, so the completion queue
+        //  is drained before anything is dispatched.
+        self.reap();
+        for (token, result) in mem::take(&mut self.backlog) {
+            self.complete(token, result);
+        }
+    }
+
+    //  Main event loop.
+    pub fn loop_fn(&mut self) {
+        loop {
+            //  Execute any due timers.
+            let timeout = self.base.base.execute_timers();
+
+            if self.base.base.get_load() == 0 && timeout == 0 {
+                break;
+            }
+
+            //  With no descriptors left the ring has nothing to complete,
+            //  so this just sleeps until the next timer is due.
+            self.wait_and_dispatch(timeout);
+        }
+    }
+}
+
+impl<'a> Drop for ZmqUring<'a> {
+    fn drop(&mut self) {
+        self.base.stop_worker();
+        //  Fields drop in declaration order, so the ring (and with it every
+        //  request still in flight) goes before the memory those requests
+        //  point to.
+    }
+}
+
+//  The I/O thread poller with the io-uring feature: io_uring where the
+//  kernel lets us set up a ring, epoll otherwise. With epoll, submit_write
+//  and take_accept do the I/O on the spot, the way the readiness based
+//  transports do, and take_read leaves reading to the caller.
+pub enum ZmqPoller<'a> {
+    Uring(Box<ZmqUring<'a>>),
+    Epoll(ZmqEpoll<'a>),
+}
+
+impl<'a> ZmqPoller<'a> {
+    pub fn new(ctx: &mut ZmqContext) -> Self {
+        match ZmqUring::setup_ring() {
+            Ok((ring, pool)) => Self::Uring(Box::new(ZmqUring::new(ctx, ring, pool))),
+            //  ENOSYS, EPERM (seccomp, io_uring_disabled) or ENOMEM for the
+            //  registered buffers.
+            Err(_) => Self::Epoll(ZmqEpoll::new(ctx)),
+        }
+    }
+
+    pub fn add_fd(&mut self, fd: &ZmqHandle, reactor_: &mut ZmqEvents) -> ZmqHandle {
+        match self {
+            Self::Uring(uring) => uring.add_fd(fd, reactor_),
+            Self::Epoll(epoll) => epoll.add_fd(fd, reactor_),
+        }
+    }
+
+    pub fn rm_fd(&mut self, handle_: &ZmqHandle) {
+        match self {
+            Self::Uring(uring) => uring.rm_fd(handle_),
+            Self::Epoll(epoll) => epoll.rm_fd(handle_),
+        }
+    }
+
+    pub fn set_pollin(&mut self, handle_: &ZmqHandle) {
+        match self {
+            Self::Uring(uring) => uring.set_pollin(handle_),
+            Self::Epoll(epoll) => epoll.set_pollin(handle_),
+        }
+    }
+
+    pub fn reset_pollin(&mut self, handle_: &ZmqHandle) {
+        match self {
+            Self::Uring(uring) => uring.reset_pollin(handle_),
+            Self::Epoll(epoll) => epoll.reset_pollin(handle_),
+        }
+    }
+
+    pub fn set_pollout(&mut self, handle_: &ZmqHandle) {
+        match self {
+            Self::Uring(uring) => uring.set_pollout(handle_),
+            Self::Epoll(epoll) => epoll.set_pollout(handle_),
+        }
+    }
+
+    pub fn reset_pollout(&mut self, handle_: &ZmqHandle) {
+        match self {
+            Self::Uring(uring) => uring.reset_pollout(handle_),
+            Self::Epoll(epoll) => epoll.reset_pollout(handle_),
+        }
+    }
+
+    //  None with epoll, which leaves reading to the caller.
+    pub fn take_read(&mut self, handle_: &ZmqHandle) -> Option<Result<registered_buffer, i32>> {
+        match self {
+            Self::Uring(uring) => Some(uring.take_read(handle_)),
+            Self::Epoll(_) => None,
+        }
+    }
+
+    pub fn submit_write(&mut self, handle_: &ZmqHandle, data: &[u8]) -> i32 {
+        match self {
+            Self::Uring(uring) => uring.submit_write(handle_, data),
+            Self::Epoll(_) => {
+                let rc = unsafe {
+                    send(
+                        *handle_,
+                        data.as_ptr() as *const c_void,
+                        data.len(),
+                        MSG_DONTWAIT | MSG_NOSIGNAL,
+                    )
+                };
+                //  Nothing could be written; as tcp_write, that is not an
+                //  error.
+                let errno = std::io::Error::last_os_error().raw_os_error();
+                if rc == -1 && matches!(errno, Some(EAGAIN) | Some(EINTR)) {
+                    return 0;
+                }
+                rc as i32
+            }
+        }
+    }
+
+    pub fn take_accept(
+        &mut self,
+        handle_: &ZmqHandle,
+    ) -> Option<(ZmqFileDesc, sockaddr_storage, socklen_t)> {
+        match self {
+            Self::Uring(uring) => uring.take_accept(handle_),
+            Self::Epoll(_) => {
+                let mut addr: sockaddr_storage = unsafe { mem::zeroed() };
+                let mut addr_len = mem::size_of::<sockaddr_storage>() as socklen_t;
+                let fd = unsafe {
+                    accept4(
+                        *handle_,
+                        &mut addr as *mut sockaddr_storage as *mut sockaddr,
+                        &mut addr_len,
+                        SOCK_CLOEXEC | SOCK_NONBLOCK,
+                    )
+                };
+                if fd == RETIRED_FD {
+                    return None;
+                }
+                Some((fd, addr, addr_len))
+            }
+        }
+    }
+
+    pub fn start(&mut self, name: &str) {
+        match self {
+            Self::Uring(uring) => uring.start(name),
+            Self::Epoll(epoll) => epoll.start(name),
+        }
+    }
+
+    pub fn stop(&mut self) {
+        match self {
+            Self::Uring(uring) => uring.stop(),
+            Self::Epoll(epoll) => epoll.stop(),
+        }
+    }
+
+    pub fn max_fds(&mut self) -> i32 {
+        match self {
+            Self::Uring(uring) => uring.max_fds(),
+            Self::Epoll(epoll) => epoll.max_fds(),
+        }
+    }
+
+    pub fn loop_fn(&mut self) {
+        match self {
+            Self::Uring(uring) => uring.loop_fn(),
+            Self::Epoll(epoll) => epoll.loop_fn(),
+        }
+    }
+}
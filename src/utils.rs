@@ -6,6 +6,7 @@ use libc::EINVAL;
 use windows::Win32::Networking::WinSock;
 use windows::Win32::Networking::WinSock::{socklen_t, ADDRESS_FAMILY, SOCKADDR};
 
+use crate::curve_encoding::{curve_keypair, curve_public_from_secret};
 use crate::platform_socket::ZmqSockaddr;
 use crate::sockaddr::ZmqSockaddr;
 
@@ -211,18 +212,11 @@ pub fn zmq_curve_keypair(z85_public_key_: &mut [u8], z85_secret_key_: &mut [u8])
     // #error "CURVE encryption library not built correctly"
     // #endif
 
-    let mut public_key: [u8; 32] = [0; 32];
-    let mut secret_key: [u8; 32] = [0; 32];
-
-    random_open();
-
-    let res: i32 = crypto_box_keypair(public_key, secret_key);
+    let (public_key, secret_key) = curve_keypair();
     zmq_z85_encode(z85_public_key_, &public_key, 32);
     zmq_z85_encode(z85_secret_key_, &secret_key, 32);
 
-    random_close();
-
-    return res;
+    return 0;
     // #else
     // (void) z85_public_key_, (void) z85_secret_key_;
     // errno = ENOTSUP;
@@ -236,21 +230,15 @@ pub fn zmq_curve_public(z85_public_key_: &mut [u8], z85_secret_key_: &str) -> an
     // #error "CURVE encryption library not built correctly"
     // #endif
 
-    let mut public_key: [u8; 32] = [0; 32];
     let mut secret_key: [u8; 32] = [0; 32];
 
-    random_open();
-
     // if (zmq_z85_decode (&mut secret_key, z85_secret_key_) == null_mut())
     //     return -1;
     zmq_z85_decode(&mut secret_key, z85_secret_key_.as_bytes())?;
 
-    // Return codes are suppressed as none of these can actually fail.
-    crypto_scalarmult_base(public_key, secret_key);
+    let public_key = curve_public_from_secret(&secret_key)?;
     zmq_z85_encode(z85_public_key_, &public_key, 32);
 
-    random_close();
-
     //     return 0;
     // // #else
     //     (void) z85_public_key_, (void) z85_secret_key_;
@@ -316,44 +304,48 @@ pub fn advance_ptr(a: &mut [u8], offset: usize) -> &mut [u8] {
     &mut a[offset..]
 }
 
-pub fn encode_base64(in_: &mut [u8], in_len_: i32, out_: &str, out_len_: i32) -> i32 {
-    let base64enc_tab: &str = "ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+//  Standard base64 with padding, written to out_ and null terminated like
+//  libzmq's. Returns the length of the encoding, or -1 if out_ is too short.
+pub fn encode_base64(in_: &[u8], in_len_: i32, out_: &mut [u8], out_len_: i32) -> i32 {
+    let base64enc_tab: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
 
     let mut io = 0;
-    let mut v = 0;
+    let mut v: u32 = 0;
     let mut rem = 0;
 
-    // for (int ii = 0; ii < in_len_; ii+= 1)
-    for ii in 0..in_len_ {
-        let ch = in_[ii];
-        v = (v << 8) | ch;
+    let out_len_ = (out_len_.max(0) as usize).min(out_.len());
+    for &ch in &in_[..in_len_.max(0) as usize] {
+        v = (v << 8) | ch as u32;
         rem += 8;
-        while (rem >= 6) {
+        while rem >= 6 {
             rem -= 6;
-            if (io >= out_len_) {
+            if io >= out_len_ {
                 return -1;
             } /* truncation is failure */
-            out_[io += 1] = base64enc_tab[(v >> rem) & 63];
+            out_[io] = base64enc_tab[((v >> rem) & 63) as usize];
+            io += 1;
         }
     }
-    if (rem) {
-        v <<= (6 - rem);
-        if (io >= out_len_) {
+    if rem != 0 {
+        v <<= 6 - rem;
+        if io >= out_len_ {
             return -1;
         } /* truncation is failure */
-        out_[io += 1] = base64enc_tab[v & 63];
+        out_[io] = base64enc_tab[(v & 63) as usize];
+        io += 1;
     }
-    while (io & 3) {
-        if (io >= out_len_) {
+    while io & 3 != 0 {
+        if io >= out_len_ {
             return -1;
         } /* truncation is failure */
-        out_[io += 1] = '=';
+        out_[io] = b'=';
+        io += 1;
     }
-    if (io >= out_len_) {
+    if io >= out_len_ {
         return -1;
     } /* no room for null terminator */
     out_[io] = 0;
-    return io;
+    io as i32
 }
 
 pub fn MAKEWORD(a: u8, b: u8) -> u16 {
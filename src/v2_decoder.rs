@@ -38,10 +38,13 @@
 // #include "wire.hpp"
 // #include "err.hpp"
 
+use bytes::Bytes;
 use libc::{EMSGSIZE, ENOMEM};
 use crate::decoder::DecoderBase;
 use crate::decoder_allocators::shared_message_memory_allocator;
 use crate::message::{ZMQ_MSG_COMMAND, ZMQ_MSG_MORE, ZmqMessage};
+use crate::v2_protocol::{V2_COMMAND_FLAG, V2_COMPRESSED_FLAG, V2_LARGE_FLAG, V2_MORE_FLAG};
+use crate::zmtp_compression::ZmqZmtpDecompressor;
 
 
 //  Decoder for ZMTP/2.x framing protocol. Converts data stream into messages.
@@ -62,6 +65,10 @@ pub struct ZmqV2Decoder
     pub _zero_copy: bool,
     // const i64 _max_msg_size;
     pub _max_msg_size: i64,
+    //  Set once compression is negotiated.
+    pub _decompressor: Option<ZmqZmtpDecompressor>,
+    //  The frame being read is compressed.
+    pub _compressed: bool,
     // ZMQ_NON_COPYABLE_NOR_MOVABLE (ZmqV2Decoder)
 }
 
@@ -95,6 +102,10 @@ impl ZmqV2Decoder {
 
     // ~ZmqV2Decoder ();
 
+    pub fn set_decompressor(&mut self, decompressor: ZmqZmtpDecompressor) {
+        self._decompressor = Some(decompressor);
+    }
+
     //  ZmqDecoderInterface interface.
     // ZmqMessage *msg () { return &in_progress; }
 
@@ -103,22 +114,33 @@ impl ZmqV2Decoder {
     pub fn flags_ready (&mut self) -> i32
     {
         self._msg_flags = 0;
-        if (self._tmpbuf[0] & v2_protocol_t::more_flag) {
+        if (self._tmpbuf[0] & V2_MORE_FLAG) != 0 {
             self._msg_flags |= ZMQ_MSG_MORE;
         }
-        if (self._tmpbuf[0] & v2_protocol_t::command_flag) == 1 {
+        //  SUBSCRIBE and CANCEL from ZMTP 3.1 peers arrive as commands; the
+        //  engine recognises them by name in process_command_message. Older
+        //  peers send 0x01/0x00 prefixed data frames, which XPUB accepts too.
+        if (self._tmpbuf[0] & V2_COMMAND_FLAG) != 0 {
             self._msg_flags |= ZMQ_MSG_COMMAND;
         }
 
+        //  Only data frames are compressed, and only by peers that
+        //  negotiated it.
+        self._compressed = (self._tmpbuf[0] & V2_COMPRESSED_FLAG) != 0;
+        if self._compressed
+            && (self._decompressor.is_none() || (self._msg_flags & ZMQ_MSG_COMMAND) != 0)
+        {
+            // errno = EPROTO;
+            return -1;
+        }
+
         //  The payload length is either one or eight bytes,
         //  depending on whether the 'large' bit is set.
-        // TODO
-        // if (self._tmpbuf[0] & v2_protocol_t::large_flag) {
-        //     self.decoder_base.next_step(&mut self._tmpbuf, 8, self.eight_byte_size_ready() as usize);
-        // }
-        // else{
-        // self.decoder_base.next_step(&mut self._tmpbuf, 1, self.one_byte_size_ready() as usize);
-        //}
+        if (self._tmpbuf[0] & V2_LARGE_FLAG) != 0 {
+            self.decoder_base.next_step(&mut self._tmpbuf, 8, Self::eight_byte_size_ready as usize);
+        } else {
+            self.decoder_base.next_step(&mut self._tmpbuf, 1, Self::one_byte_size_ready as usize);
+        }
 
         return 0;
     }
@@ -134,7 +156,7 @@ impl ZmqV2Decoder {
     {
         //  The payload size is encoded as 64-bit unsigned integer.
         //  The most significant byte comes first.
-        let msg_size = get_uint64 (_tmpbuf);
+        let msg_size = u64::from_be_bytes(self._tmpbuf);
 
         return self.size_ready (msg_size, read_from_);
     }
@@ -146,11 +168,17 @@ impl ZmqV2Decoder {
     pub fn size_ready (&mut self, msg_size_: u64, read_pos_: &[u8]) -> i32
     {
         //  Message size must not exceed the maximum allowed size.
-        if (self._max_msg_size >= 0) {
-            if ((msg_size_ > (self._max_msg_size) as u64)) {
-              // errno = EMSGSIZE;
-                return -1;
+        //  A compressed body may be slightly larger than what it stands for,
+        //  and is bounded even without a maximum size.
+        let max_size = match &self._decompressor {
+            Some(decompressor) if self._compressed => {
+                Some(decompressor.max_compressed_size(self._max_msg_size))
             }
+            _ => (self._max_msg_size >= 0).then_some(self._max_msg_size as u64),
+        };
+        if max_size.is_some_and(|max_size| msg_size_ > max_size) {
+          // errno = EMSGSIZE;
+            return -1;
         }
 
         //  Message size must fit into size_t data type.
@@ -166,7 +194,10 @@ impl ZmqV2Decoder {
         // data into a new message and complete it in the next receive.
 
         // shared_message_memory_allocator &allocator = get_allocator ();
+        //  A compressed body is replaced once read, so it is never worth
+        //  sharing the buffer with.
         if ( (!self._zero_copy
+            || self._compressed
             || msg_size_ >  (
             allocator.data () + allocator.size () - read_pos_))) {
             // a new message has started, but the size would exceed the pre-allocated arena
@@ -213,6 +244,25 @@ impl ZmqV2Decoder {
 
     pub fn message_ready (&mut self) -> i32
     {
+        //  Swap a compressed body for what it decompresses to.
+        if self._compressed {
+            let decompressed = match &self._decompressor {
+                Some(decompressor) => {
+                    decompressor.decompress(self.in_progress.data(), self._max_msg_size)
+                }
+                None => return -1,
+            };
+            let Ok(body) = decompressed else {
+                // errno = EPROTO or EMSGSIZE;
+                return -1;
+            };
+            if self.in_progress.init_bytes(Bytes::from(body)).is_err() {
+                // errno = ENOMEM;
+                return -1;
+            }
+            self.in_progress.set_flags(self._msg_flags);
+        }
+
         //  Message is completely read. Signal this to the caller
         //  and prepare to decode next message.
         self.decoder_base.next_step (_tmpbuf, 1, self.flags_ready() as usize);
@@ -34,11 +34,12 @@
 // #include "likely.hpp"
 // #include "wire.hpp"
 
-use crate::decoder_allocators::size;
 use crate::encoder::EncoderBase;
-use crate::message::{ZMQ_MSG_COMMAND, ZMQ_MSG_MORE};
-use libc::{size_t, uint8_t};
-use std::ptr::null_mut;
+use crate::message::{ZmqMessage, ZMQ_MSG_COMMAND, ZMQ_MSG_MORE};
+use crate::v2_protocol::{
+    put_frame_header, V2_COMMAND_FLAG, V2_COMPRESSED_FLAG, V2_MAX_HEADER_SIZE, V2_MORE_FLAG,
+};
+use crate::zmtp_compression::ZmqZmtpCompressor;
 
 // #include <limits.h>
 #[derive(Default, Debug, Clone)]
@@ -47,83 +48,167 @@ pub struct ZmqV2Encoder {
     pub encoder_base: EncoderBase,
     //  flags byte + size byte (or 8 bytes) + sub/cancel byte
     // unsigned char _tmp_buf[10];
-    pub _tmp_buf: [u8; 10],
-    // ZMQ_NON_COPYABLE_NOR_MOVABLE (ZmqV2Encoder)
+    pub _tmp_buf: [u8; V2_MAX_HEADER_SIZE + 1],
+    //  Set once compression is negotiated.
+    pub compressor: Option<ZmqZmtpCompressor>,
+    //  Compressed body of the message in progress, if it is sent so.
+    pub _compressed: Option<Vec<u8>>,
 }
 
 impl ZmqV2Encoder {
-    // ZmqV2Encoder (bufsize_: usize);
     pub fn new(bufsize_: usize) -> Self {
-        // encoder_base_t<ZmqV2Encoder> (bufsize_)
         let mut out = Self {
             encoder_base: EncoderBase::new(bufsize_),
-            _tmp_buf: [0; 10],
+            _tmp_buf: [0; V2_MAX_HEADER_SIZE + 1],
+            compressor: None,
+            _compressed: None,
         };
         //  Write 0 bytes to the batch and go to message_ready state.
-        out.encoder_base
-            .next_step(null_mut(), 0, out.message_ready(), true);
+        out.encoder_base.next_step(0, 0, true);
         out
     }
 
-    // void message_ready ();
+    pub fn set_compressor(&mut self, compressor: ZmqZmtpCompressor) {
+        self.compressor = Some(compressor);
+    }
+
     pub fn message_ready(&mut self) {
-        //  Encode flags.
-        let size = in_progress().size();
-        let mut header_size = 2; // flags byte + size byte
-        let mut protocol_flags = _tmp_buf[0];
-        protocol_flags = 0;
-        if (in_progress().flags() & ZMQ_MSG_MORE) {
-            protocol_flags |= v2_protocol_t::more_flag;
-        }
-        if (in_progress().size() > UCHAR_MAX) {
-            protocol_flags |= v2_protocol_t::large_flag;
-        }
-        if (in_progress().flags() & ZMQ_MSG_COMMAND) {
-            protocol_flags |= v2_protocol_t::command_flag;
-        }
-        if (in_progress().is_subscribe() || in_progress().is_cancel()) {
-            size += 1;
-        }
+        let Some(msg) = &self.encoder_base.in_progress else {
+            return;
+        };
+        self._compressed = self.compressor.as_ref().and_then(|c| c.compress(msg));
+        let header_size = match &self._compressed {
+            Some(body) => encode_compressed_header(msg, body.len(), &mut self._tmp_buf),
+            None => encode_header(msg, &mut self._tmp_buf),
+        };
 
-        //  Encode the message length. For messages less then 256 bytes,
-        //  the length is encoded as 8-bit unsigned integer. For larger
-        //  messages, 64-bit unsigned integer in network byte order is used.
-        if (size > UCHAR_MAX) {
-            put_uint64(_tmp_buf + 1, size);
-            header_size = 9; // flags byte + size 8 bytes
-        } else {
-            self._tmp_buf[1] = (size);
-        }
+        // next_step (_tmp_buf, header_size, &v2_encoder_t::size_ready, false);
+        self.encoder_base.next_step(0, header_size, false);
+    }
+
+    pub fn size_ready(&mut self) {
+        //  Write message Body into the buffer.
+        let size = self.body().len();
+        // next_step (in_progress ()->data (), in_progress ()->size (),
+        //            &v2_encoder_t::message_ready, true);
+        self.encoder_base.next_step(0, size, true);
+    }
 
-        //  Encode the subscribe/cancel byte. This is Done in the encoder as
-        //  opposed to when the subscribe message is created to allow different
-        //  protocol behaviour on the wire in the v3.1 and legacy encoders.
-        //  It results in the work being Done multiple times in case the sub
-        //  is sending the subscription/cancel to multiple pubs, but it cannot
-        //  be avoided. This processing can be moved to xsub once support for
-        //  ZMTP < 3.1 is dropped.
-        if (in_progress().is_subscribe()) {
-            _tmp_buf[header_size += 1] = 1;
-        } else if (in_progress().is_cancel()) {
-            _tmp_buf[header_size += 1] = 0;
+    //  Frame header of the message in progress, valid after message_ready.
+    pub fn header(&self) -> &[u8] {
+        &self._tmp_buf[..self.encoder_base.to_write.min(self._tmp_buf.len())]
+    }
+
+    //  Frame body of the message in progress, compressed or not.
+    pub fn body(&self) -> &[u8] {
+        match (&self._compressed, &self.encoder_base.in_progress) {
+            (Some(body), _) => body,
+            (None, Some(msg)) => frame_body(msg),
+            (None, None) => &[],
         }
+    }
+}
 
-        next_step(_tmp_buf, header_size, &ZmqV2Encoder::size_ready, false);
+//  Writes the ZMTP 2.0/3.0 frame header for `msg` into `buf` and returns
+//  its length. These peers predate the SUBSCRIBE and CANCEL commands, so
+//  subscriptions are downgraded to data frames whose first byte is 1
+//  (subscribe) or 0 (cancel), followed by the topic. This includes those
+//  that already carry the command name, e.g. forwarded from a 3.1 peer.
+pub fn encode_header(msg: &ZmqMessage, buf: &mut [u8]) -> usize {
+    let downgrade = msg.is_subscribe() || msg.is_cancel();
+    let mut protocol_flags = 0u8;
+    if (msg.flags() & ZMQ_MSG_MORE) != 0 {
+        protocol_flags |= V2_MORE_FLAG;
+    }
+    if (msg.flags() & ZMQ_MSG_COMMAND) != 0 && !downgrade {
+        protocol_flags |= V2_COMMAND_FLAG;
     }
+    let size = if downgrade {
+        msg.command_body_size() + 1
+    } else {
+        msg.size()
+    };
 
-    // void size_ready ();
-    pub fn size_ready(&mut self) {
-        //  Write message Body into the buffer.
-        next_step(
-            in_progress().data(),
-            in_progress().size(),
-            &ZmqV2Encoder::message_ready,
-            true,
-        );
-    }
-    // ~ZmqV2Encoder ();
+    let mut header_size = put_frame_header(buf, protocol_flags, size);
+
+    if downgrade {
+        buf[header_size] = if msg.is_subscribe() { 1 } else { 0 };
+        header_size += 1;
+    }
+
+    header_size
+}
+
+//  Body of the uncompressed frame that carries `msg`: the topic alone for
+//  a downgraded subscription, the message data otherwise.
+pub fn frame_body(msg: &ZmqMessage) -> &[u8] {
+    if msg.is_subscribe() || msg.is_cancel() {
+        msg.command_body()
+    } else {
+        msg.data()
+    }
 }
 
-// ZmqV2Encoder::~ZmqV2Encoder ()
-// {
-// }
+//  Writes the header of a data frame whose body was compressed down to
+//  `size` bytes. Only data frames are compressed, so MORE is the only
+//  flag to carry over.
+pub fn encode_compressed_header(msg: &ZmqMessage, size: usize, buf: &mut [u8]) -> usize {
+    let mut protocol_flags = V2_COMPRESSED_FLAG;
+    if (msg.flags() & ZMQ_MSG_MORE) != 0 {
+        protocol_flags |= V2_MORE_FLAG;
+    }
+    put_frame_header(buf, protocol_flags, size)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::{CANCEL_CMD_NAME, SUB_CMD_NAME, ZMQ_MSG_CANCEL, ZMQ_MSG_SUBSCRIBE};
+
+    fn encode(msg: &ZmqMessage) -> Vec<u8> {
+        let mut buf = [0u8; V2_MAX_HEADER_SIZE + 1];
+        let header_size = encode_header(msg, &mut buf);
+        [&buf[..header_size], frame_body(msg)].concat()
+    }
+
+    fn command(name: &[u8], flags: u8, topic: &[u8]) -> ZmqMessage {
+        let data = [name, topic].concat();
+        let mut msg = ZmqMessage::default();
+        msg.init_size(data.len()).unwrap();
+        msg.data_mut().copy_from_slice(&data);
+        msg.set_flags(ZMQ_MSG_COMMAND | flags);
+        msg
+    }
+
+    #[test]
+    fn subscriptions_become_data_frames() {
+        let mut subscribe = ZmqMessage::default();
+        subscribe.init_subscribe(3, b"abc").unwrap();
+        assert_eq!(encode(&subscribe), b"\x00\x04\x01abc");
+
+        let mut cancel = ZmqMessage::default();
+        cancel.init_cancel(3, b"abc").unwrap();
+        assert_eq!(encode(&cancel), b"\x00\x04\x00abc");
+    }
+
+    #[test]
+    fn subscription_commands_become_data_frames() {
+        let subscribe = command(SUB_CMD_NAME, ZMQ_MSG_SUBSCRIBE, b"abc");
+        assert_eq!(encode(&subscribe), b"\x00\x04\x01abc");
+
+        let cancel = command(CANCEL_CMD_NAME, ZMQ_MSG_CANCEL, b"");
+        assert_eq!(encode(&cancel), b"\x00\x01\x00");
+    }
+
+    #[test]
+    fn other_frames_go_as_they_are() {
+        let mut data = ZmqMessage::default();
+        data.init_size(2).unwrap();
+        data.data_mut().copy_from_slice(b"hi");
+        data.set_flags(ZMQ_MSG_MORE);
+        assert_eq!(encode(&data), b"\x01\x02hi");
+
+        let ping = command(b"\x04PING", 0, b"");
+        assert_eq!(encode(&ping), b"\x04\x05\x04PING");
+    }
+}
@@ -0,0 +1,62 @@
+/*
+    Copyright (c) 2007-2016 Contributors as noted in the AUTHORS file
+
+    This file is part of libzmq, the ZeroMQ core engine in C+= 1.
+
+    libzmq is free software; you can redistribute it and/or modify it under
+    the terms of the GNU Lesser General Public License (LGPL) as published
+    by the Free Software Foundation; either version 3 of the License, or
+    (at your option) any later version.
+
+    As a special exception, the Contributors give you permission to link
+    this library with independent modules to produce an executable,
+    regardless of the license terms of these independent modules, and to
+    copy and distribute the resulting executable under terms of your choice,
+    provided that you also meet, for each linked independent module, the
+    terms and conditions of the license of that module. An independent
+    module is a module which is not derived from or based on this library.
+    If you modify this library, you must extend this exception to your
+    version of the library.
+
+    libzmq is distributed in the hope that it will be useful, but WITHOUT
+    ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+    FITNESS FOR A PARTICULAR PURPOSE. See the GNU Lesser General Public
+    License for more details.
+
+    You should have received a copy of the GNU Lesser General Public License
+    along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+// #ifndef __ZMQ_V2_PROTOCOL_HPP_INCLUDED__
+// #define __ZMQ_V2_PROTOCOL_HPP_INCLUDED__
+
+//  Definition of constants for ZMTP/2.0 transport protocol.
+//  Message flags.
+pub const V2_MORE_FLAG: u8 = 1;
+pub const V2_LARGE_FLAG: u8 = 2;
+pub const V2_COMMAND_FLAG: u8 = 4;
+//  Body compressed with the algorithm negotiated through the Compression
+//  property; see zmtp_compression.rs.
+pub const V2_COMPRESSED_FLAG: u8 = 8;
+
+//  Longest frame header: flags byte + 8 byte length.
+pub const V2_MAX_HEADER_SIZE: usize = 9;
+
+//  Writes the flags byte and the length of a frame whose body is `size`
+//  bytes long. For bodies shorter than 256 bytes the length is a single
+//  byte, otherwise it's a 64-bit unsigned integer in network byte order.
+//  Returns the number of bytes written.
+pub fn put_frame_header(buf: &mut [u8], mut protocol_flags: u8, size: usize) -> usize {
+    if size > u8::MAX as usize {
+        protocol_flags |= V2_LARGE_FLAG;
+        buf[0] = protocol_flags;
+        buf[1..9].copy_from_slice(&(size as u64).to_be_bytes());
+        9
+    } else {
+        buf[0] = protocol_flags;
+        buf[1] = size as u8;
+        2
+    }
+}
+
+// #endif
@@ -34,11 +34,14 @@
 // #include "likely.hpp"
 // #include "wire.hpp"
 
-use crate::decoder_allocators::size;
 use crate::encoder::EncoderBase;
-use crate::message::{ZmqMessage, CANCEL_CMD_NAME, SUB_CMD_NAME, ZMQ_MSG_COMMAND, ZMQ_MSG_MORE};
-use libc::{memcpy, size_t};
-use std::ptr::null_mut;
+use crate::message::{
+    ZmqMessage, CANCEL_CMD_NAME, CANCEL_CMD_NAME_SIZE, SUB_CMD_NAME, SUB_CMD_NAME_SIZE,
+    ZMQ_MSG_COMMAND, ZMQ_MSG_MORE,
+};
+use crate::v2_encoder::encode_compressed_header;
+use crate::v2_protocol::{put_frame_header, V2_COMMAND_FLAG, V2_MAX_HEADER_SIZE, V2_MORE_FLAG};
+use crate::zmtp_compression::ZmqZmtpCompressor;
 
 // #include <limits.h>
 pub struct ZmqV31Encoder {
@@ -53,7 +56,11 @@ pub struct ZmqV31Encoder {
     //   void message_ready ();
 
     // unsigned char _tmp_buf[9 + ZmqMessage::SUB_CMD_NAME_SIZE];
-    pub tmp_buf: Vec<u8>,
+    pub tmp_buf: [u8; V2_MAX_HEADER_SIZE + SUB_CMD_NAME_SIZE],
+    //  Set once compression is negotiated.
+    pub compressor: Option<ZmqZmtpCompressor>,
+    //  Compressed body of the message in progress, if it is sent so.
+    pub compressed: Option<Vec<u8>>,
     // ZMQ_NON_COPYABLE_NOR_MOVABLE (ZmqV31Encoder)
 }
 
@@ -63,75 +70,100 @@ impl ZmqV31Encoder {
     pub fn new(bufsize_: usize) -> Self {
         let mut out = Self {
             encoder_base: EncoderBase::new(bufsize_),
-            tmp_buf: Vec::with_capacity(9 + ZmqMessage::SUB_CMD_NAME_SIZE),
+            tmp_buf: [0; V2_MAX_HEADER_SIZE + SUB_CMD_NAME_SIZE],
+            compressor: None,
+            compressed: None,
         };
 
         //  Write 0 bytes to the batch and go to message_ready state.
-        out.next_step(null_mut(), 0, &ZmqV31Encoder::message_ready, true);
+        out.encoder_base.next_step(0, 0, true);
         out
     }
 
+    pub fn set_compressor(&mut self, compressor: ZmqZmtpCompressor) {
+        self.compressor = Some(compressor);
+    }
+
     pub fn message_ready(&mut self) {
-        //  Encode flags.
-        let size_ = in_progress().size();
-        let mut header_size = 2; // flags byte + size byte
-        let protocol_flags = _tmp_buf[0];
-        protocol_flags = 0;
-        if (in_progress().flags() & ZMQ_MSG_MORE) {
-            protocol_flags |= v2_protocol_t::more_flag;
-        }
-        if (in_progress().size() > UCHAR_MAX) {
-            protocol_flags |= v2_protocol_t::large_flag;
-        }
-        if (in_progress().flags() & ZMQ_MSG_COMMAND
-            || in_progress().is_subscribe()
-            || in_progress().is_cancel())
-        {
-            protocol_flags |= v2_protocol_t::command_flag;
-            if (in_progress().is_subscribe()) {
-                size += ZmqMessage::SUB_CMD_NAME_SIZE;
-            } else if (in_progress().is_cancel()) {
-                size += ZmqMessage::CANCEL_CMD_NAME_SIZE;
-            }
-        }
+        let Some(msg) = &self.encoder_base.in_progress else {
+            return;
+        };
+        self.compressed = self.compressor.as_ref().and_then(|c| c.compress(msg));
+        let header_size = match &self.compressed {
+            Some(body) => encode_compressed_header(msg, body.len(), &mut self.tmp_buf),
+            None => encode_header(msg, &mut self.tmp_buf),
+        };
 
-        //  Encode the message length. For messages less then 256 bytes,
-        //  the length is encoded as 8-bit unsigned integer. For larger
-        //  messages, 64-bit unsigned integer in network byte order is used.
-        if (size > UCHAR_MAX) {
-            put_uint64(_tmp_buf + 1, size);
-            header_size = 9; // flags byte + size 8 bytes
-        } else {
-            _tmp_buf[1] = (size);
-        }
+        // next_step (_tmp_buf, header_size, &v3_1_encoder_t::size_ready, false);
+        self.encoder_base.next_step(0, header_size, false);
+    }
 
-        //  Encode the sub/cancel command string. This is Done in the encoder as
-        //  opposed to when the subscribe message is created to allow different
-        //  protocol behaviour on the wire in the v3.1 and legacy encoders.
-        //  It results in the work being Done multiple times in case the sub
-        //  is sending the subscription/cancel to multiple pubs, but it cannot
-        //  be avoided. This processing can be moved to xsub once support for
-        //  ZMTP < 3.1 is dropped.
-        if (in_progress().is_subscribe()) {
-            // memcpy (_tmp_buf + header_size, SUB_CMD_NAME,
-            //         ZmqMessage::SUB_CMD_NAME_SIZE);
-            header_size += ZmqMessage::SUB_CMD_NAME_SIZE;
-        } else if (in_progress().is_cancel()) {
-            // memcpy (_tmp_buf + header_size, CANCEL_CMD_NAME,
-            //         ZmqMessage::CANCEL_CMD_NAME_SIZE);
-            header_size += ZmqMessage::CANCEL_CMD_NAME_SIZE;
+    pub fn size_ready(&mut self) {
+        //  Write message Body into the buffer.
+        let size = self.body().len();
+        // next_step (in_progress ()->data (), in_progress ()->size (),
+        //            &v3_1_encoder_t::message_ready, true);
+        self.encoder_base.next_step(0, size, true);
+    }
+
+    //  Frame header of the message in progress, valid after message_ready.
+    pub fn header(&self) -> &[u8] {
+        &self.tmp_buf[..self.encoder_base.to_write.min(self.tmp_buf.len())]
+    }
+
+    //  Frame body of the message in progress, compressed or not.
+    pub fn body(&self) -> &[u8] {
+        match (&self.compressed, &self.encoder_base.in_progress) {
+            (Some(body), _) => body,
+            (None, Some(msg)) => msg.data(),
+            (None, None) => &[],
         }
+    }
+}
 
-        next_step(_tmp_buf, header_size, &ZmqV31Encoder::size_ready, false);
+//  Writes the ZMTP 3.1 frame header for `msg` into `buf` and returns its
+//  length. Subscriptions and cancellations become SUBSCRIBE and CANCEL
+//  commands; the topic that follows is the message body.
+pub fn encode_header(msg: &ZmqMessage, buf: &mut [u8]) -> usize {
+    //  Encode flags.
+    let mut size = msg.size();
+    let mut protocol_flags = 0u8;
+    if (msg.flags() & ZMQ_MSG_MORE) != 0 {
+        protocol_flags |= V2_MORE_FLAG;
+    }
+    //  Messages that already carry the command flag (e.g. a SUBSCRIBE
+    //  received from a 3.1 peer and forwarded) contain the name already.
+    let add_cmd_name = (msg.flags() & ZMQ_MSG_COMMAND) == 0;
+    if (msg.flags() & ZMQ_MSG_COMMAND) != 0 || msg.is_subscribe() || msg.is_cancel() {
+        protocol_flags |= V2_COMMAND_FLAG;
+        if add_cmd_name && msg.is_subscribe() {
+            size += SUB_CMD_NAME_SIZE;
+        } else if add_cmd_name && msg.is_cancel() {
+            size += CANCEL_CMD_NAME_SIZE;
+        }
     }
 
-    pub fn size_ready() {
-        //  Write message Body into the buffer.
-        next_step(
-            in_progress().data(),
-            in_progress().size(),
-            &ZmqV31Encoder::message_ready,
-            true,
-        );
+    //  Encode the message length. For messages less then 256 bytes,
+    //  the length is encoded as 8-bit unsigned integer. For larger
+    //  messages, 64-bit unsigned integer in network byte order is used.
+    let mut header_size = put_frame_header(buf, protocol_flags, size);
+
+    //  Encode the sub/cancel command string. This is Done in the encoder as
+    //  opposed to when the subscribe message is created to allow different
+    //  protocol behaviour on the wire in the v3.1 and legacy encoders.
+    //  It results in the work being Done multiple times in case the sub
+    //  is sending the subscription/cancel to multiple pubs, but it cannot
+    //  be avoided. This processing can be moved to xsub once support for
+    //  ZMTP < 3.1 is dropped.
+    if add_cmd_name {
+        if msg.is_subscribe() {
+            buf[header_size..header_size + SUB_CMD_NAME_SIZE].copy_from_slice(SUB_CMD_NAME);
+            header_size += SUB_CMD_NAME_SIZE;
+        } else if msg.is_cancel() {
+            buf[header_size..header_size + CANCEL_CMD_NAME_SIZE].copy_from_slice(CANCEL_CMD_NAME);
+            header_size += CANCEL_CMD_NAME_SIZE;
+        }
     }
+
+    header_size
 }
@@ -0,0 +1,420 @@
+//  Listening side of the vsock:// transport.
+//
+//  Endpoints are "vsock://cid:port". The CID may be "*" (any, bind only),
+//  "@" (this machine's own CID) or a number; 1 is the loopback CID, which
+//  works without a hypervisor. A port of "*" binds to an ephemeral port,
+//  reported through ZMQ_LAST_ENDPOINT. The ZMQ_VMCI_BUFFER_* and
+//  ZMQ_VMCI_CONNECT_TIMEOUT options apply to vsock sockets as well.
+//
+//  Connections carry plain ZMTP, as with TCP.
+
+use std::mem;
+
+use anyhow::{anyhow, bail};
+use libc::{
+    accept4, bind, c_int, c_uint, c_void, close, getsockname, ioctl, listen, open, setsockopt,
+    sockaddr, sockaddr_vm, socket, socklen_t, timeval, AF_VSOCK, O_CLOEXEC, O_RDONLY, SOCK_CLOEXEC,
+    SOCK_NONBLOCK, SOCK_STREAM, VMADDR_CID_ANY, VMADDR_PORT_ANY,
+};
+
+use crate::address::ZmqAddress;
+use crate::context::ZmqContext;
+use crate::defines::{ZmqFileDesc, RETIRED_FD};
+use crate::endpoint::make_unconnected_bind_endpoint_pair;
+use crate::endpoint::EndpointType::Bind;
+use crate::endpoint_uri::EndpointUriPair;
+use crate::engine::ZmqEngine;
+use crate::listener::ZmqListener;
+use crate::session_base::ZmqSessionBase;
+
+//  From <linux/vm_sockets.h>; socket level is AF_VSOCK.
+const SO_VM_SOCKETS_BUFFER_SIZE: c_int = 0;
+const SO_VM_SOCKETS_BUFFER_MIN_SIZE: c_int = 1;
+const SO_VM_SOCKETS_BUFFER_MAX_SIZE: c_int = 2;
+const SO_VM_SOCKETS_CONNECT_TIMEOUT: c_int = 6;
+//  _IO(7, 0xb9), on /dev/vsock.
+const IOCTL_VM_SOCKETS_GET_LOCAL_CID: libc::c_ulong = 0x7b9;
+
+//  The CID of the machine we are running on.
+pub fn vsock_local_cid() -> anyhow::Result<c_uint> {
+    let fd = unsafe { open(c"/dev/vsock".as_ptr(), O_RDONLY | O_CLOEXEC) };
+    if fd < 0 {
+        bail!(
+            "EADDRNOTAVAIL: cannot open /dev/vsock: {}",
+            std::io::Error::last_os_error()
+        );
+    }
+    let mut cid: c_uint = VMADDR_CID_ANY;
+    let rc = unsafe { ioctl(fd, IOCTL_VM_SOCKETS_GET_LOCAL_CID, &mut cid as *mut c_uint) };
+    unsafe { close(fd) };
+    if rc < 0 {
+        bail!("EADDRNOTAVAIL: cannot get the local vsock CID");
+    }
+    Ok(cid)
+}
+
+//  Parses "cid:port". Wildcards are only accepted when binding.
+pub fn vsock_resolve_address(addr: &str, bind: bool) -> anyhow::Result<sockaddr_vm> {
+    let (cid, port) = addr
+        .rsplit_once(':')
+        .ok_or_else(|| anyhow!("EINVAL: missing port in vsock address {}", addr))?;
+
+    let cid = match cid {
+        "*" if bind => VMADDR_CID_ANY,
+        "@" => vsock_local_cid()?,
+        _ => cid
+            .parse::<c_uint>()
+            .ok()
+            .filter(|&cid| cid != VMADDR_CID_ANY)
+            .ok_or_else(|| anyhow!("EINVAL: invalid CID in vsock address {}", addr))?,
+    };
+    let port = match port {
+        "*" if bind => VMADDR_PORT_ANY,
+        _ => port
+            .parse::<c_uint>()
+            .ok()
+            .filter(|&port| port != VMADDR_PORT_ANY)
+            .ok_or_else(|| anyhow!("EINVAL: invalid port in vsock address {}", addr))?,
+    };
+
+    let mut address: sockaddr_vm = unsafe { mem::zeroed() };
+    address.svm_family = AF_VSOCK as libc::sa_family_t;
+    address.svm_cid = cid;
+    address.svm_port = port;
+    Ok(address)
+}
+
+pub fn vsock_address_to_string(address: &sockaddr_vm) -> String {
+    if address.svm_cid == VMADDR_CID_ANY {
+        format!("vsock://*:{}", address.svm_port)
+    } else {
+        format!("vsock://{}:{}", address.svm_cid, address.svm_port)
+    }
+}
+
+//  Name of either end of a vsock socket.
+pub fn vsock_socket_name(fd: ZmqFileDesc, remote: bool) -> Option<sockaddr_vm> {
+    let mut address: sockaddr_vm = unsafe { mem::zeroed() };
+    let mut len = mem::size_of::<sockaddr_vm>() as socklen_t;
+    let ptr = &mut address as *mut sockaddr_vm as *mut sockaddr;
+    let rc = unsafe {
+        if remote {
+            libc::getpeername(fd as c_int, ptr, &mut len)
+        } else {
+            getsockname(fd as c_int, ptr, &mut len)
+        }
+    };
+    (rc == 0).then_some(address)
+}
+
+fn set_vsock_option<T>(fd: ZmqFileDesc, option: c_int, value: &T) -> bool {
+    let rc = unsafe {
+        setsockopt(
+            fd as c_int,
+            AF_VSOCK,
+            option,
+            value as *const T as *const c_void,
+            mem::size_of::<T>() as socklen_t,
+        )
+    };
+    rc == 0
+}
+
+//  Zero leaves the kernel's default alone. The kernel clamps the size to
+//  the bounds in force when it is set, so the bounds go first.
+pub fn tune_vsock_buffer_size(ctx: &ZmqContext, fd: ZmqFileDesc) {
+    for (option, size) in [
+        (SO_VM_SOCKETS_BUFFER_MAX_SIZE, ctx.vmci_buffer_max_size),
+        (SO_VM_SOCKETS_BUFFER_MIN_SIZE, ctx.vmci_buffer_min_size),
+        (SO_VM_SOCKETS_BUFFER_SIZE, ctx.vmci_buffer_size),
+    ] {
+        if size != 0 {
+            set_vsock_option(fd, option, &size);
+        }
+    }
+}
+
+//  Timeout in milliseconds.
+pub fn tune_vsock_connect_timeout(fd: ZmqFileDesc, timeout: i32) {
+    if timeout <= 0 {
+        return;
+    }
+    let timeout = timeval {
+        tv_sec: (timeout / 1000) as libc::time_t,
+        tv_usec: ((timeout % 1000) * 1000) as libc::suseconds_t,
+    };
+    set_vsock_option(fd, SO_VM_SOCKETS_CONNECT_TIMEOUT, &timeout);
+}
+
+pub fn vsock_init(
+    engine: &mut ZmqEngine,
+    fd: ZmqFileDesc,
+    address: &ZmqAddress,
+    endpoint_uri_pair: EndpointUriPair,
+) {
+    engine.fd = fd;
+    engine.address = address.clone();
+    engine.endpoint_uri_pair = endpoint_uri_pair;
+    if let Some(peer) = vsock_socket_name(fd, true) {
+        engine.peer_address = peer.svm_cid.to_string();
+    }
+}
+
+pub fn vsock_get_endpoint(engine: &mut ZmqEngine) -> EndpointUriPair {
+    engine.endpoint_uri_pair.clone()
+}
+
+pub fn vsock_set_local_address(listener: &mut ZmqListener, addr: &mut str) -> anyhow::Result<()> {
+    let address = vsock_resolve_address(addr, true)?;
+
+    let fd = unsafe { socket(AF_VSOCK, SOCK_STREAM | SOCK_CLOEXEC | SOCK_NONBLOCK, 0) };
+    if fd < 0 {
+        bail!("failed to open socket: {}", std::io::Error::last_os_error());
+    }
+    listener.fd = fd as ZmqFileDesc;
+    tune_vsock_buffer_size(listener.socket.context, listener.fd);
+
+    let rc = unsafe {
+        bind(
+            fd,
+            &address as *const sockaddr_vm as *const sockaddr,
+            mem::size_of::<sockaddr_vm>() as socklen_t,
+        )
+    };
+    if rc != 0 {
+        let err = std::io::Error::last_os_error();
+        unsafe { close(fd) };
+        listener.fd = RETIRED_FD as ZmqFileDesc;
+        bail!("failed to bind socket: {}", err);
+    }
+
+    if unsafe { listen(fd, listener.socket.context.backlog) } != 0 {
+        let err = std::io::Error::last_os_error();
+        unsafe { close(fd) };
+        listener.fd = RETIRED_FD as ZmqFileDesc;
+        bail!("failed to listen on socket: {}", err);
+    }
+
+    //  The kernel picked the port if we asked for any.
+    let bound = vsock_socket_name(listener.fd, false).unwrap_or(address);
+    listener.endpoint = vsock_address_to_string(&bound);
+    listener.socket.event_listening(
+        &make_unconnected_bind_endpoint_pair(&listener.endpoint),
+        listener.fd,
+    );
+    Ok(())
+}
+
+pub fn vsock_in_event(listener: &mut ZmqListener) -> anyhow::Result<()> {
+    let fd = vsock_accept(listener)?;
+
+    //  If connection was reset by the peer in the meantime, just ignore it.
+    if fd == RETIRED_FD as ZmqFileDesc {
+        listener
+            .socket
+            .event_accept_failed(&make_unconnected_bind_endpoint_pair(&listener.endpoint), -1);
+        return Ok(());
+    }
+    tune_vsock_buffer_size(listener.socket.context, fd);
+
+    vsock_create_engine(listener, fd)
+}
+
+pub fn vsock_accept(listener: &mut ZmqListener) -> anyhow::Result<ZmqFileDesc> {
+    let sock = unsafe {
+        accept4(
+            listener.fd as c_int,
+            std::ptr::null_mut(),
+            std::ptr::null_mut(),
+            SOCK_CLOEXEC | SOCK_NONBLOCK,
+        )
+    };
+    if sock < 0 {
+        return Ok(RETIRED_FD as ZmqFileDesc);
+    }
+    Ok(sock as ZmqFileDesc)
+}
+
+pub fn vsock_close(listener: &mut ZmqListener) -> anyhow::Result<()> {
+    let fd_for_event = listener.fd;
+    let rc = unsafe { close(listener.fd as c_int) };
+    listener.fd = RETIRED_FD as ZmqFileDesc;
+
+    if rc != 0 {
+        listener
+            .socket
+            .event_close_failed(&make_unconnected_bind_endpoint_pair(&listener.endpoint), -1);
+        bail!("failed to close socket")
+    }
+
+    listener.socket.event_closed(
+        &make_unconnected_bind_endpoint_pair(&listener.endpoint),
+        fd_for_event,
+    );
+    Ok(())
+}
+
+pub fn vsock_create_engine(listener: &mut ZmqListener, fd: ZmqFileDesc) -> anyhow::Result<()> {
+    let remote = vsock_socket_name(fd, true)
+        .map(|peer| vsock_address_to_string(&peer))
+        .unwrap_or_default();
+    let endpoint_pair = EndpointUriPair::new(&listener.endpoint, &remote, Bind);
+
+    let mut engine = ZmqEngine::default();
+    vsock_init(&mut engine, fd, &listener.address, endpoint_pair.clone());
+
+    //  Choose I/O thread to run the session in. Given that we are already
+    //  running in an I/O thread, there must be at least one available.
+    let io_thread = listener
+        .choose_io_thread(listener.socket.context.affinity)
+        .unwrap();
+
+    //  Create and launch a session object.
+    let mut session = ZmqSessionBase::create(io_thread, false, listener.socket, None)?;
+    session.inc_seqnum();
+    listener.own.launch_child(session);
+    listener.own.send_attach(&mut session, engine, false);
+
+    listener.socket.event_accepted(&endpoint_pair, fd);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use libc::{connect, getsockopt, read, write, EADDRNOTAVAIL, ENODEV};
+
+    //  VMADDR_CID_LOCAL.
+    const LOOPBACK: &str = "1";
+
+    //  None where the kernel has no vsock support.
+    fn vsock_socket() -> Option<ZmqFileDesc> {
+        let fd = unsafe { socket(AF_VSOCK, SOCK_STREAM | SOCK_CLOEXEC, 0) };
+        (fd >= 0).then_some(fd as ZmqFileDesc)
+    }
+
+    fn get_vsock_option(fd: ZmqFileDesc, option: c_int) -> u64 {
+        let mut value = 0u64;
+        let mut len = mem::size_of::<u64>() as socklen_t;
+        let rc = unsafe {
+            getsockopt(
+                fd as c_int,
+                AF_VSOCK,
+                option,
+                &mut value as *mut u64 as *mut c_void,
+                &mut len,
+            )
+        };
+        assert_eq!(rc, 0);
+        value
+    }
+
+    #[test]
+    fn buffer_size_above_the_default_maximum() {
+        let Some(fd) = vsock_socket() else {
+            return;
+        };
+        let ctx = ZmqContext {
+            vmci_buffer_size: 1 << 20,
+            vmci_buffer_min_size: 4096,
+            vmci_buffer_max_size: 2 << 20,
+            ..Default::default()
+        };
+        tune_vsock_buffer_size(&ctx, fd);
+        assert_eq!(get_vsock_option(fd, SO_VM_SOCKETS_BUFFER_MAX_SIZE), 2 << 20);
+        assert_eq!(get_vsock_option(fd, SO_VM_SOCKETS_BUFFER_MIN_SIZE), 4096);
+        assert_eq!(get_vsock_option(fd, SO_VM_SOCKETS_BUFFER_SIZE), 1 << 20);
+        unsafe { close(fd as c_int) };
+    }
+
+    #[test]
+    fn loopback_cid_round_trip() {
+        let Some(listener) = vsock_socket() else {
+            return;
+        };
+        let ctx = ZmqContext {
+            vmci_buffer_size: 1 << 20,
+            vmci_buffer_max_size: 2 << 20,
+            ..Default::default()
+        };
+
+        let address = vsock_resolve_address(&format!("{}:*", LOOPBACK), true).unwrap();
+        let len = mem::size_of::<sockaddr_vm>() as socklen_t;
+        let rc = unsafe {
+            bind(
+                listener as c_int,
+                &address as *const sockaddr_vm as *const sockaddr,
+                len,
+            )
+        };
+        if rc != 0 {
+            //  No vsock_loopback module.
+            let errno = std::io::Error::last_os_error().raw_os_error();
+            assert!(matches!(errno, Some(EADDRNOTAVAIL) | Some(ENODEV)));
+            unsafe { close(listener as c_int) };
+            return;
+        }
+        assert_eq!(unsafe { listen(listener as c_int, 1) }, 0);
+        let bound = vsock_socket_name(listener, false).unwrap();
+        assert_eq!(bound.svm_cid, 1);
+        assert_ne!(bound.svm_port, VMADDR_PORT_ANY);
+        let endpoint = vsock_address_to_string(&bound);
+        assert_eq!(endpoint, format!("vsock://1:{}", bound.svm_port));
+
+        let client = vsock_socket().unwrap();
+        tune_vsock_buffer_size(&ctx, client);
+        tune_vsock_connect_timeout(client, 1000);
+        let remote = vsock_resolve_address(endpoint.trim_start_matches("vsock://"), false).unwrap();
+        let rc = unsafe {
+            connect(
+                client as c_int,
+                &remote as *const sockaddr_vm as *const sockaddr,
+                len,
+            )
+        };
+        assert_eq!(rc, 0);
+        let server = unsafe {
+            accept4(
+                listener as c_int,
+                std::ptr::null_mut(),
+                std::ptr::null_mut(),
+                0,
+            )
+        };
+        assert!(server >= 0);
+        assert_eq!(
+            vsock_socket_name(server as ZmqFileDesc, true)
+                .unwrap()
+                .svm_cid,
+            1
+        );
+        assert_eq!(get_vsock_option(client, SO_VM_SOCKETS_BUFFER_SIZE), 1 << 20);
+
+        let sent = unsafe { write(client as c_int, b"ZMTP".as_ptr() as *const c_void, 4) };
+        assert_eq!(sent, 4);
+        let mut buf = [0u8; 4];
+        let got = unsafe { read(server, buf.as_mut_ptr() as *mut c_void, 4) };
+        assert_eq!(got, 4);
+        assert_eq!(&buf, b"ZMTP");
+
+        unsafe {
+            close(server);
+            close(client as c_int);
+            close(listener as c_int);
+        }
+    }
+
+    #[test]
+    fn wildcards_only_when_binding() {
+        let any = vsock_resolve_address("*:*", true).unwrap();
+        assert_eq!(
+            (any.svm_cid, any.svm_port),
+            (VMADDR_CID_ANY, VMADDR_PORT_ANY)
+        );
+        assert!(vsock_resolve_address("*:5555", false).is_err());
+        assert!(vsock_resolve_address("1:*", false).is_err());
+        let loopback = vsock_resolve_address("1:5555", false).unwrap();
+        assert_eq!((loopback.svm_cid, loopback.svm_port), (1, 5555));
+        assert!(vsock_resolve_address("1", false).is_err());
+    }
+}
@@ -0,0 +1,182 @@
+use std::mem;
+
+use anyhow::anyhow;
+use libc::{
+    c_int, connect, sockaddr, sockaddr_vm, socket, socklen_t, AF_VSOCK, ECONNREFUSED, ECONNRESET,
+    EINPROGRESS, SOCK_CLOEXEC, SOCK_NONBLOCK, SOCK_STREAM,
+};
+
+use crate::address::ZmqAddress;
+use crate::context::ZmqContext;
+use crate::defines::{
+    ZmqFileDesc, RETIRED_FD, ZMQ_RECONNECT_STOP_AFTER_DISCONNECT, ZMQ_RECONNECT_STOP_CONN_REFUSED,
+};
+use crate::endpoint::EndpointType::Connect;
+use crate::endpoint_uri::EndpointUriPair;
+use crate::engine::ZmqEngine;
+use crate::err::ZmqError;
+use crate::session_base::ZmqSessionBase;
+use crate::stream_connecter_base::StreamConnecterBase;
+use crate::thread_context::ZmqThreadContext;
+use crate::vsock::{
+    tune_vsock_buffer_size, tune_vsock_connect_timeout, vsock_address_to_string, vsock_init,
+    vsock_resolve_address, vsock_socket_name,
+};
+
+//  Connects to a vsock:// listener; see vsock.rs.
+pub struct VsockConnecter<'a> {
+    pub base: StreamConnecterBase<'a>,
+    pub reconnect_stop: i32,
+    pub connect_timeout: i32,
+}
+
+impl<'a> VsockConnecter<'a> {
+    //  If 'delayed_start' is true connecter first waits for a while,
+    //  then starts connection process.
+    pub fn new(
+        ctx: &mut ZmqContext,
+        io_thread_: &mut ZmqThreadContext,
+        session: &mut ZmqSessionBase,
+        addr: &mut ZmqAddress,
+        delayed_start_: bool,
+    ) -> Self {
+        // zmq_assert (_addr.protocol == ZmqTransport::ZmqVsock);
+        Self {
+            reconnect_stop: ctx.reconnect_stop,
+            connect_timeout: ctx.vmci_connect_timeout,
+            base: StreamConnecterBase::new(io_thread_, session, ctx, addr, delayed_start_),
+        }
+    }
+
+    //  Handlers for I/O events.
+    pub fn out_event(&mut self) -> anyhow::Result<()> {
+        let fd = self.connect();
+        self.base.rm_handle();
+
+        //  Handle the error condition by attempt to reconnect.
+        match fd {
+            Ok(fd) => {
+                self.base._s = RETIRED_FD as ZmqFileDesc;
+                self.create_engine(fd);
+            }
+            Err(_) => {
+                self.base.close();
+                self.base.add_reconnect_timer();
+            }
+        }
+        Ok(())
+    }
+
+    //  Internal function to start the actual connection establishment.
+    pub fn start_connecting(&mut self) -> anyhow::Result<()> {
+        //  Open the connecting socket.
+        match self.open() {
+            //  Connect was successful immediately.
+            Ok(_) => {
+                self.base._handle = Some(self.base.io_object.add_fd(self.base._s));
+                self.out_event()
+            }
+            //  Connection establishment may be delayed. Poll for its
+            //  completion.
+            Err(ZmqError::InProgress(_)) => {
+                self.base._handle = Some(self.base.io_object.add_fd(self.base._s));
+                self.base.io_object.set_pollout(self.base._handle.unwrap());
+                Ok(())
+            }
+            Err(ZmqError::ConnectionRefused(_)) => {
+                self.base.close();
+                let stop = (self.reconnect_stop & ZMQ_RECONNECT_STOP_CONN_REFUSED as i32) != 0
+                    || ((self.reconnect_stop & ZMQ_RECONNECT_STOP_AFTER_DISCONNECT as i32) != 0
+                        && self.base._socket.is_disconnected());
+                if !stop {
+                    self.base.add_reconnect_timer();
+                }
+                Ok(())
+            }
+            //  Handle any other error condition by eventual reconnect.
+            Err(_) => {
+                self.base.close();
+                self.base.add_reconnect_timer();
+                Ok(())
+            }
+        }
+    }
+
+    //  Open the vsock socket and start connecting it.
+    pub fn open(&mut self) -> Result<(), ZmqError> {
+        let address = vsock_resolve_address(&self.base._addr.addr_str, false)
+            .map_err(|e| ZmqError::InvalidInput(e.to_string()))?;
+
+        let fd = unsafe { socket(AF_VSOCK, SOCK_STREAM | SOCK_CLOEXEC | SOCK_NONBLOCK, 0) };
+        if fd < 0 {
+            return Err(ZmqError::ConnectSocketFailed(
+                "failed to create socket".to_string(),
+            ));
+        }
+        self.base._s = fd as ZmqFileDesc;
+        tune_vsock_buffer_size(self.base._socket.context, self.base._s);
+        tune_vsock_connect_timeout(self.base._s, self.connect_timeout);
+
+        let rc = unsafe {
+            connect(
+                fd,
+                &address as *const sockaddr_vm as *const sockaddr,
+                mem::size_of::<sockaddr_vm>() as socklen_t,
+            )
+        };
+        if rc == 0 {
+            return Ok(());
+        }
+
+        match std::io::Error::last_os_error().raw_os_error() {
+            Some(EINPROGRESS) => Err(ZmqError::InProgress("connect not finished yet".to_string())),
+            //  Nobody listens on the port; a peer that isn't there at all
+            //  times out instead.
+            Some(ECONNREFUSED) | Some(ECONNRESET) => Err(ZmqError::ConnectionRefused(
+                "connection refused".to_string(),
+            )),
+            _ => Err(ZmqError::ConnectSocketFailed(
+                "failed to connect to socket".to_string(),
+            )),
+        }
+    }
+
+    //  Get the file descriptor of newly created connection.
+    pub fn connect(&mut self) -> anyhow::Result<ZmqFileDesc> {
+        let mut err: c_int = 0;
+        let mut len = mem::size_of::<c_int>() as socklen_t;
+        let rc = unsafe {
+            libc::getsockopt(
+                self.base._s as c_int,
+                libc::SOL_SOCKET,
+                libc::SO_ERROR,
+                &mut err as *mut c_int as *mut libc::c_void,
+                &mut len,
+            )
+        };
+        if rc == -1 || err != 0 {
+            return Err(anyhow!("failed to connect to socket"));
+        }
+        Ok(self.base._s)
+    }
+
+    fn create_engine(&mut self, fd: ZmqFileDesc) {
+        let local = vsock_socket_name(fd, false)
+            .map(|local| vsock_address_to_string(&local))
+            .unwrap_or_default();
+        let remote = format!("vsock://{}", self.base._addr.addr_str);
+        let endpoint_pair = EndpointUriPair::new(&local, &remote, Connect);
+
+        //  Create the engine object for this connection.
+        let mut engine = ZmqEngine::default();
+        vsock_init(&mut engine, fd, self.base._addr, endpoint_pair.clone());
+
+        //  Attach the engine to the corresponding session object.
+        self.base.own.send_attach(self.base._session, engine, true);
+
+        //  Shut the connecter down.
+        self.base.own.terminate();
+
+        self.base._socket.event_connected(&endpoint_pair, fd);
+    }
+}
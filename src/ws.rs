@@ -1,8 +1,10 @@
-use anyhow::bail;
-use libc::{bind, listen, setsockopt};
+use std::sync::{Arc, Mutex};
+
+use anyhow::{anyhow, bail};
+use libc::{bind, c_int, close, listen, poll, pollfd, setsockopt, POLLIN};
 use windows::Win32::Networking::WinSock::{SO_REUSEADDR, SOCKET_ERROR, SOL_SOCKET};
-use crate::address::{get_socket_name, ZmqAddress, ZmqSocketEnd};
-use crate::defines::RETIRED_FD;
+use crate::address::{get_socket_name, resolve_address, ZmqAddress, ZmqSocketEnd};
+use crate::defines::{ZmqHandle, RETIRED_FD};
 use crate::endpoint::make_unconnected_bind_endpoint_pair;
 use crate::endpoint::EndpointType::Bind;
 use crate::engine_interface::ZmqEngineInterface;
@@ -11,11 +13,26 @@ use crate::endpoint_uri::EndpointUriPair;
 use crate::ip::make_socket_noninheritable;
 use crate::listener::ZmqListener;
 use crate::session_base::ZmqSessionBase;
-use crate::tcp::{tcp_open_socket, tune_tcp_maxrt, tune_tcp_socket};
+use crate::tcp::{tcp_local_address, tcp_open_socket, tune_tcp_maxrt, tune_tcp_socket};
 use crate::ws_engine::ZmqWsEngine;
-use crate::wss_engine::WssEngine;
+use crate::ws_router::{
+    ws_dup, ws_port_find, ws_port_register, ws_port_release, ws_split_address, ZmqWsPort,
+    ZmqWsRoute,
+};
+use crate::wss_engine::{wss_server_config, WssEngine};
 
 pub fn ws_in_event(listener: &mut ZmqListener) -> anyhow::Result<()>{
+    //  Connections for our path accepted by other binds on the port.
+    if let Some(handoff) = listener.ws_handoff.clone() {
+        for upgrade in handoff.take() {
+            ws_create_engine(listener, upgrade.fd, upgrade.request)?;
+        }
+    }
+    //  The doorbell may have been all there was.
+    if !ws_accept_pending(listener.fd) {
+        return Ok(());
+    }
+
     let mut fd = listener.accept()?;
 
     //  If connection was reset by the peer in the meantime, just ignore it.
@@ -35,10 +52,62 @@ pub fn ws_in_event(listener: &mut ZmqListener) -> anyhow::Result<()>{
     }
 
     //  Create the engine object for this connection.
-    ws_create_engine(listener, fd);
+    ws_create_engine(listener, fd, vec![])
+}
+
+fn ws_accept_pending(fd: ZmqFileDesc) -> bool {
+    let mut pfd = pollfd {
+        fd: fd as c_int,
+        events: POLLIN,
+        revents: 0,
+    };
+    unsafe { poll(&mut pfd, 1, 0) > 0 }
+}
+
+//  Called by process_plug once the socket is registered.
+pub fn ws_plug(listener: &mut ZmqListener) {
+    let Some(handoff) = listener.ws_handoff.clone() else {
+        return;
+    };
+    listener.ws_bell_handle = listener.io_object.add_fd(handoff.bell.fd);
+    listener.io_object.set_pollin(listener.ws_bell_handle);
+}
+
+pub fn ws_close(listener: &mut ZmqListener) -> anyhow::Result<()> {
+    if listener.ws_bell_handle != RETIRED_FD as ZmqHandle {
+        listener.io_object.rm_fd(listener.ws_bell_handle);
+        listener.ws_bell_handle = RETIRED_FD as ZmqHandle;
+    }
+    //  Other binds on the port keep it open.
+    if let Some(port) = listener.ws_port.take() {
+        ws_port_release(listener.socket.context, &port, &listener.ws_path);
+    }
+    listener.ws_handoff = None;
+
+    let fd_for_event = listener.fd;
+    let rc = unsafe { close(listener.fd as c_int) };
+    listener.fd = RETIRED_FD as ZmqFileDesc;
+
+    if rc != 0 {
+        listener
+            .socket
+            .event_close_failed(&make_unconnected_bind_endpoint_pair(&listener.endpoint), -1);
+        bail!("failed to close socket")
+    }
+
+    listener.socket.event_closed(
+        &make_unconnected_bind_endpoint_pair(&listener.endpoint),
+        fd_for_event,
+    );
+    Ok(())
 }
 
 pub fn ws_create_socket(listener: &mut ZmqListener, addr_: &mut str) -> anyhow::Result<()> {
+    //  The certificate is loaded once and shared by every accepted engine.
+    if listener.wss && listener.tls_cred.is_none() {
+        listener.tls_cred = Some(wss_server_config(listener.socket.context)?);
+    }
+
     // TcpAddress address;
     let mut address: ZmqAddress = ZmqAddress::default();
     listener.fd = tcp_open_socket(addr_, listener.socket.context, true, true, &mut address);
@@ -129,34 +198,56 @@ pub fn ws_create_socket(listener: &mut ZmqListener, addr_: &mut str) -> anyhow::
     //     return -1;
 }
 
-pub fn ws_set_local_address(listener: &mut ZmqListener, addr: &mut String) -> i32 {
-    if (listener.socket.context.use_fd != -1) {
+pub fn ws_set_local_address(listener: &mut ZmqListener, addr: &mut str) -> anyhow::Result<()> {
+    let (host_address, path) = ws_split_address(addr);
+    let route = ZmqWsRoute::new(listener.socket.context)?;
+    let handoff = route.handoff.clone();
+
+    let port = if (listener.socket.context.use_fd != -1) {
         //  in this case, the addr_ passed is not used and ignored, since the
         //  socket was already created by the application
         listener.fd = listener.socket.context.use_fd as ZmqFileDesc;
+        Arc::new(Mutex::new(ZmqWsPort::new(listener.fd, listener.wss)?))
     } else {
-        *addr = listener.address.resolve()?;
-
-        //  remove the path, otherwise resolving the port will fail with wildcard
-        // TODO:
-        // const char *delim = strrchr (addr_, '/');
-        // host_address: String;
-        // if (delim) {
-        //     host_address = std::string (addr_, delim - addr_);
-        // } else {
-        //     host_address = addr_;
-        // }
-
-        if (ws_create_socket(listener, listener.host_address) == -1) {
-            return -1;
+        let address = resolve_address(host_address, listener.socket.context.ipv6)?;
+        match ws_port_find(listener.socket.context, &address) {
+            //  Another bind listens there already; share its socket.
+            Some(port) => {
+                let shared = port.lock().unwrap();
+                if shared.wss || listener.wss {
+                    bail!("EADDRINUSE: {} is already bound", host_address);
+                }
+                listener.fd = ws_dup(shared.fd)?;
+                drop(shared);
+                port
+            }
+            None => {
+                ws_create_socket(listener, &mut host_address.to_string())?;
+                let port = Arc::new(Mutex::new(ZmqWsPort::new(listener.fd, listener.wss)?));
+                if let Some(local) = tcp_local_address(listener.fd) {
+                    ws_port_register(listener.socket.context, &local, port.clone());
+                }
+                port
+            }
         }
+    };
+    if let Err(e) = port.lock().unwrap().add_route(path, route) {
+        unsafe { close(listener.fd as c_int) };
+        listener.fd = RETIRED_FD as ZmqFileDesc;
+        return Err(e);
     }
 
-    listener.endpoint = get_socket_name(listener.fd, ZmqSocketEnd::SocketEndLocal)?;
+    let local = tcp_local_address(listener.fd)
+        .ok_or_else(|| anyhow!("failed to get the address of the listening socket"))?;
+    let scheme = if listener.wss { "wss" } else { "ws" };
+    listener.endpoint = format!("{}://{}{}", scheme, local, path);
+    listener.ws_port = Some(port);
+    listener.ws_path = path.to_string();
+    listener.ws_handoff = Some(handoff);
 
     listener.socket
         .event_listening(&make_unconnected_bind_endpoint_pair(&listener.endpoint), listener.fd);
-    return 0;
+    Ok(())
 }
 
 pub fn ws_accept(listener: &mut ZmqListener) -> anyhow::Result<ZmqFileDesc> {
@@ -224,7 +315,9 @@ pub fn ws_accept(listener: &mut ZmqListener) -> anyhow::Result<ZmqFileDesc> {
     todo!()
 }
 
-pub fn ws_create_engine(listener: &mut ZmqListener, fd: ZmqFileDesc) -> anyhow::Result<()> {
+//  'request' is what another bind on the port read of a connection it
+//  handed over to us.
+pub fn ws_create_engine(listener: &mut ZmqListener, fd: ZmqFileDesc, request: Vec<u8>) -> anyhow::Result<()> {
     let mut endpoint_pair = EndpointUriPair::new(
         &get_socket_name(fd, ZmqSocketEnd::SocketEndLocal).unwrap(),
         &get_socket_name(fd, ZmqSocketEnd::SocketEndRemote).unwrap(),
@@ -235,20 +328,24 @@ pub fn ws_create_engine(listener: &mut ZmqListener, fd: ZmqFileDesc) -> anyhow::
     let mut engine: ZmqEngineInterface;
     if (listener.wss) {
         // #ifdef ZMQ_HAVE_WSS
-        engine = WssEngine::new(
+        let mut wss_engine = WssEngine::new(
             fd,
             listener.socket.context,
             &mut endpoint_pair,
             &mut listener.address,
             false,
-            Some(listener.tls_cred.as_mut_slice()),
+            listener.tls_cred.clone(),
             "",
-        );
+        )?;
+        wss_engine.ws_engine.set_route(listener.ws_port.clone(), &listener.ws_path, request);
+        engine = wss_engine;
         // #else
         // zmq_assert (false);
         // #endif
     } else {
-        engine = ZmqWsEngine::new(fd, listener.socket.context, &mut endpoint_pair, &mut listener.address, false);
+        let mut ws_engine = ZmqWsEngine::new(fd, listener.socket.context, &mut endpoint_pair, &mut listener.address, false);
+        ws_engine.set_route(listener.ws_port.clone(), &listener.ws_path, request);
+        engine = ws_engine;
     }
 
     // alloc_assert (engine);
@@ -268,6 +365,10 @@ pub fn ws_create_engine(listener: &mut ZmqListener, fd: ZmqFileDesc) -> anyhow::
     // TODO
     // send_attach(session, engine, false);
 
-    listener.socket.event_accepted(&endpoint_pair, fd);
+    //  On a shared port the connection may be for another bind; the engine
+    //  reports it once the request has been routed here.
+    if listener.ws_port.is_none() {
+        listener.socket.event_accepted(&endpoint_pair, fd);
+    }
     Ok(())
 }
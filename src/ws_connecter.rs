@@ -381,7 +381,7 @@ impl ZmqWsConnecter {
         let mut engine: ZmqEngine = ZmqEngine::new();
         if (_wss) {
             // #ifdef ZMQ_HAVE_WSS
-            engine = WssEngine::new(
+            engine = match WssEngine::new(
                 fd,
                 self.options,
                 &mut endpoint_pair,
@@ -389,7 +389,16 @@ impl ZmqWsConnecter {
                 true,
                 None,
                 _hostname,
-            );
+            ) {
+                Ok(engine) => engine,
+                Err(_) => {
+                    //  Unusable TLS settings (no trust anchors, bad hostname);
+                    //  treat like a failed connect.
+                    self.close();
+                    add_reconnect_timer();
+                    return;
+                }
+            };
         // #else
         //         LIBZMQ_UNUSED (_hostname);
         //         assert (false);
@@ -43,6 +43,7 @@ use crate::decoder::DecoderBase;
 use crate::decoder_allocators::call_dec_ref;
 use crate::message::{ZMQ_MSG_CLOSE_CMD, ZMQ_MSG_COMMAND, ZMQ_MSG_MORE, ZMQ_MSG_PING, ZMQ_MSG_PONG, ZmqMessage};
 use crate::defines::v2_protocol_msg_flag::{command_flag, more_flag};
+use crate::ws_deflate::ZmqWsInflater;
 
 //  Decoder for Web socket framing protocol. Converts data stream into messages.
 //  The class has to inherit from shared_message_memory_allocator because
@@ -83,6 +84,10 @@ pub struct ZmqWsDecoder
     pub _opcode: opcode_t,
     // unsigned char _mask[4];
     pub _mask: Vec<u8>,
+    //  Set when permessage-deflate was negotiated; _deflated is the RSV1
+    //  bit of the frame in progress.
+    pub _inflater: Option<ZmqWsInflater>,
+    pub _deflated: bool,
 
     // ZMQ_NON_COPYABLE_NOR_MOVABLE (ZmqWsDecoder)
 }
@@ -91,7 +96,8 @@ impl ZmqWsDecoder {
     pub fn new(bufsize_: usize,
                maxmsgsize_: i64,
                zero_copy_: bool,
-               must_mask_: bool) -> Self
+               must_mask_: bool,
+               inflater_: Option<ZmqWsInflater>) -> Self
     {
         //         DecoderBase<ZmqWsDecoder, shared_message_memory_allocator> (bufsize_),
         //         _msg_flags (0),
@@ -111,6 +117,8 @@ impl ZmqWsDecoder {
             _size: 0,
             _opcode: (),
             _mask: vec![],
+            _inflater: inflater_,
+            _deflated: false,
         };
         // let rc = out.in_progress.init ();
         // errno_assert (rc == 0);
@@ -133,6 +141,13 @@ impl ZmqWsDecoder {
             return -1;
         }// non final messages are not supported
 
+        //  RSV1 marks a compressed message, which needs permessage-deflate
+        //  negotiated; RSV2 and RSV3 are never used.
+        self._deflated = (self._tmpbuf[0] & 0x40) != 0;
+        if ((self._tmpbuf[0] & 0x30) != 0 || (self._deflated && self._inflater.is_none())) {
+            return -1;
+        }
+
         _opcode = (_tmpbuf[0] & 0xF);
 
         _msg_flags = 0;
@@ -154,6 +169,11 @@ impl ZmqWsDecoder {
             }
         }
 
+        //  Control frames are never compressed.
+        if (self._deflated && self._opcode != opcode_binary) {
+            return -1;
+        }
+
         next_step(_tmpbuf, 1, &ZmqWsDecoder::size_first_byte_ready);
 
         return 0;
@@ -172,7 +192,7 @@ impl ZmqWsDecoder {
         if (_size < 126) {
             if (_must_mask) {
                 next_step(_tmpbuf, 4, &ZmqWsDecoder::mask_ready);
-            } else if (_opcode == opcode_binary) {
+            } else if (_opcode == opcode_binary && !self._deflated) {
                 if (_size == 0) {
                     return -1;
                 }
@@ -196,7 +216,7 @@ impl ZmqWsDecoder {
 
         if (_must_mask) {
             next_step(_tmpbuf, 4, &ZmqWsDecoder::mask_ready);
-        } else if (_opcode == opcode_binary) {
+        } else if (_opcode == opcode_binary && !self._deflated) {
             if (_size == 0) {
                 return -1;
             }
@@ -216,7 +236,7 @@ impl ZmqWsDecoder {
 
         if (_must_mask) {
             next_step(_tmpbuf, 4, &ZmqWsDecoder::mask_ready);
-        } else if (_opcode == opcode_binary) {
+        } else if (_opcode == opcode_binary && !self._deflated) {
             if (_size == 0) {
                 return -1;
             }
@@ -233,7 +253,7 @@ impl ZmqWsDecoder {
         // TODO
         // memcpy (_mask, _tmpbuf, 4);
 
-        if (_opcode == opcode_binary) {
+        if (_opcode == opcode_binary && !self._deflated) {
             if (_size == 0) {
                 return -1;
             }
@@ -272,8 +292,14 @@ impl ZmqWsDecoder {
     pub fn size_ready(&mut self, read_pos_: &[u8]) -> i32
     {
         //  Message size must not exceed the maximum allowed size.
+        //  A compressed payload is checked again once inflated; stored
+        //  blocks add 5 bytes per 64 KiB to an incompressible one.
         if (_max_msg_size >= 0) {
-            if ((_size > (_max_msg_size))) {
+            let mut max_size = self._max_msg_size;
+            if (self._deflated) {
+                max_size += self._max_msg_size / 1024 + 64;
+            }
+            if ((_size > (max_size))) {
               // errno = EMSGSIZE;
                 return -1;
             }
@@ -341,7 +367,7 @@ impl ZmqWsDecoder {
     pub fn message_ready(&mut self) -> i32
     {
         if (_must_mask) {
-            let mask_index = if _opcode == opcode_binary { 1 } else { 0 };
+            let mask_index = if _opcode == opcode_binary && !self._deflated { 1 } else { 0 };
 
             let data =
                 (in_progress.data());
@@ -353,6 +379,36 @@ impl ZmqWsDecoder {
             }
         }
 
+        if (self._deflated) {
+            //  The flags byte leads the inflated payload. Without
+            //  ZMQ_MAXMSGSIZE inflating stops at WS_DEFLATE_MAX_SIZE.
+            let mut max_size = -1;
+            if (self._max_msg_size >= 0) {
+                max_size = self._max_msg_size + 1;
+            }
+            let inflater = self._inflater.as_mut().unwrap();
+            let Ok(payload) = inflater.decompress(self.in_progress.data(), max_size) else {
+                return -1;
+            };
+            let Some((&flags, body)) = payload.split_first() else {
+                return -1;
+            };
+            if (flags & more_flag != 0) {
+                self._msg_flags |= ZMQ_MSG_MORE;
+            }
+            if (flags & command_flag != 0) {
+                self._msg_flags |= ZMQ_MSG_COMMAND;
+            }
+
+            self.in_progress.close();
+            if (self.in_progress.init_size(body.len()).is_err()) {
+                self.in_progress.init();
+                return -1;
+            }
+            self.in_progress.data_mut().copy_from_slice(body);
+            self.in_progress.set_flags(self._msg_flags);
+        }
+
         //  Message is completely read. Signal this to the caller
         //  and prepare to decode next message.
         next_step(_tmpbuf, 1, &ZmqWsDecoder::opcode_ready);
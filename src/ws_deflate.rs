@@ -0,0 +1,397 @@
+//  permessage-deflate (RFC 7692) for ws:// and wss://.
+//
+//  With ZMQ_WS_DEFLATE set, the client offers the extension in its upgrade
+//  request and the server accepts the first offer it can honour. Once
+//  negotiated, the payload of every data frame - the ZWS flags byte
+//  included - is deflated and the frame marked with RSV1; control frames
+//  are never compressed.
+//
+//  zlib can't compress with an 8 bit window, so offers that would make us
+//  do so are declined. Inflating always uses the largest window, which
+//  reads anything the peer is allowed to send.
+
+use anyhow::{anyhow, bail};
+use flate2::{Compress, Compression, Decompress, FlushCompress, FlushDecompress, Status};
+
+pub const WS_DEFLATE_EXTENSION: &str = "permessage-deflate";
+
+const WS_DEFLATE_MIN_WINDOW_BITS: u8 = 9;
+const WS_DEFLATE_MAX_WINDOW_BITS: u8 = 15;
+//  The empty stored block a sync flush ends with; it is left off the wire.
+const WS_DEFLATE_TAIL: [u8; 4] = [0x00, 0x00, 0xff, 0xff];
+const WS_DEFLATE_CHUNK: usize = 4096;
+//  Messages never inflate past this, whatever ZMQ_MAXMSGSIZE says, so that
+//  a small frame can't balloon into an arbitrarily large allocation.
+pub const WS_DEFLATE_MAX_SIZE: usize = 256 << 20;
+
+//  How far a message may inflate given ZMQ_MAXMSGSIZE (negative for none).
+fn ws_inflate_limit(max_size: i64) -> u64 {
+    if max_size >= 0 {
+        (max_size as u64).min(WS_DEFLATE_MAX_SIZE as u64)
+    } else {
+        WS_DEFLATE_MAX_SIZE as u64
+    }
+}
+
+//  The negotiated parameters, as the server's response states them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ZmqWsDeflateParams {
+    pub server_no_context_takeover: bool,
+    pub client_no_context_takeover: bool,
+    pub server_max_window_bits: u8,
+    pub client_max_window_bits: u8,
+}
+
+impl Default for ZmqWsDeflateParams {
+    fn default() -> Self {
+        Self {
+            server_no_context_takeover: false,
+            client_no_context_takeover: false,
+            server_max_window_bits: WS_DEFLATE_MAX_WINDOW_BITS,
+            client_max_window_bits: WS_DEFLATE_MAX_WINDOW_BITS,
+        }
+    }
+}
+
+//  One extension from a Sec-WebSocket-Extensions value: its name and
+//  parameters, values unquoted.
+fn ws_parse_extension(extension: &str) -> (String, Vec<(String, Option<String>)>) {
+    let mut parts = extension.split(';').map(str::trim);
+    let name = parts.next().unwrap_or_default().to_ascii_lowercase();
+    let params = parts
+        .filter(|param| !param.is_empty())
+        .map(|param| match param.split_once('=') {
+            Some((key, value)) => (
+                key.trim().to_ascii_lowercase(),
+                Some(value.trim().trim_matches('"').to_string()),
+            ),
+            None => (param.to_ascii_lowercase(), None),
+        })
+        .collect();
+    (name, params)
+}
+
+fn ws_parse_window_bits(value: &str) -> Option<u8> {
+    //  1*DIGIT, no leading zeros, 8 to 15.
+    if value.is_empty() || value.starts_with('0') || !value.bytes().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+    value.parse::<u8>().ok().filter(|bits| (8..=15).contains(bits))
+}
+
+//  The client's offer. client_max_window_bits without a value lets the
+//  server pick the window we compress with.
+pub fn ws_deflate_offer() -> String {
+    format!("{}; client_max_window_bits", WS_DEFLATE_EXTENSION)
+}
+
+//  Server side: the parameters of the first acceptable offer in the
+//  client's Sec-WebSocket-Extensions value, if any.
+pub fn ws_deflate_accept(offers: &str) -> Option<ZmqWsDeflateParams> {
+    offers.split(',').find_map(|offer| {
+        let (name, params) = ws_parse_extension(offer);
+        if name != WS_DEFLATE_EXTENSION {
+            return None;
+        }
+        let mut accepted = ZmqWsDeflateParams::default();
+        let mut seen: Vec<&str> = vec![];
+        for (key, value) in &params {
+            if seen.contains(&key.as_str()) {
+                return None;
+            }
+            seen.push(key);
+            match (key.as_str(), value.as_deref()) {
+                ("server_no_context_takeover", None) => accepted.server_no_context_takeover = true,
+                //  Echoed back, so that we can reset our inflater between
+                //  messages too.
+                ("client_no_context_takeover", None) => accepted.client_no_context_takeover = true,
+                ("server_max_window_bits", Some(bits)) => {
+                    accepted.server_max_window_bits =
+                        ws_parse_window_bits(bits).filter(|&bits| bits >= WS_DEFLATE_MIN_WINDOW_BITS)?;
+                }
+                //  A value only limits what the client compresses with;
+                //  without one we may, but need not, pick it.
+                ("client_max_window_bits", None) => {}
+                ("client_max_window_bits", Some(bits)) => {
+                    accepted.client_max_window_bits = ws_parse_window_bits(bits)?;
+                }
+                _ => return None,
+            }
+        }
+        Some(accepted)
+    })
+}
+
+//  Server side: the Sec-WebSocket-Extensions value answering an accepted
+//  offer.
+pub fn ws_deflate_response(params: &ZmqWsDeflateParams) -> String {
+    let mut response = String::from(WS_DEFLATE_EXTENSION);
+    if params.server_no_context_takeover {
+        response.push_str("; server_no_context_takeover");
+    }
+    if params.client_no_context_takeover {
+        response.push_str("; client_no_context_takeover");
+    }
+    if params.server_max_window_bits < WS_DEFLATE_MAX_WINDOW_BITS {
+        response.push_str(&format!("; server_max_window_bits={}", params.server_max_window_bits));
+    }
+    if params.client_max_window_bits < WS_DEFLATE_MAX_WINDOW_BITS {
+        response.push_str(&format!("; client_max_window_bits={}", params.client_max_window_bits));
+    }
+    response
+}
+
+//  Client side: checks the server's Sec-WebSocket-Extensions value against
+//  our offer.
+pub fn ws_deflate_parse_response(response: &str) -> anyhow::Result<ZmqWsDeflateParams> {
+    let mut extensions = response.split(',');
+    let (name, params) = ws_parse_extension(extensions.next().unwrap_or_default());
+    if name != WS_DEFLATE_EXTENSION || extensions.next().is_some() {
+        bail!("EPROTO: unexpected WebSocket extensions {}", response);
+    }
+
+    let mut accepted = ZmqWsDeflateParams::default();
+    let mut seen: Vec<&str> = vec![];
+    for (key, value) in &params {
+        if seen.contains(&key.as_str()) {
+            bail!("EPROTO: duplicate {} in permessage-deflate response", key);
+        }
+        seen.push(key);
+        match (key.as_str(), value.as_deref()) {
+            ("server_no_context_takeover", None) => accepted.server_no_context_takeover = true,
+            ("client_no_context_takeover", None) => accepted.client_no_context_takeover = true,
+            ("server_max_window_bits", Some(bits)) => {
+                accepted.server_max_window_bits = ws_parse_window_bits(bits)
+                    .ok_or_else(|| anyhow!("EPROTO: invalid server_max_window_bits {}", bits))?;
+            }
+            ("client_max_window_bits", Some(bits)) => {
+                accepted.client_max_window_bits = ws_parse_window_bits(bits)
+                    .ok_or_else(|| anyhow!("EPROTO: invalid client_max_window_bits {}", bits))?;
+                if accepted.client_max_window_bits < WS_DEFLATE_MIN_WINDOW_BITS {
+                    bail!("EPROTO: client_max_window_bits {} is not supported", bits);
+                }
+            }
+            _ => bail!("EPROTO: unexpected permessage-deflate parameter {}", key),
+        }
+    }
+    Ok(accepted)
+}
+
+//  Compresses the data frames one side sends.
+pub struct ZmqWsDeflater {
+    compress: Compress,
+    //  Whether every message starts from an empty window.
+    no_context_takeover: bool,
+}
+
+impl ZmqWsDeflater {
+    pub fn new(params: &ZmqWsDeflateParams, client: bool) -> Self {
+        let (window_bits, no_context_takeover) = if client {
+            (params.client_max_window_bits, params.client_no_context_takeover)
+        } else {
+            (params.server_max_window_bits, params.server_no_context_takeover)
+        };
+        Self {
+            compress: Compress::new_with_window_bits(Compression::default(), false, window_bits),
+            no_context_takeover,
+        }
+    }
+
+    //  Deflates one message's payload, sync flush tail removed.
+    pub fn compress(&mut self, data: &[u8]) -> anyhow::Result<Vec<u8>> {
+        let mut out = Vec::with_capacity(data.len() / 2 + 16);
+        let start = self.compress.total_in();
+        loop {
+            if out.capacity() - out.len() < 16 {
+                out.reserve(WS_DEFLATE_CHUNK);
+            }
+            let consumed = (self.compress.total_in() - start) as usize;
+            self.compress
+                .compress_vec(&data[consumed..], &mut out, FlushCompress::Sync)
+                .map_err(|e| anyhow!("failed to deflate message: {}", e))?;
+            //  The flush is complete once everything is in and there was
+            //  room to spare.
+            if self.compress.total_in() - start == data.len() as u64 && out.len() < out.capacity() {
+                break;
+            }
+        }
+        if out.ends_with(&WS_DEFLATE_TAIL) {
+            out.truncate(out.len() - WS_DEFLATE_TAIL.len());
+        }
+        if self.no_context_takeover {
+            self.compress.reset();
+        }
+        Ok(out)
+    }
+}
+
+//  Decompresses the data frames one side receives.
+pub struct ZmqWsInflater {
+    decompress: Decompress,
+    //  Whether the peer starts every message from an empty window.
+    no_context_takeover: bool,
+}
+
+impl ZmqWsInflater {
+    pub fn new(params: &ZmqWsDeflateParams, client: bool) -> Self {
+        let no_context_takeover = if client {
+            params.server_no_context_takeover
+        } else {
+            params.client_no_context_takeover
+        };
+        Self {
+            decompress: Decompress::new_with_window_bits(false, WS_DEFLATE_MAX_WINDOW_BITS),
+            no_context_takeover,
+        }
+    }
+
+    //  Inflates one message's payload, failing with EMSGSIZE once it
+    //  grows past 'max_size' bytes (negative for no limit other than
+    //  WS_DEFLATE_MAX_SIZE).
+    pub fn decompress(&mut self, data: &[u8], max_size: i64) -> anyhow::Result<Vec<u8>> {
+        let limit = ws_inflate_limit(max_size);
+        let mut input = Vec::with_capacity(data.len() + WS_DEFLATE_TAIL.len());
+        input.extend_from_slice(data);
+        input.extend_from_slice(&WS_DEFLATE_TAIL);
+
+        let mut out = Vec::with_capacity(data.len() * 2 + 16);
+        let start = self.decompress.total_in();
+        loop {
+            if out.len() == out.capacity() {
+                out.reserve(WS_DEFLATE_CHUNK);
+            }
+            let consumed = (self.decompress.total_in() - start) as usize;
+            let status = self
+                .decompress
+                .decompress_vec(&input[consumed..], &mut out, FlushDecompress::Sync)
+                .map_err(|e| anyhow!("EPROTO: failed to inflate message: {}", e))?;
+            if out.len() as u64 > limit {
+                bail!("EMSGSIZE: inflated message exceeds {} bytes", limit);
+            }
+            let done = self.decompress.total_in() - start == input.len() as u64;
+            match status {
+                //  A final block ends the stream; the next message starts
+                //  a new one.
+                Status::StreamEnd => {
+                    self.decompress.reset(false);
+                    return Ok(out);
+                }
+                _ if done && out.len() < out.capacity() => break,
+                Status::BufError if out.len() < out.capacity() => {
+                    bail!("EPROTO: truncated deflate data")
+                }
+                _ => {}
+            }
+        }
+        if self.no_context_takeover {
+            self.decompress.reset(false);
+        }
+        Ok(out)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn own_offer_is_accepted() {
+        let params = ws_deflate_accept(&ws_deflate_offer()).unwrap();
+        assert_eq!(params, ZmqWsDeflateParams::default());
+        let response = ws_deflate_response(&params);
+        assert_eq!(response, WS_DEFLATE_EXTENSION);
+        assert_eq!(ws_deflate_parse_response(&response).unwrap(), params);
+    }
+
+    #[test]
+    fn first_acceptable_offer_wins() {
+        let offers = "x-webkit-deflate-frame, \
+                      permessage-deflate; server_max_window_bits=8, \
+                      Permessage-Deflate; server_no_context_takeover; client_max_window_bits=\"10\", \
+                      permessage-deflate";
+        let params = ws_deflate_accept(offers).unwrap();
+        assert_eq!(
+            params,
+            ZmqWsDeflateParams {
+                server_no_context_takeover: true,
+                client_max_window_bits: 10,
+                ..Default::default()
+            }
+        );
+        let response = ws_deflate_response(&params);
+        assert_eq!(
+            response,
+            "permessage-deflate; server_no_context_takeover; client_max_window_bits=10"
+        );
+        assert_eq!(ws_deflate_parse_response(&response).unwrap(), params);
+    }
+
+    #[test]
+    fn invalid_offers_are_declined() {
+        for offer in [
+            "x-webkit-deflate-frame",
+            "permessage-deflate; server_max_window_bits",
+            "permessage-deflate; server_max_window_bits=16",
+            "permessage-deflate; client_max_window_bits=09",
+            "permessage-deflate; server_no_context_takeover; server_no_context_takeover",
+            "permessage-deflate; server_no_context_takeover=1",
+            "permessage-deflate; unknown",
+        ] {
+            assert_eq!(ws_deflate_accept(offer), None, "{}", offer);
+        }
+    }
+
+    #[test]
+    fn invalid_responses_are_rejected() {
+        for response in [
+            "",
+            "x-webkit-deflate-frame",
+            "permessage-deflate, permessage-deflate",
+            "permessage-deflate; client_max_window_bits=8",
+            "permessage-deflate; server_max_window_bits=x",
+            "permessage-deflate; client_no_context_takeover; client_no_context_takeover",
+            "permessage-deflate; unknown",
+        ] {
+            let err = ws_deflate_parse_response(response).unwrap_err();
+            assert!(err.to_string().starts_with("EPROTO"), "{}", response);
+        }
+    }
+
+    fn round_trip(params: &ZmqWsDeflateParams) {
+        let mut deflater = ZmqWsDeflater::new(params, true);
+        let mut inflater = ZmqWsInflater::new(params, false);
+        for i in 0..3 {
+            let message = format!("{} message {}", "abcdefgh".repeat(512), i).into_bytes();
+            let compressed = deflater.compress(&message).unwrap();
+            assert!(compressed.len() < message.len());
+            assert!(!compressed.ends_with(&WS_DEFLATE_TAIL));
+            assert_eq!(inflater.decompress(&compressed, -1).unwrap(), message);
+        }
+    }
+
+    #[test]
+    fn messages_round_trip() {
+        round_trip(&ZmqWsDeflateParams::default());
+        round_trip(&ZmqWsDeflateParams {
+            client_no_context_takeover: true,
+            client_max_window_bits: 9,
+            ..Default::default()
+        });
+    }
+
+    #[test]
+    fn inflating_is_bounded() {
+        let params = ZmqWsDeflateParams::default();
+        let compressed = ZmqWsDeflater::new(&params, false)
+            .compress(&[0u8; 65536])
+            .unwrap();
+        let mut inflater = ZmqWsInflater::new(&params, true);
+        let err = inflater.decompress(&compressed, 1024).unwrap_err();
+        assert!(err.to_string().starts_with("EMSGSIZE"));
+
+        //  Without ZMQ_MAXMSGSIZE the hard cap still applies.
+        assert_eq!(ws_inflate_limit(-1), WS_DEFLATE_MAX_SIZE as u64);
+        assert_eq!(ws_inflate_limit(1 << 40), WS_DEFLATE_MAX_SIZE as u64);
+        assert_eq!(ws_inflate_limit(1024), 1024);
+    }
+}
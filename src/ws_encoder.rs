@@ -40,6 +40,7 @@ use crate::encoder::EncoderBase;
 use crate::message::{ZMQ_MSG_COMMAND, ZMQ_MSG_MORE, ZMQ_MSG_SHARED, ZmqMessage};
 use crate::utils::put_u32;
 use crate::defines::v2_protocol_msg_flag::{command_flag, more_flag};
+use crate::ws_deflate::ZmqWsDeflater;
 
 // #include <limits.h>
 pub struct ZmqWsEncoder
@@ -60,12 +61,16 @@ pub struct ZmqWsEncoder
     pub _mask: Vec<u8>,
     pub _masked_msg: ZmqMessage,
     pub _is_binary: bool,
+    //  Set when permessage-deflate was negotiated; _deflated holds the
+    //  compressed, masked payload of the frame in progress.
+    pub _deflater: Option<ZmqWsDeflater>,
+    pub _deflated: Vec<u8>,
 
     // ZMQ_NON_COPYABLE_NOR_MOVABLE (ZmqWsEncoder)
 }
 
 impl ZmqWsEncoder {
-    pub fn new(bufsize_: usize, must_mask_: bool) -> Self
+    pub fn new(bufsize_: usize, must_mask_: bool, deflater_: Option<ZmqWsDeflater>) -> Self
 
     {
 //  EncoderBase<ZmqWsEncoder> (bufsize_), _must_mask (must_mask_)
@@ -77,6 +82,8 @@ impl ZmqWsEncoder {
             _mask: vec![],
             _masked_msg: Default::default(),
             _is_binary: false,
+            _deflater: deflater_,
+            _deflated: vec![],
         };
         next_step(null_mut(), 0, &ZmqWsEncoder::message_ready, true);
         _masked_msg.init();
@@ -105,6 +112,10 @@ impl ZmqWsEncoder {
             _is_binary = true;
         }
 
+        if (_is_binary && self.deflate_message_ready()) {
+            return;
+        }
+
         _tmp_buf[offset] = if _must_mask { 0x80 } else { 0x00 };
 
         let size = in_progress().size();
@@ -164,6 +175,69 @@ impl ZmqWsEncoder {
         next_step(_tmp_buf, offset, &ZmqWsEncoder::size_ready, false);
     }
 
+    //  With permessage-deflate the flags and subscribe/cancel bytes are
+    //  compressed along with the body, so the whole payload is built before
+    //  the header. Returns false to send the message uncompressed, which
+    //  RFC 7692 allows for any message.
+    fn deflate_message_ready(&mut self) -> bool
+    {
+        let Some(deflater) = self._deflater.as_mut() else {
+            return false;
+        };
+
+        let msg = in_progress();
+        let mut payload = Vec::with_capacity(msg.size() + 2);
+        let mut protocol_flags = 0;
+        if (msg.flags() & ZMQ_MSG_MORE != 0) {
+            protocol_flags |= more_flag;
+        }
+        if (msg.flags() & ZMQ_MSG_COMMAND != 0) {
+            protocol_flags |= command_flag;
+        }
+        payload.push(protocol_flags);
+        if (msg.is_subscribe()) {
+            payload.push(1);
+        } else if (msg.is_cancel()) {
+            payload.push(0);
+        }
+        payload.extend_from_slice(msg.data());
+
+        let Ok(deflated) = deflater.compress(&payload) else {
+            return false;
+        };
+        self._deflated = deflated;
+
+        let size = self._deflated.len();
+        let mask_bit = if self._must_mask { 0x80 } else { 0x00 };
+        self._tmp_buf.clear();
+        self._tmp_buf.push(0xc2); // Final | RSV1 | binary
+        if (size <= 125) {
+            self._tmp_buf.push(mask_bit | size as u8);
+        } else if (size <= 0xFFFF) {
+            self._tmp_buf.push(mask_bit | 126);
+            self._tmp_buf.extend_from_slice(&(size as u16).to_be_bytes());
+        } else {
+            self._tmp_buf.push(mask_bit | 127);
+            self._tmp_buf.extend_from_slice(&(size as u64).to_be_bytes());
+        }
+
+        if (self._must_mask) {
+            let mask = generate_random().to_ne_bytes();
+            self._tmp_buf.extend_from_slice(&mask);
+            for (i, byte) in self._deflated.iter_mut().enumerate() {
+                *byte ^= mask[i % 4];
+            }
+        }
+
+        next_step(self._tmp_buf, self._tmp_buf.len(), &ZmqWsEncoder::deflated_size_ready, false);
+        true
+    }
+
+    pub fn deflated_size_ready(&mut self)
+    {
+        next_step(self._deflated, self._deflated.len(), &ZmqWsEncoder::message_ready, true);
+    }
+
     pub fn size_ready(&mut self)
     {
         if (_must_mask) {
@@ -84,6 +84,7 @@ along with this program.  If not, see <http://www.gnu.org/licenses/>.
 
 use std::io::Read;
 use std::ptr::{hash, null_mut};
+use std::sync::{Arc, Mutex};
 
 use bincode::options;
 use libc::{EAGAIN, ECONNRESET, memcpy, memset, strcmp};
@@ -91,7 +92,8 @@ use crate::context::ZmqContext;
 
 use crate::defines::{ZMQ_CURVE, ZMQ_NULL, ZMQ_PLAIN, ZMQ_PROTOCOL_ERROR_WS_UNSPECIFIED};
 use crate::endpoint_uri::EndpointUriPair;
-use crate::defines::ZmqFileDesc;
+use crate::defines::{ZmqFileDesc, RETIRED_FD};
+use crate::engine_interface::ZmqErrorReason;
 use crate::mechanism::ZmqMechanism;
 use crate::mechanism::ZmqMechanismStatus::error;
 use crate::message::{ZMQ_MSG_COMMAND, ZMQ_MSG_PING, ZMQ_MSG_PONG, ZMQ_MSG_ROUTING_ID, ZmqMessage};
@@ -101,10 +103,14 @@ use crate::plain_server::PlainServer;
 use crate::utils::{copy_bytes, set_bytes};
 use crate::ws_address::WsAddress;
 use crate::ws_decoder::ZmqWsDecoder;
+use crate::ws_deflate::{ws_deflate_accept, ws_deflate_offer, ws_deflate_parse_response, ws_deflate_response, ZmqWsDeflateParams, ZmqWsDeflater, ZmqWsInflater};
 use crate::ws_encoder::ZmqWsEncoder;
+use crate::ws_router::{ws_route_request, ZmqWsHandoff, ZmqWsPort, ZmqWsRouting, ZmqWsUpgrade};
 use crate::ws_engine::ws_client_handshake_state::{client_handshake_complete, client_handshake_end_line_cr, client_handshake_error, client_handshake_initial, client_header_field_begin_name, client_header_field_colon, client_header_field_cr, client_header_field_name, client_header_field_value, client_header_field_value_trailing_space, response_line_cr, response_line_H, response_line_HT, response_line_HTT, response_line_HTTP, response_line_HTTP_slash, response_line_HTTP_slash_1, response_line_HTTP_slash_1_dot, response_line_HTTP_slash_1_dot_1, response_line_HTTP_slash_1_dot_1_space, response_line_p, response_line_pr, response_line_pro, response_line_prot, response_line_proto, response_line_protoc, response_line_protoco, response_line_protocol, response_line_protocols, response_line_s, response_line_status_1, response_line_status_10, response_line_status_101, response_line_status_101_space, response_line_sw, response_line_swi, response_line_swit, response_line_switc, response_line_switch, response_line_switchi, response_line_switchin, response_line_switching, response_line_switching_space};
 use crate::ws_engine::ws_server_handshake_state::{handshake_complete, handshake_end_line_cr, handshake_error, handshake_initial, header_field_begin_name, header_field_colon, header_field_cr, header_field_name, header_field_value, header_field_value_trailing_space, request_line_cr, request_line_G, request_line_GE, request_line_GET, request_line_GET_space, request_line_H, request_line_HT, request_line_HTT, request_line_HTTP, request_line_HTTP_slash, request_line_HTTP_slash_1, request_line_HTTP_slash_1_dot, request_line_HTTP_slash_1_dot_1, request_line_resource, request_line_resource_space};
 
+pub const WS_BUFFER_SIZE: usize = 8192;
+
 enum ws_server_handshake_state {
     handshake_initial = 0,
     request_line_G,
@@ -188,7 +194,7 @@ pub struct ZmqWsEngine {
     pub address: WsAddress,
     pub _client_handshake_state: ws_client_handshake_state,
     pub _server_handshake_state: ws_server_handshake_state,
-    pub _read_buffer: String,
+    pub _read_buffer: Vec<u8>,
     pub _write_buffer: Vec<u8>,
     //[u8;WS_BUFFER_SIZE],
     pub _header_name: String,
@@ -200,6 +206,18 @@ pub struct ZmqWsEngine {
     pub _websocket_protocol: String,
     pub _websocket_key: String,
     pub _websocket_accept: String,
+    //  permessage-deflate parameters, once negotiated.
+    pub _deflate: Option<ZmqWsDeflateParams>,
+    //  Server side: the port's routes and the path we were bound to, the
+    //  request's path and Origin, and the request as read so far, for
+    //  handing it over to another bind. See ws_router.rs.
+    pub _port: Option<Arc<Mutex<ZmqWsPort>>>,
+    pub _route: String,
+    pub _request_path: String,
+    pub _origin: String,
+    pub _request: Vec<u8>,
+    //  Request bytes read by the bind that handed the connection over.
+    pub _handed_over: Vec<u8>,
     pub _heartbeat_timeout: i32,
     pub _close_msg: ZmqMessage,
 }
@@ -290,6 +308,36 @@ impl ZmqWsEngine {
 
     // bool server_handshake ();
 
+    pub fn set_route(&mut self, port: Option<Arc<Mutex<ZmqWsPort>>>, route: &str, request: Vec<u8>) {
+        self._port = port;
+        self._route = route.to_string();
+        self._handed_over = request;
+    }
+
+    //  Which bind the request is for.
+    fn route_request(&self) -> ZmqWsRouting {
+        let Some(port) = &self._port else {
+            return ZmqWsRouting::Accept(String::new());
+        };
+        let origin = (!self._origin.is_empty()).then_some(self._origin.as_str());
+        ws_route_request(&port.lock().unwrap(), &self._route, &self._request_path, origin)
+    }
+
+    //  Passes the connection on, with everything read of it, and lets go
+    //  of the socket.
+    fn hand_over(&mut self, handoff: &ZmqWsHandoff) {
+        //  The current byte is in the request already; the read buffer holds
+        //  exactly what was read, so the rest of it is the unread tail.
+        let unread = self._insize as usize - 1;
+        let read = &self._read_buffer;
+        self._request.extend_from_slice(&read[read.len() - unread..]);
+        handoff.push(ZmqWsUpgrade {
+            fd: self._s,
+            request: std::mem::take(&mut self._request),
+        });
+        self._s = RETIRED_FD as ZmqFileDesc;
+    }
+
     pub fn plug_internal(&mut self) {
         start_ws_handshake();
         set_pollin();
@@ -337,6 +385,11 @@ impl ZmqWsEngine {
             let mut size = encode_base64(nonce, 16, self._websocket_key.clone(), MAX_HEADER_VALUE_LENGTH);
             // assert (size > 0);
 
+            let mut extensions = String::new();
+            if self._options.ws_deflate {
+                extensions = format!("Sec-WebSocket-Extensions: {}\r\n", ws_deflate_offer());
+            }
+
             self._write_buffer = format!(
                 "GET {} HTTP/1.1\r\n\
                  Host: {}\r\n\
@@ -344,8 +397,9 @@ impl ZmqWsEngine {
                  Connection: Upgrade\r\n\
                  Sec-WebSocket-Key: {}\r\n\
                  Sec-WebSocket-Protocol: {}\r\n\
+                 {}\
                  Sec-WebSocket-Version: 13\r\n\r\n",
-                self.address.path(), address.host(), self._websocket_key, protocol).into_bytes();
+                self.address.path(), address.host(), self._websocket_key, protocol, extensions).into_bytes();
             // assert (size > 0 && size < WS_BUFFER_SIZE);
             // TODO:
             // self._outpos = self._write_buffer;
@@ -425,11 +479,13 @@ impl ZmqWsEngine {
         }
 
         if (complete) {
-            self._encoder = ZmqWsEncoder::new(self._options.out_batch_size, self._client);
+            let deflater = self._deflate.map(|params| ZmqWsDeflater::new(&params, self._client));
+            self._encoder = ZmqWsEncoder::new(self._options.out_batch_size, self._client, deflater);
             // alloc_assert (_encoder);
 
+            let inflater = self._deflate.map(|params| ZmqWsInflater::new(&params, self._client));
             self._decoder = ZmqWsDecoder::new(self._options.in_batch_size, self._options.maxmsgsize,
-                                              self._options.zero_copy, !self._client);
+                                              self._options.zero_copy, !self._client, inflater);
             // alloc_assert (_decoder);
 
             self.socket().event_handshake_succeeded(self._endpoint_uri_pair, 0);
@@ -442,7 +498,15 @@ impl ZmqWsEngine {
 
 
     pub fn server_handshake(&mut self) -> anyhow::Result<()> {
-        let nbytes = self.read(&mut self._read_buffer, WS_BUFFER_SIZE)?;
+        let nbytes = if self._handed_over.is_empty() {
+            self._read_buffer.resize(WS_BUFFER_SIZE, 0);
+            let nbytes = self.read(&mut self._read_buffer, WS_BUFFER_SIZE)?;
+            self._read_buffer.truncate(nbytes.max(0) as usize);
+            nbytes
+        } else {
+            self._read_buffer = std::mem::take(&mut self._handed_over);
+            self._read_buffer.len() as i32
+        };
         if (nbytes == -1) {
             return Err(anyhow::Error::msg("read error"));
         }
@@ -452,6 +516,7 @@ impl ZmqWsEngine {
 
         while (self._insize > 0) {
             let c = (*self._inpos);
+            self._request.push(c as u8);
 
             match (self._server_handshake_state) {
                 handshake_initial => {
@@ -492,6 +557,7 @@ impl ZmqWsEngine {
                     }
                     // TODO: instead of check what is not allowed check what is allowed
                     if (c != ' ') {
+                        self._request_path.push(c);
                         self._server_handshake_state = request_line_resource;
                     } else {
                         self._server_handshake_state = request_line_GET_space;
@@ -504,6 +570,7 @@ impl ZmqWsEngine {
                     } else if (c == ' ') {
                         self._server_handshake_state = request_line_resource_space;
                     } else {
+                        self._request_path.push(c);
                         self._server_handshake_state = request_line_resource;
                     }
                 }
@@ -675,6 +742,14 @@ impl ZmqWsEngine {
                                     p = self._header_value[p..].rfind(",").expect("no comma found");
                                 }
                             }
+                        } else if self._header_name.eq_ignore_ascii_case("Sec-WebSocket-Extensions") {
+                            //  Offers may be spread over several headers; the
+                            //  first one we can honour wins.
+                            if self._options.ws_deflate && self._deflate.is_none() {
+                                self._deflate = ws_deflate_accept(&self._header_value);
+                            }
+                        } else if self._header_name.eq_ignore_ascii_case("Origin") {
+                            self._origin = self._header_value.clone();
                         }
 
                         self._server_handshake_state = header_field_cr;
@@ -696,7 +771,33 @@ impl ZmqWsEngine {
 
                 handshake_end_line_cr => {
                     if (c == '\n') {
-                        if (self._header_connection_upgrade && self._header_upgrade_websocket && self._websocket_protocol[0] != 0 && self._websocket_key[0] != 0) {
+                        //  Binds sharing the port are told apart by path.
+                        let mut route_headers = String::new();
+                        let mut rejected = false;
+                        match self.route_request() {
+                            ZmqWsRouting::Accept(headers) => {
+                                route_headers = headers;
+                                //  Held back by the listener until the
+                                //  connection was known to be ours.
+                                if self._port.is_some() {
+                                    self.socket().event_accepted(&self._endpoint_uri_pair, self._s);
+                                }
+                            }
+                            ZmqWsRouting::HandOver(handoff) => {
+                                self.hand_over(&handoff);
+                                //  Not a disconnect: the connection lives on
+                                //  with another bind and this socket never
+                                //  reported it, so no error() and no event.
+                                self.base.unplug();
+                                self.session().engine_error(false, ZmqErrorReason::ConnectionError);
+                                return false;
+                            }
+                            ZmqWsRouting::Reject(response) => {
+                                let _ = self.write(response.as_bytes());
+                                rejected = true;
+                            }
+                        }
+                        if (!rejected && self._header_connection_upgrade && self._header_upgrade_websocket && self._websocket_protocol[0] != 0 && self._websocket_key[0] != 0) {
                             self._server_handshake_state = handshake_complete;
 
                             let hash: [u8; SHA_DIGEST_LENGTH] = [0; SHA_DIGEST_LENGTH];
@@ -708,15 +809,24 @@ impl ZmqWsEngine {
                             // assert (accept_key_len > 0);
                             self._websocket_accept[accept_key_len] = 0;
 
+                            let mut extensions = String::new();
+                            if let Some(params) = &self._deflate {
+                                extensions = format!("Sec-WebSocket-Extensions: {}\r\n", ws_deflate_response(params));
+                            }
+
                             self._write_buffer = format!(
                                 "HTTP/1.1 101 Switching Protocols\r\n\
                                  Upgrade: websocket\r\n\
                                  Connection: Upgrade\r\n\
                                  Sec-WebSocket-Accept: {}\r\n\
                                  Sec-WebSocket-Protocol: {}\r\n\
+                                 {}\
+                                 {}\
                                  \r\n",
                                 self._websocket_accept,
-                                self._websocket_protocol).into_bytes();
+                                self._websocket_protocol,
+                                extensions,
+                                route_headers).into_bytes();
                             // assert(written >= 0 && written < WS_BUFFER_SIZE);
                             // TODO
                             // self._outpos = self._write_buffer;
@@ -1104,8 +1214,19 @@ impl ZmqWsEngine {
                             if (select_protocol(self._header_value.clone())) {
                                 strcpy_s(self._websocket_protocol, self._header_value.clone());
                             }
+                        } else if self._header_name.eq_ignore_ascii_case("Sec-WebSocket-Extensions") {
+                            //  The server can only accept what we offered,
+                            //  and only once.
+                            match ws_deflate_parse_response(&self._header_value) {
+                                Ok(params) if self._options.ws_deflate && self._deflate.is_none() => {
+                                    self._deflate = Some(params);
+                                }
+                                _ => self._client_handshake_state = client_handshake_error,
+                            }
+                        }
+                        if self._client_handshake_state != client_handshake_error {
+                            self._client_handshake_state = client_header_field_cr;
                         }
-                        self._client_handshake_state = client_header_field_cr;
                     } else if (self._header_value_position + 1 > MAX_HEADER_VALUE_LENGTH) {
                         self._client_handshake_state = client_handshake_error;
                     } else {
@@ -0,0 +1,340 @@
+//  Several ws:// binds on one port, told apart by request path.
+//
+//  The first bind on an address opens the listening socket; later binds on
+//  the same address (from any socket in the context) add their path to the
+//  port's routes and listen on a duplicate of it. Whichever listener
+//  accepts a connection reads the upgrade request; if the path belongs to
+//  another bind, the connection and what was read of it are handed over
+//  through that bind's doorbell, and its listener runs the handshake from
+//  the start. Unknown paths get a 404, origins not allowed by
+//  ZMQ_WS_ALLOWED_ORIGIN a 403.
+//
+//  On a shared port the accepted event waits for the routing: only the
+//  bind the request path names reports the connection, the socket that
+//  handed it over reports nothing. wss:// ports are not shared: the TLS
+//  session can't be handed over.
+
+use std::collections::HashMap;
+use std::mem;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+
+use anyhow::bail;
+use libc::{c_int, close, fcntl, F_DUPFD_CLOEXEC};
+
+use crate::context::ZmqContext;
+use crate::defines::ZmqFileDesc;
+use crate::doorbell::ZmqDoorbell;
+
+//  A connection on its way to the bind its request path names.
+#[derive(Debug)]
+pub struct ZmqWsUpgrade {
+    pub fd: ZmqFileDesc,
+    //  What was read of the upgrade request.
+    pub request: Vec<u8>,
+}
+
+//  Connections handed over to a bind, and the doorbell its listener polls.
+#[derive(Debug)]
+pub struct ZmqWsHandoff {
+    pub bell: ZmqDoorbell,
+    upgrades: Mutex<Vec<ZmqWsUpgrade>>,
+}
+
+impl ZmqWsHandoff {
+    pub fn new() -> anyhow::Result<Self> {
+        Ok(Self {
+            bell: ZmqDoorbell::new()?,
+            upgrades: Mutex::new(vec![]),
+        })
+    }
+
+    pub fn push(&self, upgrade: ZmqWsUpgrade) {
+        self.upgrades.lock().unwrap().push(upgrade);
+        self.bell.ring();
+    }
+
+    pub fn take(&self) -> Vec<ZmqWsUpgrade> {
+        self.bell.drain();
+        mem::take(&mut *self.upgrades.lock().unwrap())
+    }
+}
+
+impl Drop for ZmqWsHandoff {
+    //  Connections the listener didn't get to before it closed.
+    fn drop(&mut self) {
+        for upgrade in self.upgrades.get_mut().unwrap().drain(..) {
+            unsafe { close(upgrade.fd as c_int) };
+        }
+    }
+}
+
+//  One bind on a port.
+#[derive(Debug, Clone)]
+pub struct ZmqWsRoute {
+    //  ZMQ_WS_RESPONSE_HEADER and ZMQ_WS_ALLOWED_ORIGIN at bind time.
+    pub response_headers: Vec<String>,
+    pub allowed_origins: Vec<String>,
+    pub handoff: Arc<ZmqWsHandoff>,
+}
+
+impl ZmqWsRoute {
+    pub fn new(ctx: &ZmqContext) -> anyhow::Result<Self> {
+        Ok(Self {
+            response_headers: ctx.ws_response_headers.clone(),
+            allowed_origins: ctx.ws_allowed_origins.clone(),
+            handoff: Arc::new(ZmqWsHandoff::new()?),
+        })
+    }
+
+    //  Requests without an Origin don't come from a browser and are let
+    //  through.
+    fn allows(&self, origin: Option<&str>) -> bool {
+        match origin {
+            Some(origin) if !self.allowed_origins.is_empty() => self
+                .allowed_origins
+                .iter()
+                .any(|allowed| allowed == "*" || allowed.eq_ignore_ascii_case(origin)),
+            _ => true,
+        }
+    }
+}
+
+//  A listening address and the binds sharing it.
+#[derive(Debug)]
+pub struct ZmqWsPort {
+    //  Kept open for as long as there are routes, so that later binds
+    //  can duplicate it whichever of the earlier ones have gone.
+    pub fd: ZmqFileDesc,
+    pub wss: bool,
+    pub routes: HashMap<String, ZmqWsRoute>,
+}
+
+impl ZmqWsPort {
+    pub fn new(fd: ZmqFileDesc, wss: bool) -> anyhow::Result<Self> {
+        Ok(Self {
+            fd: ws_dup(fd)?,
+            wss,
+            routes: HashMap::new(),
+        })
+    }
+
+    pub fn add_route(&mut self, path: &str, route: ZmqWsRoute) -> anyhow::Result<()> {
+        if self.routes.contains_key(path) {
+            bail!("EADDRINUSE: path {} is already bound", path);
+        }
+        self.routes.insert(path.to_string(), route);
+        Ok(())
+    }
+}
+
+impl Drop for ZmqWsPort {
+    fn drop(&mut self) {
+        unsafe { close(self.fd as c_int) };
+    }
+}
+
+//  What to do with an upgrade request.
+pub enum ZmqWsRouting {
+    //  It is for the bind that accepted it; the extra headers for the
+    //  101 response.
+    Accept(String),
+    //  It is for another bind on the port.
+    HandOver(Arc<ZmqWsHandoff>),
+    //  The response to turn it down with.
+    Reject(String),
+}
+
+//  Picks the route for a request; 'own' is the path of the bind that
+//  accepted the connection.
+pub fn ws_route_request(
+    port: &ZmqWsPort,
+    own: &str,
+    target: &str,
+    origin: Option<&str>,
+) -> ZmqWsRouting {
+    //  The query string doesn't take part in routing.
+    let path = target.split_once('?').map_or(target, |(path, _)| path);
+    let Some(route) = port.routes.get(path) else {
+        return ZmqWsRouting::Reject(ws_http_error("404 Not Found"));
+    };
+    if path != own {
+        return ZmqWsRouting::HandOver(route.handoff.clone());
+    }
+    if !route.allows(origin) {
+        return ZmqWsRouting::Reject(ws_http_error("403 Forbidden"));
+    }
+    let headers = route
+        .response_headers
+        .iter()
+        .map(|header| format!("{}\r\n", header))
+        .collect();
+    ZmqWsRouting::Accept(headers)
+}
+
+pub fn ws_http_error(status: &str) -> String {
+    format!(
+        "HTTP/1.1 {}\r\nConnection: close\r\nContent-Length: 0\r\n\r\n",
+        status
+    )
+}
+
+//  "host:port/path" into the address to listen on and the path, "/" if
+//  there is none.
+pub fn ws_split_address(addr: &str) -> (&str, &str) {
+    //  The host may be a bracketed IPv6 address, which has no slashes.
+    match addr.find('/') {
+        Some(at) => (&addr[..at], &addr[at..]),
+        None => (addr, "/"),
+    }
+}
+
+fn ws_port_key(address: &SocketAddr) -> String {
+    address.to_string()
+}
+
+//  The port already listening on 'address', if any.
+pub fn ws_port_find(ctx: &ZmqContext, address: &SocketAddr) -> Option<Arc<Mutex<ZmqWsPort>>> {
+    //  Ephemeral ports are never shared.
+    if address.port() == 0 {
+        return None;
+    }
+    ctx.ws_ports
+        .lock()
+        .unwrap()
+        .get(&ws_port_key(address))
+        .cloned()
+}
+
+pub fn ws_port_register(ctx: &ZmqContext, address: &SocketAddr, port: Arc<Mutex<ZmqWsPort>>) {
+    ctx.ws_ports
+        .lock()
+        .unwrap()
+        .insert(ws_port_key(address), port);
+}
+
+//  Drops a bind's route; the port goes with the last one.
+pub fn ws_port_release(ctx: &ZmqContext, port: &Arc<Mutex<ZmqWsPort>>, path: &str) {
+    let mut ports = ctx.ws_ports.lock().unwrap();
+    let mut shared = port.lock().unwrap();
+    shared.routes.remove(path);
+    if shared.routes.is_empty() {
+        ports.retain(|_, registered| !Arc::ptr_eq(registered, port));
+    }
+}
+
+//  A close-on-exec duplicate of a listening socket.
+pub fn ws_dup(fd: ZmqFileDesc) -> anyhow::Result<ZmqFileDesc> {
+    let copy = unsafe { fcntl(fd as c_int, F_DUPFD_CLOEXEC, 0) };
+    if copy < 0 {
+        bail!(
+            "failed to duplicate listening socket: {}",
+            std::io::Error::last_os_error()
+        );
+    }
+    Ok(copy as ZmqFileDesc)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+    use std::os::unix::io::AsRawFd;
+
+    fn route(response_headers: &[&str], allowed_origins: &[&str]) -> ZmqWsRoute {
+        ZmqWsRoute {
+            response_headers: response_headers.iter().map(|h| h.to_string()).collect(),
+            allowed_origins: allowed_origins.iter().map(|o| o.to_string()).collect(),
+            handoff: Arc::new(ZmqWsHandoff::new().unwrap()),
+        }
+    }
+
+    fn port(listener: &TcpListener) -> ZmqWsPort {
+        let mut port = ZmqWsPort::new(listener.as_raw_fd() as ZmqFileDesc, false).unwrap();
+        port.add_route("/a", route(&["X-Route: a"], &["https://ok.example"]))
+            .unwrap();
+        port.add_route("/b", route(&[], &[])).unwrap();
+        port
+    }
+
+    #[test]
+    fn split_address() {
+        assert_eq!(
+            ws_split_address("127.0.0.1:5555/a"),
+            ("127.0.0.1:5555", "/a")
+        );
+        assert_eq!(ws_split_address("127.0.0.1:5555"), ("127.0.0.1:5555", "/"));
+        assert_eq!(ws_split_address("[::1]:5555/a/b"), ("[::1]:5555", "/a/b"));
+    }
+
+    #[test]
+    fn requests_are_routed_by_path() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = port(&listener);
+
+        match ws_route_request(&port, "/a", "/a?x=1", None) {
+            ZmqWsRouting::Accept(headers) => assert_eq!(headers, "X-Route: a\r\n"),
+            _ => panic!("expected /a to be accepted"),
+        }
+        match ws_route_request(&port, "/a", "/a", Some("HTTPS://OK.EXAMPLE")) {
+            ZmqWsRouting::Accept(_) => {}
+            _ => panic!("expected an allowed origin to be accepted"),
+        }
+        match ws_route_request(&port, "/a", "/a", Some("https://evil.example")) {
+            ZmqWsRouting::Reject(response) => assert!(response.starts_with("HTTP/1.1 403")),
+            _ => panic!("expected a foreign origin to be rejected"),
+        }
+        match ws_route_request(&port, "/a", "/b", Some("https://evil.example")) {
+            ZmqWsRouting::HandOver(handoff) => {
+                assert!(Arc::ptr_eq(&handoff, &port.routes["/b"].handoff))
+            }
+            _ => panic!("expected /b to be handed over"),
+        }
+        match ws_route_request(&port, "/a", "/c", None) {
+            ZmqWsRouting::Reject(response) => assert!(response.starts_with("HTTP/1.1 404")),
+            _ => panic!("expected an unknown path to be rejected"),
+        }
+    }
+
+    #[test]
+    fn paths_are_bound_once() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut port = port(&listener);
+        let err = port.add_route("/a", route(&[], &[])).unwrap_err();
+        assert!(err.to_string().starts_with("EADDRINUSE"));
+    }
+
+    #[test]
+    fn handoff_passes_upgrades() {
+        let handoff = ZmqWsHandoff::new().unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        handoff.push(ZmqWsUpgrade {
+            fd: ws_dup(listener.as_raw_fd() as ZmqFileDesc).unwrap(),
+            request: b"GET /b HTTP/1.1\r\n".to_vec(),
+        });
+
+        let upgrades = handoff.take();
+        assert_eq!(upgrades.len(), 1);
+        assert_eq!(upgrades[0].request, b"GET /b HTTP/1.1\r\n");
+        unsafe { close(upgrades[0].fd as c_int) };
+        assert!(handoff.take().is_empty());
+    }
+
+    #[test]
+    fn ports_live_as_long_as_their_routes() {
+        let ctx = ZmqContext::default();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        assert!(ws_port_find(&ctx, &address).is_none());
+
+        let shared = Arc::new(Mutex::new(port(&listener)));
+        ws_port_register(&ctx, &address, shared.clone());
+        assert!(ws_port_find(&ctx, &"127.0.0.1:0".parse().unwrap()).is_none());
+        assert!(Arc::ptr_eq(&ws_port_find(&ctx, &address).unwrap(), &shared));
+
+        ws_port_release(&ctx, &shared, "/a");
+        assert!(ws_port_find(&ctx, &address).is_some());
+        ws_port_release(&ctx, &shared, "/b");
+        assert!(ws_port_find(&ctx, &address).is_none());
+    }
+}
@@ -33,21 +33,21 @@
 use std::collections::VecDeque;
 use std::ffi::c_int;
 use std::mem;
-use std::ptr::null_mut;
 use anyhow::bail;
 
 use libc::{EAGAIN, EINVAL};
-use trie_rs::TrieBuilder;
 
 use crate::context::ZmqContext;
 use crate::defines::{ZMQ_ONLY_FIRST_SUBSCRIBE, ZMQ_PUB, ZMQ_SUBSCRIBE, ZMQ_TOPICS_COUNT, ZMQ_UNSUBSCRIBE, ZMQ_XPUB, ZMQ_XPUB_MANUAL, ZMQ_XPUB_MANUAL_LAST_VALUE, ZMQ_XPUB_NODROP, ZMQ_XPUB_VERBOSE, ZMQ_XPUB_VERBOSER, ZMQ_XPUB_WELCOME_MSG};
 use crate::dist::ZmqDist;
 use crate::message::{ZMQ_MSG_MORE, ZmqMessage};
 use crate::metadata::ZmqMetadata;
+use crate::mtrie::ZmqMtrieRmResult;
 
-use crate::pipe::ZmqPipe;
+use crate::pipe::{ZmqPipe, ZmqPipeId};
 use crate::socket::ZmqSocket;
 use crate::utils::copy_bytes;
+use crate::xsub::subscription_topic;
 
 // #include "xpub.hpp"
 // #include "pipe.hpp"
@@ -60,10 +60,10 @@ use crate::utils::copy_bytes;
 //     pub base: ZmqSocket,
 //     //  List of all subscriptions mapped to corresponding pipes.
 //     // mtrie_t _subscriptions;
-//     pub _subscriptions: TrieBuilder<Vec<u8>>,
+//     pub _subscriptions: ZmqMtrie,
 //     //  List of manual subscriptions mapped to corresponding pipes.
 //     // mtrie_t _manual_subscriptions;
-//     pub _manual_subscriptions: TrieBuilder<Vec<u8>>,
+//     pub _manual_subscriptions: ZmqMtrie,
 //     //  Distributor of messages holding the list of outbound pipes.
 //     // ZmqDist _dist;
 //     pub _dist: ZmqDist,
@@ -137,15 +137,16 @@ pub fn xpub_xsend(sock: &mut ZmqSocket, ctx: &mut ZmqContext, msg: &mut ZmqMessa
         sock._dist.unmatch();
 
         if sock._manual && sock._last_pipe.is_some() && sock._send_last_pipe {
-            sock._subscriptions.match_(
-                (msg.data()),
-                msg.size(),
-                mark_last_pipe_as_matching,
-                sock,
-            );
+            let last_pipe = sock._last_pipe.clone();
+            let dist = &mut sock._dist;
+            sock._subscriptions.match_(msg.data(), msg.size(), |pipe| {
+                mark_last_pipe_as_matching(dist, &last_pipe, pipe)
+            });
             sock._last_pipe = None;
         } else {
-            sock._subscriptions.match_((msg.data()), msg.size(), mark_as_matching, sock);
+            let dist = &mut sock._dist;
+            sock._subscriptions
+                .match_(msg.data(), msg.size(), |pipe| mark_as_matching(dist, pipe));
         }
         // If inverted matching is used, reverse the selection now
         if ctx.invert_matching {
@@ -236,10 +237,8 @@ pub fn xpub_xread_activated(sock: &mut ZmqSocket, ctx: &mut ZmqContext, pipe: &m
     while pipe.read(&mut msg) {
         // ZmqMetadata *metadata = msg.metadata ();
         let metadata = msg.metadata();
-        let mut msg_data = msg.data_mut(); // (msg.data ()),
         // *data = null_mut();
-        let mut data: &mut [u8] = &mut [];
-        let mut size = 0;
+        let mut topic: Vec<u8> = Vec::new();
         let mut subscribe = false;
         let mut is_subscribe_or_cancel = false;
         let mut notify = false;
@@ -248,19 +247,17 @@ pub fn xpub_xread_activated(sock: &mut ZmqSocket, ctx: &mut ZmqContext, pipe: &m
         sock._more_recv = (msg.flags() & ZMQ_MSG_MORE) != 0;
 
         if (first_part || sock._process_subscribe) {
-            //  Apply the subscription to the trie
-            if msg.is_subscribe() || msg.is_cancel() {
-                data = (msg.command_body().as_mut_slice());
-                size = msg.command_body_size();
-                subscribe = msg.is_subscribe();
-                is_subscribe_or_cancel = true;
-            } else if msg.size() > 0 && (*msg_data == 0 || *msg_data == 1) {
-                data = msg_data + 1;
-                size = msg.size() - 1;
-                subscribe = *msg_data == 1;
+            //  Apply the subscription to the trie. ZMTP 3.1 peers send
+            //  SUBSCRIBE/CANCEL commands, older peers and XSUB users send
+            //  data frames prefixed by 1 or 0.
+            if let Some((sub, body)) = subscription_topic(&msg) {
+                subscribe = sub;
+                topic = body;
                 is_subscribe_or_cancel = true;
             }
         }
+        let data = topic.as_slice();
+        let size = topic.len();
 
         if first_part {
             sock._process_subscribe = !sock._only_first_subscribe || is_subscribe_or_cancel;
@@ -270,19 +267,18 @@ pub fn xpub_xread_activated(sock: &mut ZmqSocket, ctx: &mut ZmqContext, pipe: &m
             if sock._manual {
                 // Store manual subscription to use on termination
                 if !subscribe {
-                    sock._manual_subscriptions.rm(data, size, pipe);
+                    sock._manual_subscriptions.rm(data, size, &pipe.id);
                 } else {
-                    sock._manual_subscriptions.add(data, size, pipe);
+                    sock._manual_subscriptions.add(data, size, pipe.id);
                 }
 
                 sock._pending_pipes.push_back(pipe.clone());
             } else if !subscribe {
-                let rm_result = sock._subscriptions.rm(data, size, pipe);
+                let rm_result = sock._subscriptions.rm(data, size, &pipe.id);
                 //  TODO reconsider what to do if rm_result == mtrie_t::not_found
-                // notify = rm_result != mtrie_t::values_remain || sock._verbose_unsubs;
-                notify = rm_result != sock._verbose_unsubs;
+                notify = rm_result != ZmqMtrieRmResult::ValuesRemain || sock._verbose_unsubs;
             } else {
-                let first_added = sock._subscriptions.add(data, size, pipe);
+                let first_added = sock._subscriptions.add(data, size, pipe.id);
                 notify = first_added || sock._verbose_subs;
             }
 
@@ -319,7 +315,7 @@ pub fn xpub_xread_activated(sock: &mut ZmqSocket, ctx: &mut ZmqContext, pipe: &m
             //  Process user message coming upstream from xsub socket,
             //  but not if the type is PUB, which never processes user
             //  messages
-            sock._pending_data.push_back(msg_data);
+            sock._pending_data.push_back(msg.data().to_vec());
             if metadata {
                 metadata.add_ref();
             }
@@ -361,12 +357,12 @@ pub fn xpub_xsetsockopt(sock: &mut ZmqSocket, option_: i32, optval_: &mut [u8],
             sock._only_first_subscribe = ((optval_) != 0);
         }
     } else if option_ == ZMQ_SUBSCRIBE && sock._manual {
-        if sock._last_pipe != null_mut() {
-            sock._subscriptions.add(optval_, optvallen_, sock._last_pipe.clone());
+        if let Some(last_pipe) = &sock._last_pipe {
+            sock._subscriptions.add(optval_, optvallen_, last_pipe.id);
         }
     } else if option_ == ZMQ_UNSUBSCRIBE && sock._manual {
-        if sock._last_pipe != null_mut() {
-            sock._subscriptions.rm(optval_, optvallen_, sock._last_pipe.clone());
+        if let Some(last_pipe) = &sock._last_pipe {
+            sock._subscriptions.rm(optval_, optvallen_, &last_pipe.id);
         }
     } else if option_ == ZMQ_XPUB_WELCOME_MSG {
         sock._welcome_msg.close();
@@ -410,12 +406,19 @@ pub fn xpub_xpipe_terminated(sock: &mut ZmqSocket, pipe: &mut ZmqPipe) {
     if sock._manual {
         //  Remove the pipe from the trie and send corresponding manual
         //  unsubscriptions upstream.
-        sock._manual_subscriptions.rm(pipe, send_unsubscription, sock, false);
+        let mut unsubscriptions: Vec<Vec<u8>> = vec![];
+        sock._manual_subscriptions.rm_value(
+            &pipe.id,
+            |data, size| unsubscriptions.push(data[..size].to_vec()),
+            false,
+        );
+        for data in unsubscriptions {
+            send_unsubscription(sock, &data, data.len());
+        }
         //  Remove pipe without actually sending the message as it was taken
         //  care of by the manual call above. subscriptions is the real mtrie,
         //  so the pipe must be removed from there or it will be left over.
-        // TODO
-        // sock._subscriptions.rm(pipe, stub, (null_mut()), false);
+        sock._subscriptions.rm_value(&pipe.id, |_, _| {}, false);
 
         // In case the pipe is currently set as last we must clear it to prevent
         // subscriptions from being re-added.
@@ -426,7 +429,15 @@ pub fn xpub_xpipe_terminated(sock: &mut ZmqSocket, pipe: &mut ZmqPipe) {
         //  Remove the pipe from the trie. If there are topics that nobody
         //  is interested in anymore, send corresponding unsubscriptions
         //  upstream.
-        sock._subscriptions.rm(pipe, send_unsubscription, sock, !sock._verbose_unsubs);
+        let mut unsubscriptions: Vec<Vec<u8>> = vec![];
+        sock._subscriptions.rm_value(
+            &pipe.id,
+            |data, size| unsubscriptions.push(data[..size].to_vec()),
+            !sock._verbose_unsubs,
+        );
+        for data in unsubscriptions {
+            send_unsubscription(sock, &data, data.len());
+        }
     }
 
     sock._dist.pipe_terminated(pipe);
@@ -438,17 +449,17 @@ pub fn xpub_xpipe_terminated(sock: &mut ZmqSocket, pipe: &mut ZmqPipe) {
 // static void send_unsubscription (mtrie_t::prefix_t data,
 // size: usize,
 // XPub *self_);
-pub fn send_unsubscription(sock: &mut ZmqSocket, data: TrieBuilder<Vec<u8>>, size: usize) {
+pub fn send_unsubscription(sock: &mut ZmqSocket, data: &[u8], size: usize) {
     if sock.options.type_ != ZMQ_PUB {
         //  Place the unsubscription to the queue of pending (un)subscriptions
         //  to be retrieved by the user later on.
-        // TODO:
         // Blob unsub (size + 1);
         // *unsub.data () = 0;
-        // if (size > 0) {
-        //     copy_bytes(unsub.data_mut(), 1, data, 0,size);
-        // }
-        // sock._pending_data.push_back(unsub);
+        let mut unsub = vec![0u8; size + 1];
+        if size > 0 {
+            copy_bytes(&mut unsub, 1, data, 0, size);
+        }
+        sock._pending_data.push_back(unsub);
         // self._pending_metadata.push_back ();
         sock._pending_flags.push_back(0);
 
@@ -461,13 +472,17 @@ pub fn send_unsubscription(sock: &mut ZmqSocket, data: TrieBuilder<Vec<u8>>, siz
 
 //  Function to be applied to each matching pipes.
 // static void mark_as_matching (pipe: &mut ZmqPipe, XPub *self_);
-pub fn mark_as_matching(sock: &mut ZmqSocket, pipe: &mut ZmqPipe) {
-    sock._dist.match_(pipe);
+pub fn mark_as_matching(dist: &mut ZmqDist, pipe: &mut ZmqPipeId) {
+    dist.mark_matching(*pipe);
 }
 
 // static void mark_last_pipe_as_matching (pipe: &mut ZmqPipe, XPub *self_);
-pub fn mark_last_pipe_as_matching(sock: &mut ZmqSocket, pipe: &mut ZmqPipe) {
-    if sock._last_pipe == pipe {
-        sock._dist.match_(pipe);
+pub fn mark_last_pipe_as_matching(
+    dist: &mut ZmqDist,
+    last_pipe: &Option<ZmqPipe>,
+    pipe: &mut ZmqPipeId,
+) {
+    if last_pipe.as_ref().map(|last| last.id) == Some(*pipe) {
+        dist.mark_matching(*pipe);
     }
 }
\ No newline at end of file
@@ -33,7 +33,7 @@
 use std::mem;
 use anyhow::bail;
 
-use bincode::{deserialize, options};
+use bincode::options;
 use libc::EINVAL;
 
 use crate::defines::{ZMQ_ONLY_FIRST_SUBSCRIBE, ZMQ_TOPICS_COUNT, ZMQ_XSUB_VERBOSE_UNSUBSCRIBE};
@@ -129,7 +129,7 @@ pub fn xgetsockopt(sock: &mut ZmqSocket, option_: i32, optval_: &mut [u8], optva
         // make sure to use a multi-thread safe function to avoid race conditions with I/O threads
         // where subscriptions are processed:
         // #ifdef ZMQ_USE_RADIX_TREE
-        let num_subscriptions = sock._subscriptions.size();
+        let num_subscriptions = sock._xsub_subscriptions.size();
         // #else
         //         u64 num_subscriptions = _subscriptions.num_prefixes ();
         // #endif
@@ -148,9 +148,6 @@ pub fn xgetsockopt(sock: &mut ZmqSocket, option_: i32, optval_: &mut [u8], optva
 // int xsend (ZmqMessage *msg) ;
 
 pub fn xsend(sock: &mut ZmqSocket, msg: &mut ZmqMessage) -> i32 {
-    let mut size = msg.size();
-    let mut data = msg.data().unwrap().first_mut().unwrap();
-
     let first_part = !sock._more_send;
     sock._more_send = (msg.flags() & ZMQ_MSG_MORE) != 0;
 
@@ -161,45 +158,51 @@ pub fn xsend(sock: &mut ZmqSocket, msg: &mut ZmqMessage) -> i32 {
         return sock._dist.send_to_all(msg);
     }
 
-    if msg.is_subscribe() || (size > 0 && *data == 1) {
-        //  Process subscribe message
+    //  Subscriptions arrive either as messages built by init_subscribe /
+    //  init_cancel (SUB, or XSUB fed by a 3.1-aware XPUB) or as the legacy
+    //  data frames prefixed by 1 or 0. Both are forwarded unchanged; the
+    //  encoder of each connection picks the wire format for its peer.
+    let (subscribe, topic) = match subscription_topic(msg) {
+        Some(parsed) => parsed,
+        //  User message sent upstream to XPUB socket
+        None => return sock._dist.send_to_all(msg),
+    };
+
+    sock._process_subscribe = true;
+    if subscribe {
         //  This used to filter out duplicate subscriptions,
         //  however this is already Done on the XPUB side and
         //  doing it here as well breaks ZMQ_XPUB_VERBOSE
         //  when there are forwarding devices involved.
-        if !msg.is_subscribe() {
-            data = data + 1;
-            size = size - 1;
-        }
-        sock._subscriptions.add(data, size);
-        sock._process_subscribe = true;
+        sock._xsub_subscriptions.add(&topic, topic.len());
         return sock._dist.send_to_all(msg);
     }
-    if msg.is_cancel() || (size > 0 && *data == 0) {
-        //  Process unsubscribe message
-        if !msg.is_cancel() {
-            data = data + 1;
-            size = size - 1;
-        }
-        sock._process_subscribe = true;
-        let rm_result = sock._subscriptions.rm(data, size);
-        if (rm_result || sock._verbose_unsubs) {
-            return sock._dist.send_to_all(msg);
-        }
-    } else {
-        //  User message sent upstream to XPUB socket
+
+    let rm_result = sock._xsub_subscriptions.rm(&topic, topic.len());
+    if (rm_result || sock._verbose_unsubs) {
         return sock._dist.send_to_all(msg);
     }
 
+    //  Subscription cancelled that was never made; drop the message.
     let mut rc = msg.close();
-    // errno_assert (rc == 0);
     rc = msg.init2();
-    // errno_assert (rc == 0);
 
     return 0;
 }
 
-// bool xhas_out () ;
+//  Returns (subscribe, topic) if the message is a subscription or a
+//  cancellation, in any of the forms it can take.
+pub fn subscription_topic(msg: &ZmqMessage) -> Option<(bool, Vec<u8>)> {
+    if msg.is_subscribe() || msg.is_cancel() {
+        return Some((msg.is_subscribe(), msg.command_body().to_vec()));
+    }
+    match msg.data().split_first() {
+        Some((&1, topic)) => Some((true, topic.to_vec())),
+        Some((&0, topic)) => Some((false, topic.to_vec())),
+        _ => None,
+    }
+}
+
 pub fn xhas_out(sock: &mut ZmqSocket) -> bool {
     //  Subscription can be added/removed anytime.
     return true;
@@ -301,8 +304,9 @@ pub fn xwrite_activated(sock: &mut ZmqSocket, pipe: &mut ZmqPipe) {
 // void xhiccuped (ZmqPipe *pipe_) ;
 pub fn xhiccuped(sock: &mut ZmqSocket, pipe: &mut ZmqPipe) {
     //  Send all the cached subscriptions to the hiccuped pipe.
-    sock._subscriptions.apply(send_subscription, pipe);
-    sock.pipe.flush();
+    sock._xsub_subscriptions
+        .apply(|data, size| send_subscription(pipe, data, size));
+    pipe.flush();
 }
 
 // void xpipe_terminated (ZmqPipe *pipe_) ;
@@ -314,7 +318,7 @@ pub fn xpipe_terminated(sock: &mut ZmqSocket, pipe: &mut ZmqPipe) {
 //  Check whether the message matches at least one subscription.
 // bool match (ZmqMessage *msg);
 pub fn match_(sock: &mut ZmqSocket, msg: &mut ZmqMessage) -> bool {
-    let matching = sock._subscriptions.check((msg.data()), msg.size());
+    let matching = sock._xsub_subscriptions.check(msg.data(), msg.size());
 
     return matching ^ options.invert_matching;
 }
@@ -323,19 +327,12 @@ pub fn match_(sock: &mut ZmqSocket, msg: &mut ZmqMessage) -> bool {
 //  upstream.
 // static void
 // send_subscription (unsigned char *data, size: usize, arg_: *mut c_void);
-pub fn send_subscription(
-    sock: &mut ZmqSocket,
-    data: &mut [u8],
-    size: usize,
-    arg_: &mut [u8],
-) -> anyhow::Result<()> {
-    // ZmqPipe *pipe = static_cast<ZmqPipe *> (arg_);
-    let mut pipe: ZmqPipe = deserialize(arg_)?;
-
+pub fn send_subscription(pipe: &mut ZmqPipe, data: &[u8], size: usize) {
     //  Create the subscription message.
     let mut msg = ZmqMessage::default();
-    msg.init_subscribe(size, data)?;
+    let rc = msg.init_subscribe(size, data);
     // errno_assert (rc == 0);
+    debug_assert!(rc.is_ok());
 
     //  Send it to the pipe.
     let sent = pipe.write(&mut msg);
@@ -346,5 +343,4 @@ pub fn send_subscription(
     if !sent {
         msg.close();
     }
-    Ok(())
 }
@@ -0,0 +1,305 @@
+//  Lock-free queue implementation.
+//  Only a single thread can read from the pipe at any specific moment.
+//  Only a single thread can write to the pipe at any specific moment.
+//  T is the type of the object in the queue.
+//  N is granularity of the pipe, i.e. how many items are needed to
+//  perform next memory allocation.
+//
+//  ypipe() hands out the pipe as a write end and a read end. The writer's
+//  'w' and 'f' and the reader's 'r' live in the ends that use them; only
+//  the queue and 'c' are shared.
+
+use std::fmt;
+use std::ptr::{self, null_mut};
+use std::sync::atomic::{AtomicPtr, Ordering};
+use std::sync::Arc;
+
+use crate::ypipe_base::{ZmqYPipeReader, ZmqYPipeWriter};
+use crate::yqueue::ZmqYQueue;
+
+struct ZmqYPipe<T, const N: usize> {
+    //  Allocation-efficient queue to store pipe items.
+    //  Front of the queue points to the first prefetched item, back of
+    //  the pipe points to last un-flushed item. Front is used only by
+    //  reader thread, while back is used only by writer thread.
+    queue: ZmqYQueue<T, N>,
+    //  The single point of contention between writer and reader thread.
+    //  Points past the last flushed item. If it is NULL,
+    //  reader is asleep.
+    c: AtomicPtr<T>,
+}
+
+pub struct ZmqYPipeWriteEnd<T, const N: usize> {
+    pipe: Arc<ZmqYPipe<T, N>>,
+    //  Points to the first un-flushed item.
+    w: *mut T,
+    //  Points to the first item to be flushed in the future.
+    f: *mut T,
+}
+
+pub struct ZmqYPipeReadEnd<T, const N: usize> {
+    pipe: Arc<ZmqYPipe<T, N>>,
+    //  Points to the first un-prefetched item.
+    r: *mut T,
+}
+
+//  The raw pointers keep the ends from being Sync; they only ever point
+//  into the shared queue, so the ends may move to another thread.
+unsafe impl<T: Send, const N: usize> Send for ZmqYPipeWriteEnd<T, N> {}
+unsafe impl<T: Send, const N: usize> Send for ZmqYPipeReadEnd<T, N> {}
+
+//  Creates a pipe, returning its write and read ends.
+pub fn ypipe<T: Send, const N: usize>() -> (ZmqYPipeWriteEnd<T, N>, ZmqYPipeReadEnd<T, N>) {
+    let queue = ZmqYQueue::new();
+    //  Insert terminator element into the queue.
+    let back = unsafe {
+        queue.push();
+        queue.back()
+    };
+
+    //  Let all the pointers to point to the terminator.
+    let pipe = Arc::new(ZmqYPipe {
+        queue,
+        c: AtomicPtr::new(back),
+    });
+    let writer = ZmqYPipeWriteEnd {
+        pipe: pipe.clone(),
+        w: back,
+        f: back,
+    };
+    let reader = ZmqYPipeReadEnd { pipe, r: back };
+    (writer, reader)
+}
+
+impl<T, const N: usize> fmt::Debug for ZmqYPipeWriteEnd<T, N> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ZmqYPipeWriteEnd").finish_non_exhaustive()
+    }
+}
+
+impl<T, const N: usize> fmt::Debug for ZmqYPipeReadEnd<T, N> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ZmqYPipeReadEnd").finish_non_exhaustive()
+    }
+}
+
+impl<T: Send, const N: usize> ZmqYPipeWriter<T> for ZmqYPipeWriteEnd<T, N> {
+    fn write(&mut self, value: T, incomplete: bool) {
+        let queue = &self.pipe.queue;
+        unsafe {
+            //  Place the value to the queue, add new terminator element.
+            queue.back().write(value);
+            queue.push();
+
+            //  Move the "flush up to here" pointer.
+            if !incomplete {
+                self.f = queue.back();
+            }
+        }
+    }
+
+    fn unwrite(&mut self) -> Option<T> {
+        let queue = &self.pipe.queue;
+        unsafe {
+            if self.f == queue.back() {
+                return None;
+            }
+            queue.unpush();
+            Some(queue.back().read())
+        }
+    }
+
+    fn flush(&mut self) -> bool {
+        //  If there are no un-flushed items, do nothing.
+        if self.w == self.f {
+            return true;
+        }
+
+        //  Try to set 'c' to 'f'.
+        if self
+            .pipe
+            .c
+            .compare_exchange(self.w, self.f, Ordering::AcqRel, Ordering::Acquire)
+            .is_err()
+        {
+            //  Compare-and-swap was unsuccessful because 'c' is NULL.
+            //  This means that the reader is asleep. Therefore we don't
+            //  care about thread-safeness and update c in non-atomic
+            //  manner. We'll return false to let the caller know
+            //  that reader is sleeping.
+            self.pipe.c.store(self.f, Ordering::Release);
+            self.w = self.f;
+            return false;
+        }
+
+        //  Reader is alive. Nothing special to do now. Just move
+        //  the 'first un-flushed item' pointer to 'f'.
+        self.w = self.f;
+        true
+    }
+}
+
+impl<T: Send, const N: usize> ZmqYPipeReader<T> for ZmqYPipeReadEnd<T, N> {
+    fn check_read(&mut self) -> bool {
+        let front = unsafe { self.pipe.queue.front() };
+
+        //  Was the value prefetched already? If so, return.
+        if front != self.r && !self.r.is_null() {
+            return true;
+        }
+
+        //  There's no prefetched value, so let us prefetch more values.
+        //  Prefetching is to simply retrieve the
+        //  pointer from c in atomic fashion. If there are no
+        //  items to prefetch, set c to NULL (using compare-and-swap).
+        self.r = match self.pipe.c.compare_exchange(
+            front,
+            null_mut(),
+            Ordering::AcqRel,
+            Ordering::Acquire,
+        ) {
+            Ok(previous) | Err(previous) => previous,
+        };
+
+        //  If there are no elements prefetched, exit.
+        //  During pipe's lifetime r should never be NULL, however,
+        //  it can happen during pipe shutdown when items
+        //  are being deallocated.
+        front != self.r && !self.r.is_null()
+    }
+
+    fn read(&mut self) -> Option<T> {
+        //  Try to prefetch a value.
+        if !self.check_read() {
+            return None;
+        }
+
+        //  There was at least one value prefetched.
+        //  Return it to the caller.
+        let queue = &self.pipe.queue;
+        unsafe {
+            let value = queue.front().read();
+            queue.pop();
+            Some(value)
+        }
+    }
+
+    fn probe(&mut self, f: fn(&T) -> bool) -> bool {
+        let rc = self.check_read();
+        assert!(rc);
+
+        f(unsafe { &*self.pipe.queue.front() })
+    }
+}
+
+impl<T, const N: usize> Drop for ZmqYPipe<T, N> {
+    //  Runs once both ends are gone. Drops the items still in the pipe,
+    //  flushed or not; the terminator at the back holds none.
+    fn drop(&mut self) {
+        unsafe {
+            while self.queue.front() != self.queue.back() {
+                ptr::drop_in_place(self.queue.front());
+                self.queue.pop();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicUsize;
+    use std::thread;
+
+    #[derive(Debug)]
+    struct Counted(u64, Arc<AtomicUsize>);
+
+    impl Drop for Counted {
+        fn drop(&mut self) {
+            self.1.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    #[test]
+    fn flush_publishes_complete_items_only() {
+        let (mut writer, mut reader) = ypipe::<u64, 4>();
+        writer.write(1, false);
+        //  The reader has not looked at the pipe yet, so it isn't asleep.
+        assert!(writer.flush());
+        assert_eq!(reader.read(), Some(1));
+        //  Finding the pipe empty puts it to sleep.
+        assert!(!reader.check_read());
+        writer.write(2, true);
+        writer.write(3, false);
+        writer.write(4, true);
+        assert!(!writer.flush());
+        assert_eq!(reader.read(), Some(2));
+        assert_eq!(reader.read(), Some(3));
+        assert_eq!(reader.read(), None);
+        //  Item 4 is incomplete, there is nothing to flush.
+        assert!(writer.flush());
+    }
+
+    #[test]
+    fn rollback_across_chunks() {
+        let (mut writer, mut reader) = ypipe::<u64, 2>();
+        writer.write(0, false);
+        for i in 1..8 {
+            writer.write(i, true);
+        }
+        let mut unwritten = vec![];
+        while let Some(value) = writer.unwrite() {
+            unwritten.push(value);
+        }
+        assert_eq!(unwritten, (1..8).rev().collect::<Vec<_>>());
+        writer.write(9, false);
+        writer.flush();
+        assert!(reader.probe(|v| *v == 0));
+        assert_eq!(reader.read(), Some(0));
+        assert_eq!(reader.read(), Some(9));
+        assert!(!reader.check_read());
+    }
+
+    #[test]
+    fn drop_releases_pending_items() {
+        let drops = Arc::new(AtomicUsize::new(0));
+        let (mut writer, mut reader) = ypipe::<Counted, 3>();
+        for i in 0..10 {
+            writer.write(Counted(i, drops.clone()), i == 9);
+        }
+        writer.flush();
+        assert_eq!(reader.read().map(|item| item.0), Some(0));
+        assert_eq!(drops.load(Ordering::Relaxed), 1);
+        drop(writer);
+        assert_eq!(drops.load(Ordering::Relaxed), 1);
+        drop(reader);
+        assert_eq!(drops.load(Ordering::Relaxed), 10);
+    }
+
+    //  Writes items from another thread, waking the reader
+    //  whenever flush finds it asleep, and checks they arrive in order.
+    #[test]
+    fn threads_wake_sleeping_reader() {
+        let count = 100_000u64;
+        let (mut writer, mut reader) = ypipe::<u64, 256>();
+        let main = thread::current();
+        let worker = thread::spawn(move || {
+            for i in 0..count {
+                writer.write(i, false);
+                if !writer.flush() {
+                    main.unpark();
+                }
+            }
+        });
+        for i in 0..count {
+            let value = loop {
+                match reader.read() {
+                    Some(value) => break value,
+                    None => thread::park(),
+                }
+            };
+            assert_eq!(value, i);
+        }
+        worker.join().unwrap();
+    }
+}
@@ -0,0 +1,38 @@
+//  ZmqYPipeWriter and ZmqYPipeReader abstract the two ends of ZmqYPipe and
+//  ZmqYPipeConflate, one is selected according to the conflate socket option.
+//
+//  The lock-free pipes allow a single writer and a single reader at any
+//  specific moment. Each end belongs to exactly one ZmqPipe and its copies
+//  (see ZmqPipeEnd): the ends are neither Clone nor Sync and every operation
+//  takes &mut self, so that rule is kept by the compiler rather than by the
+//  callers.
+
+use std::fmt::Debug;
+
+pub trait ZmqYPipeWriter<T>: Debug + Send {
+    //  Write an item to the pipe. Don't flush it yet. If incomplete is
+    //  set to true the item is assumed to be continued by items
+    //  subsequently written to the pipe. Incomplete items are never
+    //  flushed down the stream.
+    fn write(&mut self, value: T, incomplete: bool);
+
+    //  Pop an incomplete item from the pipe, if there is one.
+    fn unwrite(&mut self) -> Option<T>;
+
+    //  Flush all the completed items into the pipe. Returns false if
+    //  the reader thread is sleeping. In that case, caller is obliged to
+    //  wake the reader up before using the pipe again.
+    fn flush(&mut self) -> bool;
+}
+
+pub trait ZmqYPipeReader<T>: Debug + Send {
+    //  Check whether item is available for reading.
+    fn check_read(&mut self) -> bool;
+
+    //  Reads an item from the pipe, if one is available.
+    fn read(&mut self) -> Option<T>;
+
+    //  Applies the function f to the first element in the pipe and
+    //  returns the value returned by f. The pipe mustn't be empty.
+    fn probe(&mut self, f: fn(&T) -> bool) -> bool;
+}
@@ -0,0 +1,196 @@
+//  Adapter for ZMQ_CONFLATE pipes: only the most recently written item
+//  can be read, older ones are dropped as newer ones arrive.
+//
+//  Where libzmq double-buffers the item under a mutex, the latest one is
+//  held in a single atomically swapped slot here: the writer swaps its
+//  item in and drops whatever the reader had not taken, the reader swaps
+//  it out. Items are never incomplete, so there is nothing to unwrite.
+//
+//  As in libzmq, the reader marks itself asleep whenever check_read finds
+//  the slot empty. flush wakes it only then, rather than on every item.
+
+use std::fmt;
+use std::ptr::null_mut;
+use std::sync::atomic::{AtomicBool, AtomicPtr, Ordering};
+use std::sync::Arc;
+
+use crate::ypipe_base::{ZmqYPipeReader, ZmqYPipeWriter};
+
+struct ZmqYPipeConflate<T> {
+    //  The latest item, NULL once read.
+    slot: AtomicPtr<T>,
+    //  Cleared by the reader when it finds nothing to read, set again by
+    //  the flush that has to wake it.
+    reader_awake: AtomicBool,
+}
+
+pub struct ZmqYPipeConflateWriteEnd<T> {
+    pipe: Arc<ZmqYPipeConflate<T>>,
+    //  Was anything written since the last flush?
+    unflushed: bool,
+}
+
+pub struct ZmqYPipeConflateReadEnd<T> {
+    pipe: Arc<ZmqYPipeConflate<T>>,
+}
+
+unsafe impl<T: Send> Send for ZmqYPipeConflate<T> {}
+unsafe impl<T: Send> Sync for ZmqYPipeConflate<T> {}
+
+//  Creates a conflating pipe, returning its write and read ends.
+pub fn ypipe_conflate<T: Send>() -> (ZmqYPipeConflateWriteEnd<T>, ZmqYPipeConflateReadEnd<T>) {
+    let pipe = Arc::new(ZmqYPipeConflate {
+        slot: AtomicPtr::new(null_mut()),
+        reader_awake: AtomicBool::new(true),
+    });
+    let writer = ZmqYPipeConflateWriteEnd {
+        pipe: pipe.clone(),
+        unflushed: false,
+    };
+    (writer, ZmqYPipeConflateReadEnd { pipe })
+}
+
+impl<T> ZmqYPipeConflate<T> {
+    fn take(&self) -> Option<T> {
+        let item = self.slot.swap(null_mut(), Ordering::SeqCst);
+        (!item.is_null()).then(|| *unsafe { Box::from_raw(item) })
+    }
+}
+
+impl<T> fmt::Debug for ZmqYPipeConflateWriteEnd<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ZmqYPipeConflateWriteEnd")
+            .finish_non_exhaustive()
+    }
+}
+
+impl<T> fmt::Debug for ZmqYPipeConflateReadEnd<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ZmqYPipeConflateReadEnd")
+            .finish_non_exhaustive()
+    }
+}
+
+impl<T: Send> ZmqYPipeWriter<T> for ZmqYPipeConflateWriteEnd<T> {
+    fn write(&mut self, value: T, _incomplete: bool) {
+        let item = Box::into_raw(Box::new(value));
+        let previous = self.pipe.slot.swap(item, Ordering::SeqCst);
+        if !previous.is_null() {
+            drop(unsafe { Box::from_raw(previous) });
+        }
+        self.unflushed = true;
+    }
+
+    fn unwrite(&mut self) -> Option<T> {
+        None
+    }
+
+    fn flush(&mut self) -> bool {
+        if !std::mem::take(&mut self.unflushed) {
+            return true;
+        }
+        //  The item went into the slot before this, so a reader that
+        //  marked itself asleep after it will still find the item.
+        self.pipe.reader_awake.swap(true, Ordering::SeqCst)
+    }
+}
+
+impl<T: Send> ZmqYPipeReader<T> for ZmqYPipeConflateReadEnd<T> {
+    fn check_read(&mut self) -> bool {
+        if !self.pipe.slot.load(Ordering::SeqCst).is_null() {
+            return true;
+        }
+        //  Go to sleep, then look once more: an item flushed in between
+        //  is either seen here or its flush wakes us up.
+        self.pipe.reader_awake.store(false, Ordering::SeqCst);
+        !self.pipe.slot.load(Ordering::SeqCst).is_null()
+    }
+
+    fn read(&mut self) -> Option<T> {
+        if !self.check_read() {
+            return None;
+        }
+        self.pipe.take()
+    }
+
+    //  The item is taken out while f looks at it, then put back unless
+    //  the writer has replaced it meanwhile, in which case f looks at the
+    //  newer one.
+    fn probe(&mut self, f: fn(&T) -> bool) -> bool {
+        let slot = &self.pipe.slot;
+        loop {
+            let item = slot.swap(null_mut(), Ordering::SeqCst);
+            assert!(!item.is_null());
+            let rc = f(unsafe { &*item });
+            match slot.compare_exchange(null_mut(), item, Ordering::SeqCst, Ordering::SeqCst) {
+                Ok(_) => return rc,
+                Err(_) => drop(unsafe { Box::from_raw(item) }),
+            }
+        }
+    }
+}
+
+impl<T> Drop for ZmqYPipeConflate<T> {
+    fn drop(&mut self) {
+        drop(self.take());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn only_latest_item_is_read() {
+        let (mut writer, mut reader) = ypipe_conflate::<u64>();
+        writer.write(1, false);
+        writer.write(2, true);
+        assert_eq!(writer.unwrite(), None);
+        assert!(writer.flush());
+        assert!(reader.probe(|v| *v == 2));
+        assert_eq!(reader.read(), Some(2));
+        assert_eq!(reader.read(), None);
+    }
+
+    #[test]
+    fn flush_wakes_only_a_sleeping_reader() {
+        let (mut writer, mut reader) = ypipe_conflate::<u64>();
+        //  Nothing written, nothing to wake for.
+        assert!(writer.flush());
+        assert!(!reader.check_read());
+        writer.write(1, false);
+        assert!(!writer.flush());
+        writer.write(2, false);
+        assert!(writer.flush());
+        assert_eq!(reader.read(), Some(2));
+        assert!(!reader.check_read());
+        writer.write(3, false);
+        assert!(!writer.flush());
+    }
+
+    #[test]
+    fn threads_see_increasing_items() {
+        let (mut writer, mut reader) = ypipe_conflate::<u64>();
+        let main = thread::current();
+        let worker = thread::spawn(move || {
+            for i in 0..100_000 {
+                writer.write(i, false);
+                if !writer.flush() {
+                    main.unpark();
+                }
+            }
+        });
+        let mut last = 0;
+        while last != 99_999 {
+            match reader.read() {
+                Some(value) => {
+                    assert!(value >= last);
+                    last = value;
+                }
+                None => thread::park(),
+            }
+        }
+        worker.join().unwrap();
+    }
+}
@@ -0,0 +1,192 @@
+//  yqueue is an efficient queue implementation. The main goal is
+//  to minimise number of allocations/deallocations needed. Thus yqueue
+//  allocates/deallocates elements in batches of N.
+//
+//  yqueue allows one thread to use push/back function and another one
+//  to use pop/front functions. However, user must ensure that there's no
+//  pop on the empty queue and that both threads don't access the same
+//  element in unsynchronised manner. That is why all of them are unsafe;
+//  ZmqYPipe is the one user.
+//
+//  Slots are left uninitialised until written: back() is where the next
+//  item goes, and it only becomes part of the queue with push(). Items
+//  still in the queue when it is dropped are the owner's to drop.
+//
+//  T is the type of the object in the queue.
+//  N is granularity of the queue (how many pushes have to be done till
+//  actual memory allocation is required).
+
+use std::cell::UnsafeCell;
+use std::mem::MaybeUninit;
+use std::ptr::{addr_of_mut, null_mut};
+use std::sync::atomic::{AtomicPtr, Ordering};
+
+//  Individual memory chunk to hold N elements.
+struct ZmqYQueueChunk<T, const N: usize> {
+    values: [MaybeUninit<T>; N],
+    prev: *mut ZmqYQueueChunk<T, N>,
+    next: *mut ZmqYQueueChunk<T, N>,
+}
+
+//  A position in the queue: a chunk and the index within it.
+struct ZmqYQueuePos<T, const N: usize> {
+    chunk: *mut ZmqYQueueChunk<T, N>,
+    pos: usize,
+}
+
+pub struct ZmqYQueue<T, const N: usize> {
+    //  Back position may point to invalid memory if the queue is empty,
+    //  while begin & end positions are always valid. Begin position is
+    //  accessed exclusively by queue reader (front/pop), while back and
+    //  end positions are accessed exclusively by queue writer (back/push).
+    begin: UnsafeCell<ZmqYQueuePos<T, N>>,
+    back: UnsafeCell<ZmqYQueuePos<T, N>>,
+    end: UnsafeCell<ZmqYQueuePos<T, N>>,
+    //  People are likely to produce and consume at similar rates. In
+    //  this scenario holding onto the most recently freed chunk saves
+    //  us from having to call malloc/free.
+    spare_chunk: AtomicPtr<ZmqYQueueChunk<T, N>>,
+}
+
+unsafe impl<T: Send, const N: usize> Send for ZmqYQueue<T, N> {}
+unsafe impl<T: Send, const N: usize> Sync for ZmqYQueue<T, N> {}
+
+impl<T, const N: usize> ZmqYQueue<T, N> {
+    //  Create the queue.
+    pub fn new() -> Self {
+        assert!(N > 0);
+        let chunk = Self::allocate_chunk();
+        Self {
+            begin: UnsafeCell::new(ZmqYQueuePos { chunk, pos: 0 }),
+            back: UnsafeCell::new(ZmqYQueuePos {
+                chunk: null_mut(),
+                pos: 0,
+            }),
+            end: UnsafeCell::new(ZmqYQueuePos { chunk, pos: 0 }),
+            spare_chunk: AtomicPtr::new(null_mut()),
+        }
+    }
+
+    //  Returns the slot at the front of the queue. The caller must make
+    //  sure the queue is not empty. Reader only.
+    pub unsafe fn front(&self) -> *mut T {
+        let begin = &*self.begin.get();
+        (*begin.chunk).values[begin.pos].as_mut_ptr()
+    }
+
+    //  Returns the slot at the back of the queue. The caller must make
+    //  sure the queue is not empty. Writer only.
+    pub unsafe fn back(&self) -> *mut T {
+        let back = &*self.back.get();
+        (*back.chunk).values[back.pos].as_mut_ptr()
+    }
+
+    //  Adds an element to the back end of the queue. Writer only.
+    pub unsafe fn push(&self) {
+        let back = &mut *self.back.get();
+        let end = &mut *self.end.get();
+        back.chunk = end.chunk;
+        back.pos = end.pos;
+
+        end.pos += 1;
+        if end.pos != N {
+            return;
+        }
+
+        let sc = self.spare_chunk.swap(null_mut(), Ordering::AcqRel);
+        let next = if sc.is_null() {
+            Self::allocate_chunk()
+        } else {
+            sc
+        };
+        (*end.chunk).next = next;
+        (*next).prev = end.chunk;
+        end.chunk = next;
+        end.pos = 0;
+    }
+
+    //  Removes element from the back end of the queue. In other words
+    //  it rollbacks last push to the queue. Take care: Caller is
+    //  responsible for destroying the object being unpushed.
+    //  The caller must also guarantee that the queue isn't empty when
+    //  unpush is called. It cannot be done automatically as the read
+    //  side of the queue can be managed by different, completely
+    //  unsynchronised thread. Writer only.
+    pub unsafe fn unpush(&self) {
+        let back = &mut *self.back.get();
+        let end = &mut *self.end.get();
+
+        //  First, move 'back' one position backwards.
+        if back.pos > 0 {
+            back.pos -= 1;
+        } else {
+            back.pos = N - 1;
+            back.chunk = (*back.chunk).prev;
+        }
+
+        //  Now, move 'end' position backwards. Note that obsolete end chunk
+        //  is not used as a spare chunk. The analysis shows that doing so
+        //  would require free and atomic operation per chunk deallocated
+        //  instead of a simple free.
+        if end.pos > 0 {
+            end.pos -= 1;
+        } else {
+            end.pos = N - 1;
+            end.chunk = (*end.chunk).prev;
+            drop(Box::from_raw((*end.chunk).next));
+            (*end.chunk).next = null_mut();
+        }
+    }
+
+    //  Removes an element from the front end of the queue. The caller has
+    //  moved the value out of the slot already. Reader only.
+    pub unsafe fn pop(&self) {
+        let begin = &mut *self.begin.get();
+        begin.pos += 1;
+        if begin.pos == N {
+            let o = begin.chunk;
+            begin.chunk = (*begin.chunk).next;
+            begin.pos = 0;
+
+            //  'o' has been more recently used than spare_chunk,
+            //  so for cache reasons we'll get rid of the spare and
+            //  use 'o' as the spare.
+            let cs = self.spare_chunk.swap(o, Ordering::AcqRel);
+            if !cs.is_null() {
+                drop(Box::from_raw(cs));
+            }
+        }
+    }
+
+    fn allocate_chunk() -> *mut ZmqYQueueChunk<T, N> {
+        //  The values need no initialising; only the links do.
+        let mut chunk = Box::<ZmqYQueueChunk<T, N>>::new_uninit();
+        unsafe {
+            let p = chunk.as_mut_ptr();
+            addr_of_mut!((*p).prev).write(null_mut());
+            addr_of_mut!((*p).next).write(null_mut());
+            Box::into_raw(chunk.assume_init())
+        }
+    }
+}
+
+impl<T, const N: usize> Drop for ZmqYQueue<T, N> {
+    //  Destroy the queue.
+    fn drop(&mut self) {
+        let end = self.end.get_mut().chunk;
+        let mut chunk = self.begin.get_mut().chunk;
+        loop {
+            let next = unsafe { (*chunk).next };
+            unsafe { drop(Box::from_raw(chunk)) };
+            if chunk == end {
+                break;
+            }
+            chunk = next;
+        }
+
+        let sc = self.spare_chunk.swap(null_mut(), Ordering::AcqRel);
+        if !sc.is_null() {
+            unsafe { drop(Box::from_raw(sc)) };
+        }
+    }
+}
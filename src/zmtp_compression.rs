@@ -0,0 +1,258 @@
+//  Per-frame compression of ZMTP 2.0/3.x data frames.
+//
+//  With ZMQ_ZMTP_COMPRESSION set, the READY/INITIATE metadata carries a
+//  Compression property listing the algorithms we can read. Once both sides
+//  have listed one, data frames of at least ZMQ_ZMTP_COMPRESSION_THRESHOLD
+//  bytes are sent compressed if that makes them smaller, marked with the
+//  otherwise reserved V2_COMPRESSED_FLAG. Peers that don't advertise the
+//  property never see the flag, so older libzmq versions keep working.
+//
+//  Commands, the downgraded subscriptions of ZMTP 3.0 among them, are never
+//  compressed. Under CURVE and GSSAPI every message travels inside a MESSAGE
+//  command and would not compress anyway, so the property is only sent with
+//  NULL and PLAIN.
+//
+//  A compressed body is the uncompressed size as a 32-bit little-endian
+//  integer followed by an lz4 block. Frames larger than
+//  ZMTP_COMPRESSION_MAX_SIZE are never compressed, so a compressed frame
+//  claiming more is rejected even when ZMQ_MAXMSGSIZE is not set.
+
+use anyhow::bail;
+
+use crate::context::ZmqContext;
+use crate::defines::{ZMQ_NULL, ZMQ_PLAIN};
+use crate::message::{ZmqMessage, ZMQ_MSG_COMMAND};
+
+pub const ZMTP_COMPRESSION_LZ4: &str = "lz4";
+
+const ZMTP_COMPRESSION_SIZE_LEN: usize = 4;
+
+pub const ZMTP_COMPRESSION_MAX_SIZE: usize = 256 << 20;
+
+//  An lz4 block expands at most about 255 times.
+const LZ4_MAX_RATIO: usize = 255;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ZmqZmtpCompression {
+    Lz4,
+}
+
+impl ZmqZmtpCompression {
+    pub fn name(&self) -> &'static str {
+        match self {
+            ZmqZmtpCompression::Lz4 => ZMTP_COMPRESSION_LZ4,
+        }
+    }
+}
+
+//  The Compression property to advertise, if any.
+pub fn zmtp_compression_offer(ctx: &ZmqContext) -> Option<&'static str> {
+    (ctx.zmtp_compression
+        && (ctx.mechanism == ZMQ_NULL as i32 || ctx.mechanism == ZMQ_PLAIN as i32))
+        .then_some(ZmqZmtpCompression::Lz4.name())
+}
+
+//  The algorithm both sides can read, given the peer's Compression
+//  property; None if either side didn't advertise one.
+pub fn zmtp_compression_negotiate(
+    ctx: &ZmqContext,
+    peer: Option<&str>,
+) -> Option<ZmqZmtpCompression> {
+    zmtp_compression_offer(ctx)?;
+    peer?.split(',').map(str::trim).find_map(|name| match name {
+        ZMTP_COMPRESSION_LZ4 => Some(ZmqZmtpCompression::Lz4),
+        _ => None,
+    })
+}
+
+//  Compresses the data frames one side sends.
+#[derive(Debug, Clone)]
+pub struct ZmqZmtpCompressor {
+    pub algorithm: ZmqZmtpCompression,
+    //  Smaller frames are sent as they are.
+    pub threshold: usize,
+}
+
+impl ZmqZmtpCompressor {
+    pub fn new(algorithm: ZmqZmtpCompression, threshold: i32) -> Self {
+        Self {
+            algorithm,
+            threshold: threshold.max(0) as usize,
+        }
+    }
+
+    //  The compressed body of 'msg', or None if it goes out uncompressed.
+    pub fn compress(&self, msg: &ZmqMessage) -> Option<Vec<u8>> {
+        let data = msg.data();
+        if (msg.flags() & ZMQ_MSG_COMMAND) != 0
+            || msg.is_subscribe()
+            || msg.is_cancel()
+            || data.len() < self.threshold
+            || data.len() > ZMTP_COMPRESSION_MAX_SIZE
+        {
+            return None;
+        }
+        let compressed = match self.algorithm {
+            ZmqZmtpCompression::Lz4 => lz4_flex::block::compress_prepend_size(data),
+        };
+        //  Incompressible data is better off as it is.
+        (compressed.len() < data.len()).then_some(compressed)
+    }
+}
+
+//  Decompresses the data frames one side receives.
+#[derive(Debug, Clone)]
+pub struct ZmqZmtpDecompressor {
+    pub algorithm: ZmqZmtpCompression,
+}
+
+impl ZmqZmtpDecompressor {
+    pub fn new(algorithm: ZmqZmtpCompression) -> Self {
+        Self { algorithm }
+    }
+
+    //  Largest frame that may arrive compressed, given the maximum message
+    //  size (negative for no limit).
+    fn max_size(max_size: i64) -> usize {
+        match usize::try_from(max_size) {
+            Ok(max_size) => max_size.min(ZMTP_COMPRESSION_MAX_SIZE),
+            Err(_) => ZMTP_COMPRESSION_MAX_SIZE,
+        }
+    }
+
+    //  Largest compressed body a frame of up to 'max_size' bytes may have.
+    pub fn max_compressed_size(&self, max_size: i64) -> u64 {
+        let max_size = Self::max_size(max_size) as u64;
+        match self.algorithm {
+            ZmqZmtpCompression::Lz4 => {
+                ZMTP_COMPRESSION_SIZE_LEN as u64 + max_size + max_size / 255 + 16
+            }
+        }
+    }
+
+    //  Decompresses a frame body, failing with EMSGSIZE if it would grow
+    //  past 'max_size' bytes (negative for no limit).
+    pub fn decompress(&self, data: &[u8], max_size: i64) -> anyhow::Result<Vec<u8>> {
+        if data.len() < ZMTP_COMPRESSION_SIZE_LEN {
+            bail!("EPROTO: truncated compressed frame");
+        }
+        let (size, block) = data.split_at(ZMTP_COMPRESSION_SIZE_LEN);
+        let size = u32::from_le_bytes([size[0], size[1], size[2], size[3]]) as usize;
+        //  Both checked before anything is allocated for it.
+        let max_size = Self::max_size(max_size);
+        if size > max_size {
+            bail!("EMSGSIZE: decompressed frame exceeds {} bytes", max_size);
+        }
+        if size > block.len() * LZ4_MAX_RATIO + 16 {
+            bail!("EPROTO: malformed compressed frame");
+        }
+        let decompressed = match self.algorithm {
+            ZmqZmtpCompression::Lz4 => lz4_flex::block::decompress(block, size),
+        };
+        match decompressed {
+            Ok(out) if out.len() == size => Ok(out),
+            _ => bail!("EPROTO: malformed compressed frame"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::Bytes;
+
+    fn message(data: &[u8]) -> ZmqMessage {
+        let mut msg = ZmqMessage::default();
+        msg.init_bytes(Bytes::copy_from_slice(data)).unwrap();
+        msg
+    }
+
+    #[test]
+    fn negotiate_needs_both_sides() {
+        let mut ctx = ZmqContext {
+            mechanism: ZMQ_NULL as i32,
+            ..Default::default()
+        };
+        assert_eq!(zmtp_compression_negotiate(&ctx, Some("lz4")), None);
+        ctx.zmtp_compression = true;
+        assert_eq!(zmtp_compression_offer(&ctx), Some("lz4"));
+        assert_eq!(zmtp_compression_negotiate(&ctx, None), None);
+        assert_eq!(zmtp_compression_negotiate(&ctx, Some("zstd")), None);
+        assert_eq!(
+            zmtp_compression_negotiate(&ctx, Some("zstd, lz4")),
+            Some(ZmqZmtpCompression::Lz4)
+        );
+    }
+
+    #[test]
+    fn round_trip() {
+        let data = b"abcdefgh".repeat(1024);
+        let compressor = ZmqZmtpCompressor::new(ZmqZmtpCompression::Lz4, 1024);
+        let decompressor = ZmqZmtpDecompressor::new(ZmqZmtpCompression::Lz4);
+        let compressed = compressor.compress(&message(&data)).unwrap();
+        assert!(compressed.len() < data.len());
+        assert_eq!(decompressor.decompress(&compressed, -1).unwrap(), data);
+        assert_eq!(
+            decompressor
+                .decompress(&compressed, data.len() as i64)
+                .unwrap(),
+            data
+        );
+    }
+
+    #[test]
+    fn small_commands_and_incompressible_frames_go_as_they_are() {
+        let compressor = ZmqZmtpCompressor::new(ZmqZmtpCompression::Lz4, 1024);
+        assert!(compressor.compress(&message(&[0u8; 1023])).is_none());
+
+        let mut command = message(&[0u8; 4096]);
+        command.set_flags(ZMQ_MSG_COMMAND);
+        assert!(compressor.compress(&command).is_none());
+
+        //  A xorshift sequence doesn't compress.
+        let mut x: u32 = 2463534242;
+        let noise: Vec<u8> = (0..4096)
+            .map(|_| {
+                x ^= x << 13;
+                x ^= x >> 17;
+                x ^= x << 5;
+                x as u8
+            })
+            .collect();
+        assert!(compressor.compress(&message(&noise)).is_none());
+    }
+
+    #[test]
+    fn decompress_limits() {
+        let decompressor = ZmqZmtpDecompressor::new(ZmqZmtpCompression::Lz4);
+        let compressed = lz4_flex::block::compress_prepend_size(&[7u8; 10000]);
+
+        //  Over ZMQ_MAXMSGSIZE.
+        let err = decompressor.decompress(&compressed, 9999).unwrap_err();
+        assert!(err.to_string().starts_with("EMSGSIZE"));
+
+        //  A tiny frame claiming 4 GiB, with and without ZMQ_MAXMSGSIZE.
+        let bomb = [0xff, 0xff, 0xff, 0xff, 0x1f, 0x00];
+        let err = decompressor.decompress(&bomb, -1).unwrap_err();
+        assert!(err.to_string().starts_with("EMSGSIZE"));
+        let err = decompressor.decompress(&bomb, i64::MAX).unwrap_err();
+        assert!(err.to_string().starts_with("EMSGSIZE"));
+
+        //  More than the block could expand to.
+        let mut inflated = 100_000u32.to_le_bytes().to_vec();
+        inflated.extend_from_slice(&compressed[4..]);
+        let err = decompressor.decompress(&inflated, -1).unwrap_err();
+        assert!(err.to_string().starts_with("EPROTO"));
+
+        //  Truncated, and a size that doesn't match the block.
+        assert!(decompressor.decompress(&[1, 0], -1).is_err());
+        let mut wrong = 9999u32.to_le_bytes().to_vec();
+        wrong.extend_from_slice(&compressed[4..]);
+        assert!(decompressor.decompress(&wrong, -1).is_err());
+
+        assert_eq!(
+            decompressor.max_compressed_size(-1),
+            decompressor.max_compressed_size(ZMTP_COMPRESSION_MAX_SIZE as i64)
+        );
+    }
+}
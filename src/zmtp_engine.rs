@@ -67,9 +67,11 @@ use std::mem;
 use bincode::options;
 use libc::{EAGAIN, memcmp, memcpy, memset};
 use crate::context::ZmqContext;
-use crate::curve_server::curve_ZmqServer;
+use crate::curve_client::ZmqCurveClient;
+use crate::curve_server::ZmqCurveServer;
 use crate::endpoint_uri::EndpointUriPair;
 use crate::defines::ZmqFileDesc;
+use crate::engine_interface::ZmqErrorReason;
 use crate::gssapi_client::ZmqGssApiClient;
 use crate::gssapi_server::ZmqGssApiServer;
 use crate::message::{ZMQ_MSG_CANCEL, ZMQ_MSG_COMMAND, ZMQ_MSG_PING, ZMQ_MSG_PONG, ZMQ_MSG_ROUTING_ID, ZMQ_MSG_SUBSCRIBE, ZmqMessage};
@@ -82,8 +84,9 @@ use crate::v1_encoder::ZmqV1Encoder;
 use crate::v2_decoder::ZmqV2Decoder;
 use crate::v2_encoder::ZmqV2Encoder;
 use crate::v3_1_encoder::ZmqV31Encoder;
-use crate::defines::{ZMQ_CURVE, ZMQ_GSSAPI, ZMQ_NULL, ZMQ_PLAIN, ZMQ_PROTOCOL_ERROR_ZMTP_MECHANISM_MISMATCH, ZMQ_PUB, ZMQ_XPUB};
+use crate::defines::{ZMQ_CURVE, ZMQ_GSSAPI, ZMQ_MECHANISM_CUSTOM, ZMQ_NULL, ZMQ_PLAIN, ZMQ_PROTOCOL_ERROR_ZMTP_MECHANISM_MISMATCH, ZMQ_PROTOCOL_ERROR_ZMTP_UNSPECIFIED, ZMQ_PUB, ZMQ_XPUB};
 use crate::mechanism::ZmqMechanism;
+use crate::mechanism_registry::{mechanism_greeting_name, ZMTP_MECHANISM_NAME_SIZE};
 use crate::zmtp_engine::ZmtpRevisions::ZMTP_2_0;
 
 //  Protocol revisions
@@ -338,6 +341,9 @@ impl ZmqZmtpEngine {
                         copy_bytes(self._outpos, self._outsize, b"GSSAPI", 0, 6);
                     } else if (self._options.mechanism == ZMQ_CURVE) {
                         copy_bytes(self._outpos, self._outsize, b"CURVE", 0, 5);
+                    } else if (self._options.mechanism == ZMQ_MECHANISM_CUSTOM as i32) {
+                        let name = mechanism_greeting_name(&self._options.custom_mechanism);
+                        copy_bytes(self._outpos, self._outsize, &name, 0, ZMTP_MECHANISM_NAME_SIZE);
                     }
                     self._outsize += 20;
                     set_bytes(self._outpos, self._outsize, 0, 32);
@@ -477,10 +483,10 @@ impl ZmqZmtpEngine {
         else if (self._options.mechanism == ZMQ_CURVE && cmp_bytes(&self._greeting_recv, 12,
                                                                    b"CURVE\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0", 0, 20) == 0) {
             if (self._options.as_server) {
-                self._mechanism = curve_ZmqServer::new(
-                    session(), self._peer_address, self._options, downgrade_sub_);
+                self._mechanism = ZmqCurveServer::new(
+                    session(), self._peer_address, self._options, downgrade_sub_)?;
             } else {
-                self._mechanism = curve_client_t::new(session(), self._options, downgrade_sub_);
+                self._mechanism = ZmqCurveClient::new(session(), self._options, downgrade_sub_)?;
             }
             // alloc_assert (self._mechanism);
         }
@@ -496,6 +502,32 @@ impl ZmqZmtpEngine {
             // alloc_assert (self._mechanism);
         }
 // #endif
+        else if (self._options.mechanism == ZMQ_MECHANISM_CUSTOM as i32 && cmp_bytes(&self._greeting_recv, 12,
+                                                                   &mechanism_greeting_name(&self._options.custom_mechanism), 0, 20) == 0) {
+            //  Both sides announced the same registered mechanism, unless it
+            //  was unregistered since our greeting went out.
+            let factory = match ctx.mechanism_registry.get(&self._options.custom_mechanism) {
+                Some(factory) => factory,
+                None => {
+                    let endpoint = session().get_endpoint().clone();
+                    session().get_socket().event_handshake_failed_protocol(
+                        &endpoint, ZMQ_PROTOCOL_ERROR_ZMTP_MECHANISM_MISMATCH as i32);
+                    self.stream_engine_base.error(ZmqErrorReason::ProtocolError);
+                    return false;
+                }
+            };
+            match factory.create(session(), self._peer_address, ctx,
+                                 self._options.as_server != 0, downgrade_sub_) {
+                Ok(mechanism) => self._mechanism = mechanism,
+                Err(_) => {
+                    let endpoint = session().get_endpoint().clone();
+                    session().get_socket().event_handshake_failed_protocol(
+                        &endpoint, ZMQ_PROTOCOL_ERROR_ZMTP_UNSPECIFIED as i32);
+                    self.stream_engine_base.error(ZmqErrorReason::ProtocolError);
+                    return false;
+                }
+            }
+        }
         else {
             // TODO
             // socket().event_handshake_failed_protocol (
@@ -658,27 +690,10 @@ impl ZmqZmtpEngine {
     }
 
     pub fn process_command_message(&mut self, msg: &mut ZmqMessage) -> i32 {
-        let cmd_name_size = msg.data()[0];
-        let ping_name_size = ZmqMessage::PING_CMD_NAME_SIZE - 1;
-        let sub_name_size = ZmqMessage::SUB_CMD_NAME_SIZE - 1;
-        let cancel_name_size = ZmqMessage::CANCEL_CMD_NAME_SIZE - 1;
-        //  Malformed command
-        if msg.size() < (cmd_name_size + mem::size_of_val(&cmd_name_size)) as usize {
-            return -1;
-        }
-
-        let cmd_name = msg.data()[1..];
-        if cmd_name_size == ping_name_size && cmp_bytes(&cmd_name, 0, b"PING", 0, cmd_name_size as usize) == 0 {
-            msg.set_flags(ZMQ_MSG_PING);
-        }
-        if (cmd_name_size == ping_name_size && cmp_bytes(&cmd_name, 0, b"PONG", 0, cmd_name_size as usize) == 0) {
-            msg.set_flags(ZMQ_MSG_PONG);
-        }
-        if (cmd_name_size == sub_name_size && cmp_bytes(&cmd_name, 0, b"SUBSCRIBE", 0, cmd_name_size as usize) == 0) {
-            msg.set_flags(ZMQ_MSG_SUBSCRIBE);
-        }
-        if (cmd_name_size == cancel_name_size && cmp_bytes(&cmd_name, 0, b"CANCEL", 0, cmd_name_size as usize) == 0) {
-            msg.set_flags(ZMQ_MSG_CANCEL);
+        match command_type(msg.data()) {
+            Some(flag) => msg.set_flags(flag),
+            //  Malformed command
+            None => return -1,
         }
 
         if (msg.is_ping() || msg.is_pong()) {
@@ -689,9 +704,21 @@ impl ZmqZmtpEngine {
     }
 }
 
+//  Maps the name of a ZMTP 3.1 command frame (a length byte followed by the
+//  name) to the message flag the sockets act upon. Unknown commands map to
+//  no flag; None means the frame is too short to hold its own name.
+pub fn command_type(data: &[u8]) -> Option<u8> {
+    let cmd_name_size = *data.first()? as usize;
+    if data.len() < cmd_name_size + 1 {
+        return None;
+    }
 
-
-
-
-
-
+    let flag = match &data[1..cmd_name_size + 1] {
+        b"PING" => ZMQ_MSG_PING,
+        b"PONG" => ZMQ_MSG_PONG,
+        b"SUBSCRIBE" => ZMQ_MSG_SUBSCRIBE,
+        b"CANCEL" => ZMQ_MSG_CANCEL,
+        _ => 0,
+    };
+    Some(flag)
+}